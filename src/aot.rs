@@ -0,0 +1,564 @@
+//! Generates Rust source that runs a loaded project without the sb3 file.
+//!
+//! The generated `load` function rebuilds the targets and their blocks in the same
+//! order as the loaded VM, so block ids match. Every compiled script and procedure
+//! becomes a function with a `match` on the program counter, which runs until it
+//! yields and continues from the same state in the next step. Scripts the bytecode
+//! compiler skips keep running in the tree-walking interpreter.
+
+use crate::blocks::BlockRegistry;
+use crate::bytecode::*;
+use crate::*;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Frames per second of generated programs, like the stage of Scratch.
+pub const FRAME_RATE: u32 = 30;
+
+#[derive(Debug, Clone)]
+pub enum AotError {
+    /// A block has an opcode that is not in the registry and was not made by the loader,
+    /// so its function can not be named in the generated code.
+    UnknownBlockFunction { target: String, opcode: String },
+}
+
+impl std::fmt::Display for AotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownBlockFunction { target, opcode } => write!(
+                f,
+                "unknown block function of `{}` in target `{}`",
+                opcode, target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AotError {}
+
+/// Generate a module with a `pub fn load(vm: &mut VirtualMachine)` that loads the project of `vm`.
+///
+/// Blocks of extensions are looked up in the registry of the VM passed to `load`,
+/// so register them before loading.
+pub fn generate_module(vm: &VirtualMachine) -> Result<String, AotError> {
+    let (program, _) = compile_program(&vm.registry, &vm.targets, false);
+    let functions = BlockFunctions::new(&vm.registry);
+    let mut out = String::new();
+    out.push_str("// Generated by `clipcc_rust_vm::aot`, do not edit.\n\n");
+    out.push_str("use clipcc_rust_vm::blocks::BlockRegistry;\n");
+    out.push_str("use clipcc_rust_vm::bytecode::*;\n");
+    out.push_str("use clipcc_rust_vm::*;\n\n");
+
+    let target_ids = vm
+        .targets
+        .iter()
+        .filter(|(_, target)| !target.deleted)
+        .map(|(tid, _)| tid)
+        .collect::<Vec<_>>();
+    out.push_str("/// Load the project into an empty `vm`.\n");
+    out.push_str("pub fn load(vm: &mut VirtualMachine) {\n");
+    for (index, &target_id) in target_ids.iter().enumerate() {
+        if target_id == vm.stage_id {
+            writeln!(out, "    let t{} = vm.stage_id;", index).unwrap();
+            writeln!(
+                out,
+                "    vm.targets[t{}] = target_{}(&vm.registry);",
+                index, index
+            )
+            .unwrap();
+            out.push_str("    vm.resync_stage();\n");
+        } else {
+            writeln!(
+                out,
+                "    let t{} = aot::add_target(vm, target_{}(&vm.registry));",
+                index, index
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("    let mut program = CompiledProgram::default();\n");
+    let mut chunks = Vec::new();
+    for (index, &target_id) in target_ids.iter().enumerate() {
+        for (kind, map) in [
+            ("scripts", &program.scripts),
+            ("procedures", &program.procedures),
+        ] {
+            let mut entries = map
+                .iter()
+                .filter(|((tid, _), _)| *tid == target_id)
+                .map(|((_, bid), chunk)| (bid.index(), chunk))
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|(bid, _)| *bid);
+            for (bid, chunk) in entries {
+                let name = format!("{}_{}_{}", &kind[..kind.len() - 1], index, bid);
+                writeln!(
+                    out,
+                    "    program.{}.insert((t{}, aot::block_id(&vm.targets[t{}], {})), aot::chunk({}, {}));",
+                    kind, index, index, bid, chunk.slots, name
+                )
+                .unwrap();
+                chunks.push((name, chunk.clone()));
+            }
+        }
+    }
+    out.push_str("    vm.bytecode = Some(program);\n");
+    out.push_str("    vm.refresh_hats();\n");
+    out.push_str("}\n");
+
+    for (index, &target_id) in target_ids.iter().enumerate() {
+        out.push('\n');
+        write_target(&mut out, index, &vm.targets[target_id], &functions)?;
+    }
+    for (name, chunk) in chunks {
+        out.push('\n');
+        write_chunk(&mut out, &name, &chunk);
+    }
+    Ok(out)
+}
+
+/// Generate a program that loads the project of `vm`, clicks the green flag and runs until
+/// all threads finish, paced by `run_frames`.
+pub fn generate_program(vm: &VirtualMachine) -> Result<String, AotError> {
+    let mut out = generate_module(vm)?;
+    out.push_str(
+        "
+fn main() {
+    let mut vm = VirtualMachine::default();
+    load(&mut vm);
+    vm.start_flag();
+    aot::run_frames(&mut vm);
+}
+",
+    );
+    Ok(out)
+}
+
+/// Run `vm` until all threads finish, at `FRAME_RATE` frames per second.
+///
+/// Like the sequencer of scratch-vm, a frame steps until a block changes the stage or most
+/// of the frame is used, and then sleeps for the rest of it. While every thread waits for a
+/// timer, it sleeps until the first one wakes up, like `runner::run_vm`.
+pub fn run_frames(vm: &mut VirtualMachine) {
+    let frame = Duration::from_secs(1) / FRAME_RATE;
+    while !vm.is_idle() {
+        let started = Instant::now();
+        vm.runtime.redraw_requested = false;
+        let waiting = loop {
+            vm.runtime.clear_wait();
+            vm.step();
+            let waiting = vm.runtime.waiting_threads >= vm.threads.len();
+            if vm.is_idle() || waiting || vm.runtime.redraw_requested {
+                break waiting;
+            }
+            if started.elapsed() >= frame * 3 / 4 {
+                break false;
+            }
+        };
+        if vm.is_idle() {
+            break;
+        }
+        let mut sleep = frame.saturating_sub(started.elapsed());
+        if let Some(wait) = vm.runtime.min_wait_time.filter(|_| waiting) {
+            sleep = sleep.max(wait);
+        }
+        std::thread::sleep(sleep);
+    }
+}
+
+/// Add a sprite for generated code.
+pub fn add_target(vm: &mut VirtualMachine, target: Target) -> TargetId {
+    vm.new_target(target).0
+}
+
+/// Add a block for generated code. Its arguments and next block are set after all blocks are added.
+pub fn add_block(
+    blocks: &mut Arena<Block>,
+    opcode: &str,
+    toplevel: bool,
+    block_function: BlockFunction,
+) -> BlockId {
+    blocks.alloc_with_id(|id| Block {
+        self_id: id,
+        toplevel,
+        arguments: vec![],
+        opcode: opcode.into(),
+        block_function,
+        next: None,
+        block_id: String::new(),
+    })
+}
+
+/// The function of `opcode` in `registry`, or a block that does nothing if it is not registered.
+pub fn registry_function(registry: &BlockRegistry, opcode: &str) -> BlockFunction {
+    registry
+        .get(opcode)
+        .map(|info| info.block_function)
+        .unwrap_or(blocks::noop)
+}
+
+/// The id of the block at `index` in the arena of `target`.
+pub fn block_id(target: &Target, index: usize) -> BlockId {
+    let arena_id = DefaultArenaBehavior::<Block>::arena_id(target.blocks.next_id());
+    DefaultArenaBehavior::<Block>::new_id(arena_id, index)
+}
+
+/// A chunk run by a generated function.
+pub fn chunk(slots: usize, function: ChunkFunction) -> Arc<Chunk> {
+    Arc::new(Chunk {
+        code: Vec::new(),
+        slots,
+        function: Some(function),
+    })
+}
+
+/// Names block functions in the generated code by the opcode of the block.
+///
+/// Function addresses can not tell the functions apart, since optimized builds
+/// merge functions with the same code.
+struct BlockFunctions<'a> {
+    registry: &'a BlockRegistry,
+}
+
+impl<'a> BlockFunctions<'a> {
+    fn new(registry: &'a BlockRegistry) -> Self {
+        Self { registry }
+    }
+
+    /// An expression for the function of `block`, with `f` looking up an opcode in the registry.
+    fn expression(&self, target: &Target, block: &Block) -> Result<String, AotError> {
+        if block.block_id == crate::editor::DELETED {
+            return Ok("blocks::noop".into());
+        }
+        // Blocks the loader makes with its own functions
+        let path = match block.opcode.as_str() {
+            "argument_reporter_string_number" => "core_blocks::argument_reporter_string_number",
+            "argument_reporter_boolean" => "core_blocks::argument_reporter_boolean",
+            "procedures_call" => "core_blocks::procedures_call",
+            "procedures_call_return" => "core_blocks::procedures_call_return",
+            "procedures_definition" => "core_blocks::procedures_definition",
+            "procedures_return_definition" => "core_blocks::procedures_definition_return",
+            // Blocks with an unknown opcode
+            "" => "blocks::noop",
+            opcode if self.registry.get(opcode).is_some() => return Ok(format!("f({:?})", opcode)),
+            _ => {
+                return Err(AotError::UnknownBlockFunction {
+                    target: target.name.to_owned(),
+                    opcode: block.opcode.to_owned(),
+                })
+            }
+        };
+        Ok(path.into())
+    }
+}
+
+fn number_literal(n: f64) -> String {
+    if n.is_nan() {
+        "f64::NAN".into()
+    } else if n == f64::INFINITY {
+        "f64::INFINITY".into()
+    } else if n == f64::NEG_INFINITY {
+        "f64::NEG_INFINITY".into()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+/// A Rust expression for `value`, with `block` naming a block id.
+fn value_literal(value: &BlockValue, block: impl Fn(BlockId) -> String) -> String {
+    match value {
+        BlockValue::Number(n) => format!("BlockValue::Number({})", number_literal(*n)),
+        BlockValue::String(s) => format!("BlockValue::String({:?}.into())", s),
+        BlockValue::Boolean(b) => format!("BlockValue::Boolean({})", b),
+        BlockValue::BlockId(bid) => format!("BlockValue::BlockId({})", block(*bid)),
+        BlockValue::Undefined => "BlockValue::Undefined".into(),
+    }
+}
+
+fn write_target(
+    out: &mut String,
+    index: usize,
+    target: &Target,
+    functions: &BlockFunctions,
+) -> Result<(), AotError> {
+    let names = |values: &[String]| {
+        values
+            .iter()
+            .map(|v| format!("{:?}.into()", v))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    // Lines after the target is created
+    let mut body = Vec::new();
+    let no_blocks = |_: BlockId| -> String { unreachable!("variables do not hold blocks") };
+    for (id, value) in &target.variables {
+        body.push(format!(
+            "target.variables.insert({:?}.into(), {});",
+            id,
+            value_literal(value, no_blocks)
+        ));
+    }
+    for (id, values) in &target.lists {
+        let values = values
+            .iter()
+            .map(|v| value_literal(v, no_blocks))
+            .collect::<Vec<_>>()
+            .join(", ");
+        body.push(format!(
+            "target.lists.insert({:?}.into(), vec![{}]);",
+            id, values
+        ));
+    }
+    for (map, names) in [
+        ("variable_names", &target.variable_names),
+        ("list_names", &target.list_names),
+        ("broadcasts", &target.broadcasts),
+    ] {
+        for (id, name) in names {
+            body.push(format!(
+                "target.{}.insert({:?}.into(), {:?}.into());",
+                map, id, name
+            ));
+        }
+    }
+    let mut uses_registry = false;
+    if target.blocks.iter().next().is_some() {
+        body.push("let blocks = &mut target.blocks;".into());
+    }
+    for (bid, block) in target.blocks.iter() {
+        let function = functions.expression(target, block)?;
+        uses_registry |= function.starts_with("f(");
+        body.push(format!(
+            "let b{} = aot::add_block(blocks, {:?}, {}, {});",
+            bid.index(),
+            block.opcode,
+            block.toplevel,
+            function
+        ));
+    }
+    for (bid, block) in target.blocks.iter() {
+        if !block.arguments.is_empty() {
+            let arguments = block
+                .arguments
+                .iter()
+                .map(|v| value_literal(v, |bid| format!("b{}", bid.index())))
+                .collect::<Vec<_>>()
+                .join(", ");
+            body.push(format!(
+                "blocks[b{}].arguments = vec![{}];",
+                bid.index(),
+                arguments
+            ));
+        }
+        if let Some(next) = block.next {
+            body.push(format!(
+                "blocks[b{}].next = Some(b{});",
+                bid.index(),
+                next.index()
+            ));
+        }
+    }
+
+    let registry = if uses_registry {
+        "registry"
+    } else {
+        "_registry"
+    };
+    writeln!(
+        out,
+        "fn target_{}({}: &BlockRegistry) -> Target {{",
+        index, registry
+    )
+    .unwrap();
+    if uses_registry {
+        out.push_str("    let f = |opcode| aot::registry_function(registry, opcode);\n");
+    }
+    let literal = format!(
+        "Target {{
+        name: {:?}.into(),
+        x: {},
+        y: {},
+        direction: {},
+        visible: {},
+        size: {},
+        current_costume: {},
+        costumes: vec![{}],
+        sounds: vec![{}],
+        costume_assets: vec![{}],
+        sound_assets: vec![{}],
+        layer_order: {},
+        rotation_style: RotationStyle::{:?},
+        volume: {},
+        tempo: {},
+        ..Default::default()
+    }}",
+        target.name,
+        number_literal(target.x),
+        number_literal(target.y),
+        number_literal(target.direction),
+        target.visible,
+        number_literal(target.size),
+        target.current_costume,
+        names(&target.costumes),
+        names(&target.sounds),
+        names(&target.costume_assets),
+        names(&target.sound_assets),
+        target.layer_order,
+        target.rotation_style,
+        number_literal(target.volume),
+        number_literal(target.tempo),
+    );
+    if body.is_empty() {
+        writeln!(out, "    {}\n}}", literal).unwrap();
+        return Ok(());
+    }
+    writeln!(out, "    let mut target = {};", literal).unwrap();
+    for line in body {
+        writeln!(out, "    {}", line).unwrap();
+    }
+    out.push_str("    target\n}\n");
+    Ok(())
+}
+
+/// Write `chunk` as a function that runs one state per instruction.
+fn write_chunk(out: &mut String, name: &str, chunk: &Chunk) {
+    // Chunks that only push, jump and tick do not need the machine
+    let uses_machine = chunk.code.iter().any(|instr| {
+        matches!(
+            instr,
+            Instr::Call { .. } | Instr::LoopWait | Instr::CallProcedure { .. }
+        )
+    });
+    writeln!(
+        out,
+        "fn {}(t: &mut BytecodeThread, {}: &mut Machine) -> Flow {{",
+        name,
+        if uses_machine { "m" } else { "_m" }
+    )
+    .unwrap();
+    out.push_str("    loop {\n        match t.pc() {\n");
+    for (pc, instr) in chunk.code.iter().enumerate() {
+        let next = pc + 1;
+        let body = match instr {
+            Instr::Push(value) => format!(
+                "{{
+                t.push({});
+                t.jump({});
+            }}",
+                value_literal(value, |bid| format!("m.block_at({}).0", bid.index())),
+                next
+            ),
+            Instr::Call {
+                block,
+                argc,
+                reporter,
+                ..
+            } => {
+                // `broadcast and wait` always yields, unless the script ends with it
+                let broadcast_and_wait = match chunk.code.get(next) {
+                    Some(Instr::End) => "return Flow::Exit(Exit::Finished)".into(),
+                    _ => format!(
+                        "{{
+                        t.jump({});
+                        return Flow::Exit(Exit::Yield);
+                    }}",
+                        next
+                    ),
+                };
+                format!(
+                    "{{
+                let (block, function) = m.block_at({});
+                match t.call_block(m, block, function, {}, {}) {{
+                    Step::Next => t.jump({}),
+                    Step::Again => {{}}
+                    Step::Wait => return Flow::Yield,
+                    Step::BroadcastAndWait => {},
+                    Step::Exit(exit) => return Flow::Exit(exit),
+                }}
+            }}",
+                    block.index(),
+                    argc,
+                    reporter,
+                    next,
+                    broadcast_and_wait
+                )
+            }
+            Instr::Jump(to) => format!("t.jump({}),", to),
+            Instr::JumpIfFalse(to) => format!(
+                "{{
+                let to = if t.pop_condition() {{ {} }} else {{ {} }};
+                t.jump(to);
+            }}",
+                next, to
+            ),
+            Instr::JumpIfTrue(to) => format!(
+                "{{
+                let to = if t.pop_condition() {{ {} }} else {{ {} }};
+                t.jump(to);
+            }}",
+                to, next
+            ),
+            Instr::SetCounter(slot) => format!(
+                "{{
+                t.set_counter({});
+                t.jump({});
+            }}",
+                slot, next
+            ),
+            Instr::Count { slot, end } => format!(
+                "{{
+                let to = if t.count({}) {{ {} }} else {{ {} }};
+                t.jump(to);
+            }}",
+                slot, next, end
+            ),
+            Instr::Tick => format!(
+                "{{
+                t.jump({});
+                return Flow::Yield;
+            }}",
+                next
+            ),
+            Instr::LoopWait => format!(
+                "{{
+                if t.loop_wait(m) {{
+                    return Flow::Yield;
+                }}
+                t.jump({});
+            }}",
+                next
+            ),
+            Instr::Argument { name, boolean } => format!(
+                "{{
+                t.argument({:?}, {});
+                t.jump({});
+            }}",
+                name, boolean, next
+            ),
+            Instr::CallProcedure {
+                definition,
+                argc,
+                reporter,
+            } => format!(
+                "{{
+                let (definition, _) = m.block_at({});
+                if let Some(flow) = t.call_procedure(m, definition, {}, {}) {{
+                    return flow;
+                }}
+                t.jump({});
+            }}",
+                definition.index(),
+                argc,
+                reporter,
+                next
+            ),
+            Instr::Return => "return t.return_value(),".into(),
+            Instr::ReturnEmpty => "return t.return_empty(),".into(),
+            Instr::End => "return Flow::Exit(Exit::Finished),".into(),
+            #[cfg(feature = "jit")]
+            Instr::Native { .. } => unreachable!("compiled without native sites"),
+        };
+        writeln!(out, "            {} => {}", pc, body).unwrap();
+    }
+    out.push_str("            _ => unreachable!(),\n        }\n    }\n}\n");
+}
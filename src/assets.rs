@@ -0,0 +1,262 @@
+use json::JsonValue;
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zip::ZipArchive;
+
+/// A costume or sound file of a project.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    /// The file name in the project: the md5 of the data and the file extension, like `b7853f557e4426412e64bb3da6531a99.svg`.
+    pub md5ext: String,
+    /// The file content, shared by every target and VM that uses the asset.
+    pub data: Arc<[u8]>,
+}
+
+/// The md5ext of a costume or sound in `project.json`.
+///
+/// Old projects only have the `assetId` and `dataFormat` fields.
+pub(crate) fn md5ext(asset: &JsonValue) -> Option<String> {
+    if let Some(md5ext) = asset["md5ext"].as_str() {
+        return Some(md5ext.to_owned());
+    }
+    Some(format!(
+        "{}.{}",
+        asset["assetId"].as_str()?,
+        asset["dataFormat"].as_str()?
+    ))
+}
+
+/// Whether `data` has the md5 of the name `md5ext`, so it is the file the project asks for.
+/// Names that are not an md5, like in projects made by hand, are not checked.
+pub fn verify_md5(md5ext: &str, data: &[u8]) -> bool {
+    let md5 = md5ext.split('.').next().unwrap_or_default();
+    if md5.len() != 32 || !md5.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return true;
+    }
+    format!("{:x}", md5::compute(data)).eq_ignore_ascii_case(md5)
+}
+
+/// Where the loaders read costumes and sounds from.
+pub trait AssetStore {
+    /// The content of the asset named `md5ext`, `None` if the store does not have it.
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>>;
+}
+
+impl<S: AssetStore + ?Sized> AssetStore for &mut S {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        (**self).load(md5ext)
+    }
+}
+
+/// Assets kept in memory, keyed by md5ext.
+impl AssetStore for HashMap<String, Arc<[u8]>> {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        self.get(md5ext).cloned()
+    }
+}
+
+/// Assets next to `project.json` in a sb3 or sprite3 archive.
+pub struct ZipAssetStore<R> {
+    archive: ZipArchive<R>,
+}
+
+impl<R: Read + Seek> ZipAssetStore<R> {
+    pub fn new(archive: ZipArchive<R>) -> Self {
+        Self { archive }
+    }
+}
+
+impl<R: Read + Seek> AssetStore for ZipAssetStore<R> {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        let mut file = self.archive.by_name(md5ext).ok()?;
+        let mut data = Vec::with_capacity(file.size() as _);
+        file.read_to_end(&mut data).ok()?;
+        Some(data.into())
+    }
+}
+
+/// Assets in a directory, like an extracted project.
+#[derive(Debug, Clone)]
+pub struct DirAssetStore {
+    dir: PathBuf,
+}
+
+impl DirAssetStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+}
+
+impl AssetStore for DirAssetStore {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        // Only files in `dir`, a project must not read `../secret`
+        if Path::new(md5ext).file_name()? != md5ext {
+            return None;
+        }
+        std::fs::read(self.dir.join(md5ext)).ok().map(Into::into)
+    }
+}
+
+/// Assets served over plain HTTP, like a local stand-in for the Scratch asset server.
+#[derive(Debug, Clone)]
+pub struct HttpAssetStore {
+    url: String,
+}
+
+impl HttpAssetStore {
+    /// `url` is the address of an asset with `{md5ext}` in place of its name, like
+    /// `http://127.0.0.1:8601/internalapi/asset/{md5ext}/get/`. HTTPS is not supported.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl AssetStore for HttpAssetStore {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        http_get(&self.url.replace("{md5ext}", md5ext)).map(Into::into)
+    }
+}
+
+/// The body of a `200 OK` response to a HTTP/1.0 GET request.
+fn http_get(url: &str) -> Option<Vec<u8>> {
+    let url = url.strip_prefix("http://")?;
+    let (host, path) = match url.find('/') {
+        Some(i) => url.split_at(i),
+        None => (url, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    let mut stream = TcpStream::connect(address).ok()?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .ok()?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).ok()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).ok()?;
+    let header_end = response.windows(4).position(|x| x == b"\r\n\r\n")?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()?
+        .split_whitespace()
+        .nth(1)?;
+    if status != "200" {
+        return None;
+    }
+    Some(response.split_off(header_end + 4))
+}
+
+/// Assets shared by the VMs of a process, so VMs running copies of a project keep one copy of each file.
+///
+/// Set `VirtualMachine::asset_cache` before loading a project to use it. A cache made with
+/// `with_limit` forgets the least recently used assets when it grows over its limit.
+#[derive(Debug, Default)]
+pub struct AssetCache {
+    state: Mutex<CacheState>,
+    /// Most bytes of assets to keep, no limit if `None`.
+    max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Assets and when they were last used.
+    assets: HashMap<String, (Arc<[u8]>, u64)>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl CacheState {
+    fn get(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let (data, last_used) = self.assets.get_mut(md5ext)?;
+        *last_used = self.clock;
+        Some(data.clone())
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .assets
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(md5ext, _)| md5ext.to_owned());
+        if let Some((data, _)) = oldest.and_then(|x| self.assets.remove(&x)) {
+            self.bytes -= data.len();
+        }
+    }
+}
+
+impl AssetCache {
+    /// A cache that keeps at most `max_bytes` of assets.
+    pub fn with_limit(max_bytes: usize) -> Self {
+        Self {
+            state: Mutex::default(),
+            max_bytes: Some(max_bytes),
+        }
+    }
+
+    /// The cached asset named `md5ext`, or the one loaded from `store`, which is then cached.
+    /// Data that does not have the md5 of its name is not returned, see `verify_md5`.
+    pub fn load(&self, md5ext: &str, store: &mut impl AssetStore) -> Option<Arc<[u8]>> {
+        if let Some(data) = self.get(md5ext) {
+            return Some(data);
+        }
+        // Not locked while loading, another VM may load the same asset at the same time
+        let data = store.load(md5ext)?;
+        if !verify_md5(md5ext, &data) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(data) = state.get(md5ext) {
+            return Some(data);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            if data.len() > max_bytes {
+                return Some(data);
+            }
+            while state.bytes + data.len() > max_bytes {
+                state.evict_least_recently_used();
+            }
+        }
+        state.bytes += data.len();
+        let clock = state.clock;
+        state
+            .assets
+            .insert(md5ext.to_owned(), (data.clone(), clock));
+        Some(data)
+    }
+
+    pub fn get(&self, md5ext: &str) -> Option<Arc<[u8]>> {
+        self.state.lock().unwrap().get(md5ext)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().assets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the cached assets in bytes.
+    pub fn bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+
+    /// Forget every asset. VMs keep the assets they already loaded.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.assets.clear();
+        state.bytes = 0;
+    }
+}
@@ -0,0 +1,166 @@
+use clipcc_rust_vm::grader::*;
+use clipcc_rust_vm::runner::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "ScratchRust - headless player
+Usage: scrust [OPTIONS] FILE...
+
+Options:
+  --time-limit SECS   Stop each project after SECS seconds (default 10, 0 for no limit)
+  --step-limit N      Stop each project after N steps, in which every script runs until it yields
+  --seed N            Seed the random number generator
+  --input FILE        Scripted input as JSON, like
+                      {\"answers\": [\"Alice\"], \"events\": [{\"step\": 10, \"key\": \"space\"}]}
+  --jobs N            Run N projects in parallel (default: number of CPUs)
+  --bytecode          Optimize and compile projects to bytecode before running
+  --spec FILE         Grade each file against a JSON spec of expected behavior instead
+  --coverage          Report the blocks of each project that ran and that never ran
+  --output FILE       Write the JSON results to FILE instead of stdout
+  --help              Show this message
+
+Each FILE is an sb3 or sb2 file, or a directory with an extracted project.
+Prints a JSON array with the result of each file, and warnings about what is missing from
+a project to stderr. Exits with 1 if a file can not be loaded
+or makes the player panic, or with --spec, if a check fails.";
+
+struct Args {
+    files: Vec<PathBuf>,
+    options: RunOptions,
+    jobs: usize,
+    output: Option<PathBuf>,
+    spec: Option<Spec>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        files: Vec::new(),
+        options: RunOptions {
+            time_limit: Some(Duration::from_secs(10)),
+            ..Default::default()
+        },
+        jobs: std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        output: None,
+        spec: None,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| iter.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--time-limit" => {
+                let secs = value(&arg)?
+                    .parse::<f64>()
+                    .map_err(|e| format!("--time-limit: {}", e))?;
+                args.options.time_limit = if secs > 0. {
+                    Some(Duration::from_secs_f64(secs))
+                } else {
+                    None
+                };
+            }
+            "--step-limit" => {
+                let steps = value(&arg)?
+                    .parse()
+                    .map_err(|e| format!("--step-limit: {}", e))?;
+                args.options.step_limit = Some(steps);
+            }
+            "--seed" => {
+                let seed = value(&arg)?.parse().map_err(|e| format!("--seed: {}", e))?;
+                args.options.seed = Some(seed);
+            }
+            "--input" => {
+                let path = value(&arg)?;
+                let text =
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                let input = json::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+                args.options.input = ScriptedInput::from_json(&input)?;
+            }
+            "--jobs" => {
+                args.jobs = value(&arg)?.parse().map_err(|e| format!("--jobs: {}", e))?;
+            }
+            "--bytecode" => args.options.compile = true,
+            "--coverage" => args.options.coverage = true,
+            "--spec" => {
+                let path = value(&arg)?;
+                let text =
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                args.spec = Some(Spec::parse(&text).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            "--output" => args.output = Some(value(&arg)?.into()),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            option if option.starts_with("--") => return Err(format!("unknown option {}", option)),
+            file => args.files.push(file.into()),
+        }
+    }
+    if args.files.is_empty() {
+        return Err(String::new());
+    }
+    if let Some(spec) = &mut args.spec {
+        spec.coverage |= args.options.coverage;
+    }
+    Ok(args)
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("error: {}", e);
+            }
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    let (failed, results) = match &args.spec {
+        Some(spec) => {
+            let reports = grade_batch(spec, &args.files, args.jobs);
+            let failed = reports.iter().any(|r| !r.passed());
+            (failed, reports.iter().map(|r| r.to_json()).collect())
+        }
+        None => {
+            let results = run_batch(&args.files, &args.options, args.jobs);
+            // Only the results go to stdout, so it stays valid JSON
+            for (result, file) in results.iter().zip(args.files.iter()) {
+                for warning in &result.warnings {
+                    eprintln!("warning: {}: {}", file.display(), warning);
+                }
+            }
+            let failed = results
+                .iter()
+                .any(|r| matches!(r.status, RunStatus::LoadError(_) | RunStatus::Panic(_)));
+            (
+                failed,
+                results.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+            )
+        }
+    };
+    let output = results
+        .into_iter()
+        .zip(args.files.iter())
+        .map(|(result, file)| {
+            let mut value = json::object! { "file": file.to_string_lossy().as_ref() };
+            for (key, field) in result.entries() {
+                value[key] = field.clone();
+            }
+            value
+        })
+        .collect::<Vec<_>>();
+    let output = json::JsonValue::from(output).pretty(2);
+    match &args.output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, output) {
+                eprintln!("error: {}: {}", path.display(), e);
+                std::process::exit(2);
+            }
+        }
+        None => println!("{}", output),
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
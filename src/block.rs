@@ -5,15 +5,27 @@ pub struct Block {
     pub self_id: BlockId,
     pub toplevel: bool,
     pub arguments: Vec<BlockValue>,
-    pub opcode: &'static str,
+    pub opcode: String,
     pub block_function: BlockFunction,
     pub next: Option<BlockId>,
-    #[cfg(debug_assertions)]
+    /// The id of the block in `project.json`. Blocks made by the loader or the host
+    /// have a placeholder in brackets, like `[Auto Generated]`, and deleted blocks have `[Deleted]`.
     pub block_id: String,
 }
 
 pub type BlockId = Id<Block>;
 
+impl Block {
+    /// The id of the block in `project.json`, if it comes from a project.
+    pub fn sb3_id(&self) -> Option<&str> {
+        if self.block_id.is_empty() || self.block_id.starts_with('[') {
+            None
+        } else {
+            Some(&self.block_id)
+        }
+    }
+}
+
 impl std::fmt::Debug for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Block")
@@ -3,34 +3,11 @@ use std::time::{Duration, Instant};
 
 pub fn control_wait(ctx: &mut BlockContext) -> BlockResult {
     if let Some((i, d)) = ctx.stack.block_data.downcast_ref::<(Instant, Duration)>() {
-        let e = i.elapsed();
+        let e = ctx.runtime.elapsed(*i);
         if e >= *d {
             BlockResult::Resolved(None)
         } else {
-            let wait_time = *d - e;
-            if let Some(wt) = ctx
-                .global_data
-                .get_mut("MIN_WAIT_TIME")
-                .and_then(|x| x.downcast_mut::<Duration>())
-            {
-                if *wt > wait_time {
-                    *wt = wait_time;
-                }
-            } else {
-                ctx.global_data
-                    .insert("MIN_WAIT_TIME".into(), Box::new(wait_time));
-            }
-            if let Some(wt) = ctx
-                .global_data
-                .get_mut("WAITING_THREADS")
-                .and_then(|x| x.downcast_mut::<usize>())
-            {
-                *wt += 1;
-            } else {
-                ctx.global_data
-                    .insert("WAITING_THREADS".into(), Box::new(1usize));
-            }
-
+            ctx.runtime.report_wait(*d - e);
             BlockResult::Pending
         }
     } else if let Some(arg_time) = ctx.stack.arguments.get(0) {
@@ -39,7 +16,7 @@ pub fn control_wait(ctx: &mut BlockContext) -> BlockResult {
             BlockResult::Resolved(None)
         } else {
             ctx.stack.block_data = Box::new((
-                Instant::now(),
+                ctx.runtime.step_time,
                 Duration::from_millis((arg_time * 1000.) as u64),
             ));
             BlockResult::Pending
@@ -50,10 +27,13 @@ pub fn control_wait(ctx: &mut BlockContext) -> BlockResult {
 }
 
 pub fn control_repeat(ctx: &mut BlockContext) -> BlockResult {
-    type BlockData = (usize, BlockId);
-    if let Some((times, substack)) = ctx.stack.block_data.downcast_mut::<BlockData>() {
+    type BlockData = (usize, BlockId, LoopYield);
+    let warp = ctx.warp;
+    if let Some((times, substack, loop_yield)) = ctx.stack.block_data.downcast_mut::<BlockData>() {
         ctx.stack.arguments.clear();
-        if *times > 0 {
+        if *times > 0 && loop_yield.should_wait(warp, ctx.runtime) {
+            BlockResult::Pending
+        } else if *times > 0 {
             *times -= 1;
             BlockResult::PushStack(*substack)
         } else {
@@ -69,7 +49,7 @@ pub fn control_repeat(ctx: &mut BlockContext) -> BlockResult {
                     None
                 };
             if let Some(bid) = substack {
-                ctx.stack.block_data = Box::new((times - 1, bid));
+                ctx.stack.block_data = Box::new((times - 1, bid, LoopYield::default()));
                 BlockResult::PushStack(bid)
             } else {
                 BlockResult::Resolved(None)
@@ -117,6 +97,9 @@ pub fn control_if(ctx: &mut BlockContext) -> BlockResult {
 }
 
 pub fn control_forever(ctx: &mut BlockContext) -> BlockResult {
+    if ctx.arg_len() > 0 && ctx.loop_should_wait() {
+        return BlockResult::Pending;
+    }
     ctx.stack.arguments.clear(); // 因为请求完参数之后可能有返回值被推入
     BlockResult::ResolveArgument(0)
 }
@@ -137,32 +120,16 @@ pub fn control_create_clone_of_menu(ctx: &mut BlockContext) -> BlockResult {
     }
 }
 pub fn control_get_counter(ctx: &mut BlockContext) -> BlockResult {
-    if let Some(i) = ctx.global_data.get("COUNTER") {
-        if let Some(i) = i.downcast_ref::<usize>() {
-            BlockResult::Resolved(Some((*i).into()))
-        } else {
-            BlockResult::Resolved(Some(0.into()))
-        }
-    } else {
-        BlockResult::Resolved(Some(0.into()))
-    }
+    BlockResult::Resolved(Some(ctx.runtime.counter.into()))
 }
 
 pub fn control_incr_counter(ctx: &mut BlockContext) -> BlockResult {
-    if let Some(i) = ctx
-        .global_data
-        .get_mut("COUNTER")
-        .and_then(|a| a.downcast_mut::<usize>())
-    {
-        *i += 1;
-    } else {
-        ctx.global_data.insert("COUNTER".into(), Box::new(1usize));
-    }
+    ctx.runtime.counter += 1;
     BlockResult::Resolved(None)
 }
 
 pub fn control_clear_counter(ctx: &mut BlockContext) -> BlockResult {
-    ctx.global_data.insert("COUNTER".into(), Box::new(0usize));
+    ctx.runtime.counter = 0;
     BlockResult::Resolved(None)
 }
 pub fn control_delete_this_clone(_ctx: &mut BlockContext) -> BlockResult {
@@ -187,6 +154,9 @@ pub fn control_repeat_until(ctx: &mut BlockContext) -> BlockResult {
     if arg_len < 1 {
         return BlockResult::ResolveArgument(arg_len);
     } else if arg_len > 1 {
+        if ctx.loop_should_wait() {
+            return BlockResult::Pending;
+        }
         ctx.stack.arguments.clear();
         return BlockResult::ResolveArgument(0);
     }
@@ -203,6 +173,9 @@ pub fn control_while(ctx: &mut BlockContext) -> BlockResult {
     if arg_len < 1 {
         return BlockResult::ResolveArgument(arg_len);
     } else if arg_len > 1 {
+        if ctx.loop_should_wait() {
+            return BlockResult::Pending;
+        }
         ctx.stack.arguments.clear();
         return BlockResult::ResolveArgument(0);
     }
@@ -6,7 +6,12 @@ enum ListIndex {
     Index(usize),
 }
 
-fn to_list_index(index: &BlockValue, len: usize, accept_all: bool) -> ListIndex {
+fn to_list_index(
+    index: &BlockValue,
+    len: usize,
+    accept_all: bool,
+    rng: &mut rand::rngs::SmallRng,
+) -> ListIndex {
     use rand::prelude::*;
     match index {
         BlockValue::String(index) => match index.as_str() {
@@ -26,7 +31,7 @@ fn to_list_index(index: &BlockValue, len: usize, accept_all: bool) -> ListIndex
             }
             "random" | "any" => {
                 if len > 0 {
-                    let index = unsafe { THREAD_RND.gen_range(0..len) };
+                    let index = rng.gen_range(0..len);
                     ListIndex::Index(index)
                 } else {
                     ListIndex::Invalid
@@ -181,8 +186,10 @@ pub fn data_deleteoflist(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_args(2, |ctx| {
         let index = ctx.arg(0).to_owned();
         let name = ctx.arg(1).to_string();
+        let len = ctx.get_list(&name).len();
+        let index = to_list_index(&index, len, true, &mut ctx.runtime.rng);
         let list = ctx.get_list_mut(&name);
-        match to_list_index(&index, list.len(), true) {
+        match index {
             ListIndex::Index(index) => {
                 if index < list.len() {
                     list.remove(index);
@@ -211,8 +218,10 @@ pub fn data_insertatlist(ctx: &mut BlockContext) -> BlockResult {
         let item = ctx.arg(0).to_owned();
         let index = ctx.arg(1).to_owned();
         let name = ctx.arg(2).to_string();
+        let len = ctx.get_list(&name).len();
+        let index = to_list_index(&index, len, false, &mut ctx.runtime.rng);
         let list = ctx.get_list_mut(&name);
-        match to_list_index(&index, list.len(), false) {
+        match index {
             ListIndex::Index(index) => {
                 list.insert(index, item);
             }
@@ -227,8 +236,8 @@ pub fn data_replaceitemoflist(ctx: &mut BlockContext) -> BlockResult {
         let name = ctx.arg(1).to_string();
         let list_len = ctx.get_list(&name).len();
         let item = ctx.arg(2).to_owned();
-        let index = ctx.arg(0);
-        match to_list_index(index, list_len, false) {
+        let index = ctx.arg(0).to_owned();
+        match to_list_index(&index, list_len, false, &mut ctx.runtime.rng) {
             ListIndex::Index(index) => {
                 let list = ctx.get_list_mut(&name);
                 while index >= list.len() {
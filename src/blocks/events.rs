@@ -20,6 +20,19 @@ pub fn event_whenbroadcastreceived(_ctx: &mut BlockContext) -> BlockResult {
     end()
 }
 
+pub fn event_whengreaterthan(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(2, |ctx| {
+        let value = ctx.arg(1).to_number();
+        let current = match ctx.arg(0).to_string().to_lowercase().as_str() {
+            "timer" => ctx.runtime.timer_value(),
+            // There is no microphone, so the loudness is always -1 like scratch-vm
+            "loudness" => -1.,
+            _ => return ret(false),
+        };
+        ret(current > value)
+    })
+}
+
 pub fn event_broadcast(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_args(1, |ctx| {
         // println!("Broadcast {}", ctx.arg(0).to_string());
@@ -4,10 +4,20 @@ use generational_arena::Index;
 
 use crate::*;
 // looks_say
+/// Print `text`, or record it if the host installed a `SpeechLog`.
+fn speak(ctx: &mut BlockContext, text: String) {
+    let target = ctx.running_target().name.to_owned();
+    match ctx.runtime.extensions.get_mut::<SpeechLog>() {
+        Some(log) => log.0.push(Speech { target, text }),
+        None => println!("{}", text),
+    }
+}
+
 pub fn looks_say(ctx: &mut BlockContext) -> BlockResult {
     let text = ctx.stack.arguments.get(0);
     if let Some(text) = text {
-        println!("{}", text);
+        let text = text.to_string();
+        speak(ctx, text);
         BlockResult::Resolved(None)
     } else {
         BlockResult::ResolveArgument(0)
@@ -17,44 +27,23 @@ pub fn looks_say(ctx: &mut BlockContext) -> BlockResult {
 pub fn looks_sayforsecs(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_need_wait_refresh(|ctx| {
         if let Some((i, t)) = ctx.stack.block_data.downcast_ref::<(Instant, f64)>() {
-            return if i.elapsed().as_secs_f64() >= *t {
+            let elapsed = ctx.runtime.elapsed(*i);
+            return if elapsed.as_secs_f64() >= *t {
                 BlockResult::Resolved(None)
             } else {
-                let wait_time = Duration::from_secs_f64(*t) - i.elapsed();
-                if let Some(wt) = ctx
-                    .global_data
-                    .get_mut("MIN_WAIT_TIME")
-                    .and_then(|x| x.downcast_mut::<Duration>())
-                {
-                    if *wt > wait_time {
-                        *wt = wait_time;
-                    }
-                } else {
-                    ctx.global_data
-                        .insert("MIN_WAIT_TIME".into(), Box::new(wait_time));
-                }
-                if let Some(wt) = ctx
-                    .global_data
-                    .get_mut("WAITING_THREADS")
-                    .and_then(|x| x.downcast_mut::<usize>())
-                {
-                    *wt += 1;
-                } else {
-                    ctx.global_data
-                        .insert("WAITING_THREADS".into(), Box::new(1usize));
-                }
-
+                ctx.runtime
+                    .report_wait(Duration::from_secs_f64(*t) - elapsed);
                 BlockResult::Pending
             };
         }
         ctx.acquire_args(2, |ctx| {
-            let text = ctx.arg(0);
+            let text = ctx.arg(0).to_string();
             let arg_time = ctx.arg(1).to_number();
-            println!("{}", text);
+            speak(ctx, text);
             if arg_time <= 0. || arg_time.is_nan() {
                 BlockResult::Resolved(None)
             } else {
-                ctx.stack.block_data = Box::new((Instant::now(), arg_time));
+                ctx.stack.block_data = Box::new((ctx.runtime.step_time, arg_time));
                 BlockResult::Pending
             }
         })
@@ -77,6 +66,21 @@ pub fn looks_hide(ctx: &mut BlockContext) -> BlockResult {
     })
 }
 
+/// Hide every sprite and clone. Only Scratch 1.4 projects have this block.
+pub fn looks_hideallsprites(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_need_wait_refresh(|ctx| {
+        let stage = ctx.running_stage_id;
+        for (_, rt) in ctx
+            .running_targets
+            .iter_mut()
+            .filter(|(id, _)| *id != stage)
+        {
+            rt.visible = false;
+        }
+        end()
+    })
+}
+
 pub fn looks_setsizeto(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_need_wait_refresh(|ctx| {
         ctx.acquire_args(1, |ctx| {
@@ -104,6 +108,15 @@ pub fn looks_changesizeby(ctx: &mut BlockContext) -> BlockResult {
     })
 }
 
+// Sprites can not be stretched since Scratch 2, so these do nothing like in scratch-vm
+pub fn looks_changestretchby(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(1, |_| end())
+}
+
+pub fn looks_setstretchto(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(1, |_| end())
+}
+
 pub fn looks_switchbackdropto(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_need_wait_refresh(|ctx| {
         ctx.acquire_args(1, |ctx| {
@@ -1,6 +1,5 @@
 use crate::*;
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub mod controls;
 pub mod data;
@@ -28,942 +27,1358 @@ pub fn just_return(ctx: &mut BlockContext) -> BlockResult {
     BlockResult::Resolved(Some(ctx.get_block().arguments[0].to_owned()))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArgType {
-    Input,
+    /// An input, which holds a value of its type or a block.
+    Input(InputType),
+    /// A field, like a dropdown menu or the variable of `set variable to`.
     Field,
 }
 
+/// The values an input takes, like the argument types of Scratch extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputType {
+    /// Any text, like the message of `say`. Menus of other blocks are also text inputs.
+    String,
+    /// A number, like the steps of `move`.
+    Number,
+    /// A boolean slot, which holds a boolean block and no value.
+    Boolean,
+    /// A color like `#ff0000`, or a number.
+    Color,
+    /// A stack of blocks, like the inside of `forever`. Only command blocks have them.
+    Substack,
+}
+
+impl InputType {
+    /// Whether an input of this type can hold `value`, a block always fits.
+    pub fn accepts(&self, value: &BlockValue) -> bool {
+        match (self, value) {
+            (_, BlockValue::Undefined | BlockValue::BlockId(_)) => true,
+            (Self::String, _) => true,
+            (Self::Number, BlockValue::Number(_)) => true,
+            (Self::Number, BlockValue::String(v)) => {
+                v.trim().is_empty() || v.trim().parse::<f64>().is_ok()
+            }
+            (Self::Color, BlockValue::Number(_)) => true,
+            (Self::Color, BlockValue::String(v)) => {
+                v.len() == 7 && v.starts_with('#') && v[1..].chars().all(|c| c.is_ascii_hexdigit())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Why `BlockRegistry::register` refused a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    EmptyOpcode,
+    /// An input or field has an empty name.
+    EmptyArgumentName,
+    /// Two arguments have the same name, so the editor can not tell them apart.
+    DuplicateArgument(String),
+    /// A block that is not a command has a substack input.
+    SubstackNotInCommand(String),
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyOpcode => write!(f, "the opcode is empty"),
+            Self::EmptyArgumentName => write!(f, "an argument has an empty name"),
+            Self::DuplicateArgument(name) => write!(f, "duplicate argument {}", name),
+            Self::SubstackNotInCommand(name) => {
+                write!(f, "only command blocks can have the substack {}", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+/// The shape of a block in the editor, which tells how it can be used in scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockShape {
+    /// Starts a script when an event happens.
+    ///
+    /// If the script is still running when the event happens again, it is
+    /// restarted when `restart_existing_threads` is set, otherwise the event is ignored.
+    Hat { restart_existing_threads: bool },
+    /// A hat whose block function returns a boolean predicate. It is evaluated
+    /// every step and starts its script when the value changes from false to true.
+    EdgeActivatedHat,
+    /// A stack block that does something and continues with the next block.
+    Command,
+    /// Returns a value.
+    Reporter,
+    /// Returns a boolean value.
+    Boolean,
+}
+
+#[derive(Debug, Clone)]
 pub struct BlockInfo {
     pub block_function: BlockFunction,
+    /// Inputs and fields read from the project, in the order they are passed to `block_function`.
     pub arguments: Vec<(ArgType, String)>,
+    pub shape: BlockShape,
+}
+
+impl BlockInfo {
+    pub fn new(shape: BlockShape, block_function: BlockFunction) -> Self {
+        Self {
+            block_function,
+            arguments: Vec::new(),
+            shape,
+        }
+    }
+    /// Append an input argument.
+    pub fn input(mut self, name: impl Into<String>, input_type: InputType) -> Self {
+        self.arguments
+            .push((ArgType::Input(input_type), name.into()));
+        self
+    }
+    /// Append a field argument.
+    pub fn field(mut self, name: impl Into<String>) -> Self {
+        self.arguments.push((ArgType::Field, name.into()));
+        self
+    }
+    pub fn is_hat(&self) -> bool {
+        matches!(
+            self.shape,
+            BlockShape::Hat { .. } | BlockShape::EdgeActivatedHat
+        )
+    }
+    pub fn restart_existing_threads(&self) -> bool {
+        matches!(
+            self.shape,
+            BlockShape::Hat {
+                restart_existing_threads: true
+            }
+        )
+    }
+    pub fn is_reporter(&self) -> bool {
+        matches!(self.shape, BlockShape::Reporter | BlockShape::Boolean)
+    }
+    /// Check the arguments, see `RegisterError`.
+    pub fn validate(&self) -> Result<(), RegisterError> {
+        for (i, (arg_type, name)) in self.arguments.iter().enumerate() {
+            if name.is_empty() {
+                return Err(RegisterError::EmptyArgumentName);
+            }
+            if self.arguments[..i].iter().any(|(_, x)| x == name) {
+                return Err(RegisterError::DuplicateArgument(name.to_owned()));
+            }
+            if *arg_type == ArgType::Input(InputType::Substack) && self.shape != BlockShape::Command
+            {
+                return Err(RegisterError::SubstackNotInCommand(name.to_owned()));
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn register_block(opcode: &'static str, block_info: BlockInfo) {
-    unsafe {
-        BLOCKS.insert(opcode, block_info);
+/// The opcodes a `VirtualMachine` knows how to load and run.
+///
+/// `BlockRegistry::default()` contains all core blocks. Extensions add their
+/// own opcodes with [`BlockRegistry::register`] before loading a project.
+#[derive(Debug, Clone)]
+pub struct BlockRegistry {
+    blocks: HashMap<String, BlockInfo>,
+    /// Opcodes whose result only depends on their arguments.
+    pure: HashSet<String>,
+}
+
+impl BlockRegistry {
+    /// A registry without any blocks.
+    pub fn empty() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            pure: HashSet::new(),
+        }
+    }
+    /// Register an opcode, returning the previous info if it was already registered.
+    ///
+    /// The opcode is not pure, even if the replaced block was. Blocks whose arguments
+    /// do not pass `BlockInfo::validate` are refused.
+    pub fn register(
+        &mut self,
+        opcode: impl Into<String>,
+        block_info: BlockInfo,
+    ) -> Result<Option<BlockInfo>, RegisterError> {
+        let opcode = opcode.into();
+        if opcode.is_empty() {
+            return Err(RegisterError::EmptyOpcode);
+        }
+        block_info.validate()?;
+        self.pure.remove(&opcode);
+        Ok(self.blocks.insert(opcode, block_info))
+    }
+    pub fn unregister(&mut self, opcode: &str) -> Option<BlockInfo> {
+        self.pure.remove(opcode);
+        self.blocks.remove(opcode)
+    }
+    /// Mark a registered opcode as pure: its block function does not read or change any
+    /// state, and always reports the same value for the same arguments.
+    /// `VirtualMachine::optimize` folds pure blocks whose inputs are constants.
+    pub fn mark_pure(&mut self, opcode: &str) -> bool {
+        self.blocks.contains_key(opcode) && self.pure.insert(opcode.to_owned())
+    }
+    pub fn is_pure(&self, opcode: &str) -> bool {
+        self.pure.contains(opcode)
+    }
+    pub fn get(&self, opcode: &str) -> Option<&BlockInfo> {
+        self.blocks.get(opcode)
+    }
+    pub fn contains(&self, opcode: &str) -> bool {
+        self.blocks.contains_key(opcode)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &BlockInfo)> {
+        self.blocks.iter().map(|(k, v)| (k.as_str(), v))
     }
 }
 
-pub fn get_blockinfo(opcode: &str) -> Option<&BlockInfo> {
-    unsafe { BLOCKS.get(opcode) }
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        Self {
+            blocks: core_blocks(),
+            pure: [
+                "operator_add",
+                "operator_subtract",
+                "operator_multiply",
+                "operator_divide",
+                "operator_lt",
+                "operator_equals",
+                "operator_gt",
+                "operator_and",
+                "operator_or",
+                "operator_not",
+                "operator_join",
+                "operator_letter_of",
+                "operator_contains",
+                "operator_length",
+                "operator_mod",
+                "operator_round",
+                "operator_mathop",
+            ]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        }
+    }
 }
 
-pub static mut BLOCKS: Lazy<HashMap<&str, BlockInfo>> = Lazy::new(|| {
+fn core_blocks() -> HashMap<String, BlockInfo> {
     let mut h = HashMap::new();
 
     h.insert(
-        "motion_movesteps",
+        "motion_movesteps".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_movesteps,
-            arguments: vec![(ArgType::Input, "STEPS".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "STEPS".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_turnright",
+        "motion_turnright".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_turnright,
-            arguments: vec![(ArgType::Input, "DEGREES".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DEGREES".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_turnleft",
+        "motion_turnleft".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_turnleft,
-            arguments: vec![(ArgType::Input, "DEGREES".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DEGREES".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_pointindirection",
+        "motion_pointindirection".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_pointindirection,
-            arguments: vec![(ArgType::Input, "DIRECTION".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DIRECTION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_pointtowards",
+        "motion_pointtowards".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_pointtowards,
-            arguments: vec![(ArgType::Input, "TOWARDS".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "TOWARDS".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_pointtowards_menu",
+        "motion_pointtowards_menu".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_pointtowards_menu,
             arguments: vec![(ArgType::Field, "TOWARDS".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "motion_gotoxy",
+        "motion_gotoxy".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_gotoxy,
-            arguments: vec![(ArgType::Input, "X".into()), (ArgType::Input, "Y".into())],
+            arguments: vec![
+                (ArgType::Input(InputType::Number), "X".into()),
+                (ArgType::Input(InputType::Number), "Y".into()),
+            ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_goto",
+        "motion_goto".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_goto,
-            arguments: vec![(ArgType::Input, "TO".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "TO".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_goto_menu",
+        "motion_goto_menu".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_goto_menu,
             arguments: vec![(ArgType::Field, "TO".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "motion_glidesecstoxy",
+        "motion_glidesecstoxy".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_glidesecstoxy,
             arguments: vec![
-                (ArgType::Input, "SECS".into()),
-                (ArgType::Input, "X".into()),
-                (ArgType::Input, "Y".into()),
+                (ArgType::Input(InputType::Number), "SECS".into()),
+                (ArgType::Input(InputType::Number), "X".into()),
+                (ArgType::Input(InputType::Number), "Y".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_glideto",
+        "motion_glideto".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_glideto,
             arguments: vec![
-                (ArgType::Input, "SECS".into()),
-                (ArgType::Input, "TO".into()),
+                (ArgType::Input(InputType::Number), "SECS".into()),
+                (ArgType::Input(InputType::String), "TO".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_glideto_menu",
+        "motion_glideto_menu".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_glideto_menu,
             arguments: vec![
                 (ArgType::Field, "TO".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "motion_changexby",
+        "motion_changexby".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_changexby,
-            arguments: vec![(ArgType::Input, "DX".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DX".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_setx",
+        "motion_setx".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_setx,
-            arguments: vec![(ArgType::Input, "X".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "X".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_changeyby",
+        "motion_changeyby".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_changeyby,
-            arguments: vec![(ArgType::Input, "DY".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DY".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_sety",
+        "motion_sety".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_sety,
-            arguments: vec![(ArgType::Input, "Y".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "Y".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_ifonedgebounce",
+        "motion_ifonedgebounce".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_ifonedgebounce,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_setrotationstyle",
+        "motion_setrotationstyle".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_setrotationstyle,
             arguments: vec![(ArgType::Field, "STYLE".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "motion_xposition",
+        "motion_xposition".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_xposition,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "motion_yposition",
+        "motion_yposition".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_yposition,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "motion_direction",
+        "motion_direction".into(),
         BlockInfo {
             block_function: crate::core_blocks::motion_direction,
             arguments: vec![],
+            shape: BlockShape::Reporter,
+        },
+    );
+    h.insert(
+        "motion_scroll_right".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::motion_scroll_right,
+            arguments: vec![(ArgType::Input(InputType::Number), "DISTANCE".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "motion_scroll_up".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::motion_scroll_up,
+            arguments: vec![(ArgType::Input(InputType::Number), "DISTANCE".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "motion_align_scene".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::motion_align_scene,
+            arguments: vec![(ArgType::Field, "ALIGNMENT".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "motion_xscroll".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::motion_xscroll,
+            arguments: vec![],
+            shape: BlockShape::Reporter,
+        },
+    );
+    h.insert(
+        "motion_yscroll".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::motion_yscroll,
+            arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
-    //    h.insert("motion_scroll_right", BlockInfo { block_function: crate::core_blocks::motion_scroll_right, arguments: vec![(ArgType::Input, "DISTANCE".into())] });
-    //    h.insert("motion_scroll_up", BlockInfo { block_function: crate::core_blocks::motion_scroll_up, arguments: vec![(ArgType::Input, "DISTANCE".into())] });
-    //    h.insert("motion_align_scene", BlockInfo { block_function: crate::core_blocks::motion_align_scene, arguments: vec![(ArgType::Field, "ALIGNMENT".into())] });
-    //    h.insert("motion_xscroll", BlockInfo { block_function: crate::core_blocks::motion_xscroll, arguments: vec![] });
-    //    h.insert("motion_yscroll", BlockInfo { block_function: crate::core_blocks::motion_yscroll, arguments: vec![] });
     h.insert(
-        "looks_sayforsecs",
+        "looks_sayforsecs".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_sayforsecs,
             arguments: vec![
-                (ArgType::Input, "MESSAGE".into()),
-                (ArgType::Input, "SECS".into()),
+                (ArgType::Input(InputType::String), "MESSAGE".into()),
+                (ArgType::Input(InputType::Number), "SECS".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_say",
+        "looks_say".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_say,
-            arguments: vec![(ArgType::Input, "MESSAGE".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "MESSAGE".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_thinkforsecs",
+        "looks_thinkforsecs".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_sayforsecs,
             arguments: vec![
-                (ArgType::Input, "MESSAGE".into()),
-                (ArgType::Input, "SECS".into()),
+                (ArgType::Input(InputType::String), "MESSAGE".into()),
+                (ArgType::Input(InputType::Number), "SECS".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_think",
+        "looks_think".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_say,
-            arguments: vec![(ArgType::Input, "MESSAGE".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "MESSAGE".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_show",
+        "looks_show".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_show,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_hide",
+        "looks_hide".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_hide,
             arguments: vec![],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "looks_hideallsprites".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::looks_hideallsprites,
+            arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
-    // h.insert("looks_hideallsprites", BlockInfo { block_function: crate::core_blocks::looks_hideallsprites, arguments: vec![] });
     h.insert(
-        "looks_switchcostumeto",
+        "looks_switchcostumeto".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_switchcostumeto,
-            arguments: vec![(ArgType::Input, "COSTUME".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "COSTUME".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_costume",
+        "looks_costume".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_costume,
             arguments: vec![(ArgType::Field, "COSTUME".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "looks_nextcostume",
+        "looks_nextcostume".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_nextcostume,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_switchbackdropto",
+        "looks_switchbackdropto".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_switchbackdropto,
-            arguments: vec![(ArgType::Input, "BACKDROP".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "BACKDROP".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_backdrops",
+        "looks_backdrops".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_backdrops,
             arguments: vec![(ArgType::Field, "BACKDROP".into())],
+            shape: BlockShape::Reporter,
         },
     );
-    // h.insert("looks_changeeffectby", BlockInfo { block_function: crate::core_blocks::looks_changeeffectby, arguments: vec![(ArgType::Field, "EFFECT".into()), (ArgType::Input, "CHANGE".into())] });
-    // h.insert("looks_seteffectto", BlockInfo { block_function: crate::core_blocks::looks_seteffectto, arguments: vec![(ArgType::Field, "EFFECT".into()), (ArgType::Input, "VALUE".into())] });
+    // h.insert("looks_changeeffectby", BlockInfo { block_function: crate::core_blocks::looks_changeeffectby, arguments: vec![(ArgType::Field, "EFFECT".into()), (ArgType::Input(InputType::Number), "CHANGE".into())] });
+    // h.insert("looks_seteffectto", BlockInfo { block_function: crate::core_blocks::looks_seteffectto, arguments: vec![(ArgType::Field, "EFFECT".into()), (ArgType::Input(InputType::Number), "VALUE".into())] });
     // h.insert("looks_cleargraphiceffects", BlockInfo { block_function: crate::core_blocks::looks_cleargraphiceffects, arguments: vec![] });
     h.insert(
-        "looks_changesizeby",
+        "looks_changesizeby".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_changesizeby,
-            arguments: vec![(ArgType::Input, "CHANGE".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "CHANGE".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_setsizeto",
+        "looks_setsizeto".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_setsizeto,
-            arguments: vec![(ArgType::Input, "SIZE".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "SIZE".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "looks_changestretchby".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::looks_changestretchby,
+            arguments: vec![(ArgType::Input(InputType::Number), "CHANGE".into())],
+            shape: BlockShape::Command,
         },
     );
-    // h.insert("looks_changestretchby", BlockInfo { block_function: crate::core_blocks::looks_changestretchby, arguments: vec![(ArgType::Input, "CHANGE".into())] });
-    // h.insert("looks_setstretchto", BlockInfo { block_function: crate::core_blocks::looks_setstretchto, arguments: vec![(ArgType::Input, "STRETCH".into())] });
     h.insert(
-        "looks_gotofrontback",
+        "looks_setstretchto".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::looks_setstretchto,
+            arguments: vec![(ArgType::Input(InputType::Number), "STRETCH".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "looks_gotofrontback".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_gotofrontback,
             arguments: vec![(ArgType::Field, "FRONT_BACK".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_goforwardbackwardlayers",
+        "looks_goforwardbackwardlayers".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_goforwardbackwardlayers,
             arguments: vec![
                 (ArgType::Field, "FORWARD_BACKWARD".into()),
-                (ArgType::Input, "NUM".into()),
+                (ArgType::Input(InputType::Number), "NUM".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "looks_costumenumbername",
+        "looks_costumenumbername".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_costumenumbername,
-            arguments: vec![(ArgType::Input, "NUMBER_NAME".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "NUMBER_NAME".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "looks_costumenumbernamemenu",
+        "looks_costumenumbernamemenu".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_costumenumbername,
             arguments: vec![(ArgType::Field, "NUMBER_NAME".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "looks_backdropnumbername",
+        "looks_backdropnumbername".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_backdropnumbername,
-            arguments: vec![(ArgType::Input, "NUMBER_NAME".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "NUMBER_NAME".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "looks_backdropnumbernamemenu",
+        "looks_backdropnumbernamemenu".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_backdropnumbername,
             arguments: vec![(ArgType::Field, "NUMBER_NAME".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "looks_size",
+        "looks_size".into(),
         BlockInfo {
             block_function: crate::core_blocks::looks_size,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
-    // h.insert("looks_switchbackdroptoandwait", BlockInfo { block_function: crate::core_blocks::looks_switchbackdroptoandwait, arguments: vec![(ArgType::Input, "BACKDROP".into())] });
+    // h.insert("looks_switchbackdroptoandwait", BlockInfo { block_function: crate::core_blocks::looks_switchbackdroptoandwait, arguments: vec![(ArgType::Input(InputType::String), "BACKDROP".into())] });
     // h.insert("looks_nextbackdrop", BlockInfo { block_function: crate::core_blocks::looks_nextbackdrop, arguments: vec![] });
     // h.insert("looks_backdropnumbername", BlockInfo { block_function: crate::core_blocks::looks_backdropnumbername, arguments: vec![] });
-    // h.insert("sound_play", BlockInfo { block_function: crate::core_blocks::sound_play, arguments: vec![(ArgType::Input, "SOUND_MENU".into())] });
-    // h.insert("sound_playuntildone", BlockInfo { block_function: crate::core_blocks::sound_playuntildone, arguments: vec![(ArgType::Input, "SOUND_MENU".into())] });
+    // h.insert("sound_play", BlockInfo { block_function: crate::core_blocks::sound_play, arguments: vec![(ArgType::Input(InputType::String), "SOUND_MENU".into())] });
+    // h.insert("sound_playuntildone", BlockInfo { block_function: crate::core_blocks::sound_playuntildone, arguments: vec![(ArgType::Input(InputType::String), "SOUND_MENU".into())] });
     // h.insert("sound_stopallsounds", BlockInfo { block_function: crate::core_blocks::sound_stopallsounds, arguments: vec![] });
-    // h.insert("music_playDrumForBeats", BlockInfo { block_function: crate::core_blocks::music_playDrumForBeats, arguments: vec![(ArgType::Input, "DRUM".into()), (ArgType::Input, "BEATS".into())] });
-    // h.insert("music_midiPlayDrumForBeats", BlockInfo { block_function: crate::core_blocks::music_midiPlayDrumForBeats, arguments: vec![(ArgType::Input, "DRUM".into()), (ArgType::Input, "BEATS".into())] });
-    // h.insert("music_restForBeats", BlockInfo { block_function: crate::core_blocks::music_restForBeats, arguments: vec![(ArgType::Input, "BEATS".into())] });
-    // h.insert("music_playNoteForBeats", BlockInfo { block_function: crate::core_blocks::music_playNoteForBeats, arguments: vec![(ArgType::Input, "NOTE".into()), (ArgType::Input, "BEATS".into())] });
-    // h.insert("music_setInstrument", BlockInfo { block_function: crate::core_blocks::music_setInstrument, arguments: vec![(ArgType::Input, "INSTRUMENT".into())] });
-    // h.insert("music_midiSetInstrument", BlockInfo { block_function: crate::core_blocks::music_midiSetInstrument, arguments: vec![(ArgType::Input, "INSTRUMENT".into())] });
-    // h.insert("sound_changevolumeby", BlockInfo { block_function: crate::core_blocks::sound_changevolumeby, arguments: vec![(ArgType::Input, "VOLUME".into())] });
-    // h.insert("sound_setvolumeto", BlockInfo { block_function: crate::core_blocks::sound_setvolumeto, arguments: vec![(ArgType::Input, "VOLUME".into())] });
+    // h.insert("music_playDrumForBeats", BlockInfo { block_function: crate::core_blocks::music_playDrumForBeats, arguments: vec![(ArgType::Input(InputType::String), "DRUM".into()), (ArgType::Input(InputType::Number), "BEATS".into())] });
+    // h.insert("music_midiPlayDrumForBeats", BlockInfo { block_function: crate::core_blocks::music_midiPlayDrumForBeats, arguments: vec![(ArgType::Input(InputType::String), "DRUM".into()), (ArgType::Input(InputType::Number), "BEATS".into())] });
+    // h.insert("music_restForBeats", BlockInfo { block_function: crate::core_blocks::music_restForBeats, arguments: vec![(ArgType::Input(InputType::Number), "BEATS".into())] });
+    // h.insert("music_playNoteForBeats", BlockInfo { block_function: crate::core_blocks::music_playNoteForBeats, arguments: vec![(ArgType::Input(InputType::Number), "NOTE".into()), (ArgType::Input(InputType::Number), "BEATS".into())] });
+    // h.insert("music_setInstrument", BlockInfo { block_function: crate::core_blocks::music_setInstrument, arguments: vec![(ArgType::Input(InputType::String), "INSTRUMENT".into())] });
+    // h.insert("music_midiSetInstrument", BlockInfo { block_function: crate::core_blocks::music_midiSetInstrument, arguments: vec![(ArgType::Input(InputType::String), "INSTRUMENT".into())] });
+    // h.insert("sound_changevolumeby", BlockInfo { block_function: crate::core_blocks::sound_changevolumeby, arguments: vec![(ArgType::Input(InputType::Number), "VOLUME".into())] });
+    // h.insert("sound_setvolumeto", BlockInfo { block_function: crate::core_blocks::sound_setvolumeto, arguments: vec![(ArgType::Input(InputType::Number), "VOLUME".into())] });
     // h.insert("sound_volume", BlockInfo { block_function: crate::core_blocks::sound_volume, arguments: vec![] });
-    // h.insert("music_changeTempo", BlockInfo { block_function: crate::core_blocks::music_changeTempo, arguments: vec![(ArgType::Input, "TEMPO".into())] }); */
-    // h.insert("music_setTempo", BlockInfo { block_function: crate::core_blocks::music_setTempo, arguments: vec![(ArgType::Input, "TEMPO".into())] });
+    // h.insert("music_changeTempo", BlockInfo { block_function: crate::core_blocks::music_changeTempo, arguments: vec![(ArgType::Input(InputType::Number), "TEMPO".into())] }); */
+    // h.insert("music_setTempo", BlockInfo { block_function: crate::core_blocks::music_setTempo, arguments: vec![(ArgType::Input(InputType::Number), "TEMPO".into())] });
     // h.insert("music_getTempo", BlockInfo { block_function: crate::core_blocks::music_getTempo, arguments: vec![] });
     // h.insert("pen_clear", BlockInfo { block_function: crate::core_blocks::pen_clear, arguments: vec![] });
     // h.insert("pen_stamp", BlockInfo { block_function: crate::core_blocks::pen_stamp, arguments: vec![] });
     // h.insert("pen_penDown", BlockInfo { block_function: crate::core_blocks::pen_penDown, arguments: vec![] });
     // h.insert("pen_penUp", BlockInfo { block_function: crate::core_blocks::pen_penUp, arguments: vec![] });
-    // h.insert("pen_setPenColorToColor", BlockInfo { block_function: crate::core_blocks::pen_setPenColorToColor, arguments: vec![(ArgType::Input, "COLOR".into())] });
-    // h.insert("pen_changePenHueBy", BlockInfo { block_function: crate::core_blocks::pen_changePenHueBy, arguments: vec![(ArgType::Input, "HUE".into())] });
-    // h.insert("pen_setPenHueToNumber", BlockInfo { block_function: crate::core_blocks::pen_setPenHueToNumber, arguments: vec![(ArgType::Input, "HUE".into())] });
-    // h.insert("pen_changePenShadeBy", BlockInfo { block_function: crate::core_blocks::pen_changePenShadeBy, arguments: vec![(ArgType::Input, "SHADE".into())] });
-    // h.insert("pen_setPenShadeToNumber", BlockInfo { block_function: crate::core_blocks::pen_setPenShadeToNumber, arguments: vec![(ArgType::Input, "SHADE".into())] });
-    // h.insert("pen_changePenSizeBy", BlockInfo { block_function: crate::core_blocks::pen_changePenSizeBy, arguments: vec![(ArgType::Input, "SIZE".into())] });
-    // h.insert("pen_setPenSizeTo", BlockInfo { block_function: crate::core_blocks::pen_setPenSizeTo, arguments: vec![(ArgType::Input, "SIZE".into())] });
-    // h.insert("videoSensing_videoOn", BlockInfo { block_function: crate::core_blocks::videoSensing_videoOn, arguments: vec![(ArgType::Input, "ATTRIBUTE".into()), (ArgType::Input, "SUBJECT".into())] });
+    // h.insert("pen_setPenColorToColor", BlockInfo { block_function: crate::core_blocks::pen_setPenColorToColor, arguments: vec![(ArgType::Input(InputType::Color), "COLOR".into())] });
+    // h.insert("pen_changePenHueBy", BlockInfo { block_function: crate::core_blocks::pen_changePenHueBy, arguments: vec![(ArgType::Input(InputType::Number), "HUE".into())] });
+    // h.insert("pen_setPenHueToNumber", BlockInfo { block_function: crate::core_blocks::pen_setPenHueToNumber, arguments: vec![(ArgType::Input(InputType::Number), "HUE".into())] });
+    // h.insert("pen_changePenShadeBy", BlockInfo { block_function: crate::core_blocks::pen_changePenShadeBy, arguments: vec![(ArgType::Input(InputType::Number), "SHADE".into())] });
+    // h.insert("pen_setPenShadeToNumber", BlockInfo { block_function: crate::core_blocks::pen_setPenShadeToNumber, arguments: vec![(ArgType::Input(InputType::Number), "SHADE".into())] });
+    // h.insert("pen_changePenSizeBy", BlockInfo { block_function: crate::core_blocks::pen_changePenSizeBy, arguments: vec![(ArgType::Input(InputType::Number), "SIZE".into())] });
+    // h.insert("pen_setPenSizeTo", BlockInfo { block_function: crate::core_blocks::pen_setPenSizeTo, arguments: vec![(ArgType::Input(InputType::Number), "SIZE".into())] });
+    // h.insert("videoSensing_videoOn", BlockInfo { block_function: crate::core_blocks::videoSensing_videoOn, arguments: vec![(ArgType::Input(InputType::String), "ATTRIBUTE".into()), (ArgType::Input(InputType::String), "SUBJECT".into())] });
     h.insert(
-        "event_whenflagclicked",
+        "event_whenflagclicked".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_whenflagclicked,
             arguments: vec![],
+            shape: BlockShape::Hat {
+                restart_existing_threads: true,
+            },
         },
     );
     h.insert(
-        "event_whenkeypressed",
+        "event_whenkeypressed".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_whenkeypressed,
             arguments: vec![(ArgType::Field, "KEY_OPTION".into())],
+            shape: BlockShape::Hat {
+                restart_existing_threads: false,
+            },
         },
     );
     h.insert(
-        "event_whenthisspriteclicked",
+        "event_whenthisspriteclicked".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_whenthisspriteclicked,
             arguments: vec![],
+            shape: BlockShape::Hat {
+                restart_existing_threads: true,
+            },
         },
     );
     h.insert(
-        "event_whenbackdropswitchesto",
+        "event_whenbackdropswitchesto".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_whenbackdropswitchesto,
             arguments: vec![(ArgType::Field, "BACKDROP".into())],
+            shape: BlockShape::Hat {
+                restart_existing_threads: false,
+            },
         },
     );
     h.insert(
-        "event_whenbroadcastreceived",
+        "event_whenbroadcastreceived".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_whenbroadcastreceived,
             arguments: vec![(ArgType::Field, "BROADCAST_OPTION".into())],
+            shape: BlockShape::Hat {
+                restart_existing_threads: true,
+            },
+        },
+    );
+    h.insert(
+        "event_whengreaterthan".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::event_whengreaterthan,
+            arguments: vec![
+                (ArgType::Field, "WHENGREATERTHANMENU".into()),
+                (ArgType::Input(InputType::Number), "VALUE".into()),
+            ],
+            shape: BlockShape::EdgeActivatedHat,
         },
     );
     h.insert(
-        "event_broadcast",
+        "event_broadcast".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_broadcast,
-            arguments: vec![(ArgType::Input, "BROADCAST_INPUT".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "BROADCAST_INPUT".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "event_broadcastandwait",
+        "event_broadcastandwait".into(),
         BlockInfo {
             block_function: crate::core_blocks::event_broadcastandwait,
-            arguments: vec![(ArgType::Input, "BROADCAST_INPUT".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "BROADCAST_INPUT".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_wait",
+        "control_wait".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_wait,
-            arguments: vec![(ArgType::Input, "DURATION".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "DURATION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_repeat",
+        "control_repeat".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_repeat,
             arguments: vec![
-                (ArgType::Input, "TIMES".into()),
-                (ArgType::Input, "SUBSTACK".into()),
+                (ArgType::Input(InputType::Number), "TIMES".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_forever",
+        "control_forever".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_forever,
-            arguments: vec![(ArgType::Input, "SUBSTACK".into())],
+            arguments: vec![(ArgType::Input(InputType::Substack), "SUBSTACK".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_if",
+        "control_if".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_if,
             arguments: vec![
-                (ArgType::Input, "CONDITION".into()),
-                (ArgType::Input, "SUBSTACK".into()),
+                (ArgType::Input(InputType::Boolean), "CONDITION".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_if_else",
+        "control_if_else".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_if_else,
             arguments: vec![
-                (ArgType::Input, "CONDITION".into()),
-                (ArgType::Input, "SUBSTACK".into()),
-                (ArgType::Input, "SUBSTACK2".into()),
+                (ArgType::Input(InputType::Boolean), "CONDITION".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK2".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_wait_until",
+        "control_wait_until".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_wait_until,
-            arguments: vec![(ArgType::Input, "CONDITION".into())],
+            arguments: vec![(ArgType::Input(InputType::Boolean), "CONDITION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_repeat_until",
+        "control_repeat_until".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_repeat_until,
             arguments: vec![
-                (ArgType::Input, "CONDITION".into()),
-                (ArgType::Input, "SUBSTACK".into()),
+                (ArgType::Input(InputType::Boolean), "CONDITION".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_while",
+        "control_while".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_while,
             arguments: vec![
-                (ArgType::Input, "CONDITION".into()),
-                (ArgType::Input, "SUBSTACK".into()),
+                (ArgType::Input(InputType::Boolean), "CONDITION".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_for_each",
+        "control_for_each".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_for_each,
             arguments: vec![
                 (ArgType::Field, "VARIABLE".into()),
-                (ArgType::Input, "VALUE".into()),
-                (ArgType::Input, "SUBSTACK".into()),
+                (ArgType::Input(InputType::Number), "VALUE".into()),
+                (ArgType::Input(InputType::Substack), "SUBSTACK".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_stop",
+        "control_stop".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_stop,
             arguments: vec![(ArgType::Field, "STOP_OPTION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_start_as_clone",
+        "procedures_return".into(),
+        BlockInfo {
+            block_function: crate::core_blocks::procedures_return,
+            arguments: vec![(ArgType::Input(InputType::String), "VALUE".into())],
+            shape: BlockShape::Command,
+        },
+    );
+    h.insert(
+        "control_start_as_clone".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_start_as_clone,
             arguments: vec![],
+            shape: BlockShape::Hat {
+                restart_existing_threads: false,
+            },
         },
     );
     h.insert(
-        "control_create_clone_of",
+        "control_create_clone_of".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_create_clone_of,
-            arguments: vec![(ArgType::Input, "CLONE_OPTION".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "CLONE_OPTION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_create_clone_of_menu",
+        "control_create_clone_of_menu".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_create_clone_of_menu,
             arguments: vec![(ArgType::Field, "CLONE_OPTION".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "control_delete_this_clone",
+        "control_delete_this_clone".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_delete_this_clone,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_get_counter",
+        "control_get_counter".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_get_counter,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "control_incr_counter",
+        "control_incr_counter".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_incr_counter,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_clear_counter",
+        "control_clear_counter".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_clear_counter,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "control_all_at_once",
+        "control_all_at_once".into(),
         BlockInfo {
             block_function: crate::core_blocks::control_all_at_once,
-            arguments: vec![(ArgType::Input, "SUBSTACK".into())],
+            arguments: vec![(ArgType::Input(InputType::Substack), "SUBSTACK".into())],
+            shape: BlockShape::Command,
         },
     );
-    // h.insert("sensing_touchingobject", BlockInfo { block_function: crate::core_blocks::sensing_touchingobject, arguments: vec![(ArgType::Input, "TOUCHINGOBJECTMENU".into())] });
-    // h.insert("sensing_touchingcolor", BlockInfo { block_function: crate::core_blocks::sensing_touchingcolor, arguments: vec![(ArgType::Input, "COLOR".into())] });
-    // h.insert("sensing_coloristouchingcolor", BlockInfo { block_function: crate::core_blocks::sensing_coloristouchingcolor, arguments: vec![(ArgType::Input, "COLOR".into()), (ArgType::Input, "COLOR2".into())] });
+    // h.insert("sensing_touchingobject", BlockInfo { block_function: crate::core_blocks::sensing_touchingobject, arguments: vec![(ArgType::Input(InputType::String), "TOUCHINGOBJECTMENU".into())] });
+    // h.insert("sensing_touchingcolor", BlockInfo { block_function: crate::core_blocks::sensing_touchingcolor, arguments: vec![(ArgType::Input(InputType::Color), "COLOR".into())] });
+    // h.insert("sensing_coloristouchingcolor", BlockInfo { block_function: crate::core_blocks::sensing_coloristouchingcolor, arguments: vec![(ArgType::Input(InputType::Color), "COLOR".into()), (ArgType::Input(InputType::Color), "COLOR2".into())] });
     h.insert(
-        "sensing_distanceto",
+        "sensing_distanceto".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_distanceto,
-            arguments: vec![(ArgType::Input, "DISTANCETOMENU".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "DISTANCETOMENU".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "sensing_distancetomenu",
+        "sensing_distancetomenu".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_distancetomenu,
             arguments: vec![(ArgType::Field, "DISTANCETOMENU".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "sensing_askandwait",
+        "sensing_askandwait".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_askandwait,
-            arguments: vec![(ArgType::Input, "QUESTION".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "QUESTION".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "sensing_answer",
+        "sensing_answer".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_answer,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
-    // h.insert("sensing_keypressed", BlockInfo { block_function: crate::core_blocks::sensing_keypressed, arguments: vec![(ArgType::Input, "KEY_OPTION".into())] });
+    // h.insert("sensing_keypressed", BlockInfo { block_function: crate::core_blocks::sensing_keypressed, arguments: vec![(ArgType::Input(InputType::String), "KEY_OPTION".into())] });
     // h.insert("sensing_mousedown", BlockInfo { block_function: crate::core_blocks::sensing_mousedown, arguments: vec![] });
     // h.insert("sensing_mousex", BlockInfo { block_function: crate::core_blocks::sensing_mousex, arguments: vec![] });
     // h.insert("sensing_mousey", BlockInfo { block_function: crate::core_blocks::sensing_mousey, arguments: vec![] });
     // h.insert("sensing_loudness", BlockInfo { block_function: crate::core_blocks::sensing_loudness, arguments: vec![] });
     // h.insert("sensing_loud", BlockInfo { block_function: crate::core_blocks::sensing_loud, arguments: vec![] });
-    // h.insert("videoSensing_videoToggle", BlockInfo { block_function: crate::core_blocks::videoSensing_videoToggle, arguments: vec![(ArgType::Input, "VIDEO_STATE".into())] });
-    // h.insert("videoSensing_setVideoTransparency", BlockInfo { block_function: crate::core_blocks::videoSensing_setVideoTransparency, arguments: vec![(ArgType::Input, "TRANSPARENCY".into())] });
+    // h.insert("videoSensing_videoToggle", BlockInfo { block_function: crate::core_blocks::videoSensing_videoToggle, arguments: vec![(ArgType::Input(InputType::String), "VIDEO_STATE".into())] });
+    // h.insert("videoSensing_setVideoTransparency", BlockInfo { block_function: crate::core_blocks::videoSensing_setVideoTransparency, arguments: vec![(ArgType::Input(InputType::Number), "TRANSPARENCY".into())] });
     h.insert(
-        "sensing_timer",
+        "sensing_timer".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_timer,
             arguments: vec![],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "sensing_resettimer",
+        "sensing_resettimer".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_resettimer,
             arguments: vec![],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "sensing_of",
+        "sensing_of".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_of,
             arguments: vec![
                 (ArgType::Field, "PROPERTY".into()),
-                (ArgType::Input, "OBJECT".into()),
+                (ArgType::Input(InputType::String), "OBJECT".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "sensing_of_object_menu",
+        "sensing_of_object_menu".into(),
         BlockInfo {
             block_function: crate::core_blocks::sensing_of_object_menu,
             arguments: vec![(ArgType::Field, "OBJECT".into())],
+            shape: BlockShape::Reporter,
         },
     );
-    h.insert("sensing_current", BlockInfo { block_function: crate::core_blocks::sensing_current, arguments: vec![(ArgType::Field, "CURRENTMENU".into())] });
-    h.insert("sensing_dayssince2000", BlockInfo { block_function: crate::core_blocks::sensing_dayssince2000, arguments: vec![] });
-    h.insert("sensing_username", BlockInfo { block_function: crate::core_blocks::sensing_username, arguments: vec![] });
-    h.insert("sensing_userid", BlockInfo { block_function: crate::core_blocks::sensing_userid, arguments: vec![] });
+    h.insert("sensing_current".into(), BlockInfo { block_function: crate::core_blocks::sensing_current, arguments: vec![(ArgType::Field, "CURRENTMENU".into())], shape: BlockShape::Reporter });
+    h.insert("sensing_dayssince2000".into(), BlockInfo { block_function: crate::core_blocks::sensing_dayssince2000, arguments: vec![], shape: BlockShape::Reporter });
+    h.insert("sensing_username".into(), BlockInfo { block_function: crate::core_blocks::sensing_username, arguments: vec![], shape: BlockShape::Reporter });
+    h.insert("sensing_userid".into(), BlockInfo { block_function: crate::core_blocks::sensing_userid, arguments: vec![], shape: BlockShape::Reporter });
     h.insert(
-        "operator_add",
+        "operator_add".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_add,
             arguments: vec![
-                (ArgType::Input, "NUM1".into()),
-                (ArgType::Input, "NUM2".into()),
+                (ArgType::Input(InputType::Number), "NUM1".into()),
+                (ArgType::Input(InputType::Number), "NUM2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_subtract",
+        "operator_subtract".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_subtract,
             arguments: vec![
-                (ArgType::Input, "NUM1".into()),
-                (ArgType::Input, "NUM2".into()),
+                (ArgType::Input(InputType::Number), "NUM1".into()),
+                (ArgType::Input(InputType::Number), "NUM2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_multiply",
+        "operator_multiply".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_multiply,
             arguments: vec![
-                (ArgType::Input, "NUM1".into()),
-                (ArgType::Input, "NUM2".into()),
+                (ArgType::Input(InputType::Number), "NUM1".into()),
+                (ArgType::Input(InputType::Number), "NUM2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_divide",
+        "operator_divide".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_divide,
             arguments: vec![
-                (ArgType::Input, "NUM1".into()),
-                (ArgType::Input, "NUM2".into()),
+                (ArgType::Input(InputType::Number), "NUM1".into()),
+                (ArgType::Input(InputType::Number), "NUM2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_random",
+        "operator_random".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_random,
             arguments: vec![
-                (ArgType::Input, "FROM".into()),
-                (ArgType::Input, "TO".into()),
+                (ArgType::Input(InputType::Number), "FROM".into()),
+                (ArgType::Input(InputType::Number), "TO".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_lt",
+        "operator_lt".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_lt,
             arguments: vec![
-                (ArgType::Input, "OPERAND1".into()),
-                (ArgType::Input, "OPERAND2".into()),
+                (ArgType::Input(InputType::String), "OPERAND1".into()),
+                (ArgType::Input(InputType::String), "OPERAND2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_equals",
+        "operator_equals".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_equals,
             arguments: vec![
-                (ArgType::Input, "OPERAND1".into()),
-                (ArgType::Input, "OPERAND2".into()),
+                (ArgType::Input(InputType::String), "OPERAND1".into()),
+                (ArgType::Input(InputType::String), "OPERAND2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_gt",
+        "operator_gt".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_gt,
             arguments: vec![
-                (ArgType::Input, "OPERAND1".into()),
-                (ArgType::Input, "OPERAND2".into()),
+                (ArgType::Input(InputType::String), "OPERAND1".into()),
+                (ArgType::Input(InputType::String), "OPERAND2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_and",
+        "operator_and".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_and,
             arguments: vec![
-                (ArgType::Input, "OPERAND1".into()),
-                (ArgType::Input, "OPERAND2".into()),
+                (ArgType::Input(InputType::Boolean), "OPERAND1".into()),
+                (ArgType::Input(InputType::Boolean), "OPERAND2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_or",
+        "operator_or".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_or,
             arguments: vec![
-                (ArgType::Input, "OPERAND1".into()),
-                (ArgType::Input, "OPERAND2".into()),
+                (ArgType::Input(InputType::Boolean), "OPERAND1".into()),
+                (ArgType::Input(InputType::Boolean), "OPERAND2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_not",
+        "operator_not".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_not,
-            arguments: vec![(ArgType::Input, "OPERAND".into())],
+            arguments: vec![(ArgType::Input(InputType::Boolean), "OPERAND".into())],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_join",
+        "operator_join".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_join,
             arguments: vec![
-                (ArgType::Input, "STRING1".into()),
-                (ArgType::Input, "STRING2".into()),
+                (ArgType::Input(InputType::String), "STRING1".into()),
+                (ArgType::Input(InputType::String), "STRING2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_letter_of",
+        "operator_letter_of".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_letter_of,
             arguments: vec![
-                (ArgType::Input, "LETTER".into()),
-                (ArgType::Input, "STRING".into()),
+                (ArgType::Input(InputType::Number), "LETTER".into()),
+                (ArgType::Input(InputType::String), "STRING".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_contains",
+        "operator_contains".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_contains,
             arguments: vec![
-                (ArgType::Input, "STRING1".into()),
-                (ArgType::Input, "STRING2".into()),
+                (ArgType::Input(InputType::String), "STRING1".into()),
+                (ArgType::Input(InputType::String), "STRING2".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     h.insert(
-        "operator_length",
+        "operator_length".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_length,
-            arguments: vec![(ArgType::Input, "STRING".into())],
+            arguments: vec![(ArgType::Input(InputType::String), "STRING".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_mod",
+        "operator_mod".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_mod,
             arguments: vec![
-                (ArgType::Input, "NUM1".into()),
-                (ArgType::Input, "NUM2".into()),
+                (ArgType::Input(InputType::Number), "NUM1".into()),
+                (ArgType::Input(InputType::Number), "NUM2".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_round",
+        "operator_round".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_round,
-            arguments: vec![(ArgType::Input, "NUM".into())],
+            arguments: vec![(ArgType::Input(InputType::Number), "NUM".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "operator_mathop",
+        "operator_mathop".into(),
         BlockInfo {
             block_function: crate::core_blocks::operator_mathop,
             arguments: vec![
                 (ArgType::Field, "OPERATOR".into()),
-                (ArgType::Input, "NUM".into()),
+                (ArgType::Input(InputType::Number), "NUM".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_variable",
+        "data_variable".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_variable,
             arguments: vec![(ArgType::Field, "VARIABLE".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_variable",
+        "data_variable".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_variable,
             arguments: vec![(ArgType::Field, "VARIABLE".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_setvariableto",
+        "data_setvariableto".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_setvariableto,
             arguments: vec![
                 (ArgType::Field, "VARIABLE".into()),
-                (ArgType::Input, "VALUE".into()),
+                (ArgType::Input(InputType::String), "VALUE".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_changevariableby",
+        "data_changevariableby".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_changevariableby,
             arguments: vec![
                 (ArgType::Field, "VARIABLE".into()),
-                (ArgType::Input, "VALUE".into()),
+                (ArgType::Input(InputType::Number), "VALUE".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     // h.insert("data_showvariable", BlockInfo { block_function: crate::core_blocks::data_showvariable, arguments: vec![(ArgType::Field, "VARIABLE".into())] });
     // h.insert("data_hidevariable", BlockInfo { block_function: crate::core_blocks::data_hidevariable, arguments: vec![(ArgType::Field, "VARIABLE".into())] });
     h.insert(
-        "data_listcontents",
+        "data_listcontents".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_listcontents,
             arguments: vec![(ArgType::Field, "LIST".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_addtolist",
+        "data_addtolist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_addtolist,
             arguments: vec![
-                (ArgType::Input, "ITEM".into()),
+                (ArgType::Input(InputType::String), "ITEM".into()),
                 (ArgType::Field, "LIST".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_deleteoflist",
+        "data_deleteoflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_deleteoflist,
             arguments: vec![
-                (ArgType::Input, "INDEX".into()),
+                (ArgType::Input(InputType::String), "INDEX".into()),
                 (ArgType::Field, "LIST".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_deletealloflist",
+        "data_deletealloflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_deletealloflist,
             arguments: vec![(ArgType::Field, "LIST".into())],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_insertatlist",
+        "data_insertatlist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_insertatlist,
             arguments: vec![
-                (ArgType::Input, "ITEM".into()),
-                (ArgType::Input, "INDEX".into()),
+                (ArgType::Input(InputType::String), "ITEM".into()),
+                (ArgType::Input(InputType::String), "INDEX".into()),
                 (ArgType::Field, "LIST".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_replaceitemoflist",
+        "data_replaceitemoflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_replaceitemoflist,
             arguments: vec![
-                (ArgType::Input, "INDEX".into()),
+                (ArgType::Input(InputType::String), "INDEX".into()),
                 (ArgType::Field, "LIST".into()),
-                (ArgType::Input, "ITEM".into()),
+                (ArgType::Input(InputType::String), "ITEM".into()),
             ],
+            shape: BlockShape::Command,
         },
     );
     h.insert(
-        "data_itemoflist",
+        "data_itemoflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_itemoflist,
             arguments: vec![
-                (ArgType::Input, "INDEX".into()),
+                (ArgType::Input(InputType::String), "INDEX".into()),
                 (ArgType::Field, "LIST".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_lengthoflist",
+        "data_lengthoflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_lengthoflist,
             arguments: vec![(ArgType::Field, "LIST".into())],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_itemnumoflist",
+        "data_itemnumoflist".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_itemnumoflist,
             arguments: vec![
                 (ArgType::Field, "LIST".into()),
-                (ArgType::Input, "ITEM".into()),
+                (ArgType::Input(InputType::String), "ITEM".into()),
             ],
+            shape: BlockShape::Reporter,
         },
     );
     h.insert(
-        "data_listcontainsitem",
+        "data_listcontainsitem".into(),
         BlockInfo {
             block_function: crate::core_blocks::data_listcontainsitem,
             arguments: vec![
                 (ArgType::Field, "LIST".into()),
-                (ArgType::Input, "ITEM".into()),
+                (ArgType::Input(InputType::String), "ITEM".into()),
             ],
+            shape: BlockShape::Boolean,
         },
     );
     // h.insert("data_showlist", BlockInfo { block_function: crate::core_blocks::data_showlist, arguments: vec![(ArgType::Field, "LIST".into())] });
@@ -972,4 +1387,4 @@ pub static mut BLOCKS: Lazy<HashMap<&str, BlockInfo>> = Lazy::new(|| {
     // h.insert("argument_reporter_string_number", BlockInfo { block_function: crate::core_blocks::argument_reporter_string_number, arguments: vec![(ArgType::Field, "VALUE".into())] });
     // h.insert("procedures_call", BlockInfo { block_function: crate::core_blocks::procedures_call, arguments: vec![] });
     h
-});
+}
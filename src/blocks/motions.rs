@@ -104,7 +104,7 @@ pub fn motion_glidesecstoxy(ctx: &mut BlockContext) -> BlockResult {
             .downcast_ref::<GlideData>()
             .cloned()
             .unwrap();
-        let d = i.elapsed();
+        let d = ctx.runtime.elapsed(i);
         let mut rt = ctx.running_target_mut();
         if d.as_secs_f64() >= time {
             rt.x = x1;
@@ -123,7 +123,7 @@ pub fn motion_glidesecstoxy(ctx: &mut BlockContext) -> BlockResult {
             let y1 = ctx.arg(2).to_number();
             let x0 = ctx.running_target().x;
             let y0 = ctx.running_target().y;
-            let i = Instant::now();
+            let i = ctx.runtime.step_time;
             ctx.stack.block_data = Box::new((i, secs, x0, y0, x1, y1));
             pending()
         })
@@ -137,7 +137,7 @@ pub fn motion_glideto(ctx: &mut BlockContext) -> BlockResult {
             .downcast_ref::<GlideData>()
             .cloned()
             .unwrap();
-        let d = i.elapsed();
+        let d = ctx.runtime.elapsed(i);
         let mut rt = ctx.running_target_mut();
         if d.as_secs_f64() >= time {
             rt.x = x1;
@@ -156,7 +156,7 @@ pub fn motion_glideto(ctx: &mut BlockContext) -> BlockResult {
             if let Some((x1, y1)) = get_target_xy(ctx, to.as_str()) {
                 let x0 = ctx.running_target().x;
                 let y0 = ctx.running_target().y;
-                let i = Instant::now();
+                let i = ctx.runtime.step_time;
                 ctx.stack.block_data = Box::new((i, secs, x0, y0, x1, y1));
                 pending()
             } else {
@@ -220,3 +220,20 @@ pub fn motion_yposition(ctx: &mut BlockContext) -> BlockResult {
 pub fn motion_direction(ctx: &mut BlockContext) -> BlockResult {
     ret(ctx.running_target().direction)
 }
+
+// The scroll blocks come from Scratch 1.4 projects. The stage never scrolls, like scratch-vm.
+pub fn motion_scroll_right(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(1, |_| end())
+}
+pub fn motion_scroll_up(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(1, |_| end())
+}
+pub fn motion_align_scene(ctx: &mut BlockContext) -> BlockResult {
+    ctx.acquire_args(1, |_| end())
+}
+pub fn motion_xscroll(_ctx: &mut BlockContext) -> BlockResult {
+    ret(0.)
+}
+pub fn motion_yscroll(_ctx: &mut BlockContext) -> BlockResult {
+    ret(0.)
+}
@@ -44,9 +44,9 @@ pub fn operator_random(ctx: &mut BlockContext) -> BlockResult {
             return BlockResult::Resolved(Some(from.into()));
         }
         let result = if from.trunc() == from && to.trunc() == to {
-            unsafe { THREAD_RND.gen_range((from as isize)..(to as isize)) as f64 }
+            ctx.runtime.rng.gen_range((from as isize)..(to as isize)) as f64
         } else {
-            unsafe { THREAD_RND.gen_range(from..to) }
+            ctx.runtime.rng.gen_range(from..to)
         };
         BlockResult::Resolved(Some(result.into()))
     })
@@ -9,67 +9,134 @@ pub fn procedures_definition_return(_ctx: &mut BlockContext) -> BlockResult {
     end()
 }
 
-pub struct ProcedureArguments(pub Vec<BlockValue>);
-pub struct ReturnProcedureArguments(pub Vec<BlockValue>);
+/// The scope of a running procedure call, stored in the stack of the call block.
+#[derive(Debug, Clone)]
+pub struct ProcedureFrame {
+    /// The definition block of the procedure.
+    pub definition: BlockId,
+    /// Arguments bound by name, in the order of the prototype.
+    pub arguments: Vec<(String, BlockValue)>,
+    /// Whether the procedure runs without screen refresh.
+    pub warp: bool,
+}
+
+impl ProcedureFrame {
+    /// Get an argument by name. Like scratch-vm, the last argument wins if names are duplicated.
+    pub fn argument(&self, name: &str) -> Option<&BlockValue> {
+        self.arguments
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v)
+    }
+}
+
+/// Get the frame of the innermost procedure call running in `stacks`.
+pub fn current_frame(stacks: &[Stack]) -> Option<&ProcedureFrame> {
+    stacks
+        .iter()
+        .rev()
+        .find_map(|s| s.block_data.downcast_ref::<ProcedureFrame>())
+}
+
+/// Whether a thread with `stacks` is inside a procedure that runs without screen refresh.
+pub(crate) fn is_warp(stacks: &[Stack]) -> bool {
+    stacks.iter().any(|s| {
+        s.block_data
+            .downcast_ref::<ProcedureFrame>()
+            .map(|x| x.warp)
+            .unwrap_or(false)
+    })
+}
+
+/// Whether the top of `stacks` is a call block entering a procedure that is already
+/// running in the thread. Like scratch-vm, a thread yields on such calls unless it is in warp mode.
+pub(crate) fn is_recursive_call(stacks: &[Stack]) -> bool {
+    let (call, callers) = match stacks.split_last() {
+        Some(x) => x,
+        None => return false,
+    };
+    match call.block_data.downcast_ref::<ProcedureFrame>() {
+        Some(frame) => callers.iter().any(|s| {
+            s.block_data
+                .downcast_ref::<ProcedureFrame>()
+                .map(|x| x.definition == frame.definition)
+                .unwrap_or(false)
+        }),
+        None => false,
+    }
+}
+
+/// Bind the resolved arguments of a call block and enter the procedure.
+fn call_procedure(ctx: &mut BlockContext) -> BlockResult {
+    let bid = match ctx.get_block().arguments.last() {
+        Some(BlockValue::BlockId(bid)) => *bid,
+        _ => return BlockResult::Resolved(None),
+    };
+    // The definition block holds `warp`, the proccode and then the argument names
+    let frame = match ctx.target().blocks.get(bid) {
+        Some(definition) => ProcedureFrame {
+            definition: bid,
+            arguments: definition
+                .arguments
+                .iter()
+                .skip(2)
+                .map(|x| x.to_string())
+                .zip(ctx.stack.arguments.iter().cloned())
+                .collect(),
+            warp: definition
+                .arguments
+                .first()
+                .map(|x| x.to_boolean())
+                .unwrap_or(false),
+        },
+        None => return BlockResult::Resolved(None),
+    };
+    ctx.stack.block_data = Box::new(frame);
+    BlockResult::PushStack(bid)
+}
 
 pub fn procedures_call(ctx: &mut BlockContext) -> BlockResult {
     if ctx.arg_len() >= ctx.get_block().arguments.len() {
         return BlockResult::Resolved(None);
     }
-    ctx.acquire_args(ctx.get_block().arguments.len() - 1, |ctx| {
-        let bid = match ctx.get_block().arguments.last() {
-            Some(BlockValue::BlockId(bid)) => BlockResult::PushStack(*bid),
-            _ => BlockResult::Resolved(None),
-        };
-        if let BlockResult::PushStack(bid) = bid {
-            ctx.stack.block_data = Box::new(ProcedureArguments(ctx.stack.arguments.to_owned()));
-            BlockResult::PushStack(bid)
-        } else {
-            BlockResult::Resolved(None)
-        }
-    })
+    ctx.acquire_args(ctx.get_block().arguments.len() - 1, call_procedure)
 }
 
 /// ClipCC
+///
+/// Reports the value of `procedures_return`, or an empty string if the procedure ends without returning.
 pub fn procedures_call_return(ctx: &mut BlockContext) -> BlockResult {
-    if ctx.arg_len() >= ctx.get_block().arguments.len() {
-        return BlockResult::Resolved(None);
-    }
-    ctx.acquire_args(ctx.get_block().arguments.len() - 1, |ctx| {
-        let bid = match ctx.get_block().arguments.last() {
-            Some(BlockValue::BlockId(bid)) => BlockResult::PushStack(*bid),
-            _ => BlockResult::Resolved(None),
+    let len = ctx.get_block().arguments.len();
+    if ctx.arg_len() >= len {
+        return match ctx.arg(len - 1) {
+            BlockValue::Undefined => ret(""),
+            other => ret(other.to_owned()),
         };
-        if let BlockResult::PushStack(bid) = bid {
-            ctx.stack.block_data =
-                Box::new(ReturnProcedureArguments(ctx.stack.arguments.to_owned()));
-            BlockResult::PushStack(bid)
-        } else {
-            BlockResult::Resolved(None)
-        }
-    })
+    }
+    ctx.acquire_args(len - 1, call_procedure)
 }
 
+/// Reports `0` if the argument is not bound, like outside a procedure definition.
 pub fn argument_reporter_string_number(ctx: &mut BlockContext) -> BlockResult {
     if ctx.arg_len() > 0 {
-        return BlockResult::Resolved(Some(ctx.arg(0).to_owned()));
-    } else {
-        let param_id = ctx.get_block().arguments[0].to_number() as usize;
-        BlockResult::ResolveProcedureArgument(param_id)
+        return match ctx.arg(0) {
+            BlockValue::Undefined => ret(0),
+            other => ret(other.to_owned()),
+        };
     }
+    BlockResult::ResolveProcedureArgument(ctx.arg_proto(0).to_string())
 }
 
+/// Reports `false` if the argument is not bound, like outside a procedure definition.
 pub fn argument_reporter_boolean(ctx: &mut BlockContext) -> BlockResult {
-    if ctx.arg_len() > 1 {
-        return match ctx.arg(1) {
-            BlockValue::Undefined => ret(0),
+    if ctx.arg_len() > 0 {
+        return match ctx.arg(0) {
+            BlockValue::Undefined => ret(false),
             other => ret(other.to_owned()),
         };
     }
-    ctx.acquire_args(1, |ctx| {
-        let param_id = ctx.arg(0).to_number() as usize;
-        BlockResult::ResolveProcedureArgument(param_id)
-    })
+    BlockResult::ResolveProcedureArgument(ctx.arg_proto(0).to_string())
 }
 
 /// ClipCC
@@ -4,32 +4,18 @@ use crate::*;
 use std::sync::mpsc::*;
 use std::{
     io::BufRead,
-    sync::{atomic::AtomicBool, Arc, Mutex},
-    time::Instant,
+    sync::{Arc, Mutex},
 };
 
 pub fn sensing_resettimer(ctx: &mut BlockContext) -> BlockResult {
-    ctx.global_data
-        .insert("GLOBAL_TIMER".into(), Box::new(Instant::now()));
+    ctx.runtime.reset_timer();
     BlockResult::Resolved(None)
 }
 
 pub fn sensing_timer(ctx: &mut BlockContext) -> BlockResult {
-    if let Some(timer) = ctx.global_data.get("GLOBAL_TIMER") {
-        if let Some(timer) = timer.downcast_ref::<Instant>() {
-            BlockResult::Resolved(Some(timer.elapsed().as_secs_f64().into()))
-        } else {
-            sensing_timer(ctx);
-            BlockResult::Resolved(Some(0f64.into()))
-        }
-    } else {
-        sensing_resettimer(ctx);
-        BlockResult::Resolved(Some(0f64.into()))
-    }
+    BlockResult::Resolved(Some(ctx.runtime.timer_value().into()))
 }
 
-const SESING_ANSWER: &str = "SENSING_ANSWER";
-
 fn start_ask(tx: Sender<String>) {
     std::thread::spawn(move || {
         let stdin = std::io::stdin();
@@ -49,7 +35,10 @@ pub fn sensing_distanceto(ctx: &mut BlockContext) -> BlockResult {
     ctx.acquire_args(1, |ctx| {
         if ctx.is_stage() {
             ret(10000f64)
-        } else if let Some((x, y)) = get_target_xy(ctx, ctx.arg(0).to_string().as_str()) {
+        } else if let Some((x, y)) = {
+            let target_name = ctx.arg(0).to_string();
+            get_target_xy(ctx, target_name.as_str())
+        } {
             let dx = x - ctx.running_target().x;
             let dy = y - ctx.running_target().y;
             ret((dx.powi(2) + dy.powi(2)).sqrt())
@@ -64,55 +53,26 @@ pub fn sensing_distancetomenu(ctx: &mut BlockContext) -> BlockResult {
 }
 
 pub fn sensing_askandwait(ctx: &mut BlockContext) -> BlockResult {
-    type IOLock = Arc<AtomicBool>;
-    const SESING_ASK_LOCK: &str = "SESING_ASK_LOCK";
     ctx.acquire_args(1, |ctx| {
         let ask_msg = ctx.arg(0).to_string();
+        if let Some(answers) = ctx.runtime.extensions.get_mut::<Answers>() {
+            ctx.runtime.answer = answers.0.pop_front().unwrap_or_default();
+            return end();
+        }
         if let Some(self_lock) = ctx.stack.block_data.downcast_ref::<Receiver<String>>() {
             if let Ok(answer) = self_lock.try_recv() {
-                ctx.global_data
-                    .insert(SESING_ANSWER.into(), Box::new(answer));
-                if let Some(lock) = ctx.global_data.get_mut(SESING_ASK_LOCK) {
-                    if let Some(lock) = lock.downcast_mut::<IOLock>() {
-                        lock.store(false, std::sync::atomic::Ordering::SeqCst);
-                    }
-                }
+                ctx.runtime.answer = answer;
+                ctx.runtime.asking = false;
                 end()
             } else {
                 pending()
             }
-        } else if let Some(lock) = ctx.global_data.get_mut(SESING_ASK_LOCK) {
-            if let Some(lock) = lock.downcast_mut::<IOLock>() {
-                if lock.load(std::sync::atomic::Ordering::SeqCst) {
-                    pending()
-                } else {
-                    println!("{}", ask_msg);
-                    // Start a new thread to wait for the answer
-                    lock.store(true, std::sync::atomic::Ordering::SeqCst);
-                    let (tx, rx) = channel();
-                    start_ask(tx);
-                    ctx.stack.block_data = Box::new(Arc::new(Mutex::new(rx)));
-                    pending()
-                }
-            } else {
-                println!("{}", ask_msg);
-                // Start a new thread to wait for the answer
-                ctx.global_data.insert(
-                    SESING_ASK_LOCK.into(),
-                    Box::new(IOLock::new(AtomicBool::new(true))),
-                );
-                let (tx, rx) = channel();
-                start_ask(tx);
-                ctx.stack.block_data = Box::new(Arc::new(Mutex::new(rx)));
-                pending()
-            }
+        } else if ctx.runtime.asking {
+            pending()
         } else {
-            //
-            ctx.global_data.insert(
-                SESING_ASK_LOCK.into(),
-                Box::new(IOLock::new(AtomicBool::new(true))),
-            );
             println!("{}", ask_msg);
+            // Start a new thread to wait for the answer
+            ctx.runtime.asking = true;
             let (tx, rx) = channel();
             start_ask(tx);
             ctx.stack.block_data = Box::new(Arc::new(Mutex::new(rx)));
@@ -122,17 +82,7 @@ pub fn sensing_askandwait(ctx: &mut BlockContext) -> BlockResult {
 }
 
 pub fn sensing_answer(ctx: &mut BlockContext) -> BlockResult {
-    BlockResult::Resolved(
-        if let Some(answer) = ctx
-            .global_data
-            .get(SESING_ANSWER)
-            .and_then(|x| x.downcast_ref::<String>())
-        {
-            Some(answer.to_owned().into())
-        } else {
-            Default::default()
-        },
-    )
+    ret(ctx.runtime.answer.to_owned())
 }
 
 pub fn sensing_of_object_menu(ctx: &mut BlockContext) -> BlockResult {
@@ -154,7 +104,11 @@ pub fn sensing_of(ctx: &mut BlockContext) -> BlockResult {
                     }
                     "volume" => return ret(rstage.volume),
                     variable => {
-                        if let Some(value) = rstage.variables.get(variable) {
+                        // The menu holds the name of the variable
+                        if let Some(value) = stage
+                            .variable_id(variable)
+                            .and_then(|id| rstage.variables.get(id))
+                        {
                             return ret(value.to_owned());
                         }
                     }
@@ -178,7 +132,10 @@ pub fn sensing_of(ctx: &mut BlockContext) -> BlockResult {
                         "size" => return ret(rt.size),
                         "volume" => return ret(rt.volume),
                         variable => {
-                            if let Some(value) = rt.variables.get(variable) {
+                            if let Some(value) = target
+                                .variable_id(variable)
+                                .and_then(|id| rt.variables.get(id))
+                            {
                                 return ret(value.to_owned());
                             }
                         }
@@ -0,0 +1,430 @@
+use super::*;
+use crate::blocks::BlockRegistry;
+use std::collections::HashSet;
+
+type CompileResult = Result<(), String>;
+
+/// Compile the scripts and procedures of all targets.
+///
+/// With the `jit` feature, `native` adds sites that compile numeric statements and loops
+/// to native code once they are hot.
+pub fn compile_program(
+    registry: &BlockRegistry,
+    targets: &Arena<Target>,
+    native: bool,
+) -> (CompiledProgram, CompileReport) {
+    let mut report = CompileReport::default();
+    #[cfg(feature = "jit")]
+    let jit = if native {
+        super::jit::JitCompiler::new().map(|jit| Arc::new(std::sync::Mutex::new(jit)))
+    } else {
+        None
+    };
+    #[cfg(not(feature = "jit"))]
+    let _ = native;
+    // Target, top block, whether it is a procedure, the chunk and the procedures it calls
+    let mut chunks = Vec::new();
+    for (tid, target) in targets.iter() {
+        for (bid, block) in target.blocks.iter().filter(|(_, b)| b.toplevel) {
+            let is_procedure = matches!(
+                block.opcode.as_str(),
+                "procedures_definition" | "procedures_return_definition"
+            );
+            let mut compiler = Compiler {
+                registry,
+                target,
+                chunk: Chunk::default(),
+                calls: Vec::new(),
+                in_procedure: is_procedure,
+                #[cfg(feature = "jit")]
+                jit: jit.clone(),
+                #[cfg(feature = "jit")]
+                in_native: false,
+                native_sites: 0,
+            };
+            let result = if is_procedure {
+                compiler.compile_procedure(block)
+            } else {
+                compiler.compile_script(bid)
+            };
+            match result {
+                Ok(()) => {
+                    report.native_sites += compiler.native_sites;
+                    chunks.push((tid, bid, is_procedure, compiler.chunk, compiler.calls))
+                }
+                Err(reason) => report.skipped.push((tid, bid, reason)),
+            }
+        }
+    }
+    // A chunk can only run as bytecode if all procedures it calls are compiled
+    loop {
+        let procedures = chunks
+            .iter()
+            .filter(|c| c.2)
+            .map(|c| (c.0, c.1))
+            .collect::<HashSet<_>>();
+        let len = chunks.len();
+        chunks.retain(|(tid, bid, _, _, calls)| {
+            match calls.iter().find(|d| !procedures.contains(&(*tid, **d))) {
+                Some(definition) => {
+                    report.skipped.push((
+                        *tid,
+                        *bid,
+                        format!(
+                            "calls \"{}\", which is not compiled",
+                            procedure_name(&targets[*tid], *definition)
+                        ),
+                    ));
+                    false
+                }
+                None => true,
+            }
+        });
+        if chunks.len() == len {
+            break;
+        }
+    }
+    let mut program = CompiledProgram {
+        #[cfg(feature = "jit")]
+        jit,
+        ..Default::default()
+    };
+    report.compiled = chunks.len();
+    for (tid, bid, is_procedure, chunk, _) in chunks {
+        if is_procedure {
+            program.procedures.insert((tid, bid), Arc::new(chunk));
+        } else {
+            program.scripts.insert((tid, bid), Arc::new(chunk));
+        }
+    }
+    (program, report)
+}
+
+struct Compiler<'a> {
+    registry: &'a BlockRegistry,
+    target: &'a Target,
+    chunk: Chunk,
+    /// Definition blocks of the called procedures.
+    calls: Vec<BlockId>,
+    in_procedure: bool,
+    #[cfg(feature = "jit")]
+    jit: Option<Arc<std::sync::Mutex<super::jit::JitCompiler>>>,
+    /// Set while compiling code covered by a native site, which needs no sites of its own.
+    #[cfg(feature = "jit")]
+    in_native: bool,
+    native_sites: usize,
+}
+
+/// The native site of a loop being compiled.
+#[derive(Default)]
+struct LoopSite {
+    #[cfg(feature = "jit")]
+    site: Option<Arc<NativeSite>>,
+    #[cfg(feature = "jit")]
+    in_native: bool,
+}
+
+impl<'a> Compiler<'a> {
+    fn compile_script(&mut self, top_block: BlockId) -> CompileResult {
+        self.compile_chain(Some(top_block))?;
+        self.emit(Instr::End);
+        Ok(())
+    }
+
+    fn compile_procedure(&mut self, definition: &Block) -> CompileResult {
+        self.compile_chain(definition.next)?;
+        self.emit(Instr::ReturnEmpty);
+        Ok(())
+    }
+
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.chunk.code.push(instr);
+        self.chunk.code.len() - 1
+    }
+
+    fn here(&self) -> usize {
+        self.chunk.code.len()
+    }
+
+    /// Point the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.chunk.code[at] {
+            Instr::Jump(to) | Instr::JumpIfFalse(to) | Instr::JumpIfTrue(to) => *to = here,
+            Instr::Count { end, .. } => *end = here,
+            #[cfg(feature = "jit")]
+            Instr::Native { end, .. } => *end = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn block(&self, block_id: BlockId) -> Result<&'a Block, String> {
+        self.target
+            .blocks
+            .get(block_id)
+            .ok_or_else(|| format!("missing block {:?}", block_id))
+    }
+
+    /// Compile a chain of statements.
+    fn compile_chain(&mut self, mut next: Option<BlockId>) -> CompileResult {
+        while let Some(bid) = next {
+            #[cfg(feature = "jit")]
+            if let Some(after) = self.compile_native(bid)? {
+                next = after;
+                continue;
+            }
+            let block = self.block(bid)?;
+            self.compile_statement(bid, block)?;
+            next = block.next;
+        }
+        Ok(())
+    }
+
+    /// Add a site for the statements starting at `first` that native code supports, and
+    /// compile them to bytecode. Returns the block after them, or `None` if there is no site.
+    #[cfg(feature = "jit")]
+    fn compile_native(&mut self, first: BlockId) -> Result<Option<Option<BlockId>>, String> {
+        let jit = match &self.jit {
+            Some(jit) if !self.in_native => jit.clone(),
+            _ => return Ok(None),
+        };
+        let target = self.target;
+        let mut statements = Vec::new();
+        let mut next = Some(first);
+        while let Some(bid) = next {
+            match target.blocks.get(bid) {
+                Some(block) if super::jit::supports_statement(target, block) => {
+                    statements.push(bid);
+                    next = block.next;
+                }
+                _ => break,
+            }
+        }
+        if statements.is_empty() {
+            return Ok(None);
+        }
+        let site = NativeSite::statements(jit, statements.clone());
+        let native = self.emit(Instr::Native {
+            site: Arc::new(site),
+            end: 0,
+        });
+        self.native_sites += 1;
+        self.in_native = true;
+        for bid in statements {
+            let block = self.block(bid)?;
+            self.compile_statement(bid, block)?;
+        }
+        self.in_native = false;
+        self.patch(native);
+        Ok(Some(next))
+    }
+
+    /// Start compiling the body of a loop, with `counter` the slot of `repeat`.
+    /// With the `jit` feature, a loop that native code supports gets a site.
+    fn enter_loop(&mut self, block_id: BlockId, block: &Block, counter: Option<usize>) -> LoopSite {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &self.jit {
+            if !self.in_native && super::jit::supports_loop(self.target, block) {
+                self.native_sites += 1;
+                return LoopSite {
+                    site: Some(Arc::new(NativeSite::for_loop(
+                        jit.clone(),
+                        block_id,
+                        counter,
+                    ))),
+                    in_native: std::mem::replace(&mut self.in_native, true),
+                };
+            }
+        }
+        #[cfg(not(feature = "jit"))]
+        let _ = (block_id, block, counter);
+        LoopSite::default()
+    }
+
+    /// Finish the body of a loop, before it waits for the next iteration.
+    /// Returns the site to point to the end of the loop.
+    fn leave_loop(&mut self, loop_site: LoopSite) -> Option<usize> {
+        #[cfg(feature = "jit")]
+        if let Some(site) = loop_site.site {
+            self.in_native = loop_site.in_native;
+            return Some(self.emit(Instr::Native { site, end: 0 }));
+        }
+        #[cfg(not(feature = "jit"))]
+        let _ = loop_site;
+        None
+    }
+
+    /// Returns `false` if there is no substack, like an empty `if`.
+    fn compile_substack(&mut self, substack: Option<&BlockValue>) -> Result<bool, String> {
+        match substack {
+            Some(BlockValue::BlockId(bid)) => {
+                self.compile_chain(Some(*bid))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn compile_input(&mut self, input: Option<&BlockValue>) -> CompileResult {
+        match input {
+            Some(BlockValue::BlockId(bid)) => {
+                self.compile_expression(*bid)?;
+            }
+            Some(value) => {
+                self.emit(Instr::Push(value.to_owned()));
+            }
+            None => {
+                self.emit(Instr::Push(BlockValue::Undefined));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_statement(&mut self, block_id: BlockId, block: &'a Block) -> CompileResult {
+        let args = &block.arguments;
+        match block.opcode.as_str() {
+            "control_if" => {
+                self.compile_input(args.first())?;
+                let jump = self.emit(Instr::JumpIfFalse(0));
+                self.compile_substack(args.get(1))?;
+                self.patch(jump);
+            }
+            "control_if_else" => {
+                self.compile_input(args.first())?;
+                let jump_else = self.emit(Instr::JumpIfFalse(0));
+                self.compile_substack(args.get(1))?;
+                let jump_end = self.emit(Instr::Jump(0));
+                self.patch(jump_else);
+                self.compile_substack(args.get(2))?;
+                self.patch(jump_end);
+            }
+            "control_repeat" => {
+                self.compile_input(args.first())?;
+                let slot = self.chunk.slots;
+                self.chunk.slots += 1;
+                self.emit(Instr::SetCounter(slot));
+                if let Some(BlockValue::BlockId(_)) = args.get(1) {
+                    // The first iteration does not wait, and neither does the end of the loop
+                    let first = self.emit(Instr::Count { slot, end: 0 });
+                    let start = self.here();
+                    let loop_site = self.enter_loop(block_id, block, Some(slot));
+                    self.compile_substack(args.get(1))?;
+                    let count = self.emit(Instr::Count { slot, end: 0 });
+                    let native = self.leave_loop(loop_site);
+                    self.emit(Instr::LoopWait);
+                    self.emit(Instr::Jump(start));
+                    self.patch(first);
+                    self.patch(count);
+                    native.into_iter().for_each(|at| self.patch(at));
+                }
+            }
+            "control_forever" => {
+                let start = self.here();
+                self.compile_substack(args.first())?;
+                self.emit(Instr::LoopWait);
+                self.emit(Instr::Jump(start));
+            }
+            "control_while" | "control_repeat_until" => {
+                let start = self.here();
+                self.compile_input(args.first())?;
+                let jump = if block.opcode == "control_while" {
+                    self.emit(Instr::JumpIfFalse(0))
+                } else {
+                    self.emit(Instr::JumpIfTrue(0))
+                };
+                let loop_site = self.enter_loop(block_id, block, None);
+                self.compile_substack(args.get(1))?;
+                let native = self.leave_loop(loop_site);
+                self.emit(Instr::LoopWait);
+                self.emit(Instr::Jump(start));
+                self.patch(jump);
+                native.into_iter().for_each(|at| self.patch(at));
+            }
+            "control_wait_until" => {
+                let start = self.here();
+                self.compile_input(args.first())?;
+                let jump = self.emit(Instr::JumpIfTrue(0));
+                self.emit(Instr::Tick);
+                self.emit(Instr::Jump(start));
+                self.patch(jump);
+            }
+            "procedures_call" | "procedures_call_return" => self.compile_call(block, false)?,
+            "procedures_return" => {
+                self.compile_input(args.first())?;
+                if self.in_procedure {
+                    self.emit(Instr::Return);
+                } else {
+                    // Outside a procedure it stops the script
+                    self.emit(Instr::End);
+                }
+            }
+            _ => self.compile_block(block_id, block, false)?,
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, block_id: BlockId) -> CompileResult {
+        let block = self.block(block_id)?;
+        match block.opcode.as_str() {
+            "argument_reporter_string_number" | "argument_reporter_boolean" => {
+                self.emit(Instr::Argument {
+                    name: block
+                        .arguments
+                        .first()
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                    boolean: block.opcode == "argument_reporter_boolean",
+                });
+                Ok(())
+            }
+            "procedures_call" | "procedures_call_return" => self.compile_call(block, true),
+            _ => self.compile_block(block_id, block, true),
+        }
+    }
+
+    fn compile_call(&mut self, block: &'a Block, reporter: bool) -> CompileResult {
+        // The call block holds the inputs and then the definition block
+        let (definition, inputs) = match block.arguments.split_last() {
+            Some((BlockValue::BlockId(definition), inputs)) => (*definition, inputs),
+            _ => return Err("procedure call without definition".into()),
+        };
+        for input in inputs {
+            self.compile_input(Some(input))?;
+        }
+        self.calls.push(definition);
+        self.emit(Instr::CallProcedure {
+            definition,
+            argc: inputs.len(),
+            reporter,
+        });
+        Ok(())
+    }
+
+    fn compile_block(
+        &mut self,
+        block_id: BlockId,
+        block: &'a Block,
+        reporter: bool,
+    ) -> CompileResult {
+        // Blocks with substacks run them with `PushStack`, which needs the tree-walking interpreter
+        if let Some(info) = self.registry.get(&block.opcode) {
+            if info
+                .arguments
+                .iter()
+                .any(|(_, name)| name.starts_with("SUBSTACK"))
+            {
+                return Err(format!("{} is not supported", block.opcode));
+            }
+        }
+        for input in block.arguments.iter() {
+            self.compile_input(Some(input))?;
+        }
+        self.emit(Instr::Call {
+            block: block_id,
+            block_function: block.block_function,
+            argc: block.arguments.len(),
+            reporter,
+        });
+        Ok(())
+    }
+}
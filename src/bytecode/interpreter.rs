@@ -0,0 +1,497 @@
+use super::*;
+use std::time::Instant;
+
+/// Why a bytecode thread stopped running in this step.
+#[derive(Debug)]
+pub enum Exit {
+    /// Continue in the next step.
+    Yield,
+    Finished,
+    /// `stop all`.
+    StopAll,
+    /// `delete this clone`.
+    DeleteClone,
+}
+
+/// Where a chunk stopped running.
+#[derive(Debug)]
+pub enum Flow {
+    /// Yield to other threads, unless the thread runs in warp mode.
+    Yield,
+    /// The thread entered or left a procedure, so it continues in another chunk.
+    Switch,
+    /// Stop running in this step, even in warp mode.
+    Exit(Exit),
+}
+
+/// What to do after calling a block.
+#[derive(Debug)]
+pub enum Step {
+    /// Continue with the next instruction.
+    Next,
+    /// Call the block again.
+    Again,
+    /// The block is waiting. Yield, and call it again.
+    Wait,
+    /// The block was `broadcast and wait`, the thread always yields after it unless the script ends.
+    BroadcastAndWait,
+    Exit(Exit),
+}
+
+/// The VM state a bytecode thread runs with.
+pub struct Machine<'a> {
+    pub(crate) runtime: &'a mut RuntimeState,
+    pub(crate) targets: &'a mut Arena<Target>,
+    pub(crate) running_targets: &'a mut generational_arena::Arena<RunningTarget>,
+    pub(crate) program: &'a CompiledProgram,
+    pub(crate) stage_id: TargetId,
+    pub(crate) running_stage_id: RunningTargetId,
+    pub(crate) target_id: TargetId,
+    pub(crate) running_target_id: RunningTargetId,
+    /// Messages broadcast by the thread, and whether it waits for them.
+    pub(crate) broadcasts: Vec<(String, bool)>,
+    /// Clone options of the `create clone` blocks run by the thread.
+    pub(crate) clones: Vec<String>,
+}
+
+impl Machine<'_> {
+    /// The block of the running target at `index` in its arena, and its block function.
+    pub fn block_at(&self, index: usize) -> (BlockId, BlockFunction) {
+        let target = &self.targets[self.target_id];
+        let block_id = aot::block_id(target, index);
+        (block_id, target.blocks[block_id].block_function)
+    }
+}
+
+/// How a thread waits between loop iterations.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LoopState {
+    loop_yield: LoopYield,
+    /// When the thread started running in warp mode in this step.
+    warp_timer: Option<Instant>,
+}
+
+impl LoopState {
+    /// Whether a thread that yields keeps running, since it runs in warp mode and not for too long.
+    fn keeps_running(&mut self, warp: bool) -> bool {
+        warp && self.warp_timer.get_or_insert_with(Instant::now).elapsed() < WARP_TIME
+    }
+
+    /// Like `LoopWait`, and `run` continuing after it in warp mode.
+    /// Returns `true` if the thread yields before the next iteration.
+    #[cfg(feature = "jit")]
+    pub(crate) fn loop_yields(&mut self, warp: bool, runtime: &RuntimeState) -> bool {
+        while self.loop_yield.should_wait(warp, runtime) {
+            if !self.keeps_running(warp) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A running procedure and where to continue in its caller.
+#[derive(Debug, Clone)]
+struct CallFrame {
+    chunk: Arc<Chunk>,
+    pc: usize,
+    slot_base: usize,
+    operand_base: usize,
+    procedure: core_blocks::ProcedureFrame,
+    reporter: bool,
+}
+
+/// State of a thread that runs bytecode.
+///
+/// The instructions are methods too, so code generated by `aot` runs the same way.
+#[derive(Debug, Clone)]
+pub struct BytecodeThread {
+    chunk: Arc<Chunk>,
+    pc: usize,
+    slot_base: usize,
+    frames: Vec<CallFrame>,
+    operands: Vec<BlockValue>,
+    slots: Vec<usize>,
+    /// Stack of the called block, reused for every call.
+    call: Stack,
+    /// Whether `call` holds a block that is still running.
+    pending: bool,
+    /// Whether a procedure in warp mode is running.
+    warp: bool,
+    loop_state: LoopState,
+    /// Values used by native regions, reused between runs.
+    #[cfg(feature = "jit")]
+    native_slots: Vec<BlockValue>,
+}
+
+impl BytecodeThread {
+    pub(crate) fn new(chunk: Arc<Chunk>, top_block: BlockId, block: &Block) -> Self {
+        Self {
+            slots: vec![0; chunk.slots],
+            chunk,
+            pc: 0,
+            slot_base: 0,
+            frames: Vec::new(),
+            operands: Vec::with_capacity(16),
+            call: Stack::new(top_block, block),
+            pending: false,
+            warp: false,
+            loop_state: LoopState::default(),
+            #[cfg(feature = "jit")]
+            native_slots: Vec::new(),
+        }
+    }
+
+    /// Index of the next instruction in the running chunk.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Continue at `pc` in the running chunk.
+    pub fn jump(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Push a value onto the operand stack.
+    pub fn push(&mut self, value: BlockValue) {
+        self.operands.push(value);
+    }
+
+    /// Pop a condition.
+    pub fn pop_condition(&mut self) -> bool {
+        self.operands.pop().unwrap_or_default().to_boolean()
+    }
+
+    /// Pop the number of iterations of `repeat` into a counter slot.
+    pub fn set_counter(&mut self, slot: usize) {
+        let times = self.operands.pop().unwrap_or_default().to_number() as usize;
+        self.slots[self.slot_base + slot] = times;
+    }
+
+    /// Decrement a counter slot. Returns `false` if it was already zero.
+    pub fn count(&mut self, slot: usize) -> bool {
+        let counter = &mut self.slots[self.slot_base + slot];
+        if *counter == 0 {
+            false
+        } else {
+            *counter -= 1;
+            true
+        }
+    }
+
+    /// Whether the thread should yield before the next loop iteration.
+    pub fn loop_wait(&mut self, m: &mut Machine) -> bool {
+        self.loop_state.loop_yield.should_wait(self.warp, m.runtime)
+    }
+
+    /// Push the argument of the running procedure with this name.
+    pub fn argument(&mut self, name: &str, boolean: bool) {
+        let value = match self.frames.last().and_then(|f| f.procedure.argument(name)) {
+            None | Some(BlockValue::Undefined) if boolean => false.into(),
+            None | Some(BlockValue::Undefined) => 0.into(),
+            Some(value) => value.to_owned(),
+        };
+        self.operands.push(value);
+    }
+
+    /// Call a block with the last `argc` operands as its arguments.
+    pub fn call_block(
+        &mut self,
+        m: &mut Machine,
+        block: BlockId,
+        block_function: BlockFunction,
+        argc: usize,
+        reporter: bool,
+    ) -> Step {
+        if !self.pending {
+            let start = self.operands.len() - argc;
+            self.call.block_id = block;
+            self.call.block_function = block_function;
+            self.call.arguments.clear();
+            self.call.arguments.extend(self.operands.drain(start..));
+            self.call.block_data = Box::new(());
+        }
+        let result = {
+            let mut ctx = BlockContext {
+                stack: &mut self.call,
+                warp: self.warp,
+                runtime: m.runtime,
+                targets: m.targets,
+                running_targets: m.running_targets,
+                target_id: m.target_id,
+                running_stage_id: m.running_stage_id,
+                running_target_id: m.running_target_id,
+                stage_id: m.stage_id,
+            };
+            (block_function)(&mut ctx)
+        };
+        self.pending = false;
+        match result {
+            BlockResult::Resolved(value) => {
+                if reporter {
+                    self.operands.push(value.unwrap_or_default());
+                }
+                Step::Next
+            }
+            BlockResult::Pending => {
+                self.pending = true;
+                Step::Wait
+            }
+            BlockResult::ResolveArgument(_) => {
+                // The block wants more arguments than it has inputs
+                self.call.arguments.push(BlockValue::Undefined);
+                self.pending = true;
+                Step::Again
+            }
+            BlockResult::Boardcast(name) => {
+                m.broadcasts.push((name, false));
+                Step::Next
+            }
+            BlockResult::BoardcastAndWait(name) => {
+                m.broadcasts.push((name, true));
+                Step::BroadcastAndWait
+            }
+            BlockResult::CreateClone(clone_option) => {
+                m.clones.push(clone_option);
+                Step::Next
+            }
+            BlockResult::DeleteThisClone => Step::Exit(Exit::DeleteClone),
+            BlockResult::StopScript(TargetType::AllScripts) => Step::Exit(Exit::StopAll),
+            BlockResult::StopScript(TargetType::ThisScript) => Step::Exit(Exit::Finished),
+            BlockResult::StopScript(TargetType::OtherScriptsInSprite) => {
+                // TODO
+                Step::Next
+            }
+            BlockResult::PushStack(_)
+            | BlockResult::ResolveProcedureArgument(_)
+            | BlockResult::ReturnProcedure(_) => {
+                // The compiler does not call blocks that need these
+                if reporter {
+                    self.operands.push(BlockValue::Undefined);
+                }
+                Step::Next
+            }
+        }
+    }
+
+    /// Call a custom procedure with the last `argc` operands as its arguments,
+    /// and continue after this instruction when it returns.
+    ///
+    /// Returns how to continue in the procedure, which yields if it is already running
+    /// like in the tree-walking interpreter. Returns `None` if the procedure is not
+    /// compiled, then the call reports an empty string.
+    pub fn call_procedure(
+        &mut self,
+        m: &mut Machine,
+        definition: BlockId,
+        argc: usize,
+        reporter: bool,
+    ) -> Option<Flow> {
+        let start = self.operands.len() - argc;
+        let chunk = m.program.procedures.get(&(m.target_id, definition));
+        let block = m.targets[m.target_id].blocks.get(definition);
+        let (chunk, block) = match (chunk, block) {
+            (Some(chunk), Some(block)) => (chunk.clone(), block),
+            _ => {
+                self.operands.truncate(start);
+                if reporter {
+                    self.operands.push("".into());
+                }
+                return None;
+            }
+        };
+        let recursive = self
+            .frames
+            .iter()
+            .any(|f| f.procedure.definition == definition);
+        // The definition block holds `warp`, the proccode and then the argument names
+        let procedure = core_blocks::ProcedureFrame {
+            definition,
+            arguments: block
+                .arguments
+                .iter()
+                .skip(2)
+                .map(|x| x.to_string())
+                .zip(self.operands.drain(start..))
+                .collect(),
+            warp: block
+                .arguments
+                .first()
+                .map(|x| x.to_boolean())
+                .unwrap_or(false),
+        };
+        self.operands.truncate(start);
+        let slot_base = self.slots.len();
+        self.slots.resize(slot_base + chunk.slots, 0);
+        let caller = std::mem::replace(&mut self.chunk, chunk);
+        self.warp |= procedure.warp;
+        self.frames.push(CallFrame {
+            chunk: caller,
+            pc: self.pc + 1,
+            slot_base: self.slot_base,
+            operand_base: start,
+            procedure,
+            reporter,
+        });
+        self.pc = 0;
+        self.slot_base = slot_base;
+        Some(if recursive { Flow::Yield } else { Flow::Switch })
+    }
+
+    /// Pop a value and return it from the running procedure.
+    pub fn return_value(&mut self) -> Flow {
+        let value = self.operands.pop().unwrap_or_default();
+        self.return_procedure(value)
+    }
+
+    /// Return from the running procedure at its end.
+    pub fn return_empty(&mut self) -> Flow {
+        self.return_procedure(BlockValue::Undefined)
+    }
+
+    fn return_procedure(&mut self, value: BlockValue) -> Flow {
+        match self.frames.pop() {
+            Some(frame) => {
+                self.slots.truncate(self.slot_base);
+                self.operands.truncate(frame.operand_base);
+                self.chunk = frame.chunk;
+                self.pc = frame.pc;
+                self.slot_base = frame.slot_base;
+                self.warp = self.frames.iter().any(|f| f.procedure.warp);
+                if frame.reporter {
+                    self.operands.push(match value {
+                        BlockValue::Undefined => "".into(),
+                        other => other,
+                    });
+                }
+                Flow::Switch
+            }
+            None => Flow::Exit(Exit::Finished),
+        }
+    }
+
+    /// Run until the thread yields or finishes.
+    pub(crate) fn run(&mut self, m: &mut Machine) -> Exit {
+        self.loop_state.warp_timer = None;
+        loop {
+            let flow = match self.chunk.function {
+                Some(function) => function(self, m),
+                None => self.run_code(m),
+            };
+            match flow {
+                // Keep running in warp mode, unless the thread has been running for too long
+                Flow::Yield if self.loop_state.keeps_running(self.warp) => {}
+                Flow::Yield => return Exit::Yield,
+                Flow::Switch => {}
+                Flow::Exit(exit) => return exit,
+            }
+        }
+    }
+
+    /// Run the instructions of the running chunk.
+    fn run_code(&mut self, m: &mut Machine) -> Flow {
+        let chunk = self.chunk.clone();
+        loop {
+            match &chunk.code[self.pc] {
+                Instr::Push(value) => {
+                    self.operands.push(value.to_owned());
+                    self.pc += 1;
+                }
+                Instr::Call {
+                    block,
+                    block_function,
+                    argc,
+                    reporter,
+                } => match self.call_block(m, *block, *block_function, *argc, *reporter) {
+                    Step::Next => self.pc += 1,
+                    Step::Again => {}
+                    Step::Wait => return Flow::Yield,
+                    Step::BroadcastAndWait => {
+                        self.pc += 1;
+                        // Always yields, unless the script ends with it
+                        return Flow::Exit(match chunk.code[self.pc] {
+                            Instr::End => Exit::Finished,
+                            _ => Exit::Yield,
+                        });
+                    }
+                    Step::Exit(exit) => return Flow::Exit(exit),
+                },
+                Instr::Jump(to) => self.pc = *to,
+                Instr::JumpIfFalse(to) => {
+                    if self.pop_condition() {
+                        self.pc += 1;
+                    } else {
+                        self.pc = *to;
+                    }
+                }
+                Instr::JumpIfTrue(to) => {
+                    if self.pop_condition() {
+                        self.pc = *to;
+                    } else {
+                        self.pc += 1;
+                    }
+                }
+                Instr::SetCounter(slot) => {
+                    self.set_counter(*slot);
+                    self.pc += 1;
+                }
+                Instr::Count { slot, end } => {
+                    if self.count(*slot) {
+                        self.pc += 1;
+                    } else {
+                        self.pc = *end;
+                    }
+                }
+                Instr::Tick => {
+                    self.pc += 1;
+                    return Flow::Yield;
+                }
+                Instr::LoopWait => {
+                    if self.loop_wait(m) {
+                        return Flow::Yield;
+                    }
+                    self.pc += 1;
+                }
+                Instr::Argument { name, boolean } => {
+                    self.argument(name, *boolean);
+                    self.pc += 1;
+                }
+                Instr::CallProcedure {
+                    definition,
+                    argc,
+                    reporter,
+                } => {
+                    if let Some(flow) = self.call_procedure(m, *definition, *argc, *reporter) {
+                        return flow;
+                    }
+                    self.pc += 1;
+                }
+                Instr::Return => return self.return_value(),
+                Instr::ReturnEmpty => return self.return_empty(),
+                Instr::End => return Flow::Exit(Exit::Finished),
+                #[cfg(feature = "jit")]
+                Instr::Native { site, end } => match site.region(&m.targets[m.target_id]) {
+                    Some(region) => {
+                        let frame = self.frames.last().map(|f| &f.procedure);
+                        let counter = site
+                            .counter()
+                            .map(|slot| &mut self.slots[self.slot_base + slot]);
+                        let finished = region.run(
+                            &mut self.native_slots,
+                            m,
+                            frame,
+                            counter,
+                            &mut self.loop_state,
+                            self.warp,
+                        );
+                        if !finished {
+                            return Flow::Yield;
+                        }
+                        self.pc = *end;
+                    }
+                    None => self.pc += 1,
+                },
+            }
+        }
+    }
+}
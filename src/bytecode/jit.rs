@@ -0,0 +1,998 @@
+//! Compiles numeric statements and loops that run often to native code with Cranelift.
+//!
+//! Threads never yield between blocks, so a run of consecutive statements that only
+//! compute with numbers and booleans, read and write variables, and branch with `if`,
+//! can run as one native function. So can a `repeat`, `repeat until` or `while` loop
+//! made of such statements.
+//!
+//! The compiler adds a site before each run of such statements, and at the end of each
+//! such loop. A site counts how often it runs, and is compiled once it ran `JIT_THRESHOLD`
+//! times. Until then, or if Cranelift does not support the host, the bytecode after the
+//! site runs instead. A native loop starts where the bytecode loop waits for its next
+//! iteration, and waits there like `LoopWait`: when the thread yields, it returns to the
+//! interpreter and continues at the same site in the next step.
+//!
+//! A native region works on a copy of the values it uses, laid out in slots:
+//! the values are copied in when the region starts, and the variables it writes
+//! are copied back when it ends. No other block runs in between.
+
+use super::interpreter::LoopState;
+use super::*;
+use crate::core_blocks::ProcedureFrame;
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Number of runs after which a site is compiled to native code.
+pub const JIT_THRESHOLD: usize = 100;
+
+const SLOT_SIZE: i64 = std::mem::size_of::<BlockValue>() as i64;
+
+const COMPARE_LT: i64 = 0;
+const COMPARE_EQ: i64 = 1;
+const COMPARE_GT: i64 = 2;
+
+/// The code of a site.
+#[derive(Debug, Clone)]
+enum Code {
+    Statements(Vec<BlockId>),
+    /// A loop, with the counter slot of `repeat`.
+    Loop {
+        block: BlockId,
+        counter: Option<usize>,
+    },
+}
+
+/// A place in the bytecode that runs as native code once it is hot.
+pub struct NativeSite {
+    code: Code,
+    runs: AtomicUsize,
+    region: OnceLock<Option<NativeRegion>>,
+    jit: Arc<Mutex<JitCompiler>>,
+}
+
+impl std::fmt::Debug for NativeSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeSite")
+            .field("code", &self.code)
+            .field("runs", &self.runs)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl NativeSite {
+    pub(crate) fn statements(jit: Arc<Mutex<JitCompiler>>, statements: Vec<BlockId>) -> Self {
+        Self::new(jit, Code::Statements(statements))
+    }
+
+    pub(crate) fn for_loop(
+        jit: Arc<Mutex<JitCompiler>>,
+        block: BlockId,
+        counter: Option<usize>,
+    ) -> Self {
+        Self::new(jit, Code::Loop { block, counter })
+    }
+
+    fn new(jit: Arc<Mutex<JitCompiler>>, code: Code) -> Self {
+        Self {
+            code,
+            runs: AtomicUsize::new(0),
+            region: OnceLock::new(),
+            jit,
+        }
+    }
+
+    /// The counter slot of a `repeat` loop.
+    pub(crate) fn counter(&self) -> Option<usize> {
+        match self.code {
+            Code::Loop { counter, .. } => counter,
+            Code::Statements(_) => None,
+        }
+    }
+
+    /// Count a run of the site. Returns its native code, once the site is hot.
+    pub(crate) fn region(&self, target: &Target) -> Option<&NativeRegion> {
+        if let Some(region) = self.region.get() {
+            return region.as_ref();
+        }
+        if self.runs.fetch_add(1, Ordering::Relaxed) + 1 < JIT_THRESHOLD {
+            return None;
+        }
+        self.region
+            .get_or_init(|| self.jit.lock().ok()?.compile(target, &self.code))
+            .as_ref()
+    }
+}
+
+/// What a native loop needs to wait for its next iteration.
+struct LoopWaiter<'a> {
+    state: &'a mut LoopState,
+    warp: bool,
+    runtime: &'a RuntimeState,
+}
+
+/// Where the value of a slot comes from when a native region starts.
+#[derive(Debug, Clone)]
+enum Slot {
+    /// A variable of the running target or the stage, copied back if the region writes it.
+    Variable {
+        name: String,
+        written: bool,
+    },
+    /// An argument of the running procedure.
+    Argument {
+        name: String,
+        boolean: bool,
+    },
+    Constant(BlockValue),
+    /// Holds a number or boolean while it is compared with another value.
+    Scratch,
+}
+
+/// The memory of the compiled code, freed when no region uses it anymore.
+struct JitMemory(Mutex<Option<JITModule>>);
+
+// SAFETY: the module is only used behind the mutex, and the code is not changed once finalized
+unsafe impl Send for JitMemory {}
+unsafe impl Sync for JitMemory {}
+
+impl Drop for JitMemory {
+    fn drop(&mut self) {
+        if let Some(module) = self.0.get_mut().ok().and_then(|m| m.take()) {
+            // SAFETY: the regions keep the memory alive, so none of its functions can run anymore
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Native code of a site.
+pub struct NativeRegion {
+    /// Takes the slots, the counter of `repeat` and the `LoopWaiter`.
+    /// Returns 0 if the thread yields, 1 if the code finished.
+    function: unsafe extern "C" fn(*mut BlockValue, *mut usize, *mut u8) -> i8,
+    slots: Vec<Slot>,
+    _memory: Arc<JitMemory>,
+}
+
+impl std::fmt::Debug for NativeRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeRegion")
+            .field("slots", &self.slots)
+            .finish()
+    }
+}
+
+impl NativeRegion {
+    /// Run the region. `env` is a buffer reused between runs, `counter` the counter slot of
+    /// `repeat`. Returns `false` if the thread yields in a loop, then the site runs again.
+    pub(crate) fn run(
+        &self,
+        env: &mut Vec<BlockValue>,
+        m: &mut Machine,
+        frame: Option<&ProcedureFrame>,
+        counter: Option<&mut usize>,
+        loop_state: &mut LoopState,
+        warp: bool,
+    ) -> bool {
+        env.clear();
+        for slot in self.slots.iter() {
+            env.push(match slot {
+                Slot::Variable { name, .. } => m.running_targets[m.running_target_id]
+                    .variables
+                    .get(name)
+                    .or_else(|| m.running_targets[m.running_stage_id].variables.get(name))
+                    .cloned()
+                    .unwrap_or_default(),
+                Slot::Argument { name, boolean } => match frame.and_then(|f| f.argument(name)) {
+                    None | Some(BlockValue::Undefined) if *boolean => false.into(),
+                    None | Some(BlockValue::Undefined) => 0.into(),
+                    Some(value) => value.to_owned(),
+                },
+                Slot::Constant(value) => value.to_owned(),
+                Slot::Scratch => BlockValue::Undefined,
+            });
+        }
+        let mut waiter = LoopWaiter {
+            state: loop_state,
+            warp,
+            runtime: m.runtime,
+        };
+        let counter = counter.map_or(std::ptr::null_mut(), |c| c as *mut usize);
+        // SAFETY: the function only accesses the slots it was compiled with, and the counter
+        // if it was compiled for `repeat`
+        let finished = unsafe {
+            (self.function)(
+                env.as_mut_ptr(),
+                counter,
+                &mut waiter as *mut LoopWaiter as *mut u8,
+            )
+        } != 0;
+        for (slot, value) in self.slots.iter().zip(env.drain(..)) {
+            if let Slot::Variable {
+                name,
+                written: true,
+            } = slot
+            {
+                // Like `get_variable_mut`, a missing variable is created on the running target
+                let running_target_id = if m.running_targets[m.running_target_id]
+                    .variables
+                    .contains_key(name)
+                    || !m.running_targets[m.running_stage_id]
+                        .variables
+                        .contains_key(name)
+                {
+                    m.running_target_id
+                } else {
+                    m.running_stage_id
+                };
+                m.running_targets[running_target_id]
+                    .variables
+                    .insert(name.to_owned(), value);
+            }
+        }
+        finished
+    }
+}
+
+/// Whether a block can run in a native region as a statement.
+pub(crate) fn supports_statement(target: &Target, block: &Block) -> bool {
+    let args = &block.arguments;
+    match block.opcode.as_str() {
+        "data_setvariableto" | "data_changevariableby" => {
+            matches!(args.first(), Some(name) if !name.is_block())
+                && supports_expression(target, args.get(1))
+        }
+        "control_if" => {
+            supports_expression(target, args.first()) && supports_chain(target, args.get(1))
+        }
+        "control_if_else" => {
+            supports_expression(target, args.first())
+                && supports_chain(target, args.get(1))
+                && supports_chain(target, args.get(2))
+        }
+        _ => false,
+    }
+}
+
+/// Whether a loop can run as native code.
+pub(crate) fn supports_loop(target: &Target, block: &Block) -> bool {
+    let args = &block.arguments;
+    match block.opcode.as_str() {
+        "control_repeat" => supports_chain(target, args.get(1)),
+        "control_while" | "control_repeat_until" => {
+            supports_expression(target, args.first()) && supports_chain(target, args.get(1))
+        }
+        _ => false,
+    }
+}
+
+fn supports_chain(target: &Target, substack: Option<&BlockValue>) -> bool {
+    let mut next = match substack {
+        Some(BlockValue::BlockId(bid)) => Some(*bid),
+        _ => None,
+    };
+    while let Some(bid) = next {
+        match target.blocks.get(bid) {
+            Some(block) if supports_statement(target, block) => next = block.next,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn supports_expression(target: &Target, value: Option<&BlockValue>) -> bool {
+    let block = match value {
+        Some(BlockValue::BlockId(bid)) => match target.blocks.get(*bid) {
+            Some(block) => block,
+            None => return false,
+        },
+        _ => return true,
+    };
+    let args = &block.arguments;
+    match block.opcode.as_str() {
+        "operator_add" | "operator_subtract" | "operator_multiply" | "operator_divide"
+        | "operator_mod" | "operator_lt" | "operator_equals" | "operator_gt" | "operator_and"
+        | "operator_or" | "operator_not" | "operator_round" => {
+            args.iter().all(|a| supports_expression(target, Some(a)))
+        }
+        "operator_mathop" => {
+            matches!(args.first(), Some(op) if !op.is_block())
+                && supports_expression(target, args.get(1))
+        }
+        "data_variable" | "argument_reporter_string_number" | "argument_reporter_boolean" => {
+            matches!(args.first(), Some(name) if !name.is_block())
+        }
+        _ => false,
+    }
+}
+
+/// A value computed by native code.
+#[derive(Clone, Copy)]
+enum JitValue {
+    Number(Value),
+    Boolean(Value),
+    /// A value that can have any type, read from a slot when it is used.
+    Slot(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Helper {
+    ToNumber,
+    ToBoolean,
+    SetNumber,
+    SetBoolean,
+    Copy,
+    Change,
+    Compare,
+    CompareNumbers,
+    Mod,
+    Round,
+    Mathop,
+    LoopWait,
+}
+
+const HELPERS: [Helper; 12] = [
+    Helper::ToNumber,
+    Helper::ToBoolean,
+    Helper::SetNumber,
+    Helper::SetBoolean,
+    Helper::Copy,
+    Helper::Change,
+    Helper::Compare,
+    Helper::CompareNumbers,
+    Helper::Mod,
+    Helper::Round,
+    Helper::Mathop,
+    Helper::LoopWait,
+];
+
+impl Helper {
+    fn name(self) -> &'static str {
+        match self {
+            Helper::ToNumber => "clipcc_jit_to_number",
+            Helper::ToBoolean => "clipcc_jit_to_boolean",
+            Helper::SetNumber => "clipcc_jit_set_number",
+            Helper::SetBoolean => "clipcc_jit_set_boolean",
+            Helper::Copy => "clipcc_jit_copy",
+            Helper::Change => "clipcc_jit_change",
+            Helper::Compare => "clipcc_jit_compare",
+            Helper::CompareNumbers => "clipcc_jit_compare_numbers",
+            Helper::Mod => "clipcc_jit_mod",
+            Helper::Round => "clipcc_jit_round",
+            Helper::Mathop => "clipcc_jit_mathop",
+            Helper::LoopWait => "clipcc_jit_loop_wait",
+        }
+    }
+
+    fn address(self) -> *const u8 {
+        match self {
+            Helper::ToNumber => helpers::to_number as *const u8,
+            Helper::ToBoolean => helpers::to_boolean as *const u8,
+            Helper::SetNumber => helpers::set_number as *const u8,
+            Helper::SetBoolean => helpers::set_boolean as *const u8,
+            Helper::Copy => helpers::copy as *const u8,
+            Helper::Change => helpers::change as *const u8,
+            Helper::Compare => helpers::compare as *const u8,
+            Helper::CompareNumbers => helpers::compare_numbers as *const u8,
+            Helper::Mod => helpers::modulo as *const u8,
+            Helper::Round => helpers::round as *const u8,
+            Helper::Mathop => helpers::mathop as *const u8,
+            Helper::LoopWait => helpers::loop_wait as *const u8,
+        }
+    }
+
+    /// Parameter and return types, with 64-bit pointers.
+    fn types(self) -> (&'static [types::Type], &'static [types::Type]) {
+        use types::{F64, I32, I8};
+        const P: types::Type = types::I64;
+        match self {
+            Helper::ToNumber => (&[P], &[F64]),
+            Helper::ToBoolean => (&[P], &[I8]),
+            Helper::SetNumber => (&[P, F64], &[]),
+            Helper::SetBoolean => (&[P, I8], &[]),
+            Helper::Copy => (&[P, P], &[]),
+            Helper::Change => (&[P, F64], &[]),
+            Helper::Compare => (&[P, P, I32], &[I8]),
+            Helper::CompareNumbers => (&[F64, F64, I32], &[I8]),
+            Helper::Mod => (&[F64, F64], &[F64]),
+            Helper::Round => (&[F64], &[F64]),
+            Helper::Mathop => (&[I32, F64], &[F64]),
+            Helper::LoopWait => (&[P], &[I8]),
+        }
+    }
+}
+
+/// Compiles native regions. All regions compiled by one compiler share its code memory.
+pub(crate) struct JitCompiler {
+    /// Number of compiled regions.
+    pub compiled: usize,
+    memory: Arc<JitMemory>,
+    helpers: Vec<FuncId>,
+    context: cranelift_codegen::Context,
+    builder_context: FunctionBuilderContext,
+}
+
+impl std::fmt::Debug for JitCompiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JitCompiler")
+            .field("compiled", &self.compiled)
+            .finish()
+    }
+}
+
+impl JitCompiler {
+    /// Returns `None` if Cranelift does not support the host.
+    pub fn new() -> Option<Self> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        for helper in HELPERS {
+            builder.symbol(helper.name(), helper.address());
+        }
+        let mut module = JITModule::new(builder);
+        if module.target_config().pointer_type() != types::I64 {
+            return None;
+        }
+        let mut helpers = Vec::with_capacity(HELPERS.len());
+        for helper in HELPERS {
+            let mut signature = module.make_signature();
+            let (params, returns) = helper.types();
+            signature
+                .params
+                .extend(params.iter().map(|t| AbiParam::new(*t)));
+            signature
+                .returns
+                .extend(returns.iter().map(|t| AbiParam::new(*t)));
+            helpers.push(
+                module
+                    .declare_function(helper.name(), Linkage::Import, &signature)
+                    .ok()?,
+            );
+        }
+        Some(Self {
+            compiled: 0,
+            context: module.make_context(),
+            memory: Arc::new(JitMemory(Mutex::new(Some(module)))),
+            helpers,
+            builder_context: FunctionBuilderContext::new(),
+        })
+    }
+
+    /// Compile the code of a site, which must be supported.
+    fn compile(&mut self, target: &Target, code: &Code) -> Option<NativeRegion> {
+        let mut guard = self.memory.0.lock().ok()?;
+        let module = guard.as_mut()?;
+        let pointer = module.target_config().pointer_type();
+        module.clear_context(&mut self.context);
+        let signature = &mut self.context.func.signature;
+        signature.params.extend([AbiParam::new(pointer); 3]);
+        signature.returns.push(AbiParam::new(types::I8));
+        let helpers = self
+            .helpers
+            .iter()
+            .map(|id| module.declare_func_in_func(*id, &mut self.context.func))
+            .collect::<Vec<_>>();
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let (env, counter, waiter) = match builder.block_params(entry) {
+            &[env, counter, waiter] => (env, counter, waiter),
+            _ => unreachable!(),
+        };
+        let mut codegen = Codegen {
+            builder,
+            env,
+            counter,
+            waiter,
+            target,
+            helpers,
+            slots: Vec::new(),
+        };
+        match code {
+            Code::Statements(statements) => {
+                for bid in statements {
+                    codegen.statement(&target.blocks[*bid]);
+                }
+                codegen.finish(true);
+            }
+            Code::Loop { block, .. } => codegen.native_loop(&target.blocks[*block]),
+        }
+        codegen.builder.seal_all_blocks();
+        let Codegen { builder, slots, .. } = codegen;
+        builder.finalize();
+        let id = module
+            .declare_anonymous_function(&self.context.func.signature)
+            .ok()?;
+        module.define_function(id, &mut self.context).ok()?;
+        module.clear_context(&mut self.context);
+        module.finalize_definitions().ok()?;
+        let code = module.get_finalized_function(id);
+        drop(guard);
+        self.compiled += 1;
+        Some(NativeRegion {
+            // SAFETY: the function was compiled with this signature
+            function: unsafe {
+                std::mem::transmute::<
+                    *const u8,
+                    unsafe extern "C" fn(*mut BlockValue, *mut usize, *mut u8) -> i8,
+                >(code)
+            },
+            slots,
+            _memory: self.memory.clone(),
+        })
+    }
+}
+
+struct Codegen<'a, 'b> {
+    builder: FunctionBuilder<'b>,
+    env: Value,
+    counter: Value,
+    waiter: Value,
+    target: &'a Target,
+    helpers: Vec<FuncRef>,
+    slots: Vec<Slot>,
+}
+
+impl Codegen<'_, '_> {
+    fn call(&mut self, helper: Helper, args: &[Value]) -> Option<Value> {
+        let func = self.helpers[helper as usize];
+        let call = self.builder.ins().call(func, args);
+        self.builder.inst_results(call).first().copied()
+    }
+
+    fn slot_address(&mut self, slot: usize) -> Value {
+        self.builder
+            .ins()
+            .iadd_imm(self.env, slot as i64 * SLOT_SIZE)
+    }
+
+    fn variable_slot(&mut self, name: String, write: bool) -> usize {
+        let found = self
+            .slots
+            .iter_mut()
+            .position(|s| matches!(s, Slot::Variable { name: n, .. } if *n == name));
+        match found {
+            Some(index) => {
+                if let Slot::Variable { written, .. } = &mut self.slots[index] {
+                    *written |= write;
+                }
+                index
+            }
+            None => self.new_slot(Slot::Variable {
+                name,
+                written: write,
+            }),
+        }
+    }
+
+    fn new_slot(&mut self, slot: Slot) -> usize {
+        self.slots.push(slot);
+        self.slots.len() - 1
+    }
+
+    /// Return whether the code finished, or the thread yields.
+    fn finish(&mut self, finished: bool) {
+        let finished = self.builder.ins().iconst(types::I8, finished as i64);
+        self.builder.ins().return_(&[finished]);
+    }
+
+    /// A loop that starts where it waits for the next iteration, like the bytecode loop
+    /// at its site.
+    fn native_loop(&mut self, block: &Block) {
+        let args = &block.arguments;
+        let wait_block = self.builder.create_block();
+        let yield_block = self.builder.create_block();
+        let next_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let done_block = self.builder.create_block();
+        self.builder.ins().jump(wait_block, &[]);
+
+        self.builder.switch_to_block(wait_block);
+        let waiter = self.waiter;
+        let yields = self.call(Helper::LoopWait, &[waiter]).unwrap();
+        self.builder
+            .ins()
+            .brif(yields, yield_block, &[], next_block, &[]);
+        self.builder.switch_to_block(yield_block);
+        self.finish(false);
+
+        self.builder.switch_to_block(next_block);
+        match block.opcode.as_str() {
+            "control_repeat" => {
+                // The counter was decremented for this iteration before the site
+                self.builder.ins().jump(body_block, &[]);
+                self.builder.switch_to_block(body_block);
+                self.chain(args.get(1));
+                let count_block = self.builder.create_block();
+                let counter =
+                    self.builder
+                        .ins()
+                        .load(types::I64, MemFlags::trusted(), self.counter, 0);
+                self.builder
+                    .ins()
+                    .brif(counter, count_block, &[], done_block, &[]);
+                self.builder.switch_to_block(count_block);
+                let counter = self.builder.ins().iadd_imm(counter, -1);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), counter, self.counter, 0);
+                self.builder.ins().jump(wait_block, &[]);
+            }
+            _ => {
+                let condition = self.expression(args.first());
+                let condition = self.boolean(condition);
+                if block.opcode == "control_while" {
+                    self.builder
+                        .ins()
+                        .brif(condition, body_block, &[], done_block, &[]);
+                } else {
+                    self.builder
+                        .ins()
+                        .brif(condition, done_block, &[], body_block, &[]);
+                }
+                self.builder.switch_to_block(body_block);
+                self.chain(args.get(1));
+                self.builder.ins().jump(wait_block, &[]);
+            }
+        }
+
+        self.builder.switch_to_block(done_block);
+        self.finish(true);
+    }
+
+    fn chain(&mut self, substack: Option<&BlockValue>) {
+        let target = self.target;
+        let mut next = match substack {
+            Some(BlockValue::BlockId(bid)) => Some(*bid),
+            _ => None,
+        };
+        while let Some(bid) = next {
+            let block = &target.blocks[bid];
+            self.statement(block);
+            next = block.next;
+        }
+    }
+
+    fn statement(&mut self, block: &Block) {
+        let args = &block.arguments;
+        match block.opcode.as_str() {
+            "data_setvariableto" => {
+                let value = self.expression(args.get(1));
+                let slot = self.variable_slot(args[0].to_string(), true);
+                let address = self.slot_address(slot);
+                match value {
+                    JitValue::Number(v) => {
+                        self.call(Helper::SetNumber, &[address, v]);
+                    }
+                    JitValue::Boolean(v) => {
+                        self.call(Helper::SetBoolean, &[address, v]);
+                    }
+                    JitValue::Slot(source) if source != slot => {
+                        let source = self.slot_address(source);
+                        self.call(Helper::Copy, &[address, source]);
+                    }
+                    JitValue::Slot(_) => {}
+                }
+            }
+            "data_changevariableby" => {
+                let value = self.expression(args.get(1));
+                let value = self.number(value);
+                let slot = self.variable_slot(args[0].to_string(), true);
+                let address = self.slot_address(slot);
+                self.call(Helper::Change, &[address, value]);
+            }
+            "control_if" => {
+                let condition = self.expression(args.first());
+                let condition = self.boolean(condition);
+                let then_block = self.builder.create_block();
+                let end_block = self.builder.create_block();
+                self.builder
+                    .ins()
+                    .brif(condition, then_block, &[], end_block, &[]);
+                self.builder.switch_to_block(then_block);
+                self.chain(args.get(1));
+                self.builder.ins().jump(end_block, &[]);
+                self.builder.switch_to_block(end_block);
+            }
+            "control_if_else" => {
+                let condition = self.expression(args.first());
+                let condition = self.boolean(condition);
+                let then_block = self.builder.create_block();
+                let else_block = self.builder.create_block();
+                let end_block = self.builder.create_block();
+                self.builder
+                    .ins()
+                    .brif(condition, then_block, &[], else_block, &[]);
+                self.builder.switch_to_block(then_block);
+                self.chain(args.get(1));
+                self.builder.ins().jump(end_block, &[]);
+                self.builder.switch_to_block(else_block);
+                self.chain(args.get(2));
+                self.builder.ins().jump(end_block, &[]);
+                self.builder.switch_to_block(end_block);
+            }
+            _ => unreachable!("unsupported statement {}", block.opcode),
+        }
+    }
+
+    fn expression(&mut self, value: Option<&BlockValue>) -> JitValue {
+        let block = match value {
+            Some(BlockValue::BlockId(bid)) => &self.target.blocks[*bid],
+            Some(BlockValue::Number(n)) => {
+                return JitValue::Number(self.builder.ins().f64const(*n))
+            }
+            Some(BlockValue::Boolean(b)) => {
+                return JitValue::Boolean(self.builder.ins().iconst(types::I8, *b as i64))
+            }
+            Some(BlockValue::String(s)) => {
+                return match crate::numeric_literal(s) {
+                    Some(n) => JitValue::Number(self.builder.ins().f64const(n)),
+                    None => JitValue::Slot(self.new_slot(Slot::Constant(s.as_str().into()))),
+                }
+            }
+            Some(BlockValue::Undefined) | None => {
+                return JitValue::Slot(self.new_slot(Slot::Constant(BlockValue::Undefined)))
+            }
+        };
+        let args = &block.arguments;
+        match block.opcode.as_str() {
+            "operator_add" | "operator_subtract" | "operator_multiply" | "operator_divide"
+            | "operator_mod" => {
+                let left = self.expression(args.first());
+                let left = self.number(left);
+                let right = self.expression(args.get(1));
+                let right = self.number(right);
+                let ins = self.builder.ins();
+                JitValue::Number(match block.opcode.as_str() {
+                    "operator_add" => ins.fadd(left, right),
+                    "operator_subtract" => ins.fsub(left, right),
+                    "operator_multiply" => ins.fmul(left, right),
+                    "operator_divide" => ins.fdiv(left, right),
+                    _ => self.call(Helper::Mod, &[left, right]).unwrap(),
+                })
+            }
+            "operator_round" => {
+                let value = self.expression(args.first());
+                let value = self.number(value);
+                JitValue::Number(self.call(Helper::Round, &[value]).unwrap())
+            }
+            "operator_mathop" => {
+                let value = self.expression(args.get(1));
+                let value = self.number(value);
+                let op = args[0].to_string().to_lowercase();
+                let code = match op.as_str() {
+                    "abs" => return JitValue::Number(self.builder.ins().fabs(value)),
+                    "floor" => return JitValue::Number(self.builder.ins().floor(value)),
+                    "ceiling" => return JitValue::Number(self.builder.ins().ceil(value)),
+                    "sqrt" => return JitValue::Number(self.builder.ins().sqrt(value)),
+                    "sin" => 0,
+                    "cos" => 1,
+                    "tan" => 2,
+                    "asin" => 3,
+                    "acos" => 4,
+                    "atan" => 5,
+                    "ln" => 6,
+                    "log" => 7,
+                    "e ^" => 8,
+                    "10 ^" => 9,
+                    _ => return JitValue::Number(self.builder.ins().f64const(0.)),
+                };
+                let code = self.builder.ins().iconst(types::I32, code);
+                JitValue::Number(self.call(Helper::Mathop, &[code, value]).unwrap())
+            }
+            "operator_lt" | "operator_equals" | "operator_gt" => {
+                let left = self.expression(args.first());
+                let right = self.expression(args.get(1));
+                let op = match block.opcode.as_str() {
+                    "operator_lt" => COMPARE_LT,
+                    "operator_equals" => COMPARE_EQ,
+                    _ => COMPARE_GT,
+                };
+                JitValue::Boolean(self.compare(op, left, right))
+            }
+            "operator_and" | "operator_or" => {
+                let left = self.expression(args.first());
+                let left = self.boolean(left);
+                let right = self.expression(args.get(1));
+                let right = self.boolean(right);
+                JitValue::Boolean(if block.opcode == "operator_and" {
+                    self.builder.ins().band(left, right)
+                } else {
+                    self.builder.ins().bor(left, right)
+                })
+            }
+            "operator_not" => {
+                let value = self.expression(args.first());
+                let value = self.boolean(value);
+                JitValue::Boolean(self.builder.ins().bxor_imm(value, 1))
+            }
+            "data_variable" => JitValue::Slot(self.variable_slot(args[0].to_string(), false)),
+            "argument_reporter_string_number" | "argument_reporter_boolean" => {
+                let name = args[0].to_string();
+                let boolean = block.opcode == "argument_reporter_boolean";
+                let found = self.slots.iter().position(|s| {
+                    matches!(s, Slot::Argument { name: n, boolean: b } if *n == name && *b == boolean)
+                });
+                JitValue::Slot(match found {
+                    Some(index) => index,
+                    None => self.new_slot(Slot::Argument { name, boolean }),
+                })
+            }
+            _ => unreachable!("unsupported expression {}", block.opcode),
+        }
+    }
+
+    /// Like `BlockValue::to_number`.
+    fn number(&mut self, value: JitValue) -> Value {
+        match value {
+            JitValue::Number(v) => v,
+            JitValue::Boolean(v) => {
+                let v = self.builder.ins().uextend(types::I32, v);
+                self.builder.ins().fcvt_from_uint(types::F64, v)
+            }
+            JitValue::Slot(slot) => {
+                let address = self.slot_address(slot);
+                self.call(Helper::ToNumber, &[address]).unwrap()
+            }
+        }
+    }
+
+    /// Like `BlockValue::to_boolean`.
+    fn boolean(&mut self, value: JitValue) -> Value {
+        match value {
+            JitValue::Boolean(v) => v,
+            JitValue::Number(v) => {
+                let epsilon = self.builder.ins().f64const(f64::EPSILON);
+                self.builder
+                    .ins()
+                    .fcmp(FloatCC::GreaterThanOrEqual, v, epsilon)
+            }
+            JitValue::Slot(slot) => {
+                let address = self.slot_address(slot);
+                self.call(Helper::ToBoolean, &[address]).unwrap()
+            }
+        }
+    }
+
+    /// Like the `PartialEq` and `PartialOrd` implementations of `BlockValue`.
+    fn compare(&mut self, op: i64, left: JitValue, right: JitValue) -> Value {
+        if let (JitValue::Number(a), JitValue::Number(b)) = (left, right) {
+            // Numbers are compared inline, unless one of them is NaN
+            let ordered = self.builder.ins().fcmp(FloatCC::Ordered, a, b);
+            let fast_block = self.builder.create_block();
+            let slow_block = self.builder.create_block();
+            let end_block = self.builder.create_block();
+            let result = self.builder.append_block_param(end_block, types::I8);
+            self.builder
+                .ins()
+                .brif(ordered, fast_block, &[], slow_block, &[]);
+            self.builder.switch_to_block(fast_block);
+            let fast = match op {
+                COMPARE_LT => self.builder.ins().fcmp(FloatCC::LessThan, a, b),
+                COMPARE_GT => self.builder.ins().fcmp(FloatCC::GreaterThan, a, b),
+                _ => {
+                    let difference = self.builder.ins().fsub(a, b);
+                    let difference = self.builder.ins().fabs(difference);
+                    let epsilon = self.builder.ins().f64const(f64::EPSILON);
+                    self.builder
+                        .ins()
+                        .fcmp(FloatCC::LessThan, difference, epsilon)
+                }
+            };
+            self.builder.ins().jump(end_block, &[fast]);
+            self.builder.switch_to_block(slow_block);
+            let op = self.builder.ins().iconst(types::I32, op);
+            let slow = self.call(Helper::CompareNumbers, &[a, b, op]).unwrap();
+            self.builder.ins().jump(end_block, &[slow]);
+            self.builder.switch_to_block(end_block);
+            return result;
+        }
+        let left = self.materialize(left);
+        let right = self.materialize(right);
+        let op = self.builder.ins().iconst(types::I32, op);
+        self.call(Helper::Compare, &[left, right, op]).unwrap()
+    }
+
+    /// The address of a slot holding `value`.
+    fn materialize(&mut self, value: JitValue) -> Value {
+        match value {
+            JitValue::Slot(slot) => self.slot_address(slot),
+            JitValue::Number(v) => {
+                let slot = self.new_slot(Slot::Scratch);
+                let address = self.slot_address(slot);
+                self.call(Helper::SetNumber, &[address, v]);
+                address
+            }
+            JitValue::Boolean(v) => {
+                let slot = self.new_slot(Slot::Scratch);
+                let address = self.slot_address(slot);
+                self.call(Helper::SetBoolean, &[address, v]);
+                address
+            }
+        }
+    }
+}
+
+/// Functions called by native code for what it does not do inline.
+mod helpers {
+    use super::*;
+
+    pub unsafe extern "C" fn to_number(value: *const BlockValue) -> f64 {
+        (*value).to_number()
+    }
+    pub unsafe extern "C" fn to_boolean(value: *const BlockValue) -> i8 {
+        (*value).to_boolean() as i8
+    }
+    pub unsafe extern "C" fn set_number(value: *mut BlockValue, number: f64) {
+        *value = number.into();
+    }
+    pub unsafe extern "C" fn set_boolean(value: *mut BlockValue, boolean: i8) {
+        *value = (boolean != 0).into();
+    }
+    pub unsafe extern "C" fn copy(value: *mut BlockValue, source: *const BlockValue) {
+        *value = (*source).to_owned();
+    }
+    /// Like `data_changevariableby`.
+    pub unsafe extern "C" fn change(value: *mut BlockValue, by: f64) {
+        *value = ((*value).to_number() + by).into();
+    }
+    pub unsafe extern "C" fn compare(
+        left: *const BlockValue,
+        right: *const BlockValue,
+        op: i32,
+    ) -> i8 {
+        compare_values(&*left, &*right, op)
+    }
+    pub extern "C" fn compare_numbers(left: f64, right: f64, op: i32) -> i8 {
+        compare_values(&left.into(), &right.into(), op)
+    }
+    fn compare_values(left: &BlockValue, right: &BlockValue, op: i32) -> i8 {
+        (match op as i64 {
+            COMPARE_LT => left.partial_cmp(right) == Some(std::cmp::Ordering::Less),
+            COMPARE_GT => left.partial_cmp(right) == Some(std::cmp::Ordering::Greater),
+            _ => left == right,
+        }) as i8
+    }
+    /// Like `operator_mod`.
+    pub extern "C" fn modulo(n: f64, m: f64) -> f64 {
+        let mut r = n % m;
+        if r / m < 0.0 {
+            r += m
+        }
+        r
+    }
+    pub extern "C" fn round(n: f64) -> f64 {
+        n.round()
+    }
+    /// Like `LoopWait`. Returns 1 if the thread yields.
+    pub unsafe extern "C" fn loop_wait(waiter: *mut u8) -> i8 {
+        let waiter = &mut *(waiter as *mut LoopWaiter);
+        waiter.state.loop_yields(waiter.warp, waiter.runtime) as i8
+    }
+    /// Like `operator_mathop`, for the operators without an instruction.
+    pub extern "C" fn mathop(code: i32, num: f64) -> f64 {
+        match code {
+            0 => (num.to_radians().sin() * 10.).trunc() / 10.,
+            1 => (num.to_radians().cos() * 10.).trunc() / 10.,
+            2 => num.tan(),
+            3 => num.asin().to_degrees(),
+            4 => num.acos().to_degrees(),
+            5 => num.atan().to_degrees(),
+            6 => num.ln(),
+            7 => num.log10(),
+            8 => num.exp(),
+            _ => (10f64).powf(num),
+        }
+    }
+}
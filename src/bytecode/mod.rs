@@ -0,0 +1,172 @@
+//! Compiles block scripts into linear bytecode, and runs it.
+//!
+//! Control blocks (`if`, loops, `wait until`) and custom procedures are lowered into
+//! jumps, counter slots and call frames. Other blocks are called with their
+//! arguments taken from the operand stack, so they run the same block functions as
+//! the tree-walking interpreter. Scripts that use blocks the compiler does not
+//! understand keep running in the tree-walking interpreter.
+//!
+//! Threads yield where the tree-walking interpreter yields them: at the end of a loop
+//! iteration, while a block or `wait until` waits, and on recursive procedure calls.
+//! So threads interleave the same way with either interpreter.
+
+mod compiler;
+mod interpreter;
+#[cfg(feature = "jit")]
+mod jit;
+
+pub use compiler::*;
+pub use interpreter::*;
+#[cfg(feature = "jit")]
+pub use jit::{NativeSite, JIT_THRESHOLD};
+
+use crate::*;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub enum Instr {
+    /// Push a constant.
+    Push(BlockValue),
+    /// Call a block with the last `argc` operands as its arguments.
+    /// Pushes the result if the block is used as a reporter.
+    Call {
+        block: BlockId,
+        block_function: BlockFunction,
+        argc: usize,
+        reporter: bool,
+    },
+    Jump(usize),
+    /// Pop a condition and jump if it is false.
+    JumpIfFalse(usize),
+    /// Pop a condition and jump if it is true.
+    JumpIfTrue(usize),
+    /// Pop the number of iterations of `repeat` into a counter slot.
+    SetCounter(usize),
+    /// Jump to `end` if the counter slot is zero, otherwise decrement it.
+    Count {
+        slot: usize,
+        end: usize,
+    },
+    /// Yields to other threads unless the thread runs in warp mode, like `wait until` while its condition is false.
+    Tick,
+    /// Before the next loop iteration. Waits while `LoopYield` says so.
+    LoopWait,
+    /// Push the argument of the running procedure with this name.
+    Argument {
+        name: String,
+        boolean: bool,
+    },
+    /// Call a custom procedure with the last `argc` operands as its arguments.
+    CallProcedure {
+        definition: BlockId,
+        argc: usize,
+        reporter: bool,
+    },
+    /// Pop a value and return it from the running procedure.
+    Return,
+    /// End of a procedure.
+    ReturnEmpty,
+    /// End of a script.
+    End,
+    /// Run the statements up to `end`, or the rest of the loop iteration ending at `end`,
+    /// as native code once the site is hot. Otherwise continue with their bytecode.
+    #[cfg(feature = "jit")]
+    Native {
+        site: Arc<NativeSite>,
+        end: usize,
+    },
+}
+
+/// The bytecode of a script or a procedure.
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<Instr>,
+    /// Number of counter slots used by the chunk.
+    pub slots: usize,
+    /// Runs the chunk instead of `code`. Set for chunks generated by `aot`.
+    pub function: Option<ChunkFunction>,
+}
+
+/// A chunk compiled to Rust, which runs from `BytecodeThread::pc` until it stops.
+pub type ChunkFunction = fn(&mut BytecodeThread, &mut Machine) -> Flow;
+
+/// Compiled scripts and procedures of all targets.
+#[derive(Debug, Clone, Default)]
+pub struct CompiledProgram {
+    /// Keyed by the target and the top block of the script.
+    pub scripts: HashMap<(TargetId, BlockId), Arc<Chunk>>,
+    /// Keyed by the target and the definition block of the procedure.
+    pub procedures: HashMap<(TargetId, BlockId), Arc<Chunk>>,
+    /// Compiles the sites of the program once they are hot.
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Option<Arc<std::sync::Mutex<jit::JitCompiler>>>,
+}
+
+impl CompiledProgram {
+    /// Number of sites compiled to native code so far.
+    #[cfg(feature = "jit")]
+    pub fn native_regions(&self) -> usize {
+        self.jit
+            .as_ref()
+            .and_then(|jit| jit.lock().ok().map(|jit| jit.compiled))
+            .unwrap_or(0)
+    }
+}
+
+/// Result of `VirtualMachine::compile_bytecode`.
+#[derive(Debug, Clone, Default)]
+pub struct CompileReport {
+    /// Number of compiled scripts and procedures.
+    pub compiled: usize,
+    /// Number of sites, runs of statements or loops, that are compiled to native code once
+    /// they ran `JIT_THRESHOLD` times. Always 0 without the `jit` feature.
+    pub native_sites: usize,
+    /// Scripts and procedures that are left to the tree-walking interpreter, with the reason.
+    pub skipped: Vec<(TargetId, BlockId, String)>,
+}
+
+impl VirtualMachine {
+    /// Compile the scripts of all targets to bytecode. Threads started after this call run the bytecode.
+    ///
+    /// Threads started while a tracer, a profiler, coverage or breakpoints are set keep using the
+    /// tree-walking interpreter, so those tools see every block. Call it again after
+    /// changing blocks, or `clear_bytecode` to go back to the tree-walking interpreter.
+    ///
+    /// With the `jit` feature, numeric statements and loops that run often are compiled to native code.
+    pub fn compile_bytecode(&mut self) -> CompileReport {
+        let (program, report) = compile_program(&self.registry, &self.targets, true);
+        self.bytecode = Some(program);
+        report
+    }
+
+    pub fn clear_bytecode(&mut self) {
+        self.bytecode = None;
+    }
+
+    /// The bytecode state for a thread starting at `top_block`, if the script is compiled.
+    pub(crate) fn bytecode_thread(
+        &self,
+        running_target_id: RunningTargetId,
+        top_block: BlockId,
+    ) -> Option<Box<BytecodeThread>> {
+        if self.tracer.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.debugger.is_active()
+        {
+            return None;
+        }
+        let target_id = self.running_targets.get(running_target_id)?.target_id;
+        let chunk = self
+            .bytecode
+            .as_ref()?
+            .scripts
+            .get(&(target_id, top_block))?;
+        let block = self.targets[target_id].blocks.get(top_block)?;
+        Some(Box::new(BytecodeThread::new(
+            chunk.clone(),
+            top_block,
+            block,
+        )))
+    }
+}
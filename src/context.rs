@@ -2,8 +2,10 @@ use crate::*;
 
 #[derive(Debug)]
 pub struct BlockContext<'a> {
-    pub global_data: &'a mut HashMap<String, Box<dyn Any + Send + Sync>>,
+    pub runtime: &'a mut RuntimeState,
     pub stack: &'a mut Stack,
+    /// Whether the thread is running a procedure without screen refresh.
+    pub warp: bool,
     pub stage_id: TargetId,
     pub running_stage_id: generational_arena::Index,
     pub target_id: TargetId,
@@ -12,7 +14,44 @@ pub struct BlockContext<'a> {
     pub running_targets: &'a mut generational_arena::Arena<RunningTarget>,
 }
 
-struct RefreshStackData(Option<BlockValue>, usize);
+/// Yield policy shared by loop blocks, following scratch-vm.
+///
+/// A loop yields to other threads at the end of each iteration. In warp mode the
+/// thread keeps running, unless it has been running for too long. If a block
+/// requested a redraw of the stage, the loop instead waits until the host redraws
+/// the stage, so each iteration of an animation takes one frame.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoopYield {
+    /// The stage frame the loop waits to pass.
+    redraw_frame: Option<usize>,
+    /// Whether the loop yielded for the coming iteration.
+    yielded: bool,
+}
+
+impl LoopYield {
+    /// Call before each iteration after the first one, until it returns `false`.
+    /// Returns `true` while the loop should wait.
+    pub fn should_wait(&mut self, warp: bool, runtime: &RuntimeState) -> bool {
+        if let Some(frame) = self.redraw_frame {
+            if runtime.stage_frame.is_some_and(|x| x <= frame) {
+                return true;
+            }
+            self.redraw_frame = None;
+            return false;
+        }
+        if self.yielded {
+            self.yielded = false;
+            return false;
+        }
+        match runtime.stage_frame {
+            Some(stage_frame) if !warp && runtime.redraw_requested => {
+                self.redraw_frame = Some(stage_frame)
+            }
+            _ => self.yielded = true,
+        }
+        true
+    }
+}
 
 impl BlockContext<'_> {
     #[inline(always)]
@@ -139,49 +178,34 @@ impl BlockContext<'_> {
         }
     }
 
+    /// Whether a block changed the stage since it was last redrawn.
     #[inline(always)]
     pub fn is_stage_dirty(&self) -> bool {
-        if let Some(stage_frame) = self
-            .global_data
-            .get("STAGE_FRAME")
-            .and_then(|x| x.downcast_ref::<usize>())
-        {
-            if let Some(v) = self.stack.block_data.downcast_ref::<RefreshStackData>() {
-                stage_frame <= &v.1
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+        self.runtime.redraw_requested
     }
-    /// 标记等待舞台更新，配合 `is_stage_dirty` 使用将模块等待到舞台更新后继续执行
+    /// 运行会改变舞台的模块，完成后请求舞台更新。循环会在下一次迭代前等待舞台更新，见 `LoopYield`
     #[inline(always)]
     pub fn acquire_need_wait_refresh(
         &mut self,
         callback: impl FnOnce(&mut Self) -> BlockResult,
     ) -> BlockResult {
-        if let Some(result) = self.stack.block_data.downcast_ref::<RefreshStackData>() {
-            if self.is_stage_dirty() {
-                pending()
-            } else {
-                BlockResult::Resolved(result.0.to_owned())
-            }
-        } else {
-            match callback(self) {
-                BlockResult::Resolved(value) => {
-                    if let Some(stage_frame) = self
-                        .global_data
-                        .get("STAGE_FRAME")
-                        .and_then(|x| x.downcast_ref::<usize>())
-                    {
-                        self.stack.block_data = Box::new(RefreshStackData(value, *stage_frame));
-                    } else {
-                        self.stack.block_data = Box::new(RefreshStackData(value, 0));
-                    }
-                    pending()
-                }
-                other => other,
+        let result = callback(self);
+        if let BlockResult::Resolved(_) = result {
+            self.runtime.redraw_requested = true;
+        }
+        result
+    }
+    /// Check whether a loop block should wait before its next iteration, see `LoopYield`.
+    #[inline(always)]
+    pub fn loop_should_wait(&mut self) -> bool {
+        let warp = self.warp;
+        match self.stack.block_data.downcast_mut::<LoopYield>() {
+            Some(state) => state.should_wait(warp, self.runtime),
+            None => {
+                let mut state = LoopYield::default();
+                let wait = state.should_wait(warp, self.runtime);
+                self.stack.block_data = Box::new(state);
+                wait
             }
         }
     }
@@ -0,0 +1,158 @@
+use crate::*;
+use json::JsonValue;
+use std::io::Write;
+
+/// Counts how often each block runs.
+///
+/// A block is executed once its block function is called, and hit each time it finishes.
+/// Blocks that never finish, like `forever`, are executed with 0 hits.
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    /// Keyed by the target and the block.
+    pub hits: HashMap<(TargetId, BlockId), u64>,
+}
+
+impl Coverage {
+    pub(crate) fn record(&mut self, target_id: TargetId, block_id: BlockId, result: &BlockResult) {
+        let hits = self.hits.entry((target_id, block_id)).or_default();
+        if result.is_finished() {
+            *hits += 1;
+        }
+    }
+
+    pub fn is_executed(&self, target_id: TargetId, block_id: BlockId) -> bool {
+        self.hits.contains_key(&(target_id, block_id))
+    }
+
+    /// Executed and unexecuted blocks of every target of `vm`, in the order of `vm.targets`.
+    /// Deleted targets are left out.
+    ///
+    /// Only blocks from `project.json` are reported, blocks made by the loader or the host are left out.
+    pub fn report(&self, vm: &VirtualMachine) -> CoverageReport {
+        let targets = vm
+            .targets
+            .iter()
+            .filter(|(_, target)| !target.deleted)
+            .map(|(target_id, target)| TargetCoverage {
+                name: target.name.to_owned(),
+                blocks: target
+                    .blocks
+                    .iter()
+                    .filter_map(|(block_id, block)| {
+                        Some(BlockCoverage {
+                            block_id,
+                            sb3_id: block.sb3_id()?.to_owned(),
+                            opcode: block.opcode.to_owned(),
+                            hits: self.hits.get(&(target_id, block_id)).copied(),
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        CoverageReport { targets }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockCoverage {
+    pub block_id: BlockId,
+    /// The id of the block in `project.json`.
+    pub sb3_id: String,
+    pub opcode: String,
+    /// How often the block finished, `None` if it never ran.
+    pub hits: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetCoverage {
+    pub name: String,
+    pub blocks: Vec<BlockCoverage>,
+}
+
+impl TargetCoverage {
+    pub fn executed(&self) -> impl Iterator<Item = &BlockCoverage> {
+        self.blocks.iter().filter(|b| b.hits.is_some())
+    }
+
+    pub fn unexecuted(&self) -> impl Iterator<Item = &BlockCoverage> {
+        self.blocks.iter().filter(|b| b.hits.is_none())
+    }
+}
+
+/// Block coverage of a project, made by `Coverage::report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub targets: Vec<TargetCoverage>,
+}
+
+impl CoverageReport {
+    /// Add the hits of another run of the same project.
+    pub fn merge(&mut self, other: &CoverageReport) {
+        for (target, other) in self.targets.iter_mut().zip(&other.targets) {
+            for (block, other) in target.blocks.iter_mut().zip(&other.blocks) {
+                if let Some(hits) = other.hits {
+                    *block.hits.get_or_insert(0) += hits;
+                }
+            }
+        }
+    }
+
+    /// The report as a JSON object with the fields `total`, `executed` and `targets`.
+    ///
+    /// Each target has the fields `name`, `total`, `executed`, `blocks` with the `sb3_id`,
+    /// `block` (the index of the block in its target), `opcode` and `hits` of every block,
+    /// and `unexecuted`, the sb3 ids of the blocks that never ran.
+    pub fn to_json(&self) -> JsonValue {
+        let mut total = 0;
+        let mut executed = 0;
+        let mut targets = JsonValue::new_array();
+        for target in &self.targets {
+            let target_executed = target.executed().count();
+            total += target.blocks.len();
+            executed += target_executed;
+            let _ = targets.push(json::object! {
+                "name": target.name.as_str(),
+                "total": target.blocks.len(),
+                "executed": target_executed,
+                "blocks": target
+                    .blocks
+                    .iter()
+                    .map(|b| json::object! {
+                        "sb3_id": b.sb3_id.as_str(),
+                        "block": b.block_id.index(),
+                        "opcode": b.opcode.as_str(),
+                        "hits": b.hits,
+                    })
+                    .collect::<Vec<_>>(),
+                "unexecuted": target
+                    .unexecuted()
+                    .map(|b| b.sb3_id.as_str())
+                    .collect::<Vec<_>>(),
+            });
+        }
+        json::object! {
+            "total": total,
+            "executed": executed,
+            "targets": targets,
+        }
+    }
+
+    /// Write the report in the LCOV tracefile format, with a record for each target.
+    ///
+    /// The source file is the target name and the line number of a block is its index in
+    /// the target plus one. Executed blocks that never finished are written with 1 hit, so
+    /// LCOV tools count them as covered.
+    pub fn write_lcov(&self, mut w: impl Write) -> std::io::Result<()> {
+        for target in &self.targets {
+            writeln!(w, "SF:{}", target.name)?;
+            for block in &target.blocks {
+                let hits = block.hits.map(|h| h.max(1)).unwrap_or(0);
+                writeln!(w, "DA:{},{}", block.block_id.index() + 1, hits)?;
+            }
+            writeln!(w, "LH:{}", target.executed().count())?;
+            writeln!(w, "LF:{}", target.blocks.len())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
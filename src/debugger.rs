@@ -0,0 +1,166 @@
+use crate::*;
+use std::collections::HashSet;
+
+/// Where a paused VM stopped: the thread and the block it is about to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PausePoint {
+    pub thread_id: usize,
+    pub block_id: BlockId,
+}
+
+/// Breakpoints and pause state of a `VirtualMachine`.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: HashSet<BlockId>,
+    paused: bool,
+    pause_point: Option<PausePoint>,
+    /// The thread being single-stepped, the other threads do not run.
+    stepping: Option<usize>,
+    /// Stack uid and block of the last pause, so the thread does not pause there again when resumed.
+    last_hit: Option<(usize, BlockId)>,
+}
+
+impl Debugger {
+    /// Whether breakpoints are set or a thread is being single-stepped.
+    pub(crate) fn is_active(&self) -> bool {
+        !self.breakpoints.is_empty() || self.stepping.is_some()
+    }
+
+    /// Whether `thread_id` should not run in this step.
+    #[inline(always)]
+    pub(crate) fn is_holding(&self, thread_id: usize) -> bool {
+        self.paused || self.stepping.map(|t| t != thread_id).unwrap_or(false)
+    }
+
+    /// Called before a thread runs a block. Returns `true` if the thread pauses before it starts.
+    #[inline(always)]
+    pub(crate) fn should_pause(&mut self, thread_id: usize, stack: &Stack) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        // Only pause when the block starts, not while it resolves arguments or waits
+        if !stack.arguments.is_empty() || !stack.block_data.is::<()>() {
+            return false;
+        }
+        let entry = (stack.uid, stack.block_id);
+        if self.last_hit == Some(entry) {
+            return false;
+        }
+        if self.stepping == Some(thread_id) || self.breakpoints.contains(&stack.block_id) {
+            self.paused = true;
+            self.stepping = None;
+            self.pause_point = Some(PausePoint {
+                thread_id,
+                block_id: stack.block_id,
+            });
+            self.last_hit = Some(entry);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl VirtualMachine {
+    /// Pause before any thread runs `block_id`.
+    pub fn set_breakpoint(&mut self, block_id: BlockId) -> bool {
+        self.debugger.breakpoints.insert(block_id)
+    }
+    pub fn remove_breakpoint(&mut self, block_id: BlockId) -> bool {
+        self.debugger.breakpoints.remove(&block_id)
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.breakpoints.clear();
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = &BlockId> {
+        self.debugger.breakpoints.iter()
+    }
+
+    /// Pause all threads. `step` does nothing until `resume` is called.
+    pub fn pause(&mut self) {
+        self.debugger.paused = true;
+    }
+    pub fn resume(&mut self) {
+        self.debugger.paused = false;
+        self.debugger.pause_point = None;
+    }
+    pub fn is_paused(&self) -> bool {
+        self.debugger.paused
+    }
+    /// The breakpoint or step that paused the VM, `None` if it was paused by `pause`.
+    pub fn pause_point(&self) -> Option<PausePoint> {
+        self.debugger.pause_point
+    }
+
+    /// Run one block of a thread while the VM is paused, and pause again.
+    ///
+    /// Returns the block the thread will run next, or `None` if the thread finished.
+    pub fn step_thread(&mut self, thread_id: usize) -> Option<BlockId> {
+        if !self.debugger.paused || self.thread(thread_id).is_none() {
+            return None;
+        }
+        self.debugger.paused = false;
+        self.debugger.stepping = Some(thread_id);
+        self.step();
+        self.debugger.stepping = None;
+        self.debugger.paused = true;
+        match self.thread(thread_id).and_then(|t| t.stacks.last()) {
+            Some(stack) => {
+                let point = PausePoint {
+                    thread_id,
+                    block_id: stack.block_id,
+                };
+                self.debugger.last_hit = Some((stack.uid, stack.block_id));
+                self.debugger.pause_point = Some(point);
+                Some(point.block_id)
+            }
+            None => {
+                self.debugger.pause_point = None;
+                None
+            }
+        }
+    }
+
+    pub fn thread(&self, thread_id: usize) -> Option<&Thread> {
+        self.threads.iter().find(|t| t.thread_id == thread_id)
+    }
+
+    /// Arguments of the innermost procedure call running in a thread.
+    pub fn procedure_arguments(&self, thread_id: usize) -> Option<&[(String, BlockValue)]> {
+        let thread = self.thread(thread_id)?;
+        core_blocks::current_frame(&thread.stacks).map(|frame| frame.arguments.as_slice())
+    }
+
+    /// Get a variable by name like blocks of the running target see it, falling back to the stage.
+    pub fn variable(&self, running_target_id: RunningTargetId, name: &str) -> Option<&BlockValue> {
+        [running_target_id, self.running_stage_id]
+            .into_iter()
+            .find_map(|rtid| {
+                let rt = self.running_targets.get(rtid)?;
+                let id = self.targets[rt.target_id].variable_id(name).unwrap_or(name);
+                rt.variables.get(id)
+            })
+    }
+
+    /// Set a variable by name like blocks of the running target do. Returns `false` if there is no such variable.
+    pub fn set_variable(
+        &mut self,
+        running_target_id: RunningTargetId,
+        name: &str,
+        value: BlockValue,
+    ) -> bool {
+        let running_stage_id = self.running_stage_id;
+        for rtid in [running_target_id, running_stage_id] {
+            let rt = match self.running_targets.get_mut(rtid) {
+                Some(rt) => rt,
+                None => continue,
+            };
+            let id = self.targets[rt.target_id].variable_id(name).unwrap_or(name);
+            if let Some(v) = rt.variables.get_mut(id) {
+                *v = value;
+                return true;
+            }
+        }
+        false
+    }
+}
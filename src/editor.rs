@@ -0,0 +1,566 @@
+//! Changes to the targets and blocks of a running VM, for editors that use it as their runtime.
+//!
+//! Running threads keep running the edited scripts: a moved block continues with the blocks
+//! after it in its new place, and new field values are read the next time a block runs.
+//! Threads that are running a deleted block are stopped, and threads that run compiled
+//! bytecode of an edited target are stopped since their code is out of date.
+
+use crate::blocks::{ArgType, BlockShape};
+use crate::*;
+use std::collections::HashSet;
+
+/// The `block_id` of deleted blocks. The arena keeps them, but nothing refers to them.
+pub(crate) const DELETED: &str = "[Deleted]";
+
+/// Why an edit was refused. The VM is not changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    /// No target with this id, or it was deleted.
+    UnknownTarget,
+    /// The target has no block with this id, or it was deleted.
+    UnknownBlock,
+    /// The block has no input or field with this name.
+    UnknownArgument(String),
+    /// The argument holds a block, so it has no value to set.
+    ArgumentHasBlock(String),
+    /// Blocks can only be put in inputs, not in fields.
+    NotAnInput(String),
+    /// The input can not hold the value, like text in a number input. See `InputType::accepts`.
+    WrongType(String),
+    /// A stack of blocks can not be moved into itself.
+    MoveIntoItself,
+    /// The stage can not be deleted.
+    DeleteStage,
+    UnknownVariable(String),
+    UnknownList(String),
+    UnknownBroadcast(String),
+    /// A variable, list or broadcast with the new name already exists.
+    NameTaken(String),
+}
+
+impl std::fmt::Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownTarget => write!(f, "unknown target"),
+            Self::UnknownBlock => write!(f, "unknown block"),
+            Self::UnknownArgument(name) => write!(f, "the block has no argument {}", name),
+            Self::ArgumentHasBlock(name) => write!(f, "the argument {} holds a block", name),
+            Self::NotAnInput(name) => write!(f, "{} is a field, not an input", name),
+            Self::WrongType(name) => write!(f, "the value does not fit the input {}", name),
+            Self::MoveIntoItself => write!(f, "can not move blocks into themselves"),
+            Self::DeleteStage => write!(f, "can not delete the stage"),
+            Self::UnknownVariable(name) => write!(f, "unknown variable: {}", name),
+            Self::UnknownList(name) => write!(f, "unknown list: {}", name),
+            Self::UnknownBroadcast(name) => write!(f, "unknown broadcast: {}", name),
+            Self::NameTaken(name) => write!(f, "the name {} is taken", name),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+/// Where `insert_block` and `move_block` put a stack of blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockPosition {
+    /// A new script.
+    TopLevel,
+    /// After a block. The blocks that followed it go after the stack.
+    After(BlockId),
+    /// In an input or a substack of a block, by name like `SUBSTACK` or `CONDITION`.
+    /// A substack that was there goes after the stack, a reporter becomes a top-level block.
+    Input(BlockId, String),
+}
+
+/// Where a block is in its target.
+enum Parent {
+    TopLevel,
+    Next(BlockId),
+    Argument(BlockId, usize),
+}
+
+/// Indexes and blocks of the arguments that are inside `block`, like inputs and substacks.
+/// Procedure calls also hold their definition, which is not inside them.
+fn child_arguments(block: &Block) -> impl Iterator<Item = (usize, BlockId)> + '_ {
+    let len = match block.opcode.as_str() {
+        "procedures_call" | "procedures_call_return" => block.arguments.len().saturating_sub(1),
+        _ => block.arguments.len(),
+    };
+    block.arguments[..len]
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| match x {
+            BlockValue::BlockId(id) => Some((i, *id)),
+            _ => None,
+        })
+}
+
+/// `block_id` with the blocks inside it and, with `with_next`, the blocks after it.
+fn collect_blocks(target: &Target, block_id: BlockId, with_next: bool) -> Vec<BlockId> {
+    let mut blocks = Vec::new();
+    let mut pending = vec![block_id];
+    while let Some(id) = pending.pop() {
+        if let Some(block) = target.blocks.get(id) {
+            blocks.push(id);
+            pending.extend(child_arguments(block).map(|(_, x)| x));
+            if with_next || id != block_id {
+                pending.extend(block.next);
+            }
+        }
+    }
+    blocks
+}
+
+fn parent(target: &Target, block_id: BlockId) -> Option<Parent> {
+    if target.blocks[block_id].toplevel {
+        return Some(Parent::TopLevel);
+    }
+    for (id, block) in target.blocks.iter() {
+        if block.next == Some(block_id) {
+            return Some(Parent::Next(id));
+        }
+        if let Some((index, _)) = child_arguments(block).find(|(_, x)| *x == block_id) {
+            return Some(Parent::Argument(id, index));
+        }
+    }
+    None
+}
+
+/// The last block of the stack that starts with `block_id`.
+fn last_block(target: &Target, mut block_id: BlockId) -> BlockId {
+    while let Some(next) = target.blocks[block_id].next {
+        block_id = next;
+    }
+    block_id
+}
+
+impl VirtualMachine {
+    /// The sprite or the stage named `name`.
+    pub fn find_target(&self, name: &str) -> Option<TargetId> {
+        self.running_targets
+            .iter()
+            .find(|(_, rt)| !rt.is_clone && rt.name == name)
+            .map(|(_, rt)| rt.target_id)
+    }
+
+    /// The block of `target_id` with this id in `project.json`.
+    pub fn find_block(&self, target_id: TargetId, sb3_id: &str) -> Option<BlockId> {
+        self.targets
+            .get(target_id)?
+            .blocks
+            .iter()
+            .find(|(_, block)| block.block_id == sb3_id)
+            .map(|(id, _)| id)
+    }
+
+    /// Layer order that puts a new sprite in front of the running targets.
+    pub(crate) fn front_layer_order(&self) -> usize {
+        self.running_targets
+            .iter()
+            .map(|(_, rt)| rt.layer_order)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+
+    /// Add a sprite made by the host in front of the other sprites.
+    /// Its hats run like the ones of loaded sprites.
+    pub fn add_target(&mut self, mut target: Target) -> (TargetId, RunningTargetId) {
+        target.layer_order = self.front_layer_order();
+        let ids = self.new_target(target);
+        self.refresh_hats();
+        ids
+    }
+
+    /// Delete a sprite with its clones, and stop their threads.
+    ///
+    /// The target keeps its slot in `targets` so other ids stay valid, see `Target::deleted`.
+    pub fn delete_target(&mut self, target_id: TargetId) -> Result<(), EditError> {
+        if target_id == self.stage_id {
+            return Err(EditError::DeleteStage);
+        }
+        let running_targets = self
+            .running_targets
+            .iter()
+            .filter(|(_, rt)| rt.target_id == target_id)
+            .map(|(id, rt)| (id, rt.is_clone))
+            .collect::<Vec<_>>();
+        if running_targets.is_empty() {
+            return Err(EditError::UnknownTarget);
+        }
+        let blocks = self.targets[target_id]
+            .blocks
+            .iter()
+            .map(|(id, _)| id)
+            .collect::<HashSet<_>>();
+        let target = &mut self.targets[target_id];
+        target.blocks = Arena::new();
+        target.deleted = true;
+        self.blocks_changed(target_id, &blocks);
+        for (id, _) in running_targets.iter() {
+            self.running_targets.remove(*id);
+        }
+        self.notify(|o, vm| {
+            for (id, _) in running_targets.iter().filter(|(_, is_clone)| *is_clone) {
+                o.clone_deleted(vm, *id);
+            }
+        });
+        Ok(())
+    }
+
+    /// Add a block made by the host. `arguments` are in the order of the registry,
+    /// see `BlockInfo::arguments`. Returns the new block.
+    pub fn insert_block(
+        &mut self,
+        target_id: TargetId,
+        opcode: &str,
+        arguments: &[BlockValue],
+        position: BlockPosition,
+    ) -> Result<BlockId, EditError> {
+        self.live_target(target_id)?;
+        // Checked before the block is added, so a refused edit leaves no block behind
+        let input = self.position_input(target_id, &position)?;
+        if let Some(info) = self.registry.get(opcode) {
+            for ((arg_type, name), value) in info.arguments.iter().zip(arguments) {
+                match arg_type {
+                    ArgType::Input(input_type) if !input_type.accepts(value) => {
+                        return Err(EditError::WrongType(name.to_owned()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let block_id =
+            self.targets[target_id].new_block(&self.registry, opcode, None, arguments);
+        self.place_block(target_id, block_id, position, input);
+        self.blocks_changed(target_id, &HashSet::new());
+        Ok(block_id)
+    }
+
+    /// Move a block and the blocks after it, like dragging it in the editor.
+    pub fn move_block(
+        &mut self,
+        target_id: TargetId,
+        block_id: BlockId,
+        position: BlockPosition,
+    ) -> Result<(), EditError> {
+        self.live_block(target_id, block_id)?;
+        let input = self.position_input(target_id, &position)?;
+        if let BlockPosition::After(anchor) | BlockPosition::Input(anchor, _) = &position {
+            if collect_blocks(&self.targets[target_id], block_id, true).contains(anchor) {
+                return Err(EditError::MoveIntoItself);
+            }
+        }
+        let target = &mut self.targets[target_id];
+        match parent(target, block_id) {
+            Some(Parent::TopLevel) => target.blocks[block_id].toplevel = false,
+            Some(Parent::Next(id)) => target.blocks[id].next = None,
+            Some(Parent::Argument(id, index)) => {
+                target.blocks[id].arguments[index] = BlockValue::Undefined
+            }
+            None => {}
+        }
+        self.place_block(target_id, block_id, position, input);
+        self.blocks_changed(target_id, &HashSet::new());
+        Ok(())
+    }
+
+    /// Check that blocks can be put at `position`, and return the index of its input.
+    fn position_input(
+        &self,
+        target_id: TargetId,
+        position: &BlockPosition,
+    ) -> Result<Option<usize>, EditError> {
+        match position {
+            BlockPosition::TopLevel => Ok(None),
+            BlockPosition::After(anchor) => self.live_block(target_id, *anchor).map(|_| None),
+            BlockPosition::Input(anchor, name) => {
+                let (index, arg_type) = self.argument(target_id, *anchor, name)?;
+                if arg_type == ArgType::Field {
+                    return Err(EditError::NotAnInput(name.to_owned()));
+                }
+                Ok(Some(index))
+            }
+        }
+    }
+
+    /// Put a detached block at a position checked by `position_input`.
+    fn place_block(
+        &mut self,
+        target_id: TargetId,
+        block_id: BlockId,
+        position: BlockPosition,
+        input: Option<usize>,
+    ) {
+        let is_reporter = self
+            .registry
+            .get(&self.targets[target_id].blocks[block_id].opcode)
+            .map(|x| matches!(x.shape, BlockShape::Reporter | BlockShape::Boolean))
+            .unwrap_or(false);
+        let target = &mut self.targets[target_id];
+        match (position, input) {
+            (BlockPosition::After(anchor), _) => {
+                let next = target.blocks[anchor].next.replace(block_id);
+                let last = last_block(target, block_id);
+                target.blocks[last].next = next;
+            }
+            (BlockPosition::Input(anchor, _), Some(index)) => {
+                let arguments = &mut target.blocks[anchor].arguments;
+                if arguments.len() <= index {
+                    arguments.resize(index + 1, BlockValue::Undefined);
+                }
+                let old = std::mem::replace(&mut arguments[index], BlockValue::BlockId(block_id));
+                if let BlockValue::BlockId(old) = old {
+                    if is_reporter {
+                        target.blocks[old].toplevel = true;
+                    } else {
+                        let last = last_block(target, block_id);
+                        target.blocks[last].next = Some(old);
+                    }
+                }
+            }
+            _ => target.blocks[block_id].toplevel = true,
+        }
+    }
+
+    /// Delete a block with the blocks inside it. The blocks after it take its place.
+    pub fn delete_block(
+        &mut self,
+        target_id: TargetId,
+        block_id: BlockId,
+    ) -> Result<(), EditError> {
+        self.live_block(target_id, block_id)?;
+        let target = &mut self.targets[target_id];
+        let next = target.blocks[block_id].next;
+        match parent(target, block_id) {
+            Some(Parent::TopLevel) => {
+                if let Some(next) = next {
+                    target.blocks[next].toplevel = true;
+                }
+            }
+            Some(Parent::Next(id)) => target.blocks[id].next = next,
+            Some(Parent::Argument(id, index)) => {
+                target.blocks[id].arguments[index] =
+                    next.map(BlockValue::BlockId).unwrap_or_default()
+            }
+            None => {}
+        }
+        let deleted = collect_blocks(target, block_id, false);
+        self.delete_blocks(target_id, deleted);
+        Ok(())
+    }
+
+    /// Delete a block with the blocks inside it and after it, like a whole script
+    /// when it is the top block.
+    pub fn delete_script(
+        &mut self,
+        target_id: TargetId,
+        block_id: BlockId,
+    ) -> Result<(), EditError> {
+        self.live_block(target_id, block_id)?;
+        let target = &mut self.targets[target_id];
+        match parent(target, block_id) {
+            Some(Parent::Next(id)) => target.blocks[id].next = None,
+            Some(Parent::Argument(id, index)) => {
+                target.blocks[id].arguments[index] = BlockValue::Undefined
+            }
+            Some(Parent::TopLevel) | None => {}
+        }
+        let deleted = collect_blocks(target, block_id, true);
+        self.delete_blocks(target_id, deleted);
+        Ok(())
+    }
+
+    /// Set a field of a block, or the value of an input that holds no block,
+    /// like the variable of `set variable to` or the 10 of `move 10 steps`.
+    pub fn set_field(
+        &mut self,
+        target_id: TargetId,
+        block_id: BlockId,
+        name: &str,
+        value: BlockValue,
+    ) -> Result<(), EditError> {
+        self.live_block(target_id, block_id)?;
+        let (index, arg_type) = self.argument(target_id, block_id, name)?;
+        if let ArgType::Input(input_type) = arg_type {
+            if !input_type.accepts(&value) {
+                return Err(EditError::WrongType(name.to_owned()));
+            }
+        }
+        let arguments = &mut self.targets[target_id].blocks[block_id].arguments;
+        if arguments.len() <= index {
+            arguments.resize(index + 1, BlockValue::Undefined);
+        }
+        if arguments[index].is_block() {
+            return Err(EditError::ArgumentHasBlock(name.to_owned()));
+        }
+        arguments[index] = value;
+        self.blocks_changed(target_id, &HashSet::new());
+        Ok(())
+    }
+
+    /// Rename a variable of a target. Blocks refer to variables by id, so they keep working.
+    pub fn rename_variable(
+        &mut self,
+        target_id: TargetId,
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), EditError> {
+        self.rename_data(target_id, old_name, new_name, |t| &mut t.variable_names)
+            .map_err(|e| e.unwrap_or_else(|| EditError::UnknownVariable(old_name.to_owned())))
+    }
+
+    /// Rename a list of a target, like `rename_variable`.
+    pub fn rename_list(
+        &mut self,
+        target_id: TargetId,
+        old_name: &str,
+        new_name: &str,
+    ) -> Result<(), EditError> {
+        self.rename_data(target_id, old_name, new_name, |t| &mut t.list_names)
+            .map_err(|e| e.unwrap_or_else(|| EditError::UnknownList(old_name.to_owned())))
+    }
+
+    /// Rename a broadcast message. Blocks refer to messages by id, so they keep working.
+    ///
+    /// Messages are found by name ignoring case, so `new_name` must not be the name of
+    /// another message in any case.
+    pub fn rename_broadcast(&mut self, old_name: &str, new_name: &str) -> Result<(), EditError> {
+        let broadcasts = &mut self.targets[self.stage_id].broadcasts;
+        let id = broadcasts
+            .iter()
+            .find(|(_, x)| x.as_str() == old_name)
+            .map(|(id, _)| id.to_owned())
+            .ok_or_else(|| EditError::UnknownBroadcast(old_name.to_owned()))?;
+        let taken = broadcasts
+            .iter()
+            .any(|(other, x)| *other != id && x.to_lowercase() == new_name.to_lowercase());
+        if taken {
+            return Err(EditError::NameTaken(new_name.to_owned()));
+        }
+        broadcasts.insert(id, new_name.to_owned());
+        Ok(())
+    }
+
+    /// Returns `Err(None)` if the target has no variable or list named `old_name`.
+    fn rename_data(
+        &mut self,
+        target_id: TargetId,
+        old_name: &str,
+        new_name: &str,
+        names: fn(&mut Target) -> &mut BTreeMap<String, String>,
+    ) -> Result<(), Option<EditError>> {
+        if !self
+            .running_targets
+            .iter()
+            .any(|(_, rt)| rt.target_id == target_id)
+        {
+            return Err(Some(EditError::UnknownTarget));
+        }
+        if !names(&mut self.targets[target_id])
+            .values()
+            .any(|x| x == old_name)
+        {
+            return Err(None);
+        }
+        // Locals can not shadow globals, and globals can not shadow locals
+        let is_stage = target_id == self.stage_id;
+        let stage_id = self.stage_id;
+        let mut taken = false;
+        for (id, target) in self.targets.iter_mut() {
+            if id == target_id || id == stage_id || is_stage {
+                taken |= names(target).values().any(|x| x == new_name);
+            }
+        }
+        if taken {
+            return Err(Some(EditError::NameTaken(new_name.to_owned())));
+        }
+        if let Some(name) = names(&mut self.targets[target_id])
+            .values_mut()
+            .find(|x| x.as_str() == old_name)
+        {
+            *name = new_name.to_owned();
+        }
+        Ok(())
+    }
+
+    fn live_target(&self, target_id: TargetId) -> Result<&Target, EditError> {
+        self.targets
+            .get(target_id)
+            .filter(|target| !target.deleted)
+            .ok_or(EditError::UnknownTarget)
+    }
+
+    fn live_block(&self, target_id: TargetId, block_id: BlockId) -> Result<&Block, EditError> {
+        match self.live_target(target_id)?.blocks.get(block_id) {
+            Some(block) if block.block_id != DELETED => Ok(block),
+            _ => Err(EditError::UnknownBlock),
+        }
+    }
+
+    /// Index of an input or field in the arguments of a block.
+    fn argument(
+        &self,
+        target_id: TargetId,
+        block_id: BlockId,
+        name: &str,
+    ) -> Result<(usize, ArgType), EditError> {
+        let block = self.live_block(target_id, block_id)?;
+        self.registry
+            .get(&block.opcode)
+            .and_then(|info| {
+                info.arguments
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (_, x))| x == name)
+                    .map(|(i, (arg_type, _))| (i, *arg_type))
+            })
+            .ok_or_else(|| EditError::UnknownArgument(name.to_owned()))
+    }
+
+    /// Detached blocks are left in the arena, so only mark them.
+    fn delete_blocks(&mut self, target_id: TargetId, blocks: Vec<BlockId>) {
+        let target = &mut self.targets[target_id];
+        for id in blocks.iter() {
+            let block = &mut target.blocks[*id];
+            block.toplevel = false;
+            block.next = None;
+            block.arguments.clear();
+            block.block_function = blocks::noop;
+            block.block_id = DELETED.into();
+        }
+        self.blocks_changed(target_id, &blocks.into_iter().collect());
+    }
+
+    /// Stop the threads that run a deleted block or compiled bytecode of the target,
+    /// and update the hats, breakpoints and compiled scripts.
+    fn blocks_changed(&mut self, target_id: TargetId, deleted: &HashSet<BlockId>) {
+        let running_targets = &self.running_targets;
+        let mut stopped = Vec::new();
+        self.threads.retain(|t| {
+            let stop = running_targets
+                .get(t.running_target_id)
+                .map(|rt| rt.target_id == target_id)
+                .unwrap_or(false)
+                && (t.bytecode.is_some()
+                    || deleted.contains(&t.top_block)
+                    || t.stacks.iter().any(|s| deleted.contains(&s.block_id)));
+            if stop {
+                stopped.push((t.thread_id, t.running_target_id));
+            }
+            !stop
+        });
+        self.notify(|o, vm| {
+            for (thread_id, running_target_id) in stopped.iter() {
+                o.thread_finished(vm, *thread_id, *running_target_id);
+            }
+        });
+        for id in deleted.iter() {
+            self.remove_breakpoint(*id);
+        }
+        if let Some(program) = &mut self.bytecode {
+            program.scripts.retain(|(id, _), _| *id != target_id);
+            program.procedures.retain(|(id, _), _| *id != target_id);
+        }
+        self.refresh_hats();
+    }
+}
@@ -0,0 +1,94 @@
+use crate::blocks::{BlockRegistry, RegisterError};
+
+/// Metadata of an extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionInfo {
+    /// The id listed in the `extensions` field of `project.json`, like `pen` or `clipcc.extension.example`.
+    pub id: String,
+    /// The version of the implementation, like `1.0.0`.
+    pub version: String,
+    /// The category its blocks are listed under in the editor, like `Pen`.
+    pub category: String,
+}
+
+/// A Rust implementation of a Scratch or ClipCC extension.
+pub trait ExtensionProvider: Send + Sync {
+    fn info(&self) -> ExtensionInfo;
+    /// Register the blocks of this extension. Called when a project that uses it is loaded.
+    fn register(&self, registry: &mut BlockRegistry) -> Result<(), RegisterError>;
+}
+
+/// What to do when a project needs an extension that no provider implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingExtensionPolicy {
+    /// Load the project without the extension's blocks, see `LoadReport::warnings`.
+    #[default]
+    Warn,
+    /// Refuse to load the project.
+    Refuse,
+}
+
+/// What was loaded with a project, and what is missing from it.
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    /// Extensions that are provided and registered.
+    pub loaded: Vec<ExtensionInfo>,
+    /// Ids of extensions that no provider implements.
+    pub missing: Vec<String>,
+    /// md5exts of costumes and sounds that could not be found, or whose data does not have
+    /// the md5 of their name. The project runs without them.
+    pub missing_assets: Vec<String>,
+    /// Opcodes of blocks that no extension implements, sorted. The blocks are skipped.
+    pub unknown_opcodes: Vec<String>,
+}
+
+impl LoadReport {
+    /// What is missing from the project, one line each, for a player to show.
+    /// The loader prints nothing itself, so it does not mix with the output of a player.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for id in &self.missing {
+            warnings.push(format!("missing extension: {}", id));
+        }
+        for opcode in &self.unknown_opcodes {
+            warnings.push(format!("unknown opcode: {}", opcode));
+        }
+        for md5ext in &self.missing_assets {
+            warnings.push(format!("missing asset: {}", md5ext));
+        }
+        warnings
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    /// The project needs extensions that are missing and the policy is `Refuse`.
+    MissingExtensions(Vec<String>),
+    /// The project file could not be read.
+    Io(String),
+    /// The file is not a zip archive with a valid `project.json`.
+    InvalidProject(String),
+    /// A block of the extension with this id could not be registered.
+    InvalidExtension(String, RegisterError),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingExtensions(ids) => {
+                write!(f, "missing extensions: {}", ids.join(", "))
+            }
+            Self::Io(e) => write!(f, "can not read the project: {}", e),
+            Self::InvalidProject(e) => write!(f, "invalid project: {}", e),
+            Self::InvalidExtension(id, e) => write!(f, "invalid extension {}: {}", id, e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl std::fmt::Debug for dyn ExtensionProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.info().fmt(f)
+    }
+}
@@ -0,0 +1,551 @@
+//! Grades projects against a declarative spec of expected behavior.
+//!
+//! A spec is JSON like
+//!
+//! ```json
+//! {
+//!     "time_limit": 5,
+//!     "seed": 1,
+//!     "bytecode": true,
+//!     "coverage": true,
+//!     "cases": [
+//!         {
+//!             "name": "greets the user",
+//!             "answers": ["Alice"],
+//!             "events": [{"time": 0.5, "key": "space"}, {"step": 30, "broadcast": "check"}],
+//!             "expect": {
+//!                 "finishes": true,
+//!                 "say": ["What's your name?", "Hello Alice"],
+//!                 "say_contains": ["Alice"],
+//!                 "variables": {"Stage": {"score": 10}},
+//!                 "lists": {"Sprite1": {"names": ["Alice"]}},
+//!                 "sprites": {"Sprite1": {"x": 0, "y": 100, "direction": 90, "tolerance": 0.5}}
+//!             }
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! Every case runs in a fresh VM with `runner::run_vm`, and every expectation becomes a check
+//! that passes or fails on its own. `say` may also be an object keyed by sprite name to only
+//! look at what one sprite said. Values are compared like the `=` block of Scratch.
+//! With `bytecode`, projects are optimized and compiled to bytecode before they run, like
+//! with `--bytecode` of scrust. With `coverage`, the report also lists the blocks that ran
+//! in any case. Unknown keys and cases without checks are errors, so typos do not make
+//! checks pass.
+
+use crate::runner::*;
+use crate::*;
+use json::JsonValue;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Something a case expects from the run.
+#[derive(Debug, Clone)]
+pub enum Expectation {
+    /// All scripts finish before the limits.
+    Finishes,
+    /// Exactly this say and think output, in order. Only of `target` if it is set.
+    Say {
+        target: Option<String>,
+        texts: Vec<String>,
+    },
+    /// Some say or think output contains this text.
+    SayContains(String),
+    /// The final value of a variable.
+    Variable {
+        target: String,
+        name: String,
+        value: BlockValue,
+    },
+    /// The final items of a list.
+    List {
+        target: String,
+        name: String,
+        values: Vec<BlockValue>,
+    },
+    /// The final position of a sprite. Unset coordinates are not checked.
+    Position {
+        target: String,
+        x: Option<f64>,
+        y: Option<f64>,
+        direction: Option<f64>,
+        tolerance: f64,
+    },
+}
+
+/// One run of a project with its input and expectations.
+#[derive(Debug, Clone, Default)]
+pub struct Case {
+    pub name: String,
+    pub input: ScriptedInput,
+    /// Overrides the time limit of the spec.
+    pub time_limit: Option<Duration>,
+    /// Overrides the step limit of the spec.
+    pub step_limit: Option<usize>,
+    pub expect: Vec<Expectation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Spec {
+    pub time_limit: Option<Duration>,
+    pub step_limit: Option<usize>,
+    pub seed: Option<u64>,
+    /// Optimize and compile projects to bytecode before running them.
+    pub compile: bool,
+    /// Collect the blocks that run in any case into `Report::coverage`.
+    pub coverage: bool,
+    pub cases: Vec<Case>,
+}
+
+impl Default for Spec {
+    fn default() -> Self {
+        Self {
+            time_limit: Some(Duration::from_secs(10)),
+            step_limit: None,
+            seed: None,
+            compile: false,
+            coverage: false,
+            cases: Vec::new(),
+        }
+    }
+}
+
+/// Result of one expectation of one case.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub case: String,
+    /// What was checked, like `variable Stage.score`.
+    pub check: String,
+    pub passed: bool,
+    /// Why the check failed. Empty if it passed.
+    pub message: String,
+}
+
+/// Checks of all cases of a spec against one project.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+    /// Blocks that ran in any case, if `Spec::coverage` is set.
+    pub coverage: Option<CoverageReport>,
+}
+
+fn parse_time_limit(value: &JsonValue) -> Result<Option<Option<Duration>>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    match value.as_f64() {
+        Some(secs) if secs > 0. => Ok(Some(Some(Duration::from_secs_f64(secs)))),
+        Some(_) => Ok(Some(None)),
+        None => Err(format!("invalid time limit: {}", value.dump())),
+    }
+}
+
+/// Fail on the first key of the object `value` that is not in `keys`.
+fn check_keys(value: &JsonValue, keys: &[&str], what: &str) -> Result<(), String> {
+    match value.entries().find(|(key, _)| !keys.contains(key)) {
+        Some((key, _)) => Err(format!("unknown key {:?} in the {}", key, what)),
+        None => Ok(()),
+    }
+}
+
+fn json_to_block_value(value: &JsonValue) -> Result<BlockValue, String> {
+    if let Some(s) = value.as_str() {
+        Ok(s.into())
+    } else if let Some(n) = value.as_f64() {
+        Ok(n.into())
+    } else if let Some(b) = value.as_bool() {
+        Ok(b.into())
+    } else {
+        Err(format!("invalid value: {}", value.dump()))
+    }
+}
+
+fn json_to_string(value: &JsonValue) -> String {
+    match value.as_str() {
+        Some(s) => s.to_owned(),
+        None => value.dump(),
+    }
+}
+
+impl Spec {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Self::from_json(&json::parse(text).map_err(|e| e.to_string())?)
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let mut spec = Self::default();
+        check_keys(
+            value,
+            &[
+                "time_limit",
+                "step_limit",
+                "seed",
+                "bytecode",
+                "coverage",
+                "cases",
+            ],
+            "spec",
+        )?;
+        if let Some(limit) = parse_time_limit(&value["time_limit"])? {
+            spec.time_limit = limit;
+        }
+        spec.step_limit = value["step_limit"].as_usize();
+        spec.seed = value["seed"].as_u64();
+        spec.compile = value["bytecode"].as_bool().unwrap_or(false);
+        spec.coverage = value["coverage"].as_bool().unwrap_or(false);
+        if !value["cases"].is_array() {
+            return Err("the spec has no cases".to_owned());
+        }
+        for (index, case) in value["cases"].members().enumerate() {
+            let name = match case["name"].as_str() {
+                Some(name) => name.to_owned(),
+                None => format!("case {}", index + 1),
+            };
+            let case =
+                Case::from_json(name.clone(), case).map_err(|e| format!("{}: {}", name, e))?;
+            spec.cases.push(case);
+        }
+        Ok(spec)
+    }
+
+    /// Options of `runner::run_vm` for a case.
+    pub fn run_options(&self, case: &Case) -> RunOptions {
+        RunOptions {
+            time_limit: case.time_limit.or(self.time_limit),
+            step_limit: case.step_limit.or(self.step_limit),
+            seed: self.seed,
+            fixed_step: None,
+            input: case.input.clone(),
+            compile: self.compile,
+            coverage: self.coverage,
+        }
+    }
+}
+
+impl Case {
+    fn from_json(name: String, value: &JsonValue) -> Result<Self, String> {
+        check_keys(
+            value,
+            &[
+                "name",
+                "answers",
+                "events",
+                "time_limit",
+                "step_limit",
+                "expect",
+            ],
+            "case",
+        )?;
+        let mut case = Self {
+            name,
+            input: ScriptedInput::from_json(value)?,
+            time_limit: None,
+            step_limit: value["step_limit"].as_usize(),
+            expect: Vec::new(),
+        };
+        if let Some(limit) = parse_time_limit(&value["time_limit"])? {
+            // A time limit of 0 runs the case until it finishes, ignoring the limit of the spec
+            case.time_limit = Some(limit.unwrap_or(Duration::MAX));
+        }
+        let expect = &value["expect"];
+        check_keys(
+            expect,
+            &[
+                "finishes",
+                "say",
+                "say_contains",
+                "variables",
+                "lists",
+                "sprites",
+            ],
+            "expectations",
+        )?;
+        if expect["finishes"].as_bool() == Some(true) {
+            case.expect.push(Expectation::Finishes);
+        }
+        let say = &expect["say"];
+        if say.is_array() {
+            case.expect.push(Expectation::Say {
+                target: None,
+                texts: say.members().map(json_to_string).collect(),
+            });
+        } else {
+            for (target, texts) in say.entries() {
+                case.expect.push(Expectation::Say {
+                    target: Some(target.to_owned()),
+                    texts: texts.members().map(json_to_string).collect(),
+                });
+            }
+        }
+        for text in expect["say_contains"].members() {
+            case.expect
+                .push(Expectation::SayContains(json_to_string(text)));
+        }
+        for (target, variables) in expect["variables"].entries() {
+            for (name, value) in variables.entries() {
+                case.expect.push(Expectation::Variable {
+                    target: target.to_owned(),
+                    name: name.to_owned(),
+                    value: json_to_block_value(value)?,
+                });
+            }
+        }
+        for (target, lists) in expect["lists"].entries() {
+            for (name, values) in lists.entries() {
+                case.expect.push(Expectation::List {
+                    target: target.to_owned(),
+                    name: name.to_owned(),
+                    values: values
+                        .members()
+                        .map(json_to_block_value)
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+        }
+        for (target, position) in expect["sprites"].entries() {
+            check_keys(position, &["x", "y", "direction", "tolerance"], "position")?;
+            case.expect.push(Expectation::Position {
+                target: target.to_owned(),
+                x: position["x"].as_f64(),
+                y: position["y"].as_f64(),
+                direction: position["direction"].as_f64(),
+                tolerance: position["tolerance"].as_f64().unwrap_or(0.),
+            });
+        }
+        if case.expect.is_empty() {
+            return Err("the case has no checks".to_owned());
+        }
+        Ok(case)
+    }
+}
+
+impl Expectation {
+    /// Short name of the check, like `variable Stage.score`.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Finishes => "finishes".to_owned(),
+            Self::Say { target: None, .. } => "say".to_owned(),
+            Self::Say {
+                target: Some(target),
+                ..
+            } => format!("say {}", target),
+            Self::SayContains(text) => format!("say contains {:?}", text),
+            Self::Variable { target, name, .. } => format!("variable {}.{}", target, name),
+            Self::List { target, name, .. } => format!("list {}.{}", target, name),
+            Self::Position { target, .. } => format!("position {}", target),
+        }
+    }
+
+    /// Check a run. Returns why it failed.
+    pub fn check(&self, result: &RunResult) -> Result<(), String> {
+        match self {
+            Self::Finishes => match result.status {
+                RunStatus::Finished => Ok(()),
+                _ => Err(format!("stopped at the {}", result.status.as_str())),
+            },
+            Self::Say { target, texts } => {
+                let said = result
+                    .speech
+                    .iter()
+                    .filter(|s| target.as_ref().map(|t| *t == s.target).unwrap_or(true))
+                    .map(|s| s.text.as_str())
+                    .collect::<Vec<_>>();
+                if said == *texts {
+                    Ok(())
+                } else {
+                    Err(format!("expected {:?}, got {:?}", texts, said))
+                }
+            }
+            Self::SayContains(text) => {
+                if result.speech.iter().any(|s| s.text.contains(text.as_str())) {
+                    Ok(())
+                } else {
+                    Err(format!("nothing said contains {:?}", text))
+                }
+            }
+            Self::Variable {
+                target,
+                name,
+                value,
+            } => match result.variables.get(target).and_then(|v| v.get(name)) {
+                Some(actual) if actual == value => Ok(()),
+                Some(actual) => Err(format!("expected {}, got {}", value, actual)),
+                None => Err("no such variable".to_owned()),
+            },
+            Self::List {
+                target,
+                name,
+                values,
+            } => match result.lists.get(target).and_then(|l| l.get(name)) {
+                Some(actual) if actual == values => Ok(()),
+                Some(actual) => Err(format!(
+                    "expected [{}], got [{}]",
+                    join_values(values),
+                    join_values(actual)
+                )),
+                None => Err("no such list".to_owned()),
+            },
+            Self::Position {
+                target,
+                x,
+                y,
+                direction,
+                tolerance,
+            } => {
+                let position = result.sprites.get(target).ok_or("no such sprite")?;
+                let mut errors = Vec::new();
+                for (axis, expected, actual) in [
+                    ("x", x, position.x),
+                    ("y", y, position.y),
+                    ("direction", direction, position.direction),
+                ] {
+                    if let Some(expected) = expected {
+                        if (expected - actual).abs() > *tolerance {
+                            errors.push(format!("expected {} {}, got {}", axis, expected, actual));
+                        }
+                    }
+                }
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(errors.join(", "))
+                }
+            }
+        }
+    }
+}
+
+fn join_values(values: &[BlockValue]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+
+    /// The report as a JSON object with the fields `passed`, `total`, `failed` and `checks`,
+    /// and `coverage` if it was collected.
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = json::object! {
+            "passed": self.passed(),
+            "total": self.checks.len(),
+            "failed": self.checks.iter().filter(|c| !c.passed).count(),
+            "checks": self
+                .checks
+                .iter()
+                .map(|c| json::object! {
+                    "case": c.case.as_str(),
+                    "check": c.check.as_str(),
+                    "passed": c.passed,
+                    "message": c.message.as_str(),
+                })
+                .collect::<Vec<_>>(),
+        };
+        if let Some(coverage) = &self.coverage {
+            value["coverage"] = coverage.to_json();
+        }
+        value
+    }
+}
+
+/// Run every case of `spec` against an sb3 file in memory.
+pub fn grade_data(spec: &Spec, data: &[u8]) -> Report {
+    grade(spec, &Arc::default(), |vm| {
+        sb3_loader::load_sb3_data(vm, data)
+    })
+}
+
+/// Run every case of `spec` against an sb3 file, or an extracted project directory.
+pub fn grade_file(spec: &Spec, path: &Path) -> Report {
+    grade_file_with_cache(spec, path, &Arc::default())
+}
+
+fn grade_file_with_cache(spec: &Spec, path: &Path, asset_cache: &Arc<AssetCache>) -> Report {
+    if path.is_dir() {
+        return grade(spec, asset_cache, |vm| {
+            sb3_loader::load_project_dir(vm, path)
+        });
+    }
+    match std::fs::read(path) {
+        Ok(data) => grade(spec, asset_cache, |vm| sb3_loader::load_sb3_data(vm, &data)),
+        Err(e) => Report {
+            checks: vec![Check {
+                case: String::new(),
+                check: "load".to_owned(),
+                passed: false,
+                message: LoadError::Io(e.to_string()).to_string(),
+            }],
+            coverage: None,
+        },
+    }
+}
+
+/// Run every case of `spec` on a new VM that `load` loads the project into.
+/// The VMs share the costumes and sounds of `asset_cache`.
+fn grade(
+    spec: &Spec,
+    asset_cache: &Arc<AssetCache>,
+    load: impl Fn(&mut VirtualMachine) -> Result<LoadReport, LoadError>,
+) -> Report {
+    let mut report = Report::default();
+    for case in &spec.cases {
+        let mut vm = VirtualMachine {
+            asset_cache: Some(asset_cache.clone()),
+            ..Default::default()
+        };
+        if let Err(e) = load(&mut vm) {
+            report.checks.push(Check {
+                case: case.name.clone(),
+                check: "load".to_owned(),
+                passed: false,
+                message: e.to_string(),
+            });
+            continue;
+        }
+        let mut result = run_vm(&mut vm, &spec.run_options(case));
+        if let Some(coverage) = result.coverage.take() {
+            match &mut report.coverage {
+                Some(report) => report.merge(&coverage),
+                None => report.coverage = Some(coverage),
+            }
+        }
+        for expectation in &case.expect {
+            let outcome = expectation.check(&result);
+            report.checks.push(Check {
+                case: case.name.clone(),
+                check: expectation.name(),
+                passed: outcome.is_ok(),
+                message: outcome.err().unwrap_or_default(),
+            });
+        }
+    }
+    report
+}
+
+/// Grade sb3 files on `jobs` threads. The reports are in the order of `paths`.
+pub fn grade_batch(spec: &Spec, paths: &[PathBuf], jobs: usize) -> Vec<Report> {
+    let asset_cache = Arc::default();
+    parallel_map(paths, jobs, |path| {
+        grade_file_with_cache(spec, path, &asset_cache)
+    })
+    .into_iter()
+    .map(|report| {
+        report.unwrap_or_else(|e| Report {
+            checks: vec![Check {
+                case: String::new(),
+                check: "run".to_owned(),
+                passed: false,
+                message: format!("panicked: {}", e),
+            }],
+            coverage: None,
+        })
+    })
+    .collect()
+}
@@ -0,0 +1,128 @@
+use crate::blocks::BlockShape;
+use crate::*;
+
+impl VirtualMachine {
+    /// Collect the edge-activated hats of all targets.
+    ///
+    /// Called after loading a project and by the editing methods like `insert_block`.
+    /// Hosts that change `Target::blocks` directly should call it again so new hats are evaluated.
+    pub fn refresh_hats(&mut self) {
+        self.edge_activated_hats.clear();
+        for (tid, target) in self.targets.iter() {
+            for (bid, block) in target.blocks.iter() {
+                if block.toplevel
+                    && self
+                        .registry
+                        .get(&block.opcode)
+                        .map(|x| x.shape == BlockShape::EdgeActivatedHat)
+                        .unwrap_or(false)
+                {
+                    self.edge_activated_hats.push((tid, bid));
+                }
+            }
+        }
+    }
+
+    /// Evaluate the predicates of the edge-activated hats, and start the
+    /// scripts whose predicate changed from false to true since the last step.
+    ///
+    /// Called at the end of a step, so the predicates see what the threads changed in
+    /// it, like hat threads that scratch-vm runs after the other threads. The scripts
+    /// start running in the next step.
+    pub(crate) fn start_edge_activated_hats(&mut self) {
+        if self.edge_activated_hats.is_empty() {
+            return;
+        }
+        let running_targets = &self.running_targets;
+        self.runtime
+            .edge_hat_values
+            .retain(|(rtid, _), _| running_targets.contains(*rtid));
+        let mut fired = Vec::new();
+        for i in 0..self.edge_activated_hats.len() {
+            let (tid, bid) = self.edge_activated_hats[i];
+            let running_targets = self
+                .running_targets
+                .iter()
+                .filter(|x| x.1.target_id == tid)
+                .map(|x| x.0)
+                .collect::<Vec<_>>();
+            for rtid in running_targets {
+                // Also evaluated while the script runs, so the hat fires again after the
+                // predicate went back to false. A script that is still running is not restarted.
+                let value = self
+                    .evaluate_block(rtid, bid)
+                    .map(|x| x.to_boolean())
+                    .unwrap_or(false);
+                let old_value = self.runtime.edge_hat_values.insert((rtid, bid), value);
+                if value && !old_value.unwrap_or(false) {
+                    fired.push((rtid, bid));
+                }
+            }
+        }
+        for (rtid, bid) in fired {
+            self.start_hat(rtid, bid);
+        }
+    }
+
+    /// Run a reporter block to completion and return its value, without following its `next` block.
+    ///
+    /// Returns `None` if the block or one of its inputs has to wait, since it cannot finish synchronously.
+    pub fn evaluate_block(
+        &mut self,
+        running_target_id: RunningTargetId,
+        block_id: BlockId,
+    ) -> Option<BlockValue> {
+        let target_id = self.running_targets.get(running_target_id)?.target_id;
+        let block = self.targets.get(target_id)?.blocks.get(block_id)?;
+        let mut stacks = vec![Stack::new(block_id, block)];
+        loop {
+            let stack = stacks.last_mut()?;
+            let block_function = stack.block_function;
+            let block_result = {
+                let mut ctx = BlockContext {
+                    stack,
+                    warp: false,
+                    runtime: &mut self.runtime,
+                    targets: &mut self.targets,
+                    running_targets: &mut self.running_targets,
+                    target_id,
+                    running_stage_id: self.running_stage_id,
+                    running_target_id,
+                    stage_id: self.stage_id,
+                };
+                (block_function)(&mut ctx)
+            };
+            let target = self.targets.get(target_id)?;
+            match block_result {
+                BlockResult::Resolved(result) => {
+                    stacks.pop();
+                    let result = result.unwrap_or_default();
+                    if let Some(prev_stack) = stacks.last_mut() {
+                        prev_stack.arguments.push(result);
+                    } else {
+                        return Some(result);
+                    }
+                }
+                BlockResult::ResolveArgument(index) => {
+                    let stack = stacks.last_mut()?;
+                    match target.blocks[stack.block_id].arguments.get(index) {
+                        Some(BlockValue::BlockId(bid)) => {
+                            if let Some(block) = target.blocks.get(*bid) {
+                                stacks.push(Stack::new(*bid, block));
+                            } else {
+                                stack.arguments.push(BlockValue::Undefined);
+                            }
+                        }
+                        Some(other) => stack.arguments.push(other.to_owned()),
+                        None => stack.arguments.push(BlockValue::Undefined),
+                    }
+                }
+                BlockResult::ResolveProcedureArgument(_) => {
+                    // Not inside a procedure
+                    stacks.last_mut()?.arguments.push(BlockValue::Undefined);
+                }
+                _ => return None,
+            }
+        }
+    }
+}
@@ -9,25 +9,48 @@ mod test_projects;
 pub mod uid;
 pub mod util;
 pub(crate) use util::*;
+pub mod aot;
 pub mod blocks;
+pub mod bytecode;
+pub mod grader;
+pub mod runner;
 pub use block_value::BlockValue;
+mod assets;
 mod block;
 mod context;
+mod coverage;
+mod debugger;
+mod editor;
+mod extension;
+mod hats;
+mod observer;
+mod optimizer;
+mod profiler;
+mod runtime;
 mod target;
+mod tracer;
+pub use assets::*;
 pub use block::*;
 pub use context::*;
+pub use coverage::*;
+pub use debugger::*;
+pub use editor::*;
+pub use extension::*;
+pub use observer::*;
+pub use optimizer::*;
+pub use profiler::*;
+pub use runtime::*;
 pub use target::*;
+pub use tracer::*;
 pub mod core_blocks;
+pub mod sb2_loader;
 pub mod sb3_loader;
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-pub static mut THREAD_RND: once_cell::sync::Lazy<rand::rngs::SmallRng> =
-    once_cell::sync::Lazy::new(|| {
-        use rand::prelude::*;
-        rand::rngs::SmallRng::from_entropy()
-    });
+/// How long a thread in warp mode can run in one step before it yields, like scratch-vm.
+pub const WARP_TIME: std::time::Duration = std::time::Duration::from_millis(500);
 
 #[derive(Debug)]
 pub enum TargetType {
@@ -44,7 +67,10 @@ pub enum BlockResult {
     Resolved(Option<BlockValue>),
     PushStack(BlockId),
     // 一些特殊指令
-    ResolveProcedureArgument(usize),
+    /// Resolve the argument of the running procedure with this name.
+    /// The value is `Undefined` if the argument is not bound.
+    ResolveProcedureArgument(String),
+    /// Return from the running procedure, and report the value from the call block.
     ReturnProcedure(BlockValue),
     Boardcast(String),
     BoardcastAndWait(String),
@@ -53,6 +79,19 @@ pub enum BlockResult {
     DeleteThisClone,
 }
 
+impl BlockResult {
+    /// Whether the block finished running, instead of waiting or asking the VM for something.
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self,
+            Self::Pending
+                | Self::ResolveArgument(_)
+                | Self::PushStack(_)
+                | Self::ResolveProcedureArgument(_)
+        )
+    }
+}
+
 pub type BlockFunction = fn(&mut BlockContext) -> BlockResult;
 
 pub struct Stack {
@@ -64,6 +103,16 @@ pub struct Stack {
 }
 
 impl Stack {
+    pub fn new(block_id: BlockId, block: &Block) -> Self {
+        Self {
+            uid: crate::uid::uid(),
+            block_id,
+            block_function: block.block_function,
+            arguments: Vec::with_capacity(block.arguments.len()),
+            block_data: Box::new(()),
+        }
+    }
+
     pub fn require_resolve(&self, argument_id: usize) -> bool {
         matches!(&self.arguments[argument_id], BlockValue::BlockId(_))
     }
@@ -96,46 +145,91 @@ static THREAD_ID_COUNTER: core::sync::atomic::AtomicUsize = core::sync::atomic::
 #[derive(Debug, Clone)]
 pub struct Thread {
     pub running_target_id: generational_arena::Index,
+    /// The block this thread started from, usually a hat block.
+    pub top_block: BlockId,
     pub thread_id: usize,
     pub awaiting_thread: Vec<usize>,
     pub stacks: Vec<Stack>,
+    /// Set if the thread runs compiled bytecode instead of `stacks`.
+    pub bytecode: Option<Box<bytecode::BytecodeThread>>,
 }
 
 impl Thread {
-    pub fn new(running_target_id: generational_arena::Index) -> Self {
+    pub fn new(running_target_id: generational_arena::Index, top_block: BlockId) -> Self {
         Self {
             running_target_id,
+            top_block,
             thread_id: THREAD_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
             awaiting_thread: Vec::with_capacity(16),
             stacks: Vec::with_capacity(16),
+            bytecode: None,
         }
     }
 
+    /// Create a thread whose top block is the block of the first stack.
     pub fn new_with_stacks(
         running_target_id: generational_arena::Index,
         stacks: Vec<Stack>,
     ) -> Self {
         Self {
             running_target_id,
+            top_block: stacks[0].block_id,
             thread_id: THREAD_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
             awaiting_thread: Vec::with_capacity(16),
             stacks,
+            bytecode: None,
         }
     }
+
+    /// Run the thread again from its top block. The thread gets a new id,
+    /// so threads waiting for the old run (like `broadcast and wait`) continue.
+    pub fn restart(&mut self, top_block: &Block) {
+        self.thread_id = THREAD_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.awaiting_thread.clear();
+        self.stacks.clear();
+        self.stacks.push(Stack::new(self.top_block, top_block));
+    }
 }
 
 #[derive(Debug)]
 pub struct VirtualMachine {
     pub stage_id: TargetId,
     pub running_stage_id: generational_arena::Index,
-    pub global_data: HashMap<String, Box<dyn Any + Send + Sync>>,
+    pub runtime: RuntimeState,
+    /// Blocks this VM can load and run.
+    pub registry: blocks::BlockRegistry,
+    /// Extensions that projects loaded into this VM can use.
+    pub extension_providers: Vec<std::sync::Arc<dyn ExtensionProvider>>,
+    pub missing_extension_policy: MissingExtensionPolicy,
+    /// Observers of thread and clone events.
+    pub observers: Vec<Box<dyn VmObserver>>,
+    pub debugger: Debugger,
+    /// Records every block that runs when set.
+    pub tracer: Option<Tracer>,
+    /// Measures the time spent in blocks when set.
+    pub profiler: Option<Profiler>,
+    /// Counts the blocks that run when set.
+    pub coverage: Option<Coverage>,
+    /// Costumes and sounds of the loaded projects, keyed by md5ext.
+    pub assets: HashMap<String, Asset>,
+    /// Loaders share assets with other VMs through this cache when set.
+    pub asset_cache: Option<std::sync::Arc<AssetCache>>,
+    /// Compiled scripts, see `compile_bytecode`.
+    pub bytecode: Option<bytecode::CompiledProgram>,
     pub targets: Arena<Target>,
     pub running_targets: generational_arena::Arena<RunningTarget>,
     pub threads: Vec<Thread>,
     pub new_clones: Vec<Index>,
     threads_removed: Vec<usize>,
+    edge_activated_hats: Vec<(TargetId, BlockId)>,
 }
 
+// Hosts run many VMs in parallel on worker threads
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VirtualMachine>();
+};
+
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 impl VirtualMachine {
     
@@ -146,6 +240,10 @@ impl VirtualMachine {
             .insert(self.targets[tid].make_target(tid, false));
         (tid, rtid)
     }
+    /// Make an extension available to projects loaded after this call.
+    pub fn add_extension_provider(&mut self, provider: impl ExtensionProvider + 'static) {
+        self.extension_providers.push(std::sync::Arc::new(provider));
+    }
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = resyncStage))]
     pub fn resync_stage(&mut self) {
         let stage = &self.targets[self.stage_id];
@@ -156,21 +254,24 @@ impl VirtualMachine {
     
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = markStageRefreshed))]
     pub fn mark_stage_refreshed(&mut self) {
-        if let Some(sf) = self
-            .global_data
-            .get_mut("STAGE_FRAME")
-            .and_then(|x| x.downcast_mut::<usize>())
-        {
+        self.runtime.redraw_requested = false;
+        if let Some(sf) = &mut self.runtime.stage_frame {
             *sf += 1;
         } else {
-            self.global_data
-                .insert("STAGE_FRAME".to_owned(), Box::new(0usize));
+            self.runtime.stage_frame = Some(0);
         }
     }
 }
 
 impl Default for VirtualMachine {
     fn default() -> Self {
+        Self::new(blocks::BlockRegistry::default())
+    }
+}
+
+impl VirtualMachine {
+    /// Create an empty VM that loads blocks from `registry`.
+    pub fn new(registry: blocks::BlockRegistry) -> Self {
         let mut targets = Arena::with_capacity(1);
         let mut running_targets = generational_arena::Arena::with_capacity(1);
         let stage_id = targets.alloc(Target {
@@ -179,47 +280,131 @@ impl Default for VirtualMachine {
         });
         let running_stage_id =
             running_targets.insert(targets[stage_id].make_target(stage_id, false));
-        let mut global_data: HashMap<String, Box<dyn Any + Send + Sync>> =
-            HashMap::with_capacity(16);
-        global_data.insert("COUNTER".into(), Box::new(0usize)); // 计数器相关的模块只增不减
-        global_data.insert("GLOBAL_TIMER".into(), Box::new(std::time::Instant::now()));
         Self {
             stage_id,
-            global_data,
+            runtime: RuntimeState::default(),
+            registry,
+            extension_providers: Vec::new(),
+            missing_extension_policy: MissingExtensionPolicy::default(),
+            observers: Vec::new(),
+            debugger: Debugger::default(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            assets: HashMap::new(),
+            asset_cache: None,
+            bytecode: None,
             targets,
             running_targets,
             running_stage_id,
             threads: Vec::with_capacity(16),
             threads_removed: Vec::with_capacity(16),
             new_clones: Vec::with_capacity(16),
+            edge_activated_hats: Vec::new(),
         }
     }
+
+    /// Start the `when key pressed` scripts of `key`, like `space` or `a`.
+    /// Returns the ids of the started threads.
+    pub fn press_key(&mut self, key: &str) -> Vec<usize> {
+        self.start_topblock_if("event_whenkeypressed", |b| {
+            b.arguments
+                .first()
+                .map(|k| {
+                    let k = k.to_string();
+                    k == key || k == "any"
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    /// Broadcast the message with this name or id from the host.
+    /// Returns the ids of the started threads.
+    pub fn broadcast(&mut self, message: &str) -> Vec<usize> {
+        self.start_broadcast(message)
+    }
+
+    /// Start the scripts that receive `message`, and return the ids of the started threads.
+    ///
+    /// Hats hold the id of the message. Like scratch-vm, `message` is looked up by id and
+    /// then by name ignoring case, so messages named by reporters reach their hats.
+    pub(crate) fn start_broadcast(&mut self, message: &str) -> Vec<usize> {
+        let broadcasts = &self.targets[self.stage_id].broadcasts;
+        let id = broadcasts
+            .get_key_value(message)
+            .or_else(|| {
+                broadcasts
+                    .iter()
+                    .find(|(_, name)| name.to_lowercase() == message.to_lowercase())
+            })
+            .map_or(message, |(id, _)| id.as_str())
+            .to_owned();
+        let started = self.start_topblock_if("event_whenbroadcastreceived", |b| {
+            b.arguments
+                .first()
+                .map(|x| x.to_string() == id)
+                .unwrap_or(false)
+        });
+        self.notify(|o, vm| o.broadcast(vm, &id, &started));
+        started
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 impl VirtualMachine {
     pub(crate) fn start_block(&mut self, target_id: TargetId, block_id: BlockId) {
-        if let Some(target) = self.targets.get(target_id) {
-            if let Some(block) = target.blocks.get(block_id) {
-                if let Some((rtid, _running_target)) = self
-                    .running_targets
-                    .iter()
-                    .find(|a| a.1.target_id == target_id && !a.1.is_clone)
-                {
-                    self.threads.push(Thread::new_with_stacks(
-                        rtid,
-                        vec![Stack {
-                            uid: crate::uid::uid(),
-                            block_id,
-                            block_function: block.block_function,
-                            arguments: Vec::with_capacity(block.arguments.len()),
-                            block_data: Box::new(()),
-                        }],
-                    ));
-                }
+        if let Some((rtid, _running_target)) = self
+            .running_targets
+            .iter()
+            .find(|a| a.1.target_id == target_id && !a.1.is_clone)
+        {
+            self.start_hat(rtid, block_id);
+        }
+    }
+    /// Start the script of a hat block, following the `restart_existing_threads`
+    /// rule of the hat if the script is already running on the target.
+    ///
+    /// Returns the id of the started or restarted thread.
+    pub(crate) fn start_hat(
+        &mut self,
+        running_target_id: generational_arena::Index,
+        block_id: BlockId,
+    ) -> Option<usize> {
+        let target_id = self.running_targets.get(running_target_id)?.target_id;
+        let block = self.targets[target_id].blocks.get(block_id)?;
+        let bytecode = self.bytecode_thread(running_target_id, block_id);
+        if let Some(thread) = self
+            .threads
+            .iter_mut()
+            .find(|t| t.running_target_id == running_target_id && t.top_block == block_id)
+        {
+            let restart = self
+                .registry
+                .get(&block.opcode)
+                .map(|x| x.restart_existing_threads())
+                .unwrap_or(false);
+            if !restart {
+                return None;
             }
+            let old_thread_id = thread.thread_id;
+            thread.restart(block);
+            thread.bytecode = bytecode;
+            let thread_id = thread.thread_id;
+            self.notify(|o, vm| {
+                o.thread_finished(vm, old_thread_id, running_target_id);
+                o.thread_started(vm, thread_id);
+            });
+            return Some(thread_id);
         }
+        let mut thread =
+            Thread::new_with_stacks(running_target_id, vec![Stack::new(block_id, block)]);
+        thread.bytecode = bytecode;
+        let thread_id = thread.thread_id;
+        self.threads.push(thread);
+        self.notify(|o, vm| o.thread_started(vm, thread_id));
+        Some(thread_id)
     }
+
     pub(crate) fn start_opcode_of_running_target(
         &mut self,
         opcode: &str,
@@ -227,66 +412,88 @@ impl VirtualMachine {
         running_target_id: generational_arena::Index,
     ) {
         if let Some(target) = self.running_targets.get(running_target_id) {
-            for (bid, block) in self.targets[target.target_id].blocks.iter() {
-                if (!toplevel || block.toplevel) && block.opcode == opcode {
-                    self.threads.push(Thread::new_with_stacks(
-                        running_target_id,
-                        vec![Stack {
-                            uid: crate::uid::uid(),
-                            block_id: bid,
-                            block_function: block.block_function,
-                            arguments: Vec::new(),
-                            block_data: Box::new(()),
-                        }],
-                    ));
-                }
+            let block_ids = self.targets[target.target_id]
+                .blocks
+                .iter()
+                .filter(|(_, block)| (!toplevel || block.toplevel) && block.opcode == opcode)
+                .map(|(bid, _)| bid)
+                .collect::<Vec<_>>();
+            for bid in block_ids {
+                self.start_hat(running_target_id, bid);
             }
         }
     }
 
-    pub(crate) fn start_topblock_if(&mut self, opcode: &str, callback: impl Fn(&Block) -> bool) {
+    /// Start the top blocks with `opcode` that `callback` accepts, and return the ids of the started threads.
+    pub(crate) fn start_topblock_if(
+        &mut self,
+        opcode: &str,
+        callback: impl Fn(&Block) -> bool,
+    ) -> Vec<usize> {
+        let mut hats = Vec::new();
         for (rtid, running_target) in self.running_targets.iter() {
             for (bid, block) in self.targets[running_target.target_id].blocks.iter() {
                 if block.toplevel && block.opcode == opcode && callback(block) {
-                    self.threads.push(Thread::new_with_stacks(
-                        rtid,
-                        vec![Stack {
-                            uid: crate::uid::uid(),
-                            block_id: bid,
-                            block_function: block.block_function,
-                            arguments: Vec::new(),
-                            block_data: Box::new(()),
-                        }],
-                    ));
+                    hats.push((rtid, bid));
                 }
             }
         }
+        hats.into_iter()
+            .filter_map(|(rtid, bid)| self.start_hat(rtid, bid))
+            .collect()
     }
 
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = startOpcode))]
     pub fn start_opcode(&mut self, opcode: &str) {
-        self.threads.clear();
-        for (tid, target) in self.running_targets.iter_mut() {
+        let stopped = std::mem::take(&mut self.threads);
+        self.notify(|o, vm| {
+            for thread in stopped.iter() {
+                o.thread_finished(vm, thread.thread_id, thread.running_target_id);
+            }
+        });
+        for (tid, target) in self.running_targets.iter() {
             for (bid, block) in self.targets[target.target_id].blocks.iter() {
                 if block.toplevel && block.opcode == opcode {
-                    self.threads.push(Thread::new_with_stacks(
-                        tid,
-                        vec![Stack {
-                            uid: crate::uid::uid(),
-                            block_id: bid,
-                            block_function: block.block_function,
-                            arguments: Vec::new(),
-                            block_data: Box::new(()),
-                        }],
-                    ));
+                    let mut thread = Thread::new_with_stacks(tid, vec![Stack::new(bid, block)]);
+                    thread.bytecode = self.bytecode_thread(tid, bid);
+                    self.threads.push(thread);
                 }
             }
         }
+        let started = self.threads.iter().map(|t| t.thread_id).collect::<Vec<_>>();
+        self.notify(|o, vm| {
+            for thread_id in started.iter() {
+                o.thread_started(vm, *thread_id);
+            }
+        });
+    }
+    /// Stop all threads and delete all clones, like the stop sign or the `stop all` block.
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = stopAll))]
+    pub fn stop_all(&mut self) {
+        let stopped = std::mem::take(&mut self.threads);
+        let mut deleted_clones = Vec::new();
+        self.running_targets.retain(|rtid, a| {
+            if a.is_clone {
+                deleted_clones.push(rtid);
+            }
+            !a.is_clone
+        });
+        self.notify(|o, vm| {
+            for thread in stopped.iter() {
+                o.thread_finished(vm, thread.thread_id, thread.running_target_id);
+            }
+            for clone_id in deleted_clones.iter() {
+                o.clone_deleted(vm, *clone_id);
+            }
+            o.stop_all(vm);
+        });
     }
     /// Clear all clones and threads, and then fire the flag event
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = startFlag))]
     pub fn start_flag(&mut self) {
-        self.running_targets.retain(|_, a| !a.is_clone);
+        self.stop_all();
+        self.runtime.reset_timer();
+        self.runtime.edge_hat_values.clear();
         self.start_opcode("event_whenflagclicked")
     }
     
@@ -297,22 +504,11 @@ impl VirtualMachine {
     pub(crate) fn run_until_idle(&mut self, max_time: std::time::Duration) -> Option<std::time::Duration> {
         let time = std::time::Instant::now();
         while !self.is_idle() && time.elapsed() < max_time {
-            self.global_data.remove("MIN_WAIT_TIME");
-            self.global_data.remove("WAITING_THREADS");
+            self.runtime.clear_wait();
             self.step();
-            if let Some(waiting_threads) = self
-                .global_data
-                .get("WAITING_THREADS")
-                .and_then(|x| x.downcast_ref::<usize>())
-            {
-                if *waiting_threads >= self.threads.len() {
-                    if let Some(min_wait_time) = self
-                        .global_data
-                        .get("MIN_WAIT_TIME")
-                        .and_then(|x| x.downcast_ref::<std::time::Duration>())
-                    {
-                        return Some(*min_wait_time);
-                    }
+            if self.runtime.waiting_threads >= self.threads.len() {
+                if let Some(min_wait_time) = self.runtime.min_wait_time {
+                    return Some(min_wait_time);
                 }
             }
         }
@@ -340,13 +536,10 @@ impl VirtualMachine {
             for (i, stack) in thread.stacks.iter().enumerate() {
                 println!("  Stack {}: {}", i, stack.uid);
                 let block = t.blocks.get(stack.block_id).unwrap();
-                #[cfg(debug_assertions)]
                 println!(
                     "    Block {} {}: {:?}",
                     block.block_id, block.opcode, stack.block_id
                 );
-                #[cfg(not(debug_assertions))]
-                println!("    Block {}: {:?}", block.opcode, stack.block_id);
                 for arg in &stack.arguments {
                     println!("      Arg: {:?}", arg);
                 }
@@ -355,10 +548,27 @@ impl VirtualMachine {
         println!("VMThreadStacks End");
     }
     pub fn step(&mut self) {
+        if self.is_paused() {
+            return;
+        }
+        self.runtime.step_time = match self.runtime.fixed_step {
+            Some(step) => self.runtime.step_time + step,
+            None => std::time::Instant::now(),
+        };
+        let was_idle = self.threads.is_empty();
+        let running_threads = if self.observers.is_empty() {
+            Vec::new()
+        } else {
+            self.threads
+                .iter()
+                .map(|t| (t.thread_id, t.running_target_id))
+                .collect()
+        };
+        let mut deleted_clones = Vec::new();
         let mut should_stop_everything = false;
         let mut clone_list: Vec<generational_arena::Index> = Vec::with_capacity(16);
-        let mut boardcast_list: Vec<(usize, String)> = Vec::with_capacity(16);
-        let mut boardcast_wait_list: Vec<(usize, String)> = Vec::with_capacity(16);
+        // The thread, the message and whether the thread waits for it
+        let mut boardcast_list: Vec<(usize, String, bool)> = Vec::with_capacity(16);
         let mut boardcast_finished_list: Vec<usize> = Vec::with_capacity(16);
         self.threads_removed.clear();
         // Clean waiting threads
@@ -393,27 +603,102 @@ impl VirtualMachine {
                  thread_id,
                  awaiting_thread,
                  stacks,
+                 top_block,
+                 bytecode,
              }| {
                 if should_stop_everything {
                     return false;
                 }
-                if !awaiting_thread.is_empty() {
+                if !awaiting_thread.is_empty() || self.debugger.is_holding(*thread_id) {
                     return true;
                 }
                 let target_id = self.running_targets.get(*tid).unwrap().target_id;
+                if let Some(bytecode) = bytecode {
+                    let empty_program = bytecode::CompiledProgram::default();
+                    let mut machine = bytecode::Machine {
+                        runtime: &mut self.runtime,
+                        targets: &mut self.targets,
+                        running_targets: &mut self.running_targets,
+                        program: self.bytecode.as_ref().unwrap_or(&empty_program),
+                        stage_id: self.stage_id,
+                        running_stage_id: self.running_stage_id,
+                        target_id,
+                        running_target_id: *tid,
+                        broadcasts: Vec::new(),
+                        clones: Vec::new(),
+                    };
+                    let exit = bytecode.run(&mut machine);
+                    boardcast_list.extend(
+                        machine
+                            .broadcasts
+                            .into_iter()
+                            .map(|(name, wait)| (*thread_id, name, wait)),
+                    );
+                    clone_list.extend(
+                        machine
+                            .clones
+                            .iter()
+                            .filter_map(|x| clone_source(&self.running_targets, *tid, x)),
+                    );
+                    return match exit {
+                        bytecode::Exit::Yield => true,
+                        bytecode::Exit::Finished => false,
+                        bytecode::Exit::StopAll => {
+                            should_stop_everything = true;
+                            false
+                        }
+                        bytecode::Exit::DeleteClone => {
+                            if self.running_targets[*tid].is_clone {
+                                self.running_targets.remove(*tid);
+                                deleted_clones.push(*tid);
+                            }
+                            false
+                        }
+                    };
+                }
 
-                let only_one_stack = stacks.len() == 1;
+                let mut warp_timer = None;
+                // Like scratch-vm, a thread runs until a block is pending, which includes the end of a
+                // loop iteration, or until it calls a procedure it is already in. Keep running
+                // the thread in warp mode, unless it has been running for too long
+                macro_rules! yield_thread {
+                    () => {
+                        if core_blocks::is_warp(stacks)
+                            && warp_timer
+                                .get_or_insert_with(std::time::Instant::now)
+                                .elapsed()
+                                < WARP_TIME
+                        {
+                            continue;
+                        } else {
+                            return true;
+                        }
+                    };
+                }
                 loop {
+                    let only_one_stack = stacks.len() == 1;
+                    let warp = core_blocks::is_warp(stacks);
                     let block_result = if let Some(stack) = stacks.last_mut() {
                         let block_function = stack.block_function;
                         if !self.running_targets.contains(*tid) {
                             return false;
                         }
+                        if self.debugger.should_pause(*thread_id, stack) {
+                            return true;
+                        }
 
+                        let block_id = stack.block_id;
+                        let trace = self
+                            .tracer
+                            .as_ref()
+                            .map(|_| (std::time::Instant::now(), stack.arguments.to_owned()));
+                        let profile_start =
+                            self.profiler.as_ref().map(|_| std::time::Instant::now());
                         let block_result = {
                             let mut ctx = BlockContext {
                                 stack,
-                                global_data: &mut self.global_data,
+                                warp,
+                                runtime: &mut self.runtime,
                                 targets: &mut self.targets,
                                 running_targets: &mut self.running_targets,
                                 target_id,
@@ -423,6 +708,40 @@ impl VirtualMachine {
                             };
                             (block_function)(&mut ctx)
                         };
+                        if let (Some(tracer), Some((start, arguments))) = (&mut self.tracer, trace) {
+                            if block_result.is_finished() {
+                                if let (Some(rt), Some(block)) = (
+                                    self.running_targets.get(*tid),
+                                    self.targets[target_id].blocks.get(block_id),
+                                ) {
+                                    tracer.trace(
+                                        *thread_id,
+                                        &rt.name,
+                                        block_id,
+                                        block,
+                                        &arguments,
+                                        &block_result,
+                                        start.elapsed(),
+                                    );
+                                }
+                            }
+                        }
+                        if let (Some(profiler), Some(start)) = (&mut self.profiler, profile_start) {
+                            let elapsed = start.elapsed();
+                            if let Some(block) = self.targets[target_id].blocks.get(block_id) {
+                                profiler.record(
+                                    target_id,
+                                    *top_block,
+                                    stacks,
+                                    block,
+                                    &block_result,
+                                    elapsed,
+                                );
+                            }
+                        }
+                        if let Some(coverage) = &mut self.coverage {
+                            coverage.record(target_id, block_id, &block_result);
+                        }
                         if self.targets.get(target_id).is_some() {
                             Some(block_result)
                         } else {
@@ -435,7 +754,7 @@ impl VirtualMachine {
                         {
                             if let Some(target) = self.targets.get_mut(target_id) {
                                 match block_result {
-                                    BlockResult::Pending => return true,
+                                    BlockResult::Pending => yield_thread!(),
                                     BlockResult::ResolveArgument(index) => {
                                         if let Some(stack) = stacks.last_mut() {
                                             if let Some(block) = target.blocks.get(stack.block_id) {
@@ -453,7 +772,7 @@ impl VirtualMachine {
                                                                     arguments: vec![],
                                                                     block_data: Box::new(()),
                                                                 });
-                                                                return true;
+                                                                continue;
                                                             } else {
                                                                 stack
                                                                     .arguments
@@ -474,6 +793,7 @@ impl VirtualMachine {
                                     }
                                     BlockResult::PushStack(bid) => {
                                         let block = target.blocks.get(bid).unwrap();
+                                        let recursive = core_blocks::is_recursive_call(stacks);
                                         stacks.push(Stack {
                                             uid: crate::uid::uid(),
                                             block_id: bid,
@@ -481,7 +801,9 @@ impl VirtualMachine {
                                             arguments: Vec::with_capacity(block.arguments.len()),
                                             block_data: Box::new(()),
                                         });
-                                        return true;
+                                        if recursive {
+                                            yield_thread!();
+                                        }
                                     }
                                     BlockResult::Resolved(result) => {
                                         if let Some(stack) = stacks.last_mut() {
@@ -493,7 +815,7 @@ impl VirtualMachine {
                                                 stack.block_data = Box::new(());
                                                 stack.arguments.clear();
                                                 stack.block_function = block.block_function;
-                                                return true;
+                                                continue;
                                             } else {
                                                 // Pop stack and push the result to the previous stack arguments
                                                 if only_one_stack {
@@ -504,7 +826,7 @@ impl VirtualMachine {
                                                     prev_stack
                                                         .arguments
                                                         .push(result.unwrap_or_default());
-                                                    return true;
+                                                    continue;
                                                 }
                                             }
                                         } else {
@@ -522,26 +844,16 @@ impl VirtualMachine {
                                         }
                                         TargetType::OtherScriptsInSprite => {
                                             // TODO
-                                            return true;
+                                            continue;
                                         }
                                     },
                                     BlockResult::CreateClone(clone_option) => {
                                         if let Some(stack) = stacks.last_mut() {
-                                            match clone_option.as_str() {
-                                                "_myself_" => {
-                                                    clone_list.push(*tid);
-                                                }
-                                                other => {
-                                                    if let Some(tid) =
-                                                        self.running_targets.iter().find(|a| {
-                                                            !a.1.is_clone
-                                                                && a.1.name.as_str() == other
-                                                        })
-                                                    {
-                                                        clone_list.push(tid.0);
-                                                    }
-                                                }
-                                            }
+                                            clone_list.extend(clone_source(
+                                                &self.running_targets,
+                                                *tid,
+                                                &clone_option,
+                                            ));
                                             // Same as Resolved
                                             let block = target.blocks.get(stack.block_id).unwrap();
                                             if let Some(next) = block.next {
@@ -551,7 +863,7 @@ impl VirtualMachine {
                                                 stack.block_data = Box::new(());
                                                 stack.arguments.clear();
                                                 stack.block_function = block.block_function;
-                                                return true;
+                                                continue;
                                             } else {
                                                 // Pop stack and push the result to the previous stack arguments
                                                 if only_one_stack {
@@ -562,7 +874,7 @@ impl VirtualMachine {
                                                     prev_stack
                                                         .arguments
                                                         .push(BlockValue::Undefined);
-                                                    return true;
+                                                    continue;
                                                 }
                                             }
                                         }
@@ -570,36 +882,15 @@ impl VirtualMachine {
                                     BlockResult::DeleteThisClone => {
                                         if self.running_targets[*tid].is_clone {
                                             self.running_targets.remove(*tid);
+                                            deleted_clones.push(*tid);
                                         }
                                         return false;
                                     }
-                                    BlockResult::ResolveProcedureArgument(i) => {
-                                        let arg = if let Some(s) =
-                                            stacks.iter().rev().find(|s| {
-                                                self.targets[target_id].blocks[s.block_id].opcode
-                                                    == "procedures_call"
-                                                    && (
-                                                        s.block_data.is::<core_blocks::ProcedureArguments>() ||
-                                                        s.block_data.is::<core_blocks::ReturnProcedureArguments>()
-                                                    )
-                                            }) {
-                                            if let Some(v) = s
-                                                .block_data
-                                                .downcast_ref::<core_blocks::ProcedureArguments>()
-                                                .and_then(|x| x.0.get(i))
-                                            {
-                                                match v {
-                                                    BlockValue::BlockId(_) => {
-                                                        BlockValue::Number(0.0)
-                                                    }
-                                                    v => v.to_owned(),
-                                                }
-                                            } else {
-                                                BlockValue::Number(0.0)
-                                            }
-                                        } else {
-                                            BlockValue::Number(0.0)
-                                        };
+                                    BlockResult::ResolveProcedureArgument(name) => {
+                                        let arg = core_blocks::current_frame(stacks)
+                                            .and_then(|frame| frame.argument(&name))
+                                            .cloned()
+                                            .unwrap_or_default();
                                         if let Some(stack) = stacks.last_mut() {
                                             stack.arguments.push(arg);
                                         } else {
@@ -607,7 +898,7 @@ impl VirtualMachine {
                                         }
                                     }
                                     BlockResult::Boardcast(name) => {
-                                        boardcast_list.push((*thread_id, name));
+                                        boardcast_list.push((*thread_id, name, false));
                                         if let Some(stack) = stacks.last_mut() {
                                             let block = target.blocks.get(stack.block_id).unwrap();
                                             if let Some(next) = block.next {
@@ -617,7 +908,7 @@ impl VirtualMachine {
                                                 stack.block_data = Box::new(());
                                                 stack.arguments.clear();
                                                 stack.block_function = block.block_function;
-                                                return true;
+                                                continue;
                                             } else {
                                                 // Pop stack and push the result to the previous stack arguments
                                                 if only_one_stack {
@@ -628,7 +919,7 @@ impl VirtualMachine {
                                                     prev_stack
                                                         .arguments
                                                         .push(BlockValue::Undefined);
-                                                    return true;
+                                                    continue;
                                                 }
                                             }
                                         } else {
@@ -636,7 +927,7 @@ impl VirtualMachine {
                                         }
                                     }
                                     BlockResult::BoardcastAndWait(name) => {
-                                        boardcast_wait_list.push((*thread_id, name));
+                                        boardcast_list.push((*thread_id, name, true));
                                         if let Some(stack) = stacks.last_mut() {
                                             let block = target.blocks.get(stack.block_id).unwrap();
                                             if let Some(next) = block.next {
@@ -664,36 +955,17 @@ impl VirtualMachine {
                                             return false;
                                         }
                                     }
-                                    BlockResult::ReturnProcedure(_result) => {
-                                        let arg = if let Some(s) =
-                                            stacks.iter().rev().find(|s| {
-                                                self.targets[target_id].blocks[s.block_id].opcode
-                                                    == "procedures_call"
-                                                    && (
-                                                        s.block_data.is::<core_blocks::ProcedureArguments>() ||
-                                                        s.block_data.is::<core_blocks::ReturnProcedureArguments>()
-                                                    )
-                                            }) {
-                                            if let Some(v) = s
-                                                .block_data
-                                                .downcast_ref::<core_blocks::ReturnProcedureArguments>()
-                                                .and_then(|x| x.0.get(0))
-                                            {
-                                                match v {
-                                                    BlockValue::BlockId(_) => {
-                                                        BlockValue::Number(0.0)
-                                                    }
-                                                    v => v.to_owned(),
-                                                }
-                                            } else {
-                                                BlockValue::Number(0.0)
-                                            }
-                                        } else {
-                                            BlockValue::Number(0.0)
-                                        };
-                                        if let Some(stack) = stacks.last_mut() {
-                                            stack.arguments.push(arg);
+                                    BlockResult::ReturnProcedure(value) => {
+                                        if let Some(i) = stacks.iter().rposition(|s| {
+                                            s.block_data.is::<core_blocks::ProcedureFrame>()
+                                        }) {
+                                            // Unwind to the call block, which reports the value
+                                            stacks.truncate(i + 1);
+                                            stacks[i].arguments.push(value);
+                                            continue;
                                         } else {
+                                            // Not inside a procedure, stop the script
+                                            self.threads_removed.push(*thread_id);
                                             return false;
                                         }
                                     }
@@ -708,9 +980,7 @@ impl VirtualMachine {
                 }
             },
         );
-        if should_stop_everything {
-            self.threads.clear();
-        } else if !self.threads_removed.is_empty() {
+        if !self.threads_removed.is_empty() {
             for i in self.threads_removed.drain(..) {
                 for (x, t) in self.threads.iter().enumerate() {
                     if t.thread_id == i {
@@ -720,40 +990,30 @@ impl VirtualMachine {
                 }
             }
         }
-        for (_tid, name) in boardcast_list {
-            self.start_topblock_if("event_whenbroadcastreceived", |b| {
-                b.arguments
-                    .first()
-                    .and_then(|x| {
-                        if x.to_string().as_str() == name {
-                            Some(())
-                        } else {
-                            None
-                        }
-                    })
-                    .is_some()
-            })
+        self.notify(|o, vm| {
+            for (thread_id, running_target_id) in running_threads.iter() {
+                if !vm.threads.iter().any(|t| t.thread_id == *thread_id) {
+                    o.thread_finished(vm, *thread_id, *running_target_id);
+                }
+            }
+            for clone_id in deleted_clones.iter() {
+                o.clone_deleted(vm, *clone_id);
+            }
+        });
+        if should_stop_everything {
+            // Scratch starts hats and clones right away, so stop all also stops them
+            boardcast_list.clear();
+            clone_list.clear();
+            self.stop_all();
         }
-        for (tid, name) in boardcast_wait_list {
-            let before_len = self.threads.len();
-            self.start_topblock_if("event_whenbroadcastreceived", |b| {
-                b.arguments
-                    .first()
-                    .and_then(|x| {
-                        if x.to_string().as_str() == name {
-                            Some(())
-                        } else {
-                            None
-                        }
-                    })
-                    .is_some()
-            });
-            let current_len = self.threads.len();
-            let awaiting_thread = (before_len..current_len)
-                .map(|x| self.threads[x].thread_id)
-                .collect();
-            if let Some(thread) = self.threads.iter_mut().find(|t| t.thread_id == tid) {
-                thread.awaiting_thread = awaiting_thread;
+        // Messages that threads wait for go last, so they wait for the restarted threads
+        boardcast_list.sort_by_key(|(_, _, wait)| *wait);
+        for (tid, name, wait) in boardcast_list {
+            let started = self.start_broadcast(&name);
+            if wait {
+                if let Some(thread) = self.threads.iter_mut().find(|t| t.thread_id == tid) {
+                    thread.awaiting_thread = started;
+                }
             }
         }
         for rtid in clone_list.iter() {
@@ -763,8 +1023,29 @@ impl VirtualMachine {
                 new_clone.layer_order = self.running_targets.len();
                 let new_rtid = self.running_targets.insert(new_clone);
                 self.new_clones.push(new_rtid);
+                self.notify(|o, vm| o.clone_created(vm, new_rtid));
                 self.start_opcode_of_running_target("control_start_as_clone", true, new_rtid);
             }
         }
+        self.start_edge_activated_hats();
+        if !was_idle && self.threads.is_empty() {
+            self.notify(|o, vm| o.idle(vm));
+        }
+    }
+}
+
+/// The running target that `create clone of` with `clone_option` clones when a thread of
+/// `running_target_id` runs it: the target itself for `_myself_`, otherwise the sprite with that name.
+fn clone_source(
+    running_targets: &generational_arena::Arena<RunningTarget>,
+    running_target_id: RunningTargetId,
+    clone_option: &str,
+) -> Option<RunningTargetId> {
+    match clone_option {
+        "_myself_" => Some(running_target_id),
+        name => running_targets
+            .iter()
+            .find(|(_, rt)| !rt.is_clone && rt.name == name)
+            .map(|(rtid, _)| rtid),
     }
 }
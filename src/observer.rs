@@ -0,0 +1,52 @@
+use crate::*;
+
+/// Receives thread and clone events from a `VirtualMachine`.
+///
+/// Every method does nothing by default. They are called after the change,
+/// so `vm` is already in the new state.
+pub trait VmObserver: Send {
+    /// A script started, or restarted with a new thread id.
+    fn thread_started(&mut self, _vm: &VirtualMachine, _thread_id: usize) {}
+    /// A script finished or was stopped. The thread is no longer in `vm.threads`.
+    fn thread_finished(
+        &mut self,
+        _vm: &VirtualMachine,
+        _thread_id: usize,
+        _running_target_id: RunningTargetId,
+    ) {
+    }
+    fn clone_created(&mut self, _vm: &VirtualMachine, _clone_id: RunningTargetId) {}
+    /// A clone was deleted. It is no longer in `vm.running_targets`.
+    fn clone_deleted(&mut self, _vm: &VirtualMachine, _clone_id: RunningTargetId) {}
+    /// A message was broadcast, starting the threads in `started_threads`.
+    fn broadcast(&mut self, _vm: &VirtualMachine, _name: &str, _started_threads: &[usize]) {}
+    /// Every thread was stopped and every clone deleted, by `VirtualMachine::stop_all`,
+    /// a `stop all` block or the green flag.
+    fn stop_all(&mut self, _vm: &VirtualMachine) {}
+    /// The last running thread finished.
+    fn idle(&mut self, _vm: &VirtualMachine) {}
+}
+
+impl std::fmt::Debug for dyn VmObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VmObserver")
+    }
+}
+
+impl VirtualMachine {
+    /// Register an observer that is notified of thread and clone events.
+    pub fn add_observer(&mut self, observer: impl VmObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub(crate) fn notify(&mut self, mut f: impl FnMut(&mut dyn VmObserver, &VirtualMachine)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in observers.iter_mut() {
+            f(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+}
@@ -0,0 +1,204 @@
+use crate::blocks::ArgType;
+use crate::*;
+
+/// The type of value an input always has, as far as it can be known before running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Number,
+    Boolean,
+    String,
+    /// Depends on the state of the project, like variables.
+    Any,
+}
+
+/// Result of `VirtualMachine::optimize`.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeReport {
+    /// Number of pure blocks replaced by their value.
+    pub folded: usize,
+    /// Number of string inputs stored as numbers, see `input_type` and `infer_type`.
+    pub numeric_literals: usize,
+}
+
+/// The value a string literal can be replaced with without changing how any block sees it.
+///
+/// The number must print back to the same string, and have the same truthiness.
+pub fn numeric_literal(value: &str) -> Option<f64> {
+    if value.starts_with('-') || value.starts_with('+') {
+        return None;
+    }
+    let number = value.parse::<f64>().ok()?;
+    if number.to_string() == value && (number == 0. || number >= f64::EPSILON) {
+        Some(number)
+    } else {
+        None
+    }
+}
+
+/// The type of the value reported by a block with `opcode`.
+pub fn output_type(opcode: &str) -> ValueType {
+    match opcode {
+        "operator_add" | "operator_subtract" | "operator_multiply" | "operator_divide"
+        | "operator_random" | "operator_length" | "operator_mod" | "operator_round"
+        | "operator_mathop" => ValueType::Number,
+        "operator_lt" | "operator_equals" | "operator_gt" | "operator_and" | "operator_or"
+        | "operator_not" | "operator_contains" => ValueType::Boolean,
+        "operator_join" | "operator_letter_of" => ValueType::String,
+        _ => ValueType::Any,
+    }
+}
+
+/// Infer the type of an argument of a block of `target`.
+pub fn infer_type(target: &Target, value: &BlockValue) -> ValueType {
+    match value {
+        BlockValue::Number(_) => ValueType::Number,
+        BlockValue::Boolean(_) => ValueType::Boolean,
+        BlockValue::String(v) if numeric_literal(v).is_some() => ValueType::Number,
+        BlockValue::String(_) => ValueType::String,
+        BlockValue::BlockId(bid) => target
+            .blocks
+            .get(*bid)
+            .map(|b| output_type(&b.opcode))
+            .unwrap_or(ValueType::Any),
+        BlockValue::Undefined => ValueType::Any,
+    }
+}
+
+/// The type a block with `opcode` converts its inputs to before using them.
+pub fn input_type(opcode: &str) -> ValueType {
+    match opcode {
+        "operator_add" | "operator_subtract" | "operator_multiply" | "operator_divide"
+        | "operator_random" | "operator_mod" | "operator_round" | "operator_mathop" => {
+            ValueType::Number
+        }
+        "operator_and" | "operator_or" | "operator_not" => ValueType::Boolean,
+        "operator_join" | "operator_letter_of" | "operator_length" | "operator_contains" => {
+            ValueType::String
+        }
+        // Comparisons look at both inputs to choose between numbers and strings
+        _ => ValueType::Any,
+    }
+}
+
+fn is_comparison(opcode: &str) -> bool {
+    matches!(opcode, "operator_lt" | "operator_equals" | "operator_gt")
+}
+
+impl VirtualMachine {
+    /// Optimize the loaded scripts. Call it after loading a project, and before `compile_bytecode`.
+    ///
+    /// Pure blocks whose inputs are constants, like `(1) + (2)`, are replaced by their value.
+    /// Numeric string inputs of blocks that always read them as numbers, like arithmetic or
+    /// comparisons against a value inferred to be a number, are stored as numbers so
+    /// `to_number` and comparisons do not parse them.
+    pub fn optimize(&mut self) -> OptimizeReport {
+        let mut report = OptimizeReport::default();
+        let target_ids = self.targets.iter().map(|(tid, _)| tid).collect::<Vec<_>>();
+        for target_id in target_ids {
+            // Pure blocks never read the running target, but evaluating them needs one
+            let running_target_id = self
+                .running_targets
+                .iter()
+                .find(|(_, rt)| rt.target_id == target_id && !rt.is_clone)
+                .map(|(rtid, _)| rtid);
+            if let Some(running_target_id) = running_target_id {
+                report.folded += self.fold_constants(target_id, running_target_id);
+            }
+            report.numeric_literals += self.store_numeric_literals(target_id);
+        }
+        report
+    }
+
+    fn fold_constants(&mut self, target_id: TargetId, running_target_id: RunningTargetId) -> usize {
+        let mut folded = 0;
+        loop {
+            let target = &self.targets[target_id];
+            let is_constant = |bid: BlockId| {
+                target
+                    .blocks
+                    .get(bid)
+                    .map(|b| {
+                        self.registry.is_pure(&b.opcode)
+                            && !b.arguments.iter().any(|a| a.is_block())
+                    })
+                    .unwrap_or(false)
+            };
+            let foldable = target
+                .blocks
+                .iter()
+                .flat_map(|(parent, block)| {
+                    block
+                        .arguments
+                        .iter()
+                        .enumerate()
+                        .filter_map(move |(i, a)| match a {
+                            BlockValue::BlockId(bid) => Some((parent, i, *bid)),
+                            _ => None,
+                        })
+                })
+                .filter(|(_, _, bid)| is_constant(*bid))
+                .collect::<Vec<_>>();
+            if foldable.is_empty() {
+                return folded;
+            }
+            let mut changed = false;
+            for (parent, index, bid) in foldable {
+                if let Some(value) = self.evaluate_block(running_target_id, bid) {
+                    self.targets[target_id].blocks[parent].arguments[index] = value;
+                    folded += 1;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return folded;
+            }
+        }
+    }
+
+    fn store_numeric_literals(&mut self, target_id: TargetId) -> usize {
+        let target = &self.targets[target_id];
+        let mut literals = Vec::new();
+        for (bid, block) in target.blocks.iter() {
+            let info = match self.registry.get(&block.opcode) {
+                Some(info) if info.arguments.len() == block.arguments.len() => info,
+                // Procedure blocks lay out their arguments differently
+                _ => continue,
+            };
+            let inputs = info
+                .arguments
+                .iter()
+                .zip(block.arguments.iter())
+                .enumerate()
+                .filter(|(_, ((arg_type, _), _))| matches!(arg_type, ArgType::Input(_)))
+                .map(|(i, (_, value))| (i, value))
+                .collect::<Vec<_>>();
+            for &(index, value) in &inputs {
+                let number = match value {
+                    BlockValue::String(v) => match numeric_literal(v) {
+                        Some(number) => number,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                // A number compares like a numeric string only against another number,
+                // `1 = "true"` is true but `"1" = "true"` is not.
+                let numeric = if is_comparison(&block.opcode) {
+                    inputs
+                        .iter()
+                        .filter(|(i, _)| *i != index)
+                        .all(|(_, other)| infer_type(target, other) == ValueType::Number)
+                } else {
+                    input_type(&block.opcode) == ValueType::Number
+                };
+                if numeric {
+                    literals.push((bid, index, number));
+                }
+            }
+        }
+        let count = literals.len();
+        for (bid, index, number) in literals {
+            self.targets[target_id].blocks[bid].arguments[index] = BlockValue::Number(number);
+        }
+        count
+    }
+}
@@ -0,0 +1,152 @@
+use crate::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+/// Number of calls and total time spent in block functions of an opcode, script or procedure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub calls: u64,
+    pub time: Duration,
+}
+
+/// Where a block ran: the target, the top block of the script, the procedures being called and the block.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ProfileStack {
+    target_id: TargetId,
+    script: BlockId,
+    procedures: Vec<BlockId>,
+    block: BlockId,
+}
+
+/// Accumulates the time spent in each block function call.
+///
+/// An opcode is called once each time a block finishes, a script each time a thread
+/// starts it and a procedure each time a call block enters it. The time of an opcode
+/// is only spent in its own blocks, reporters and procedures they run are recorded
+/// under their own opcodes. The time of a script or procedure is inclusive: it adds up
+/// every block it runs, including reporters and the procedures it calls.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub opcodes: HashMap<String, ProfileEntry>,
+    /// Keyed by the target and the top block of the script.
+    pub scripts: HashMap<(TargetId, BlockId), ProfileEntry>,
+    /// Keyed by the target and the definition block of the procedure.
+    pub procedures: HashMap<(TargetId, BlockId), ProfileEntry>,
+    stacks: HashMap<ProfileStack, Duration>,
+}
+
+impl Profiler {
+    pub(crate) fn record(
+        &mut self,
+        target_id: TargetId,
+        top_block: BlockId,
+        stacks: &[Stack],
+        block: &Block,
+        result: &BlockResult,
+        elapsed: Duration,
+    ) {
+        let finished = result.is_finished();
+        if !self.opcodes.contains_key(&block.opcode) {
+            self.opcodes
+                .insert(block.opcode.to_owned(), ProfileEntry::default());
+        }
+        let opcode = self.opcodes.get_mut(&block.opcode).unwrap();
+        opcode.time += elapsed;
+        if finished {
+            opcode.calls += 1;
+        }
+        let script = self.scripts.entry((target_id, top_block)).or_default();
+        script.time += elapsed;
+        if block.self_id == top_block && finished {
+            script.calls += 1;
+        }
+        // The frame of the running stack belongs to the procedure it calls,
+        // the call block itself runs in its caller
+        let procedures = stacks[..stacks.len().saturating_sub(1)]
+            .iter()
+            .filter_map(|s| s.block_data.downcast_ref::<core_blocks::ProcedureFrame>())
+            .map(|frame| frame.definition)
+            .collect::<Vec<_>>();
+        if let BlockResult::PushStack(bid) = result {
+            if block.opcode == "procedures_call" || block.opcode == "procedures_call_return" {
+                self.procedures.entry((target_id, *bid)).or_default().calls += 1;
+            }
+        }
+        let mut counted = Vec::with_capacity(procedures.len());
+        for definition in procedures.iter() {
+            // Count recursive calls once
+            if !counted.contains(definition) {
+                counted.push(*definition);
+                self.procedures
+                    .entry((target_id, *definition))
+                    .or_default()
+                    .time += elapsed;
+            }
+        }
+        *self
+            .stacks
+            .entry(ProfileStack {
+                target_id,
+                script: top_block,
+                procedures,
+                block: block.self_id,
+            })
+            .or_default() += elapsed;
+    }
+
+    /// Write the profile in the folded stack format of flamegraph tools, with times in nanoseconds.
+    ///
+    /// Each line looks like `Sprite1;event_whenflagclicked#3;my block %s;motion_movesteps 1200`.
+    pub fn write_folded(&self, vm: &VirtualMachine, mut w: impl Write) -> std::io::Result<()> {
+        let mut folded: BTreeMap<String, u128> = BTreeMap::new();
+        for (stack, time) in self.stacks.iter() {
+            let target = match vm.targets.get(stack.target_id) {
+                Some(target) => target,
+                None => continue,
+            };
+            let mut frames = vec![target.name.to_owned(), script_name(target, stack.script)];
+            frames.extend(
+                stack
+                    .procedures
+                    .iter()
+                    .map(|bid| procedure_name(target, *bid)),
+            );
+            frames.push(
+                target
+                    .blocks
+                    .get(stack.block)
+                    .map(|b| b.opcode.to_owned())
+                    .unwrap_or_default(),
+            );
+            let line = frames
+                .iter()
+                .map(|x| x.replace(';', ":"))
+                .collect::<Vec<_>>()
+                .join(";");
+            *folded.entry(line).or_default() += time.as_nanos();
+        }
+        for (line, time) in folded {
+            writeln!(w, "{} {}", line, time)?;
+        }
+        Ok(())
+    }
+}
+
+/// A name for a script, like `event_whenflagclicked#3`.
+pub fn script_name(target: &Target, top_block: BlockId) -> String {
+    match target.blocks.get(top_block) {
+        Some(block) => format!("{}#{}", block.opcode, top_block.index()),
+        None => format!("#{}", top_block.index()),
+    }
+}
+
+/// The proccode of a procedure, like `move %s steps`.
+pub fn procedure_name(target: &Target, definition: BlockId) -> String {
+    target
+        .blocks
+        .get(definition)
+        .and_then(|b| b.arguments.get(1))
+        .map(|x| x.to_string())
+        .unwrap_or_default()
+}
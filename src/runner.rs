@@ -0,0 +1,421 @@
+//! Runs projects without a stage, for command-line players and batch grading.
+
+use crate::*;
+use json::JsonValue;
+use rand::SeedableRng;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Input sent to a project while it runs.
+#[derive(Debug, Clone)]
+pub enum InputEvent {
+    /// Press a key, like `space` or `a`.
+    KeyPress(String),
+    /// Broadcast a message by name.
+    Broadcast(String),
+}
+
+/// When an input event is sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Before this step.
+    Step(usize),
+    /// Before the first step after this much time has passed.
+    Time(Duration),
+}
+
+impl Trigger {
+    fn is_due(&self, steps: usize, elapsed: Duration) -> bool {
+        match self {
+            Self::Step(step) => *step <= steps,
+            Self::Time(time) => *time <= elapsed,
+        }
+    }
+}
+
+/// Input of a headless run.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedInput {
+    /// Answers to `ask and wait` blocks, in order.
+    pub answers: Vec<String>,
+    /// Events and when they are sent.
+    pub events: Vec<(Trigger, InputEvent)>,
+}
+
+impl ScriptedInput {
+    /// Parse input like `{"answers": ["Alice"], "events": [{"step": 10, "key": "space"}, {"time": 1.5, "broadcast": "start"}]}`.
+    /// `time` is in seconds.
+    pub fn from_json(value: &JsonValue) -> Result<Self, String> {
+        let mut input = Self::default();
+        for answer in value["answers"].members() {
+            match answer.as_str() {
+                Some(answer) => input.answers.push(answer.to_owned()),
+                None => input.answers.push(answer.dump()),
+            }
+        }
+        for event in value["events"].members() {
+            let trigger = match event["time"].as_f64() {
+                Some(secs) if secs >= 0. => Trigger::Time(Duration::from_secs_f64(secs)),
+                Some(_) => return Err(format!("negative time: {}", event.dump())),
+                None => Trigger::Step(event["step"].as_usize().unwrap_or(0)),
+            };
+            let event = if let Some(key) = event["key"].as_str() {
+                InputEvent::KeyPress(key.to_owned())
+            } else if let Some(message) = event["broadcast"].as_str() {
+                InputEvent::Broadcast(message.to_owned())
+            } else {
+                return Err(format!("unknown input event: {}", event.dump()));
+            };
+            input.events.push((trigger, event));
+        }
+        Ok(input)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Stop after running this long.
+    pub time_limit: Option<Duration>,
+    /// Stop after this many calls of `VirtualMachine::step`. In a step every running
+    /// thread runs until it yields, so it is not a frame of a player with a stage.
+    pub step_limit: Option<usize>,
+    /// Seed of the random number generator, for repeatable runs.
+    pub seed: Option<u64>,
+    /// Advance the clock of the project by this much per step instead of reading the system
+    /// clock, see `RuntimeState::fixed_step`. Runs that wait are repeatable and do not sleep.
+    pub fixed_step: Option<Duration>,
+    pub input: ScriptedInput,
+    /// Optimize and compile the project to bytecode before running it.
+    /// Ignored with `coverage`, since folding constants removes blocks from the scripts.
+    pub compile: bool,
+    /// Collect block coverage into `RunResult::coverage`.
+    pub coverage: bool,
+}
+
+/// Why a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunStatus {
+    /// All scripts finished.
+    Finished,
+    TimeLimit,
+    StepLimit,
+    /// The project could not be loaded.
+    LoadError(String),
+    /// The VM panicked while loading or running the project, with the panic message.
+    Panic(String),
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Finished => "finished",
+            Self::TimeLimit => "time_limit",
+            Self::StepLimit => "step_limit",
+            Self::LoadError(_) => "load_error",
+            Self::Panic(_) => "panic",
+        }
+    }
+}
+
+/// Position of a sprite at the end of a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpritePosition {
+    pub x: f64,
+    pub y: f64,
+    pub direction: f64,
+}
+
+/// Result of a headless run.
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub status: RunStatus,
+    /// Calls of `VirtualMachine::step`.
+    pub steps: usize,
+    pub elapsed: Duration,
+    /// Everything the sprites said or thought, in order.
+    pub speech: Vec<Speech>,
+    /// Final values of variables, keyed by target name and then variable name.
+    pub variables: BTreeMap<String, BTreeMap<String, BlockValue>>,
+    /// Final values of lists, keyed by target name and then list name.
+    pub lists: BTreeMap<String, BTreeMap<String, Vec<BlockValue>>>,
+    /// Final positions of sprites, keyed by sprite name. Clones are left out.
+    pub sprites: BTreeMap<String, SpritePosition>,
+    /// Executed and unexecuted blocks, if `RunOptions::coverage` is set.
+    pub coverage: Option<CoverageReport>,
+    /// What was missing from the project when it was loaded, see `LoadReport::warnings`.
+    pub warnings: Vec<String>,
+}
+
+impl RunResult {
+    fn new(status: RunStatus) -> Self {
+        Self {
+            status,
+            steps: 0,
+            elapsed: Duration::ZERO,
+            speech: Vec::new(),
+            variables: BTreeMap::new(),
+            lists: BTreeMap::new(),
+            sprites: BTreeMap::new(),
+            coverage: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The result as a JSON object with the fields `status`, `error`, `steps`,
+    /// `elapsed` (in seconds), `say`, `variables`, `lists`, `sprites` and `warnings`, and
+    /// `coverage` if it was collected.
+    pub fn to_json(&self) -> JsonValue {
+        let mut variables = JsonValue::new_object();
+        for (target, values) in &self.variables {
+            let mut object = JsonValue::new_object();
+            for (name, value) in values {
+                object[name.as_str()] = block_value_to_json(value);
+            }
+            variables[target.as_str()] = object;
+        }
+        let mut lists = JsonValue::new_object();
+        for (target, values) in &self.lists {
+            let mut object = JsonValue::new_object();
+            for (name, list) in values {
+                object[name.as_str()] = list
+                    .iter()
+                    .map(block_value_to_json)
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            lists[target.as_str()] = object;
+        }
+        let mut sprites = JsonValue::new_object();
+        for (name, position) in &self.sprites {
+            sprites[name.as_str()] = json::object! {
+                "x": position.x,
+                "y": position.y,
+                "direction": position.direction,
+            };
+        }
+        let error = match &self.status {
+            RunStatus::LoadError(e) | RunStatus::Panic(e) => e.as_str().into(),
+            _ => JsonValue::Null,
+        };
+        let mut value = json::object! {
+            "status": self.status.as_str(),
+            "error": error,
+            "steps": self.steps,
+            "elapsed": self.elapsed.as_secs_f64(),
+            "say": self
+                .speech
+                .iter()
+                .map(|s| json::object! { "target": s.target.as_str(), "text": s.text.as_str() })
+                .collect::<Vec<_>>(),
+            "variables": variables,
+            "lists": lists,
+            "sprites": sprites,
+            "warnings": self.warnings.clone(),
+        };
+        if let Some(coverage) = &self.coverage {
+            value["coverage"] = coverage.to_json();
+        }
+        value
+    }
+}
+
+/// Click the green flag of a loaded project and run it until all scripts finish or it hits a limit.
+pub fn run_vm(vm: &mut VirtualMachine, options: &RunOptions) -> RunResult {
+    if let Some(seed) = options.seed {
+        vm.runtime.rng = rand::rngs::SmallRng::seed_from_u64(seed);
+    }
+    vm.runtime.fixed_step = options.fixed_step;
+    if options.coverage {
+        vm.coverage = Some(Coverage::default());
+    } else if options.compile {
+        vm.optimize();
+        vm.compile_bytecode();
+    }
+    vm.runtime.extensions.insert(SpeechLog::default());
+    vm.runtime
+        .extensions
+        .insert(Answers(options.input.answers.iter().cloned().collect()));
+    let mut events = options.input.events.iter().collect::<Vec<_>>();
+    let mut steps = 0;
+    let started = Instant::now();
+    vm.start_flag();
+    let status = loop {
+        let elapsed = started.elapsed();
+        events.retain(|(trigger, event)| {
+            if !trigger.is_due(steps, elapsed) {
+                return true;
+            }
+            match event {
+                InputEvent::KeyPress(key) => vm.press_key(key),
+                InputEvent::Broadcast(message) => vm.broadcast(message),
+            };
+            false
+        });
+        if vm.is_idle() && events.is_empty() {
+            break RunStatus::Finished;
+        }
+        if options
+            .step_limit
+            .map(|limit| steps >= limit)
+            .unwrap_or(false)
+        {
+            break RunStatus::StepLimit;
+        }
+        let remaining = options
+            .time_limit
+            .map(|limit| limit.saturating_sub(started.elapsed()));
+        if remaining == Some(Duration::ZERO) {
+            break RunStatus::TimeLimit;
+        }
+        // Nothing runs until the next event, sleep if it is sent at a time and not a step
+        if vm.is_idle() {
+            if let Some(next) = next_time(&events) {
+                let wait = next.saturating_sub(started.elapsed());
+                std::thread::sleep(remaining.map(|r| r.min(wait)).unwrap_or(wait));
+                continue;
+            }
+        }
+        vm.runtime.clear_wait();
+        vm.step();
+        steps += 1;
+        // Sleep while every thread waits for a timer
+        let waiting = vm.runtime.waiting_threads >= vm.threads.len();
+        if !vm.is_idle() && waiting && options.fixed_step.is_none() {
+            if let Some(wait) = vm.runtime.min_wait_time {
+                std::thread::sleep(remaining.map(|r| r.min(wait)).unwrap_or(wait));
+            }
+        }
+    };
+    let mut result = RunResult::new(status);
+    result.steps = steps;
+    result.elapsed = started.elapsed();
+    result.speech = vm
+        .runtime
+        .extensions
+        .remove::<SpeechLog>()
+        .map(|log| log.0)
+        .unwrap_or_default();
+    vm.runtime.extensions.remove::<Answers>();
+    result.coverage = vm.coverage.take().map(|coverage| coverage.report(vm));
+    for (id, rt) in vm.running_targets.iter().filter(|(_, rt)| !rt.is_clone) {
+        // Blocks keep variables by id, results show their names
+        let target = &vm.targets[rt.target_id];
+        let variables = rt
+            .variables
+            .iter()
+            .map(|(id, value)| (target.variable_name(id).to_owned(), value.to_owned()))
+            .collect();
+        let lists = rt
+            .lists
+            .iter()
+            .map(|(id, list)| (target.list_name(id).to_owned(), list.to_owned()))
+            .collect();
+        result.variables.insert(rt.name.to_owned(), variables);
+        result.lists.insert(rt.name.to_owned(), lists);
+        if id != vm.running_stage_id {
+            result.sprites.insert(
+                rt.name.to_owned(),
+                SpritePosition {
+                    x: rt.x,
+                    y: rt.y,
+                    direction: rt.direction,
+                },
+            );
+        }
+    }
+    result
+}
+
+/// The time of the first of `events`, or `None` if some are sent at a step.
+fn next_time(events: &[&(Trigger, InputEvent)]) -> Option<Duration> {
+    events
+        .iter()
+        .map(|(trigger, _)| match trigger {
+            Trigger::Time(time) => Some(*time),
+            Trigger::Step(_) => None,
+        })
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .min()
+}
+
+/// Load an sb3 or sb2 file, or an extracted project directory, and run it with `run_vm`.
+pub fn run_file(path: &Path, options: &RunOptions) -> RunResult {
+    run_file_with_cache(path, options, None)
+}
+
+fn run_file_with_cache(
+    path: &Path,
+    options: &RunOptions,
+    asset_cache: Option<Arc<AssetCache>>,
+) -> RunResult {
+    let mut vm = VirtualMachine {
+        asset_cache,
+        ..Default::default()
+    };
+    match sb3_loader::load_path(&mut vm, path) {
+        Ok(report) => RunResult {
+            warnings: report.warnings(),
+            ..run_vm(&mut vm, options)
+        },
+        Err(e) => RunResult::new(RunStatus::LoadError(e.to_string())),
+    }
+}
+
+/// Run sb3 files on `jobs` threads. The results are in the order of `paths`.
+/// Copies of the same costumes and sounds are loaded once.
+pub fn run_batch(paths: &[PathBuf], options: &RunOptions, jobs: usize) -> Vec<RunResult> {
+    let asset_cache = Arc::new(AssetCache::default());
+    parallel_map(paths, jobs, |path| {
+        run_file_with_cache(path, options, Some(asset_cache.clone()))
+    })
+    .into_iter()
+    .map(|result| result.unwrap_or_else(|e| RunResult::new(RunStatus::Panic(e))))
+    .collect()
+}
+
+/// Call `f` on every item on `jobs` threads. The results are in the order of `items`.
+///
+/// A panic in `f` only ends its own item, which gets the panic message as the error.
+/// This needs `panic = "unwind"`, which the release profile does not have, see `Cargo.toml`.
+pub(crate) fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<Result<R, String>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+                // Each job has its own VM, nothing shared is left half changed
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(item)))
+                    .map_err(|e| panic_message(&*e));
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.to_owned()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
@@ -0,0 +1,170 @@
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use crate::{BlockId, RunningTargetId};
+
+/// VM-wide state shared by all threads and blocks.
+#[derive(Debug)]
+pub struct RuntimeState {
+    /// Value of the ClipCC counter blocks.
+    pub counter: usize,
+    /// Start time of the `sensing_timer` block.
+    pub timer: Instant,
+    /// When the current step started. Like scratch-vm, the timer is read at this time,
+    /// so it does not change while a step runs. Blocks that wait measure time with it too.
+    pub step_time: Instant,
+    /// Advance `step_time` by this much each step instead of reading the system clock.
+    /// Projects that wait then run the same way every time, and take the same steps.
+    pub fixed_step: Option<Duration>,
+    /// How many times the host has redrawn the stage.
+    /// `None` if the host never reported a redraw, which means blocks never wait for one.
+    pub stage_frame: Option<usize>,
+    /// Whether a block changed the stage since the last redraw, which makes loops wait for the next frame.
+    pub redraw_requested: bool,
+    /// Shortest remaining time of the waiting blocks in the current step.
+    pub min_wait_time: Option<Duration>,
+    /// How many threads are waiting on a timer in the current step.
+    pub waiting_threads: usize,
+    /// The last answer of `sensing_askandwait`.
+    pub answer: String,
+    /// Whether a thread is currently asking a question.
+    pub asking: bool,
+    /// Last predicate value of each edge-activated hat, for each running target.
+    pub edge_hat_values: HashMap<(RunningTargetId, BlockId), bool>,
+    /// Type-keyed state for extensions and hosts.
+    pub extensions: Extensions,
+    /// Random numbers of this VM. Seed it to make `pick random` repeatable.
+    pub rng: SmallRng,
+}
+
+impl Default for RuntimeState {
+    fn default() -> Self {
+        Self {
+            counter: 0,
+            timer: Instant::now(),
+            step_time: Instant::now(),
+            fixed_step: None,
+            stage_frame: None,
+            redraw_requested: false,
+            min_wait_time: None,
+            waiting_threads: 0,
+            answer: String::new(),
+            asking: false,
+            edge_hat_values: HashMap::new(),
+            extensions: Extensions::default(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+}
+
+impl RuntimeState {
+    /// Seconds since the timer was reset, as of the current step.
+    pub fn timer_value(&self) -> f64 {
+        self.step_time
+            .saturating_duration_since(self.timer)
+            .as_secs_f64()
+    }
+
+    /// Time since `since` as of the current step, for blocks that wait.
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.step_time.saturating_duration_since(since)
+    }
+
+    /// Reset the timer to zero for the rest of the current step.
+    pub fn reset_timer(&mut self) {
+        self.timer = self.step_time;
+    }
+
+    /// Record a thread that is waiting for `wait_time`, so the host can sleep until it wakes up.
+    pub fn report_wait(&mut self, wait_time: Duration) {
+        match &mut self.min_wait_time {
+            Some(wt) if *wt <= wait_time => {}
+            wt => *wt = Some(wait_time),
+        }
+        self.waiting_threads += 1;
+    }
+
+    /// Clear the wait bookkeeping before running a step.
+    pub fn clear_wait(&mut self) {
+        self.min_wait_time = None;
+        self.waiting_threads = 0;
+    }
+}
+
+/// A message shown by `say` or `think`.
+#[derive(Debug, Clone)]
+pub struct Speech {
+    /// Name of the sprite that said it.
+    pub target: String,
+    pub text: String,
+}
+
+/// Insert it into `RuntimeState::extensions` to record speech instead of printing it.
+#[derive(Debug, Clone, Default)]
+pub struct SpeechLog(pub Vec<Speech>);
+
+/// Insert it into `RuntimeState::extensions` to answer `ask and wait` from it instead of stdin.
+/// Questions asked after the last answer get an empty answer.
+#[derive(Debug, Clone, Default)]
+pub struct Answers(pub VecDeque<String>);
+
+/// A map that stores at most one value of each type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Insert a value, returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|x| x.downcast().ok())
+            .map(|x| *x)
+    }
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|x| x.downcast_ref())
+    }
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|x| x.downcast_mut())
+    }
+    /// Get the value of type `T`, inserting `T::default()` if there is none.
+    pub fn get_or_default<T: Any + Send + Sync + Default>(&mut self) -> &mut T {
+        self.map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut()
+            .unwrap()
+    }
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|x| x.downcast().ok())
+            .map(|x| *x)
+    }
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
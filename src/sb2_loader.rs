@@ -0,0 +1,718 @@
+//! Loads Scratch 2.0 projects.
+//!
+//! A Scratch 2 `project.json` is converted to a Scratch 3 one, which is then loaded with
+//! `sb3_loader::load_project`. Scripts are nested arrays like `["doRepeat", 10, [["forward:", 10]]]`,
+//! and each legacy opcode is mapped to its Scratch 3 opcode and inputs, like scratch-vm does.
+//! Variables, lists and broadcasts get their name as id. Costumes and sounds keep their md5,
+//! see `Sb2AssetStore` for the names they have in a sb2 archive.
+
+use crate::*;
+use json::JsonValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// An argument of a Scratch 2 block, in the order they appear after the opcode.
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    Input(&'static str),
+    /// An input with a menu: the opcode of the shadow block and its field.
+    Menu(&'static str, &'static str, &'static str),
+    Field(&'static str),
+    Variable(&'static str),
+    List(&'static str),
+    /// The message of a broadcast block.
+    Broadcast(&'static str),
+    /// The message of a `when I receive` hat.
+    BroadcastField(&'static str),
+    Substack(&'static str),
+    /// A field with a value that Scratch 2 kept in the opcode. It takes no argument.
+    ConstField(&'static str, &'static str),
+    /// An input with a value that Scratch 2 kept in the opcode. It takes no argument.
+    ConstInput(&'static str, &'static str),
+}
+
+use Arg::*;
+
+/// The Scratch 3 opcode and arguments of a Scratch 2 opcode.
+fn spec(opcode: &str) -> Option<(&'static str, &'static [Arg])> {
+    Some(match opcode {
+        // Motion
+        "forward:" => ("motion_movesteps", &[Input("STEPS")]),
+        "turnRight:" => ("motion_turnright", &[Input("DEGREES")]),
+        "turnLeft:" => ("motion_turnleft", &[Input("DEGREES")]),
+        "heading:" => ("motion_pointindirection", &[Input("DIRECTION")]),
+        "pointTowards:" => (
+            "motion_pointtowards",
+            &[Menu("TOWARDS", "motion_pointtowards_menu", "TOWARDS")],
+        ),
+        "gotoX:y:" => ("motion_gotoxy", &[Input("X"), Input("Y")]),
+        "gotoSpriteOrMouse:" => ("motion_goto", &[Menu("TO", "motion_goto_menu", "TO")]),
+        "glideSecs:toX:y:elapsed:from:" => (
+            "motion_glidesecstoxy",
+            &[Input("SECS"), Input("X"), Input("Y")],
+        ),
+        "changeXposBy:" => ("motion_changexby", &[Input("DX")]),
+        "xpos:" => ("motion_setx", &[Input("X")]),
+        "changeYposBy:" => ("motion_changeyby", &[Input("DY")]),
+        "ypos:" => ("motion_sety", &[Input("Y")]),
+        "bounceOffEdge" => ("motion_ifonedgebounce", &[]),
+        "setRotationStyle" => ("motion_setrotationstyle", &[Field("STYLE")]),
+        "xpos" => ("motion_xposition", &[]),
+        "ypos" => ("motion_yposition", &[]),
+        "heading" => ("motion_direction", &[]),
+        "scrollRight" => ("motion_scroll_right", &[Input("DISTANCE")]),
+        "scrollUp" => ("motion_scroll_up", &[Input("DISTANCE")]),
+        "scrollAlign" => ("motion_align_scene", &[Field("ALIGNMENT")]),
+        "xScroll" => ("motion_xscroll", &[]),
+        "yScroll" => ("motion_yscroll", &[]),
+        // Looks
+        "say:duration:elapsed:from:" => ("looks_sayforsecs", &[Input("MESSAGE"), Input("SECS")]),
+        "say:" => ("looks_say", &[Input("MESSAGE")]),
+        "think:duration:elapsed:from:" => {
+            ("looks_thinkforsecs", &[Input("MESSAGE"), Input("SECS")])
+        }
+        "think:" => ("looks_think", &[Input("MESSAGE")]),
+        "show" => ("looks_show", &[]),
+        "hide" => ("looks_hide", &[]),
+        "hideAll" => ("looks_hideallsprites", &[]),
+        "lookLike:" => (
+            "looks_switchcostumeto",
+            &[Menu("COSTUME", "looks_costume", "COSTUME")],
+        ),
+        "nextCostume" => ("looks_nextcostume", &[]),
+        "startScene" => (
+            "looks_switchbackdropto",
+            &[Menu("BACKDROP", "looks_backdrops", "BACKDROP")],
+        ),
+        "changeSizeBy:" => ("looks_changesizeby", &[Input("CHANGE")]),
+        "setSizeTo:" => ("looks_setsizeto", &[Input("SIZE")]),
+        "changeStretchBy:" => ("looks_changestretchby", &[Input("CHANGE")]),
+        "setStretchTo:" => ("looks_setstretchto", &[Input("STRETCH")]),
+        "comeToFront" => ("looks_gotofrontback", &[ConstField("FRONT_BACK", "front")]),
+        "goBackByLayers:" => (
+            "looks_goforwardbackwardlayers",
+            &[ConstField("FORWARD_BACKWARD", "backward"), Input("NUM")],
+        ),
+        "costumeIndex" => (
+            "looks_costumenumbername",
+            &[ConstInput("NUMBER_NAME", "number")],
+        ),
+        "sceneName" => (
+            "looks_backdropnumbername",
+            &[ConstInput("NUMBER_NAME", "name")],
+        ),
+        "backgroundIndex" => (
+            "looks_backdropnumbername",
+            &[ConstInput("NUMBER_NAME", "number")],
+        ),
+        "scale" => ("looks_size", &[]),
+        // Events
+        "whenGreenFlag" => ("event_whenflagclicked", &[]),
+        "whenKeyPressed" => ("event_whenkeypressed", &[Field("KEY_OPTION")]),
+        "whenClicked" => ("event_whenthisspriteclicked", &[]),
+        "whenSceneStarts" => ("event_whenbackdropswitchesto", &[Field("BACKDROP")]),
+        "whenSensorGreaterThan" => (
+            "event_whengreaterthan",
+            &[Field("WHENGREATERTHANMENU"), Input("VALUE")],
+        ),
+        "whenIReceive" => (
+            "event_whenbroadcastreceived",
+            &[BroadcastField("BROADCAST_OPTION")],
+        ),
+        "broadcast:" => ("event_broadcast", &[Broadcast("BROADCAST_INPUT")]),
+        "doBroadcastAndWait" => ("event_broadcastandwait", &[Broadcast("BROADCAST_INPUT")]),
+        // Control
+        "wait:elapsed:from:" => ("control_wait", &[Input("DURATION")]),
+        "doRepeat" => ("control_repeat", &[Input("TIMES"), Substack("SUBSTACK")]),
+        "doForever" => ("control_forever", &[Substack("SUBSTACK")]),
+        "doIf" => ("control_if", &[Input("CONDITION"), Substack("SUBSTACK")]),
+        "doIfElse" => (
+            "control_if_else",
+            &[
+                Input("CONDITION"),
+                Substack("SUBSTACK"),
+                Substack("SUBSTACK2"),
+            ],
+        ),
+        "doWaitUntil" => ("control_wait_until", &[Input("CONDITION")]),
+        "doUntil" => (
+            "control_repeat_until",
+            &[Input("CONDITION"), Substack("SUBSTACK")],
+        ),
+        "doWhile" => ("control_while", &[Input("CONDITION"), Substack("SUBSTACK")]),
+        "doForLoop" => (
+            "control_for_each",
+            &[Variable("VARIABLE"), Input("VALUE"), Substack("SUBSTACK")],
+        ),
+        "stopScripts" => ("control_stop", &[Field("STOP_OPTION")]),
+        "whenCloned" => ("control_start_as_clone", &[]),
+        "createCloneOf" => (
+            "control_create_clone_of",
+            &[Menu(
+                "CLONE_OPTION",
+                "control_create_clone_of_menu",
+                "CLONE_OPTION",
+            )],
+        ),
+        "deleteClone" => ("control_delete_this_clone", &[]),
+        "COUNT" => ("control_get_counter", &[]),
+        "INCR_COUNT" => ("control_incr_counter", &[]),
+        "CLR_COUNT" => ("control_clear_counter", &[]),
+        "warpSpeed" => ("control_all_at_once", &[Substack("SUBSTACK")]),
+        // Sensing
+        "touching:" => (
+            "sensing_touchingobject",
+            &[Menu(
+                "TOUCHINGOBJECTMENU",
+                "sensing_touchingobjectmenu",
+                "TOUCHINGOBJECTMENU",
+            )],
+        ),
+        "distanceTo:" => (
+            "sensing_distanceto",
+            &[Menu(
+                "DISTANCETOMENU",
+                "sensing_distancetomenu",
+                "DISTANCETOMENU",
+            )],
+        ),
+        "doAsk" => ("sensing_askandwait", &[Input("QUESTION")]),
+        "answer" => ("sensing_answer", &[]),
+        "keyPressed:" => (
+            "sensing_keypressed",
+            &[Menu("KEY_OPTION", "sensing_keyoptions", "KEY_OPTION")],
+        ),
+        "mousePressed" => ("sensing_mousedown", &[]),
+        "mouseX" => ("sensing_mousex", &[]),
+        "mouseY" => ("sensing_mousey", &[]),
+        "timer" => ("sensing_timer", &[]),
+        "timerReset" => ("sensing_resettimer", &[]),
+        "getAttribute:of:" => (
+            "sensing_of",
+            &[
+                Field("PROPERTY"),
+                Menu("OBJECT", "sensing_of_object_menu", "OBJECT"),
+            ],
+        ),
+        "timeAndDate" => ("sensing_current", &[Field("CURRENTMENU")]),
+        "timestamp" => ("sensing_dayssince2000", &[]),
+        "getUserName" => ("sensing_username", &[]),
+        // Operators
+        "+" => ("operator_add", &[Input("NUM1"), Input("NUM2")]),
+        "-" => ("operator_subtract", &[Input("NUM1"), Input("NUM2")]),
+        "*" => ("operator_multiply", &[Input("NUM1"), Input("NUM2")]),
+        "/" => ("operator_divide", &[Input("NUM1"), Input("NUM2")]),
+        "randomFrom:to:" => ("operator_random", &[Input("FROM"), Input("TO")]),
+        "<" => ("operator_lt", &[Input("OPERAND1"), Input("OPERAND2")]),
+        "=" => ("operator_equals", &[Input("OPERAND1"), Input("OPERAND2")]),
+        ">" => ("operator_gt", &[Input("OPERAND1"), Input("OPERAND2")]),
+        "&" => ("operator_and", &[Input("OPERAND1"), Input("OPERAND2")]),
+        "|" => ("operator_or", &[Input("OPERAND1"), Input("OPERAND2")]),
+        "not" => ("operator_not", &[Input("OPERAND")]),
+        "concatenate:with:" => ("operator_join", &[Input("STRING1"), Input("STRING2")]),
+        "letter:of:" => ("operator_letter_of", &[Input("LETTER"), Input("STRING")]),
+        "stringLength:" => ("operator_length", &[Input("STRING")]),
+        "%" => ("operator_mod", &[Input("NUM1"), Input("NUM2")]),
+        "rounded" => ("operator_round", &[Input("NUM")]),
+        "computeFunction:of:" => ("operator_mathop", &[Field("OPERATOR"), Input("NUM")]),
+        // Data
+        "setVar:to:" => (
+            "data_setvariableto",
+            &[Variable("VARIABLE"), Input("VALUE")],
+        ),
+        "changeVar:by:" => (
+            "data_changevariableby",
+            &[Variable("VARIABLE"), Input("VALUE")],
+        ),
+        "showVariable:" => ("data_showvariable", &[Variable("VARIABLE")]),
+        "hideVariable:" => ("data_hidevariable", &[Variable("VARIABLE")]),
+        "append:toList:" => ("data_addtolist", &[Input("ITEM"), List("LIST")]),
+        "deleteLine:ofList:" => ("data_deleteoflist", &[Input("INDEX"), List("LIST")]),
+        "insert:at:ofList:" => (
+            "data_insertatlist",
+            &[Input("ITEM"), Input("INDEX"), List("LIST")],
+        ),
+        "setLine:ofList:to:" => (
+            "data_replaceitemoflist",
+            &[Input("INDEX"), List("LIST"), Input("ITEM")],
+        ),
+        "getLine:ofList:" => ("data_itemoflist", &[Input("INDEX"), List("LIST")]),
+        "lineCountOfList:" => ("data_lengthoflist", &[List("LIST")]),
+        "list:contains:" => ("data_listcontainsitem", &[List("LIST"), Input("ITEM")]),
+        "showList:" => ("data_showlist", &[List("LIST")]),
+        "hideList:" => ("data_hidelist", &[List("LIST")]),
+        _ => return None,
+    })
+}
+
+/// Whether `project` is a Scratch 2 `project.json`, which has the stage at its root.
+pub fn is_sb2_project(project: &JsonValue) -> bool {
+    project["objName"].is_string() && !project["targets"].is_array()
+}
+
+/// Convert a Scratch 2 `project.json` to a Scratch 3 one.
+pub fn convert_project(project: &JsonValue) -> Result<JsonValue, LoadError> {
+    if !is_sb2_project(project) {
+        return Err(LoadError::InvalidProject(
+            "not a Scratch 2 project".to_owned(),
+        ));
+    }
+    let mut broadcasts = Vec::new();
+    let mut stage = convert_target(project, true, &mut broadcasts);
+    let mut targets = vec![];
+    // Children are in layer order, from back to front. Watchers have no `objName`.
+    for (index, child) in project["children"]
+        .members()
+        .filter(|c| c["objName"].is_string())
+        .enumerate()
+    {
+        let mut target = convert_target(child, false, &mut broadcasts);
+        target["layerOrder"] = (index + 1).into();
+        targets.push(target);
+    }
+    for name in broadcasts {
+        stage["broadcasts"][name.as_str()] = name.as_str().into();
+    }
+    targets.insert(0, stage);
+    Ok(json::object! {
+        "targets": targets,
+        "extensions": JsonValue::new_array(),
+        "monitors": JsonValue::new_array(),
+        "meta": json::object! { "semver": "3.0.0" },
+    })
+}
+
+/// Convert a Scratch 2 `project.json` and load it into `vm`.
+pub fn load_project(
+    vm: &mut VirtualMachine,
+    project: &JsonValue,
+) -> std::result::Result<LoadReport, LoadError> {
+    sb3_loader::load_project(vm, &convert_project(project)?)
+}
+
+/// Scratch 3 loads values that are not a string, a number or a boolean as strings.
+fn convert_value(value: &JsonValue) -> JsonValue {
+    if value.is_string() || value.is_number() || value.is_boolean() {
+        value.clone()
+    } else if value.is_null() {
+        "".into()
+    } else {
+        value.dump().into()
+    }
+}
+
+fn convert_target(object: &JsonValue, is_stage: bool, broadcasts: &mut Vec<String>) -> JsonValue {
+    let mut variables = JsonValue::new_object();
+    for variable in object["variables"].members() {
+        let name = variable["name"].as_str().unwrap_or_default();
+        variables[name] = json::array![name, convert_value(&variable["value"])];
+    }
+    let mut lists = JsonValue::new_object();
+    for list in object["lists"].members() {
+        let name = list["listName"].as_str().unwrap_or_default();
+        let contents = list["contents"]
+            .members()
+            .map(convert_value)
+            .collect::<Vec<_>>();
+        lists[name] = json::array![name, contents];
+    }
+    let mut converter = Converter {
+        blocks: JsonValue::new_object(),
+        next_id: 0,
+        broadcasts,
+    };
+    for script in object["scripts"].members() {
+        let (x, y) = (script[0].as_f64(), script[1].as_f64());
+        if let Some(id) = converter.convert_stack(&script[2], None) {
+            let top = &mut converter.blocks[id.as_str()];
+            top["topLevel"] = true.into();
+            top["x"] = x.unwrap_or(0.).into();
+            top["y"] = y.unwrap_or(0.).into();
+        }
+    }
+    let rotation_style = match object["rotationStyle"].as_str() {
+        Some("leftRight") => "left-right",
+        Some("none") => "don't rotate",
+        _ => "all around",
+    };
+    let name = if is_stage {
+        "Stage"
+    } else {
+        object["objName"].as_str().unwrap_or_default()
+    };
+    json::object! {
+        "isStage": is_stage,
+        "name": name,
+        "variables": variables,
+        "lists": lists,
+        "broadcasts": JsonValue::new_object(),
+        "blocks": converter.blocks,
+        "costumes": object["costumes"].members().map(convert_costume).collect::<Vec<_>>(),
+        "sounds": object["sounds"].members().map(convert_sound).collect::<Vec<_>>(),
+        "currentCostume": object["currentCostumeIndex"].as_usize().unwrap_or(0),
+        "layerOrder": 0,
+        "visible": object["visible"].as_bool().unwrap_or(true),
+        "x": object["scratchX"].as_f64().unwrap_or(0.),
+        "y": object["scratchY"].as_f64().unwrap_or(0.),
+        "size": object["scale"].as_f64().unwrap_or(1.) * 100.,
+        "direction": object["direction"].as_f64().unwrap_or(90.),
+        "rotationStyle": rotation_style,
+    }
+}
+
+/// A Scratch 3 costume with the md5 of the Scratch 2 one as `md5ext`, like scratch-vm.
+fn convert_costume(costume: &JsonValue) -> JsonValue {
+    let mut converted = json::object! {
+        "name": costume["costumeName"].as_str().unwrap_or_default(),
+        "bitmapResolution": costume["bitmapResolution"].as_f64().unwrap_or(1.),
+        "rotationCenterX": costume["rotationCenterX"].as_f64().unwrap_or(0.),
+        "rotationCenterY": costume["rotationCenterY"].as_f64().unwrap_or(0.),
+    };
+    add_md5ext(&mut converted, &costume["baseLayerMD5"]);
+    converted
+}
+
+/// A Scratch 3 sound with the md5 of the Scratch 2 one as `md5ext`, like scratch-vm.
+fn convert_sound(sound: &JsonValue) -> JsonValue {
+    let mut converted = json::object! {
+        "name": sound["soundName"].as_str().unwrap_or_default(),
+        "rate": sound["rate"].as_f64().unwrap_or(0.),
+        "sampleCount": sound["sampleCount"].as_f64().unwrap_or(0.),
+    };
+    add_md5ext(&mut converted, &sound["md5"]);
+    converted
+}
+
+fn add_md5ext(asset: &mut JsonValue, md5: &JsonValue) {
+    if let Some((asset_id, data_format)) = md5.as_str().and_then(|x| x.split_once('.')) {
+        asset["assetId"] = asset_id.into();
+        asset["dataFormat"] = data_format.into();
+        asset["md5ext"] = md5.clone();
+    }
+}
+
+/// The costumes and sounds of a sb2 archive, which names them by their id in the project,
+/// like `0.png`, where sb3 uses the md5ext. Assets are looked up by md5ext first, so the
+/// asset server and extracted projects work too.
+pub struct Sb2AssetStore<S> {
+    store: S,
+    /// Files in the archive, keyed by md5ext.
+    files: HashMap<String, String>,
+}
+
+impl<S: AssetStore> Sb2AssetStore<S> {
+    /// Read the ids of the assets of the Scratch 2 `project.json` from `store`.
+    pub fn new(project: &JsonValue, store: S) -> Self {
+        let mut files = HashMap::new();
+        let objects = std::iter::once(project).chain(project["children"].members());
+        for object in objects.filter(|x| x["objName"].is_string()) {
+            let costumes = object["costumes"]
+                .members()
+                .map(|c| (&c["baseLayerMD5"], &c["baseLayerID"]));
+            let sounds = object["sounds"]
+                .members()
+                .map(|s| (&s["md5"], &s["soundID"]));
+            for (md5, id) in costumes.chain(sounds) {
+                // Assets that were never saved have the id -1
+                if let (Some(md5ext), Some(id)) = (md5.as_str(), id.as_u64()) {
+                    if let Some((_, ext)) = md5ext.split_once('.') {
+                        files.insert(md5ext.to_owned(), format!("{}.{}", id, ext));
+                    }
+                }
+            }
+        }
+        Self { store, files }
+    }
+}
+
+impl<S: AssetStore> AssetStore for Sb2AssetStore<S> {
+    fn load(&mut self, md5ext: &str) -> Option<Arc<[u8]>> {
+        if let Some(data) = self.store.load(md5ext) {
+            return Some(data);
+        }
+        self.store.load(self.files.get(md5ext)?)
+    }
+}
+
+/// Whether a Scratch 2 argument is a block, like `["xpos"]`, rather than a substack or a value.
+fn is_block(value: &JsonValue) -> bool {
+    value[0].is_string()
+}
+
+/// Scratch 2 proccodes use `%n` for number arguments, Scratch 3 only has `%s`.
+fn convert_proccode(proccode: &str) -> String {
+    proccode.replace("%n", "%s")
+}
+
+fn argument_ids(count: usize) -> String {
+    json::stringify(
+        (0..count)
+            .map(|i| format!("input{}", i))
+            .collect::<Vec<_>>(),
+    )
+}
+
+struct Converter<'a> {
+    /// Scratch 3 blocks keyed by id.
+    blocks: JsonValue,
+    next_id: usize,
+    broadcasts: &'a mut Vec<String>,
+}
+
+impl Converter<'_> {
+    fn new_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("sb2_{}", self.next_id)
+    }
+
+    fn add_broadcast(&mut self, name: &str) {
+        if !self.broadcasts.iter().any(|b| b == name) {
+            self.broadcasts.push(name.to_owned());
+        }
+    }
+
+    /// Convert a list of blocks, returning the id of the first one.
+    fn convert_stack(&mut self, stack: &JsonValue, parent: Option<&str>) -> Option<String> {
+        let mut first = None;
+        let mut prev: Option<String> = None;
+        for block in stack.members().filter(|b| is_block(b)) {
+            let id = self.convert_block(block, prev.as_deref().or(parent));
+            match &prev {
+                Some(prev) => self.blocks[prev.as_str()]["next"] = id.as_str().into(),
+                None => first = Some(id.clone()),
+            }
+            prev = Some(id);
+        }
+        first
+    }
+
+    fn new_block(&mut self, opcode: &str, parent: Option<&str>) -> (String, JsonValue) {
+        let block = json::object! {
+            "opcode": opcode,
+            "next": JsonValue::Null,
+            "parent": parent,
+            "inputs": JsonValue::new_object(),
+            "fields": JsonValue::new_object(),
+            "shadow": false,
+            "topLevel": false,
+        };
+        (self.new_id(), block)
+    }
+
+    /// An input holding a value or a block.
+    fn convert_input(&mut self, value: &JsonValue, parent: &str) -> Option<JsonValue> {
+        if is_block(value) {
+            match value[0].as_str() {
+                // Scratch 3 keeps variable and list reporters in the input
+                Some("readVariable") => {
+                    let name = convert_value(&value[1]).to_string();
+                    Some(json::array![
+                        3,
+                        json::array![12, name.as_str(), name.as_str()],
+                        json::array![10, ""]
+                    ])
+                }
+                Some("contentsOfList:") => {
+                    let name = convert_value(&value[1]).to_string();
+                    Some(json::array![
+                        3,
+                        json::array![13, name.as_str(), name.as_str()],
+                        json::array![10, ""]
+                    ])
+                }
+                _ => {
+                    let id = self.convert_block(value, Some(parent));
+                    Some(json::array![3, id, json::array![10, ""]])
+                }
+            }
+        } else if value.is_number() {
+            Some(json::array![1, json::array![4, value.clone()]])
+        } else if value.is_string() {
+            Some(json::array![1, json::array![10, value.clone()]])
+        } else {
+            // Empty boolean inputs are false or null
+            None
+        }
+    }
+
+    fn convert_block(&mut self, block: &JsonValue, parent: Option<&str>) -> String {
+        let opcode = block[0].as_str().unwrap_or_default();
+        let args = block.members().skip(1).collect::<Vec<_>>();
+        match opcode {
+            "procDef" => return self.convert_definition(block, parent),
+            "call" => return self.convert_call(block, parent),
+            _ => {}
+        }
+        let (id, mut json) = match opcode {
+            "readVariable" => {
+                let (id, mut json) = self.new_block("data_variable", parent);
+                let name = convert_value(&block[1]).to_string();
+                json["fields"]["VARIABLE"] = json::array![name.as_str(), name.as_str()];
+                (id, json)
+            }
+            "contentsOfList:" => {
+                let (id, mut json) = self.new_block("data_listcontents", parent);
+                let name = convert_value(&block[1]).to_string();
+                json["fields"]["LIST"] = json::array![name.as_str(), name.as_str()];
+                (id, json)
+            }
+            "getParam" => {
+                let opcode = if block[2].as_str() == Some("b") {
+                    "argument_reporter_boolean"
+                } else {
+                    "argument_reporter_string_number"
+                };
+                let (id, mut json) = self.new_block(opcode, parent);
+                json["fields"]["VALUE"] = json::array![convert_value(&block[1]), JsonValue::Null];
+                (id, json)
+            }
+            _ => match spec(opcode) {
+                Some((sb3_opcode, spec_args)) => {
+                    let (id, mut json) = self.new_block(sb3_opcode, parent);
+                    let mut args = args.iter();
+                    for spec_arg in spec_args {
+                        let value = match spec_arg {
+                            ConstField(name, value) => {
+                                json["fields"][*name] = json::array![*value, JsonValue::Null];
+                                continue;
+                            }
+                            ConstInput(name, value) => {
+                                json["inputs"][*name] = json::array![1, json::array![10, *value]];
+                                continue;
+                            }
+                            _ => match args.next() {
+                                Some(value) => *value,
+                                None => break,
+                            },
+                        };
+                        self.convert_argument(&id, &mut json, *spec_arg, value);
+                    }
+                    (id, json)
+                }
+                // Loaded as an unknown opcode
+                None => self.new_block(opcode, parent),
+            },
+        };
+        if opcode == "stopScripts" {
+            let has_next = block[1].as_str() == Some("other scripts in sprite")
+                || block[1].as_str() == Some("other scripts in stage");
+            json["mutation"] = json::object! {
+                "tagName": "mutation",
+                "children": JsonValue::new_array(),
+                "hasnext": has_next.to_string(),
+            };
+        }
+        self.blocks[id.as_str()] = json;
+        id
+    }
+
+    fn convert_argument(&mut self, id: &str, json: &mut JsonValue, arg: Arg, value: &JsonValue) {
+        match arg {
+            Input(name) => {
+                if let Some(input) = self.convert_input(value, id) {
+                    json["inputs"][name] = input;
+                }
+            }
+            Menu(name, shadow_opcode, field) => {
+                let (shadow_id, mut shadow) = self.new_block(shadow_opcode, Some(id));
+                shadow["shadow"] = true.into();
+                let menu_value = if is_block(value) {
+                    "".into()
+                } else if value.as_str() == Some("Stage")
+                    && shadow_opcode == "sensing_of_object_menu"
+                {
+                    // Scratch 3 names the stage `_stage_` in menus
+                    "_stage_".into()
+                } else {
+                    convert_value(value)
+                };
+                shadow["fields"][field] = json::array![menu_value, JsonValue::Null];
+                self.blocks[shadow_id.as_str()] = shadow;
+                json["inputs"][name] = if is_block(value) {
+                    let block_id = self.convert_block(value, Some(id));
+                    json::array![3, block_id, shadow_id]
+                } else {
+                    json::array![1, shadow_id]
+                };
+            }
+            Field(name) => {
+                let value = match (name, value.as_str()) {
+                    // Scratch 3 uses upper case sensor names
+                    ("WHENGREATERTHANMENU", Some(sensor)) => sensor.to_uppercase().into(),
+                    _ => convert_value(value),
+                };
+                json["fields"][name] = json::array![value, JsonValue::Null];
+            }
+            Variable(name) | List(name) => {
+                let value = convert_value(value).to_string();
+                json["fields"][name] = json::array![value.as_str(), value.as_str()];
+            }
+            Broadcast(name) => {
+                json["inputs"][name] = if is_block(value) {
+                    let block_id = self.convert_block(value, Some(id));
+                    json::array![3, block_id, json::array![11, "", ""]]
+                } else {
+                    let message = convert_value(value).to_string();
+                    self.add_broadcast(&message);
+                    json::array![1, json::array![11, message.as_str(), message.as_str()]]
+                };
+            }
+            BroadcastField(name) => {
+                let message = convert_value(value).to_string();
+                self.add_broadcast(&message);
+                json["fields"][name] = json::array![message.as_str(), message.as_str()];
+            }
+            Substack(name) => {
+                if let Some(first) = self.convert_stack(value, Some(id)) {
+                    json["inputs"][name] = json::array![2, first];
+                }
+            }
+            ConstField(..) | ConstInput(..) => unreachable!(),
+        }
+    }
+
+    /// `["procDef", proccode, argument names, argument defaults, warp]`
+    fn convert_definition(&mut self, block: &JsonValue, parent: Option<&str>) -> String {
+        let (id, mut definition) = self.new_block("procedures_definition", parent);
+        let (prototype_id, mut prototype) = self.new_block("procedures_prototype", Some(&id));
+        let names = block[2]
+            .members()
+            .map(|n| n.as_str().unwrap_or_default().to_owned())
+            .collect::<Vec<_>>();
+        let defaults = block[3].members().map(convert_value).collect::<Vec<_>>();
+        prototype["shadow"] = true.into();
+        prototype["mutation"] = json::object! {
+            "tagName": "mutation",
+            "children": JsonValue::new_array(),
+            "proccode": convert_proccode(block[1].as_str().unwrap_or_default()),
+            "argumentids": argument_ids(names.len()),
+            "argumentnames": json::stringify(names),
+            "argumentdefaults": json::stringify(defaults),
+            "warp": block[4].as_bool().unwrap_or(false).to_string(),
+        };
+        self.blocks[prototype_id.as_str()] = prototype;
+        definition["inputs"]["custom_block"] = json::array![1, prototype_id];
+        self.blocks[id.as_str()] = definition;
+        id
+    }
+
+    /// `["call", proccode, arguments...]`
+    fn convert_call(&mut self, block: &JsonValue, parent: Option<&str>) -> String {
+        let (id, mut call) = self.new_block("procedures_call", parent);
+        let args = block.members().skip(2).collect::<Vec<_>>();
+        call["mutation"] = json::object! {
+            "tagName": "mutation",
+            "children": JsonValue::new_array(),
+            "proccode": convert_proccode(block[1].as_str().unwrap_or_default()),
+            "argumentids": argument_ids(args.len()),
+            "warp": "false",
+        };
+        for (index, value) in args.into_iter().enumerate() {
+            if let Some(input) = self.convert_input(value, &id) {
+                call["inputs"][format!("input{}", index).as_str()] = input;
+            }
+        }
+        self.blocks[id.as_str()] = call;
+        id
+    }
+}
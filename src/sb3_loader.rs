@@ -1,4 +1,4 @@
-use crate::blocks::get_blockinfo;
+use crate::blocks::BlockRegistry;
 use crate::*;
 use json::*;
 use std::collections::HashSet;
@@ -6,11 +6,39 @@ use std::io::Read;
 use std::path::Path;
 use zip::*;
 
-fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
+/// Register the extensions listed in `project.json` from the providers of `vm`.
+///
+/// Nothing is registered if the project is refused for missing extensions.
+pub fn load_extensions(
+    vm: &mut VirtualMachine,
+    project: &JsonValue,
+) -> std::result::Result<LoadReport, LoadError> {
+    let mut report = LoadReport::default();
+    let mut providers = Vec::new();
+    for id in project["extensions"].members().filter_map(|x| x.as_str()) {
+        match vm.extension_providers.iter().find(|x| x.info().id == id) {
+            Some(provider) => providers.push(provider),
+            None => report.missing.push(id.to_owned()),
+        }
+    }
+    if !report.missing.is_empty() && vm.missing_extension_policy == MissingExtensionPolicy::Refuse {
+        return Err(LoadError::MissingExtensions(report.missing));
+    }
+    for provider in providers {
+        let info = provider.info();
+        provider
+            .register(&mut vm.registry)
+            .map_err(|e| LoadError::InvalidExtension(info.id.clone(), e))?;
+        report.loaded.push(info);
+    }
+    Ok(report)
 }
 
-pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
+pub fn load_project(
+    vm: &mut VirtualMachine,
+    project: &JsonValue,
+) -> std::result::Result<LoadReport, LoadError> {
+    let mut report = load_extensions(vm, project)?;
     let mut unknown_opcodes = HashSet::new();
     fn json_value_to_block_value(v: &JsonValue) -> BlockValue {
         if v.is_string() {
@@ -18,13 +46,16 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
         } else if v.is_number() {
             BlockValue::Number(v.as_f64().unwrap())
         } else if v.is_boolean() {
-            BlockValue::Boolean(v[1].as_bool().unwrap_or(false))
+            BlockValue::Boolean(v.as_bool().unwrap_or(false))
+        } else if v.is_null() {
+            BlockValue::Undefined
         } else {
-            dbg!(v);
-            unimplemented!();
+            // Some editors save lists or objects as variable values
+            BlockValue::String(v.dump())
         }
     }
     fn setup_target(
+        registry: &BlockRegistry,
         target: &mut Target,
         target_json: &JsonValue,
         unknown_opcodes: &mut HashSet<String>,
@@ -34,6 +65,7 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
         let mut procedures_block: ProcBlockMap = HashMap::with_capacity(32);
         fn register_procedure(
             procedures_block: &mut ProcBlockMap,
+            target_blocks: &mut id_arena::Arena<Block>,
             block_id: BlockId,
             blocks: &JsonValue,
             block: &JsonValue,
@@ -65,6 +97,10 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                         .filter_map(|v| v.as_str())
                         .map(|x| x.to_owned())
                         .collect::<Vec<String>>();
+                    // The definition block holds `warp`, the proccode and then the argument names
+                    let definition = &mut target_blocks[block_id].arguments;
+                    definition.push(proccode.as_str().into());
+                    definition.extend(argumentnames.iter().map(|x| BlockValue::from(x.as_str())));
                     procedures_block.insert(proccode, (block_id, argumentids, argumentnames));
                 }
             }
@@ -85,20 +121,34 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
             .members()
             .map(|v| v["name"].as_str().unwrap().to_owned())
             .collect::<Vec<String>>();
-        // variables
-        for (_vid, variable) in target_json["variables"].entries() {
+        target.costume_assets = target_json["costumes"]
+            .members()
+            .map(|v| crate::assets::md5ext(v).unwrap_or_default())
+            .collect();
+        target.sound_assets = target_json["sounds"]
+            .members()
+            .map(|v| crate::assets::md5ext(v).unwrap_or_default())
+            .collect();
+        // variables, keyed by id like the blocks refer to them
+        for (vid, variable) in target_json["variables"].entries() {
             let name = variable[0].as_str().unwrap().to_owned();
             let value: BlockValue = json_value_to_block_value(&variable[1]);
-            target.variables.insert(name, value);
+            target.variables.insert(vid.to_owned(), value);
+            target.variable_names.insert(vid.to_owned(), name);
         }
         // lists
-        for (_lid, list) in target_json["lists"].entries() {
+        for (lid, list) in target_json["lists"].entries() {
             let name = list[0].as_str().unwrap().to_owned();
             let mut value = Vec::with_capacity(list[1].len());
             for v in list[1].members() {
                 value.push(json_value_to_block_value(v));
             }
-            target.lists.insert(name, value);
+            target.lists.insert(lid.to_owned(), value);
+            target.list_names.insert(lid.to_owned(), name);
+        }
+        for (id, name) in target_json["broadcasts"].entries() {
+            let name = name.as_str().unwrap_or_default().to_owned();
+            target.broadcasts.insert(id.to_owned(), name);
         }
         fn parse_input_to_block_value(blocks: &mut Arena<Block>, input: &JsonValue) -> BlockValue {
             if input.is_string() {
@@ -139,9 +189,8 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                                                 arguments: vec![vid.into()],
                                                 block_function: crate::core_blocks::data_variable,
                                                 next: None,
-                                                opcode: "data_variable",
+                                                opcode: "data_variable".into(),
                                                 toplevel: false,
-                                                #[cfg(debug_assertions)]
                                                 block_id: "[Auto Generated]".into(),
                                             });
                                             BlockValue::BlockId(bid)
@@ -154,9 +203,8 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                                                 block_function:
                                                     crate::core_blocks::data_listcontents,
                                                 next: None,
-                                                opcode: "data_variable",
+                                                opcode: "data_listcontents".into(),
                                                 toplevel: false,
-                                                #[cfg(debug_assertions)]
                                                 block_id: "[Auto Generated]".into(),
                                             });
                                             BlockValue::BlockId(bid)
@@ -178,29 +226,29 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
             }
         }
         fn parse_block(
+            registry: &BlockRegistry,
             target_json: &JsonValue,
             block_meta: &JsonValue,
             blocks: &mut id_arena::Arena<Block>,
             prev_id: Option<BlockId>,
             procedures_block: &ProcBlockMap,
-            procedure: &str,
             block_id: &str,
             unknown_opcodes: &mut HashSet<String>,
         ) -> BlockId {
             let mut prev_id = prev_id;
             let mut block_meta = block_meta;
+            let mut block_id = block_id;
             let mut top_block = None;
             loop {
                 let opcode = block_meta["opcode"].as_str().unwrap_or_default().to_owned();
-                if let Some(opcode_meta) = get_blockinfo(opcode.as_str()) {
+                if let Some(opcode_meta) = registry.get(opcode.as_str()) {
                     let bid = blocks.alloc_with_id(|id| Block {
                         self_id: id,
                         arguments: vec![],
                         block_function: opcode_meta.block_function,
                         next: None,
-                        opcode: string_to_static_str(block_meta["opcode"].as_str().unwrap().to_owned()),
+                        opcode: opcode.to_owned(),
                         toplevel: block_meta["topLevel"].as_bool().unwrap_or(false),
-                        #[cfg(debug_assertions)]
                         block_id: block_id.into(),
                     });
                     if top_block.is_none() {
@@ -215,19 +263,19 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                         .arguments
                         .iter()
                         .map(|(arg_type, arg_name)| match arg_type {
-                            blocks::ArgType::Input => {
+                            blocks::ArgType::Input(_) => {
                                 let input = &block_meta["inputs"][arg_name][1];
                                 if input.is_string() {
                                     // Block Id
                                     let block_id = input.as_str().unwrap();
                                     let block_meta = &target_json["blocks"][block_id];
                                     BlockValue::BlockId(parse_block(
+                                        registry,
                                         target_json,
                                         block_meta,
                                         blocks,
                                         None,
                                         procedures_block,
-                                        procedure,
                                         block_id,
                                         unknown_opcodes,
                                     ))
@@ -251,49 +299,35 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                         prev_id = Some(bid);
                         let next_meta_id = block_meta["next"].as_str().unwrap();
                         block_meta = &target_json["blocks"][next_meta_id];
+                        block_id = next_meta_id;
                     } else {
                         return top_block.unwrap();
                     }
                 } else if opcode.as_str() == "argument_reporter_string_number"
                     || opcode.as_str() == "argument_reporter_boolean"
                 {
-                    let value = block_meta["fields"]["VALUE"][0]
+                    // Arguments are looked up by name when the reporter runs, like scratch-vm
+                    let argument: BlockValue = block_meta["fields"]["VALUE"][0]
                         .as_str()
-                        .unwrap_or_default();
-                    let argument = if let Some(procmeta) = procedures_block.get(procedure) {
-                        if let Some((argid, _)) = procmeta
-                            .2
-                            .iter()
-                            .enumerate()
-                            .find(|x| x.1.as_str() == value)
-                        {
-                            argid.into()
-                        } else {
-                            usize::MAX.into()
-                        }
-                    } else {
-                        usize::MAX.into()
-                    };
+                        .unwrap_or_default()
+                        .into();
                     let bid = blocks.alloc_with_id(|bid| Block {
                         self_id: bid,
                         toplevel: false,
                         arguments: vec![argument],
-                        opcode: if opcode.as_str() == "argument_reporter_string_number" {
-                            "argument_reporter_string_number"
-                        } else {
-                            "argument_reporter_boolean"
-                        },
+                        opcode: opcode.to_owned(),
                         block_function: if opcode.as_str() == "argument_reporter_string_number" {
                             core_blocks::argument_reporter_string_number
                         } else {
                             core_blocks::argument_reporter_boolean
                         },
-                        #[cfg(debug_assertions)]
                         block_id: block_id.into(),
                         next: None,
                     });
                     return bid;
-                } else if opcode.as_str() == "procedures_call" {
+                } else if opcode.as_str() == "procedures_call"
+                    || opcode.as_str() == "procedures_call_return"
+                {
                     let proccode = block_meta["mutation"]["proccode"]
                         .as_str()
                         .unwrap_or_default();
@@ -301,11 +335,14 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                         let bid = blocks.alloc_with_id(|id| Block {
                             self_id: id,
                             arguments: vec![],
-                            block_function: core_blocks::procedures_call,
+                            block_function: if opcode.as_str() == "procedures_call" {
+                                core_blocks::procedures_call
+                            } else {
+                                core_blocks::procedures_call_return
+                            },
                             next: None,
-                            opcode: "procedures_call",
+                            opcode: opcode.to_owned(),
                             toplevel: block_meta["topLevel"].as_bool().unwrap_or(false),
-                            #[cfg(debug_assertions)]
                             block_id: block_id.into(),
                         });
                         if top_block.is_none() {
@@ -326,12 +363,12 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                                     let block_id = input.as_str().unwrap();
                                     let block_meta = &target_json["blocks"][block_id];
                                     BlockValue::BlockId(parse_block(
+                                        registry,
                                         target_json,
                                         block_meta,
                                         blocks,
                                         None,
                                         procedures_block,
-                                        procedure,
                                         block_id,
                                         unknown_opcodes,
                                     ))
@@ -346,21 +383,22 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                             prev_id = Some(bid);
                             let next_meta_id = block_meta["next"].as_str().unwrap();
                             block_meta = &target_json["blocks"][next_meta_id];
+                            block_id = next_meta_id;
                         } else {
                             return top_block.unwrap();
                         }
                     } else if block_meta["next"].is_string() {
                         let next_meta_id = block_meta["next"].as_str().unwrap();
                         block_meta = &target_json["blocks"][next_meta_id];
+                        block_id = next_meta_id;
                     } else {
                         return blocks.alloc_with_id(|id| Block {
                             block_function: crate::blocks::noop,
-                            opcode: "",
+                            opcode: String::new(),
                             arguments: vec![],
                             self_id: id,
                             next: None,
                             toplevel: false,
-                            #[cfg(debug_assertions)]
                             block_id: block_id.into(),
                         });
                     }
@@ -369,17 +407,17 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                     unknown_opcodes.insert(opcode.clone());
                     let next_meta_id = block_meta["next"].as_str().unwrap();
                     block_meta = &target_json["blocks"][next_meta_id];
+                    block_id = next_meta_id;
                 } else {
                     // println!("WARN: Unknown opcode: {}", opcode);
                     unknown_opcodes.insert(opcode.clone());
                     return blocks.alloc_with_id(|id| Block {
                         block_function: crate::blocks::noop,
-                        opcode: "",
+                        opcode: String::new(),
                         arguments: vec![],
                         self_id: id,
                         next: None,
                         toplevel: false,
-                        #[cfg(debug_assertions)]
                         block_id: block_id.into(),
                     });
                 }
@@ -393,27 +431,28 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                     || block_meta["opcode"].as_str().unwrap_or("")
                         == "procedures_return_definition")
             {
+                let warp = &target_json["blocks"][block_meta["inputs"]["custom_block"][1]
+                    .as_str()
+                    .unwrap_or_default()]["mutation"]["warp"];
+                // Scratch 3 saves `warp` as a string, older projects as a boolean
+                let warp = warp.as_bool().unwrap_or(warp.as_str() == Some("true"));
                 let block_id = target.blocks.alloc_with_id(|id| Block {
                     self_id: id,
                     toplevel: true,
-                    arguments: vec![],
-                    opcode: match block_meta["opcode"].as_str().unwrap_or("") {
-                        "procedures_definition" => "procedures_definition",
-                        "procedures_return_definition" => "procedures_return_definition",
-                        _ => unreachable!(),
-                    },
+                    arguments: vec![warp.into()],
+                    opcode: block_meta["opcode"].as_str().unwrap_or("").to_owned(),
                     block_function: match block_meta["opcode"].as_str().unwrap_or("") {
                         "procedures_definition" => core_blocks::procedures_definition,
                         "procedures_return_definition" => core_blocks::procedures_definition_return,
                         _ => unreachable!(),
                     },
                     next: None,
-                    #[cfg(debug_assertions)]
                     block_id: block_meta_id.into(),
                 });
 
                 register_procedure(
                     &mut procedures_block,
+                    &mut target.blocks,
                     block_id,
                     &target_json["blocks"],
                     block_meta,
@@ -437,16 +476,16 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                         if !proccode.is_empty() {
                             let procmeta = procedures_block.get(&proccode).unwrap();
                             let blockid = procmeta.0;
-                            let block_meta = &target_json["blocks"]
-                                [block_meta["next"].as_str().unwrap_or_default()];
+                            let next_meta_id = block_meta["next"].as_str().unwrap_or_default();
+                            let block_meta = &target_json["blocks"][next_meta_id];
                             parse_block(
+                                registry,
                                 target_json,
                                 block_meta,
                                 &mut target.blocks,
                                 Some(blockid),
                                 &procedures_block,
-                                proccode.as_str(),
-                                block_meta_id,
+                                next_meta_id,
                                 unknown_opcodes,
                             );
                         }
@@ -454,12 +493,12 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
                     "" => {}
                     _ => {
                         parse_block(
+                            registry,
                             target_json,
                             block_meta,
                             &mut target.blocks,
                             None,
                             &procedures_block,
-                            "",
                             block_meta_id,
                             unknown_opcodes,
                         );
@@ -471,44 +510,390 @@ pub fn load_project(vm: &mut VirtualMachine, project: &JsonValue) {
     for target_json in project["targets"].members() {
         if target_json["isStage"].as_bool().unwrap() {
             let stage = vm.targets.get_mut(vm.stage_id).unwrap();
-            setup_target(stage, target_json, &mut unknown_opcodes);
+            setup_target(&vm.registry, stage, target_json, &mut unknown_opcodes);
             vm.resync_stage();
         } else {
             let mut target = Target::default();
-            setup_target(&mut target, target_json, &mut unknown_opcodes);
+            setup_target(&vm.registry, &mut target, target_json, &mut unknown_opcodes);
             vm.new_target(target);
         }
     }
-    let mut unknown_opcodes = unknown_opcodes.into_iter().collect::<Vec<_>>();
-    unknown_opcodes.sort();
-    for unknown_opcode in unknown_opcodes {
-        println!("WARN: Unknown opcode: {}", unknown_opcode);
+    report.unknown_opcodes = unknown_opcodes.into_iter().collect();
+    report.unknown_opcodes.sort();
+    vm.refresh_hats();
+    Ok(report)
+}
+
+/// Add the costumes and sounds of `project` that are not in `vm.assets` yet from `store`,
+/// or from `vm.asset_cache` if it has them. Returns the md5exts that could not be found.
+pub fn load_assets(
+    vm: &mut VirtualMachine,
+    project: &JsonValue,
+    mut store: impl AssetStore,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    for target_json in project["targets"].members() {
+        let files = target_json["costumes"]
+            .members()
+            .chain(target_json["sounds"].members());
+        for md5ext in files.filter_map(crate::assets::md5ext) {
+            if vm.assets.contains_key(&md5ext) || missing.contains(&md5ext) {
+                continue;
+            }
+            let data = match &vm.asset_cache {
+                Some(cache) => cache.load(&md5ext, &mut store),
+                None => store
+                    .load(&md5ext)
+                    .filter(|data| crate::assets::verify_md5(&md5ext, data)),
+            };
+            match data {
+                Some(data) => {
+                    let asset = Asset {
+                        md5ext: md5ext.clone(),
+                        data,
+                    };
+                    vm.assets.insert(md5ext, asset);
+                }
+                None => missing.push(md5ext),
+            }
+        }
+    }
+    missing
+}
+
+fn load_project_with_assets(
+    vm: &mut VirtualMachine,
+    project: &JsonValue,
+    store: impl AssetStore,
+) -> std::result::Result<LoadReport, LoadError> {
+    if sb2_loader::is_sb2_project(project) {
+        let converted = sb2_loader::convert_project(project)?;
+        let mut report = load_project(vm, &converted)?;
+        let store = sb2_loader::Sb2AssetStore::new(project, store);
+        report.missing_assets = load_assets(vm, &converted, store);
+        return Ok(report);
+    }
+    let mut report = load_project(vm, project)?;
+    report.missing_assets = load_assets(vm, project, store);
+    Ok(report)
+}
+
+/// Load a bare `project.json` into `vm`, with the costumes and sounds from `store`.
+pub fn load_project_json(
+    vm: &mut VirtualMachine,
+    json: &str,
+    store: impl AssetStore,
+) -> std::result::Result<LoadReport, LoadError> {
+    let project = json::parse(json).map_err(|e| LoadError::InvalidProject(e.to_string()))?;
+    load_project_with_assets(vm, &project, store)
+}
+
+/// Load a project extracted to a directory, with `project.json` next to its costumes and sounds.
+pub fn load_project_dir(
+    vm: &mut VirtualMachine,
+    dir: impl AsRef<Path>,
+) -> std::result::Result<LoadReport, LoadError> {
+    let dir = dir.as_ref();
+    let json = std::fs::read_to_string(dir.join("project.json"))
+        .map_err(|e| LoadError::Io(e.to_string()))?;
+    load_project_json(vm, &json, DirAssetStore::new(dir))
+}
+
+/// Load a sb3 or sb2 file, or a directory with an extracted project.
+pub fn load_path(
+    vm: &mut VirtualMachine,
+    path: impl AsRef<Path>,
+) -> std::result::Result<LoadReport, LoadError> {
+    if path.as_ref().is_dir() {
+        load_project_dir(vm, path)
+    } else {
+        load_sb3(vm, path)
+    }
+}
+
+/// Load a sb3 file from memory into `vm`. Scratch 2 projects are converted with `sb2_loader`.
+pub fn load_sb3_data(
+    vm: &mut VirtualMachine,
+    data: &[u8],
+) -> std::result::Result<LoadReport, LoadError> {
+    load_sb3_archive(vm, std::io::Cursor::new(data))
+}
+
+/// Load a sb3 file into `vm`, so blocks registered in `vm.registry` can be used by the project.
+/// Scratch 2 projects are converted with `sb2_loader`.
+pub fn load_sb3(
+    vm: &mut VirtualMachine,
+    file_path: impl AsRef<Path>,
+) -> std::result::Result<LoadReport, LoadError> {
+    let r = std::fs::OpenOptions::new()
+        .read(true)
+        .open(file_path.as_ref())
+        .map_err(|e| LoadError::Io(e.to_string()))?;
+    load_sb3_archive(vm, r)
+}
+
+fn load_sb3_archive(
+    vm: &mut VirtualMachine,
+    r: impl Read + std::io::Seek,
+) -> std::result::Result<LoadReport, LoadError> {
+    let mut archive = ZipArchive::new(r).map_err(|e| LoadError::InvalidProject(e.to_string()))?;
+    let project = read_archive_json(&mut archive, "project.json")?;
+    load_project_with_assets(vm, &project, ZipAssetStore::new(archive))
+}
+
+fn read_archive_json(
+    archive: &mut ZipArchive<impl Read + std::io::Seek>,
+    name: &str,
+) -> std::result::Result<JsonValue, LoadError> {
+    let invalid = |e: &dyn std::fmt::Display| LoadError::InvalidProject(e.to_string());
+    let mut file = archive.by_name(name).map_err(|e| invalid(&e))?;
+    let mut json_file = String::with_capacity(file.size() as _);
+    file.read_to_string(&mut json_file)
+        .map_err(|e| invalid(&e))?;
+    json::parse(&json_file).map_err(|e| invalid(&e))
+}
+
+/// Add the sprite of a sprite3 file in memory to `vm`, see `load_sprite`.
+pub fn load_sprite3_data(
+    vm: &mut VirtualMachine,
+    data: &[u8],
+) -> std::result::Result<(TargetId, LoadReport), LoadError> {
+    load_sprite3_archive(vm, std::io::Cursor::new(data))
+}
+
+/// Add the sprite of a sprite3 file to `vm`, see `load_sprite`.
+pub fn load_sprite3(
+    vm: &mut VirtualMachine,
+    file_path: impl AsRef<Path>,
+) -> std::result::Result<(TargetId, LoadReport), LoadError> {
+    let r = std::fs::OpenOptions::new()
+        .read(true)
+        .open(file_path.as_ref())
+        .map_err(|e| LoadError::Io(e.to_string()))?;
+    load_sprite3_archive(vm, r)
+}
+
+fn load_sprite3_archive(
+    vm: &mut VirtualMachine,
+    r: impl Read + std::io::Seek,
+) -> std::result::Result<(TargetId, LoadReport), LoadError> {
+    let mut archive = ZipArchive::new(r).map_err(|e| LoadError::InvalidProject(e.to_string()))?;
+    let sprite = read_archive_json(&mut archive, "sprite.json")?;
+    load_sprite(vm, &sprite, ZipAssetStore::new(archive))
+}
+
+/// Add a sprite from `sprite.json` to a loaded project, like dropping it into the editor.
+///
+/// The sprite is renamed to `Sprite2`, `Sprite3`... if its name is taken, and it is put
+/// in front of the other sprites. Local variables and lists that conflict with global ones
+/// are fixed like in scratch-vm, see `rename_global_conflicts`, and its messages are merged
+/// into the stage's, see `merge_broadcasts`. Returns the new target.
+pub fn load_sprite(
+    vm: &mut VirtualMachine,
+    sprite: &JsonValue,
+    store: impl AssetStore,
+) -> std::result::Result<(TargetId, LoadReport), LoadError> {
+    if !sprite["name"].is_string() {
+        return Err(LoadError::InvalidProject(
+            "the sprite has no name".to_owned(),
+        ));
+    }
+    let mut sprite = sprite.clone();
+    let names = vm
+        .running_targets
+        .iter()
+        .filter(|(_, t)| !t.is_clone)
+        .map(|(_, t)| t.name.as_str())
+        .collect::<Vec<_>>();
+    let name = unused_name(sprite["name"].as_str().unwrap(), &names);
+    let layer_order = vm.front_layer_order();
+    rename_global_conflicts(&vm.targets[vm.stage_id], &name, &mut sprite);
+    let broadcasts = merge_broadcasts(&vm.targets[vm.stage_id], &mut sprite);
+    sprite["name"] = name.into();
+    sprite["isStage"] = false.into();
+    sprite["layerOrder"] = layer_order.into();
+    let extensions = sprite_extensions(&sprite);
+    let project = object! {
+        "targets": array![sprite],
+        "extensions": extensions,
+    };
+    let target_id = vm.targets.next_id();
+    let mut report = load_project(vm, &project)?;
+    let stage_id = vm.stage_id;
+    vm.targets[stage_id].broadcasts.extend(broadcasts);
+    report.missing_assets = load_assets(vm, &project, store);
+    Ok((target_id, report))
+}
+
+/// `name`, or `name` with the first free number if it is taken, like `Sprite2` for `Sprite1`.
+fn unused_name(name: &str, taken: &[&str]) -> String {
+    if !taken.contains(&name) {
+        return name.to_owned();
+    }
+    let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    (2..)
+        .map(|i| format!("{}{}", base, i))
+        .find(|x| !taken.contains(&x.as_str()))
+        .unwrap()
+}
+
+/// Fix the variables and lists of `sprite` that conflict with the global ones, like scratch-vm.
+///
+/// Blocks refer to variables by id, so a local with the id of a global gets a new id, and
+/// references to a global of another project that has the name of a global of this one
+/// use the id of this one. A local with the name of a global is renamed to `Sprite: name`.
+fn rename_global_conflicts(stage: &Target, sprite_name: &str, sprite: &mut JsonValue) {
+    for (key, field, primitive, globals) in [
+        ("variables", "VARIABLE", 12, &stage.variable_names),
+        ("lists", "LIST", 13, &stage.list_names),
+    ] {
+        let mut new_ids = HashMap::new();
+        let mut locals = JsonValue::new_object();
+        for (id, variable) in sprite[key].entries() {
+            let mut variable = variable.clone();
+            let name = variable[0].as_str().unwrap_or_default();
+            if globals.values().any(|x| x == name) {
+                variable[0] = format!("{}: {}", sprite_name, name).into();
+            }
+            if globals.contains_key(id) {
+                let new_id = format!("{}: {}", sprite_name, id);
+                new_ids.insert(id.to_owned(), new_id.clone());
+                locals[new_id] = variable;
+            } else {
+                locals[id] = variable;
+            }
+        }
+        // A reference is a field `[name, id]` or a primitive `[12, name, id]`
+        let new_id = |name: &JsonValue, id: &JsonValue| -> Option<String> {
+            let id = id.as_str()?;
+            if let Some(new_id) = new_ids.get(id) {
+                return Some(new_id.to_owned());
+            }
+            if locals.has_key(id) || globals.contains_key(id) {
+                return None;
+            }
+            let name = name.as_str()?;
+            globals
+                .iter()
+                .find(|(_, x)| x.as_str() == name)
+                .map(|(id, _)| id.to_owned())
+        };
+        let fix_primitive = |value: &mut JsonValue| {
+            if value[0].as_u8() == Some(primitive) {
+                if let Some(id) = new_id(&value[1], &value[2]) {
+                    value[2] = id.into();
+                }
+            }
+        };
+        for (_, block) in sprite["blocks"].entries_mut() {
+            // Top-level reporters are primitives with a position
+            fix_primitive(block);
+            let reference = &block["fields"][field];
+            if let Some(id) = new_id(&reference[0], &reference[1]) {
+                block["fields"][field][1] = id.into();
+            }
+            for (_, input) in block["inputs"].entries_mut() {
+                input.members_mut().for_each(fix_primitive);
+            }
+        }
+        sprite[key] = locals;
+    }
+}
+
+/// Point the messages of `sprite` to the stage's ones with the same name ignoring case, like
+/// scratch-vm, and return the messages the stage does not have yet.
+///
+/// Messages are global, so the sprite's `broadcasts` are removed. A new message keeps its id
+/// unless the stage uses it for another message.
+fn merge_broadcasts(stage: &Target, sprite: &mut JsonValue) -> BTreeMap<String, String> {
+    let mut new_ids = HashMap::<String, String>::new();
+    let mut added = BTreeMap::new();
+    let mut merge = |id: &str, name: &str| -> String {
+        if let Some(new_id) = new_ids.get(id) {
+            return new_id.to_owned();
+        }
+        let same_name = |(_, x): &(&String, &String)| x.to_lowercase() == name.to_lowercase();
+        let new_id = match stage.broadcasts.iter().find(same_name) {
+            Some((stage_id, _)) => stage_id.to_owned(),
+            None => match added.iter().find(same_name) {
+                Some((added_id, _)) => added_id.to_owned(),
+                None => {
+                    let new_id = (1..)
+                        .map(|i| match i {
+                            1 => id.to_owned(),
+                            i => format!("{}{}", id, i),
+                        })
+                        .find(|x| !stage.broadcasts.contains_key(x) && !added.contains_key(x))
+                        .unwrap();
+                    added.insert(new_id.clone(), name.to_owned());
+                    new_id
+                }
+            },
+        };
+        new_ids.insert(id.to_owned(), new_id.clone());
+        new_id
+    };
+    for (id, name) in sprite["broadcasts"].entries() {
+        merge(id, name.as_str().unwrap_or_default());
+    }
+    // A reference is a field `[name, id]` or a primitive `[11, name, id]`
+    let mut fix = |name: &JsonValue, id: &mut JsonValue| {
+        if let (Some(name), Some(old_id)) = (name.as_str(), id.as_str()) {
+            *id = merge(old_id, name).into();
+        }
+    };
+    for (_, block) in sprite["blocks"].entries_mut() {
+        let field = &mut block["fields"]["BROADCAST_OPTION"];
+        let name = field[0].clone();
+        fix(&name, &mut field[1]);
+        for (_, input) in block["inputs"].entries_mut() {
+            for value in input.members_mut() {
+                if value[0].as_u8() == Some(11) {
+                    let name = value[1].clone();
+                    fix(&name, &mut value[2]);
+                }
+            }
+        }
+    }
+    sprite["broadcasts"] = JsonValue::new_object();
+    added
+}
+
+/// Extensions used by the blocks of a sprite, `sprite.json` does not list them.
+fn sprite_extensions(sprite: &JsonValue) -> Vec<String> {
+    const CORE: [&str; 10] = [
+        "argument",
+        "control",
+        "data",
+        "event",
+        "looks",
+        "motion",
+        "operator",
+        "procedures",
+        "sensing",
+        "sound",
+    ];
+    let mut extensions = Vec::new();
+    for (_, block) in sprite["blocks"].entries() {
+        if let Some((prefix, _)) = block["opcode"].as_str().and_then(|x| x.split_once('_')) {
+            if !CORE.contains(&prefix) && !extensions.iter().any(|x| x == prefix) {
+                extensions.push(prefix.to_owned());
+            }
+        }
     }
+    extensions
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_name = createVMFromSb3Data))]
 pub fn create_vm_from_sb3_data(data: &[u8]) -> VirtualMachine {
     let mut vm = VirtualMachine::default();
-    let r = std::io::Cursor::new(data);
-    let mut file = ZipArchive::new(r).unwrap();
-    let mut project_file = file.by_name("project.json").unwrap();
-    let mut json_file = String::with_capacity(project_file.size() as _);
-    project_file.read_to_string(&mut json_file).unwrap();
-    let project = json::parse(&json_file).unwrap();
-    load_project(&mut vm, &project);
+    // The default policy only warns about missing extensions
+    load_sb3_data(&mut vm, data).unwrap();
     vm
 }
 pub fn create_vm_from_sb3(file_path: impl AsRef<Path>) -> VirtualMachine {
     let mut vm = VirtualMachine::default();
-    let r = std::fs::OpenOptions::new()
-        .read(true)
-        .open(file_path.as_ref())
-        .unwrap();
-    let mut file = ZipArchive::new(r).unwrap();
-    let mut project_file = file.by_name("project.json").unwrap();
-    let mut json_file = String::with_capacity(project_file.size() as _);
-    project_file.read_to_string(&mut json_file).unwrap();
-    let project = json::parse(&json_file).unwrap();
-    load_project(&mut vm, &project);
+    // The default policy only warns about missing extensions
+    load_sb3(&mut vm, file_path).unwrap();
     vm
 }
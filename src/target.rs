@@ -2,10 +2,7 @@ use std::str::FromStr;
 
 use generational_arena::Index;
 
-use crate::{
-    blocks::get_blockinfo,
-    *,
-};
+use crate::{blocks::BlockRegistry, *};
 
 /// Current rotation style.
 #[derive(Debug, Clone, Copy)]
@@ -60,6 +57,12 @@ pub struct Target {
     /// Dictionary of variables and their list values for this target.
     /// Key is the variable id.
     pub lists: BTreeMap<String, Vec<BlockValue>>,
+    /// Names of variables, keyed by their id. Blocks refer to variables by id.
+    pub variable_names: BTreeMap<String, String>,
+    /// Names of lists, keyed by their id.
+    pub list_names: BTreeMap<String, String>,
+    /// Names of broadcast messages, keyed by their id. Projects keep them in the stage.
+    pub broadcasts: BTreeMap<String, String>,
     /// Scratch X coordinate. Currently should range from -240 to 240.
     pub x: f64,
     /// Scratch Y coordinate. Currently should range from -180 to 180.
@@ -76,6 +79,10 @@ pub struct Target {
     pub costumes: Vec<String>,
     /// Costume names.
     pub sounds: Vec<String>,
+    /// md5ext of each costume, in the order of `costumes`. Empty for targets made by the host.
+    pub costume_assets: Vec<String>,
+    /// md5ext of each sound, in the order of `sounds`.
+    pub sound_assets: Vec<String>,
     /// Current layer order index.
     pub layer_order: usize,
     /// Current rotation style.
@@ -85,6 +92,9 @@ pub struct Target {
     /// Current tempo (used by the music extension).
     /// This property is global to the project and stored in the stage.
     pub tempo: f64,
+    /// Set by `VirtualMachine::delete_target`. The target keeps its slot in
+    /// `VirtualMachine::targets` so other ids stay valid, without blocks or running targets.
+    pub deleted: bool,
 }
 
 impl Default for Target {
@@ -100,12 +110,18 @@ impl Default for Target {
             blocks: Arena::new(),
             variables: BTreeMap::new(),
             lists: BTreeMap::new(),
+            variable_names: BTreeMap::new(),
+            list_names: BTreeMap::new(),
+            broadcasts: BTreeMap::new(),
             x: 0.0,
             y: 0.0,
             current_costume: 0,
             costumes: Vec::new(),
             sounds: Vec::new(),
+            costume_assets: Vec::new(),
+            sound_assets: Vec::new(),
             rotation_style: RotationStyle::default(),
+            deleted: false,
         }
     }
 }
@@ -145,6 +161,23 @@ pub struct RunningTarget {
 }
 
 impl Target {
+    /// The name of the variable with this id. Variables made by blocks or the host
+    /// have no name, so their id is used.
+    pub fn variable_name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.variable_names.get(id).map_or(id, |x| x.as_str())
+    }
+    /// The id of the variable named `name`.
+    pub fn variable_id(&self, name: &str) -> Option<&str> {
+        find_id(&self.variable_names, name)
+    }
+    /// The name of the list with this id, like `variable_name`.
+    pub fn list_name<'a>(&'a self, id: &'a str) -> &'a str {
+        self.list_names.get(id).map_or(id, |x| x.as_str())
+    }
+    /// The id of the list named `name`.
+    pub fn list_id(&self, name: &str) -> Option<&str> {
+        find_id(&self.list_names, name)
+    }
     pub fn make_target(&self, target_id: TargetId, is_clone: bool) -> RunningTarget {
         RunningTarget {
             target_id,
@@ -166,21 +199,22 @@ impl Target {
     }
     pub fn new_block(
         &mut self,
+        registry: &BlockRegistry,
         opcode: &str,
         parent: Option<BlockId>,
         arguments: &[BlockValue],
     ) -> BlockId {
-        let func = get_blockinfo(opcode)
+        let func = registry
+            .get(opcode)
             .map(|a| a.block_function)
             .unwrap_or_else(|| blocks::noop);
         let bid = self.blocks.alloc_with_id(|bid| Block {
             self_id: bid,
             toplevel: parent.is_none(),
             arguments: arguments.to_vec(),
-            opcode: string_to_static_str(opcode.to_owned()),
+            opcode: opcode.to_owned(),
             block_function: func,
             next: None,
-            #[cfg(debug_assertions)]
             block_id: "[Host Generated]".into(),
         });
         if let Some(parent) = parent {
@@ -192,5 +226,12 @@ impl Target {
     }
 }
 
+fn find_id<'a>(names: &'a BTreeMap<String, String>, name: &str) -> Option<&'a str> {
+    names
+        .iter()
+        .find(|(_, x)| x.as_str() == name)
+        .map(|(id, _)| id.as_str())
+}
+
 pub type TargetId = Id<Target>;
 pub type RunningTargetId = Index;
@@ -7,18 +7,25 @@ fn test_all() {
     use crate::blocks::*;
     use crate::*;
 
+    #[derive(Default)]
+    struct SayText(Vec<String>);
+
     fn run_test(file: &str) -> Vec<String> {
-        let mut vm = crate::sb3_loader::create_vm_from_sb3(file);
+        let mut vm = VirtualMachine::default();
+        vm.registry
+            .register(
+                "looks_say",
+                BlockInfo::new(BlockShape::Command, collect_say)
+                    .input("MESSAGE", InputType::String),
+            )
+            .unwrap();
+        crate::sb3_loader::load_sb3(&mut vm, file).unwrap();
         let timer = Instant::now();
         vm.start_flag();
         while !vm.is_idle() || timer.elapsed().as_secs() < 10 {
             vm.step();
         }
-        return if let Some(x) = vm
-            .global_data
-            .get("SAY_TEXT")
-            .and_then(|x| x.downcast_ref::<Vec<String>>())
-        {
+        return if let Some(SayText(x)) = vm.runtime.extensions.get::<SayText>() {
             x.to_owned()
         } else if timer.elapsed().as_secs() > 10 {
             vec!["fail test time out".to_owned()]
@@ -31,30 +38,17 @@ fn test_all() {
         let text = ctx.stack.arguments.get(0);
         if let Some(text) = text {
             println!("{}", text);
-            if let Some(x) = ctx
-                .global_data
-                .get_mut("SAY_TEXT")
-                .and_then(|x| x.downcast_mut::<Vec<String>>())
-            {
-                x.push(text.to_string());
-            } else {
-                ctx.global_data
-                    .insert("SAY_TEXT".to_string(), Box::new(vec![text.to_string()]));
-            }
+            ctx.runtime
+                .extensions
+                .get_or_default::<SayText>()
+                .0
+                .push(text.to_string());
             BlockResult::Resolved(None)
         } else {
             BlockResult::ResolveArgument(0)
         }
     }
 
-    register_block(
-        "looks_say",
-        BlockInfo {
-            block_function: collect_say,
-            arguments: vec![(ArgType::Input, "MESSAGE".into())],
-        },
-    );
-
     let test_dir = format!("{}/test", env!("CARGO_MANIFEST_DIR"));
     let files = std::fs::read_dir(test_dir).unwrap();
     let mut tested = 0;
@@ -77,3 +71,2682 @@ fn test_all() {
         panic!("Some tests failed");
     }
 }
+
+#[test]
+fn test_grader() {
+    use crate::grader::*;
+
+    let spec = Spec::parse(
+        r#"{
+            "time_limit": 5,
+            "cases": [{
+                "expect": {
+                    "finishes": true,
+                    "say": ["plan 1", "pass green flag thread did start", "end"],
+                    "say_contains": ["fail"]
+                }
+            }]
+        }"#,
+    )
+    .unwrap();
+    let file = format!(
+        "{}/test/event-when-green-flag.sb3",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let report = grade_file(&spec, file.as_ref());
+    let passed = report.checks.iter().map(|c| c.passed).collect::<Vec<_>>();
+    assert_eq!(passed, [true, true, false]);
+    assert!(!report.passed());
+
+    // Typos and cases that check nothing are refused instead of passing
+    let errors = [
+        (
+            r#"{"timelimit": 5, "cases": []}"#,
+            r#"unknown key "timelimit" in the spec"#,
+        ),
+        (
+            r#"{"cases": [{"name": "a", "expected": {"finishes": true}}]}"#,
+            r#"a: unknown key "expected" in the case"#,
+        ),
+        (
+            r#"{"cases": [{"expect": {"finishes": true, "variable": {}}}]}"#,
+            r#"case 1: unknown key "variable" in the expectations"#,
+        ),
+        (
+            r#"{"cases": [{"expect": {"sprites": {"Cat": {"z": 0}}}}]}"#,
+            r#"case 1: unknown key "z" in the position"#,
+        ),
+        (
+            r#"{"cases": [{"expect": {"finishes": false}}]}"#,
+            "case 1: the case has no checks",
+        ),
+    ];
+    for (spec, error) in errors {
+        assert_eq!(Spec::parse(spec).unwrap_err(), error);
+    }
+    let spec = Spec::parse(r#"{"bytecode": true, "cases": [{"expect": {"finishes": true}}]}"#);
+    assert!(spec.unwrap().compile);
+}
+
+#[test]
+fn test_grader_variables() {
+    use crate::grader::*;
+
+    let spec = Spec::parse(
+        r#"{
+            "time_limit": 5,
+            "cases": [{
+                "expect": {
+                    "variables": {"Stage": {"global": 4}, "Sprite3": {"global": 4}},
+                    "lists": {"Stage": {"_answer": [4, 5, 3]}}
+                }
+            }]
+        }"#,
+    )
+    .unwrap();
+    let file = format!(
+        "{}/test/data-operators-global.sb3",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let report = grade_file(&spec, file.as_ref());
+    let passed = report.checks.iter().map(|c| c.passed).collect::<Vec<_>>();
+    // Blocks of the sprite change the global variable, the sprite has none
+    assert_eq!(passed, [true, false, true]);
+    assert_eq!(report.checks[1].message, "no such variable");
+}
+
+#[test]
+fn test_coverage() {
+    use crate::runner::*;
+
+    let file = format!(
+        "{}/test/control-if-true-then-else.sb3",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(5)),
+        coverage: true,
+        ..Default::default()
+    };
+    let result = run_file(file.as_ref(), &options);
+    let coverage = result.coverage.unwrap();
+    let sprite = coverage
+        .targets
+        .iter()
+        .find(|t| t.name == "Sprite1")
+        .unwrap();
+    // Only the else branch never runs
+    let unexecuted = sprite.unexecuted().collect::<Vec<_>>();
+    assert_eq!(unexecuted.len(), 1);
+    assert_eq!(unexecuted[0].opcode, "looks_say");
+    assert!(sprite.executed().any(|b| b.opcode == "control_if_else"));
+}
+
+#[test]
+fn test_run_variables() {
+    use crate::runner::*;
+    use crate::*;
+
+    let file = format!(
+        "{}/test/data-operators-global.sb3",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let result = run_file(file.as_ref(), &options);
+    let said = result.speech.iter().map(|s| s.text.as_str()).collect::<Vec<_>>();
+    assert!(said.contains(&"pass global var is 4"));
+    // Blocks set the variable by id, the result has it by name
+    assert_eq!(result.variables["Stage"]["global"], BlockValue::from("4"));
+    assert!(result.variables["Sprite3"].is_empty());
+}
+
+#[test]
+fn test_run_timed_events() {
+    use crate::runner::*;
+    use crate::*;
+    use std::time::Duration;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {
+                "receive": {"opcode": "event_whenbroadcastreceived", "next": "say",
+                    "parent": null, "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]},
+                    "topLevel": true},
+                "say": {"opcode": "looks_say", "next": null, "parent": "receive",
+                    "inputs": {"MESSAGE": [1, [10, "Hello"]]}, "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let options = RunOptions {
+        time_limit: Some(Duration::from_secs(5)),
+        input: ScriptedInput {
+            answers: Vec::new(),
+            events: vec![(
+                Trigger::Time(Duration::from_millis(200)),
+                InputEvent::Broadcast("go".to_owned()),
+            )],
+        },
+        ..Default::default()
+    };
+    let result = run_vm(&mut vm, &options);
+    assert_eq!(result.status, RunStatus::Finished);
+    assert_eq!(result.speech[0].text, "Hello");
+    assert!(result.elapsed >= Duration::from_millis(200));
+    // The idle VM sleeps until the event instead of stepping
+    assert!(result.steps < 5);
+}
+
+#[test]
+fn test_batch_panic() {
+    use crate::runner::*;
+
+    // A panicking job only fails itself
+    let results = parallel_map(&[1, 2, 3], 2, |x| {
+        if *x == 2 {
+            panic!("bad project");
+        }
+        x * 10
+    });
+    assert_eq!(results, [Ok(10), Err("bad project".to_owned()), Ok(30)]);
+
+    let dir = std::env::temp_dir().join(format!("scrust-batch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("project.json"),
+        r#"{
+            "targets": [{
+                "isStage": true,
+                "name": "Stage",
+                "variables": {"v1": ["items", [1, 2]], "v2": ["empty", null]},
+                "lists": {},
+                "blocks": {},
+                "costumes": [],
+                "sounds": []
+            }],
+            "extensions": []
+        }"#,
+    )
+    .unwrap();
+    let results = run_batch(std::slice::from_ref(&dir), &RunOptions::default(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(results[0].status, RunStatus::Finished);
+    assert_eq!(results[0].variables["Stage"]["items"], "[1,2]".into());
+}
+
+#[test]
+fn test_aot_frames() {
+    use crate::*;
+    use std::time::{Duration, Instant};
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["done", 0], "v2": ["moves", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "repeat", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "repeat": {"opcode": "control_repeat", "next": "wait", "parent": "flag",
+                    "inputs": {"TIMES": [1, [6, "3"]], "SUBSTACK": [2, "move"]}, "fields": {},
+                    "topLevel": false},
+                "move": {"opcode": "motion_changexby", "next": "count", "parent": "repeat",
+                    "inputs": {"DX": [1, [4, "10"]]}, "fields": {}, "topLevel": false},
+                "count": {"opcode": "data_changevariableby", "next": null, "parent": "move",
+                    "inputs": {"VALUE": [1, [4, "1"]]},
+                    "fields": {"VARIABLE": ["moves", "v2"]}, "topLevel": false},
+                "wait": {"opcode": "control_wait", "next": "done", "parent": "repeat",
+                    "inputs": {"DURATION": [1, [5, "0.2"]]}, "fields": {}, "topLevel": false},
+                "done": {"opcode": "data_setvariableto", "next": null, "parent": "wait",
+                    "inputs": {"VALUE": [1, [10, "1"]]},
+                    "fields": {"VARIABLE": ["done", "v1"]}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let started = Instant::now();
+    vm.start_flag();
+    aot::run_frames(&mut vm);
+    // Each move changes the stage and ends a frame, then the wait sleeps
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    let stage = vm.running_stage_id;
+    assert_eq!(vm.variable(stage, "moves"), Some(&3.into()));
+    assert_eq!(vm.variable(stage, "done"), Some(&1.into()));
+}
+
+#[test]
+#[ignore = "builds the generated program with cargo, run with --ignored"]
+fn test_aot_program() {
+    use crate::*;
+    use std::process::Command;
+
+    let root = env!("CARGO_MANIFEST_DIR");
+    let mut vm = VirtualMachine::default();
+    sb3_loader::load_sb3(
+        &mut vm,
+        format!("{}/test/procedures-recursive-default-number.sb3", root),
+    )
+    .unwrap();
+    let program = aot::generate_program(&vm).unwrap();
+
+    // Build the program as a crate of its own, with the locked versions of the dependencies
+    let dir = std::env::temp_dir().join(format!("scrust-aot-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::write(
+        dir.join("Cargo.toml"),
+        format!(
+            "[package]\nname = \"aot-program\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\nclipcc_rust_vm = {{ path = {:?} }}\n\n[workspace]\n",
+            root
+        ),
+    )
+    .unwrap();
+    std::fs::copy(format!("{}/Cargo.lock", root), dir.join("Cargo.lock")).unwrap();
+    std::fs::write(dir.join("src/main.rs"), program).unwrap();
+    let output = Command::new(env!("CARGO"))
+        .args(["run", "--quiet", "--offline"])
+        .current_dir(&dir)
+        .env("CARGO_TARGET_DIR", format!("{}/target/aot-program", root))
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // `looks_say` prints without a speech log
+    let said = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        said.lines().collect::<Vec<_>>(),
+        [
+            "plan 2",
+            "pass number1 is correct (1)",
+            "pass number1 is correct (0)",
+            "end"
+        ]
+    );
+}
+
+#[test]
+fn test_sb2_project() {
+    use crate::runner::*;
+    use crate::*;
+    use std::io::Write;
+
+    let project = json::parse(
+        r#"{
+            "objName": "Stage",
+            "variables": [{"name": "score", "value": 0, "isPersistent": false}],
+            "lists": [{"listName": "names", "contents": []}],
+            "costumes": [{"costumeName": "backdrop1"}],
+            "children": [{
+                "objName": "Cat",
+                "scratchX": 0,
+                "scratchY": 0,
+                "direction": 90,
+                "costumes": [{"costumeName": "costume1"}],
+                "scripts": [
+                    [0, 0, [
+                        ["whenGreenFlag"],
+                        ["setVar:to:", "score", 0],
+                        ["doRepeat", 3, [["changeVar:by:", "score", 2]]],
+                        ["append:toList:", "Alice", "names"],
+                        ["call", "greet %n", ["readVariable", "score"]],
+                        ["gotoX:y:", 10, 20],
+                        ["changeStretchBy:", 10],
+                        ["broadcast:", "done"]
+                    ]],
+                    [0, 100, [
+                        ["procDef", "greet %n", ["n"], [1], false],
+                        ["say:", ["concatenate:with:", "score ", ["getParam", "n", "r"]]]
+                    ]],
+                    [0, 200, [
+                        ["whenIReceive", "done"],
+                        ["doIfElse", ["=", ["readVariable", "score"], 6],
+                            [["say:", "pass"]],
+                            [["say:", "fail"]]]
+                    ]]
+                ]
+            }, {"target": "Cat", "cmd": "getVar:", "param": "score"}]
+        }"#,
+    )
+    .unwrap();
+    let mut vm = VirtualMachine::default();
+    sb2_loader::load_project(&mut vm, &project).unwrap();
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let result = run_vm(&mut vm, &options);
+    let said = result.speech.iter().map(|s| s.text.as_str()).collect::<Vec<_>>();
+    assert_eq!(said, ["score 6", "pass"]);
+    assert_eq!(result.variables["Stage"]["score"], BlockValue::from(6.));
+    assert_eq!(result.lists["Stage"]["names"], [BlockValue::from("Alice")]);
+    assert_eq!(result.sprites["Cat"].x, 10.);
+    assert_eq!(result.sprites["Cat"].y, 20.);
+
+    // Assets have their md5 as md5ext, sb2 archives name them by id
+    let cat = format!("{:x}.svg", md5::compute(b"<svg>cat</svg>"));
+    let meow = format!("{:x}.wav", md5::compute(b"meow"));
+    let project = json::object! {
+        "objName": "Stage",
+        "costumes": [{
+            "costumeName": "backdrop1",
+            "baseLayerID": -1,
+            "baseLayerMD5": "739b5e2a2435f6e1ec2993791b423146.png",
+            "rotationCenterX": 240,
+            "rotationCenterY": 180,
+        }],
+        "children": [{
+            "objName": "Cat",
+            "costumes": [{
+                "costumeName": "costume1",
+                "baseLayerID": 0,
+                "baseLayerMD5": cat.as_str(),
+                "bitmapResolution": 1,
+                "rotationCenterX": 47,
+                "rotationCenterY": 55,
+            }],
+            "sounds": [{"soundName": "meow", "soundID": 0, "md5": meow.as_str(), "rate": 22050}],
+        }],
+    };
+    let converted = sb2_loader::convert_project(&project).unwrap();
+    let costume = &converted["targets"][1]["costumes"][0];
+    assert_eq!(costume["md5ext"], cat.as_str());
+    assert_eq!(costume["rotationCenterX"], 47.);
+    let mut sb2 = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    sb2.start_file("project.json", Default::default()).unwrap();
+    sb2.write_all(project.dump().as_bytes()).unwrap();
+    sb2.start_file("0.svg", Default::default()).unwrap();
+    sb2.write_all(b"<svg>cat</svg>").unwrap();
+    sb2.start_file("0.wav", Default::default()).unwrap();
+    sb2.write_all(b"meow").unwrap();
+    let data = sb2.finish().unwrap().into_inner();
+    let mut vm = VirtualMachine::default();
+    let report = sb3_loader::load_sb3_data(&mut vm, &data).unwrap();
+    assert_eq!(
+        report.missing_assets,
+        ["739b5e2a2435f6e1ec2993791b423146.png"]
+    );
+    let cat_target = vm.find_target("Cat").unwrap();
+    assert_eq!(vm.targets[cat_target].costume_assets, [cat.as_str()]);
+    assert_eq!(vm.targets[cat_target].sound_assets, [meow.as_str()]);
+    assert_eq!(&*vm.assets[&cat].data, b"<svg>cat</svg>");
+    assert_eq!(&*vm.assets[&meow].data, b"meow");
+}
+
+#[test]
+fn test_project_dir_and_sprite3() {
+    use crate::*;
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("scrust-project-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("project.json"),
+        r#"{
+            "targets": [{
+                "isStage": true,
+                "name": "Stage",
+                "variables": {"g1": ["score", 0], "g2": ["level", 0]},
+                "lists": {},
+                "blocks": {},
+                "costumes": [{"name": "backdrop1", "md5ext": "backdrop.svg"}],
+                "sounds": []
+            }, {
+                "isStage": false,
+                "name": "Sprite1",
+                "variables": {},
+                "lists": {},
+                "blocks": {},
+                "costumes": [{"name": "costume1", "assetId": "cat", "dataFormat": "svg"}],
+                "sounds": [],
+                "layerOrder": 1
+            }],
+            "extensions": []
+        }"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("backdrop.svg"), "<svg/>").unwrap();
+    let mut vm = VirtualMachine::default();
+    let report = sb3_loader::load_project_dir(&mut vm, &dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(report.missing_assets, ["cat.svg"]);
+    assert_eq!(&*vm.assets["backdrop.svg"].data, b"<svg/>");
+
+    let mut sprite3 = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    sprite3
+        .start_file("sprite.json", Default::default())
+        .unwrap();
+    sprite3
+        .write_all(
+            br#"{
+                "isStage": false,
+                "name": "Sprite1",
+                "variables": {"l1": ["score", 5], "l2": ["lives", 3], "g2": ["speed", 0]},
+                "lists": {},
+                "blocks": {
+                    "a": {
+                        "opcode": "data_setvariableto",
+                        "next": "b",
+                        "parent": null,
+                        "inputs": {"VALUE": [1, [10, "1"]]},
+                        "fields": {"VARIABLE": ["score", "l1"]},
+                        "topLevel": true
+                    },
+                    "b": {
+                        "opcode": "data_setvariableto",
+                        "next": "c",
+                        "parent": "a",
+                        "inputs": {"VALUE": [1, [10, "2"]]},
+                        "fields": {"VARIABLE": ["level", "other-project-level"]},
+                        "topLevel": false
+                    },
+                    "c": {
+                        "opcode": "data_setvariableto",
+                        "next": null,
+                        "parent": "b",
+                        "inputs": {"VALUE": [3, [12, "lives", "l2"], [10, ""]]},
+                        "fields": {"VARIABLE": ["speed", "g2"]},
+                        "topLevel": false
+                    }
+                },
+                "costumes": [{"name": "costume1", "md5ext": "dog.svg"}],
+                "sounds": []
+            }"#,
+        )
+        .unwrap();
+    sprite3.start_file("dog.svg", Default::default()).unwrap();
+    sprite3.write_all(b"<svg>dog</svg>").unwrap();
+    let data = sprite3.finish().unwrap().into_inner();
+    let (target_id, report) = sb3_loader::load_sprite3_data(&mut vm, &data).unwrap();
+    assert!(report.missing_assets.is_empty());
+    let sprite = &vm.targets[target_id];
+    assert_eq!(sprite.name, "Sprite2");
+    assert_eq!(sprite.layer_order, 2);
+    assert_eq!(sprite.costume_assets, ["dog.svg"]);
+    let variables = sprite
+        .variable_names
+        .iter()
+        .map(|(id, name)| (id.as_str(), name.as_str()))
+        .collect::<Vec<_>>();
+    // The local with the id of a global gets a new id, the one with its name a new name
+    assert_eq!(
+        variables,
+        [
+            ("Sprite2: g2", "speed"),
+            ("l1", "Sprite2: score"),
+            ("l2", "lives")
+        ]
+    );
+    assert_eq!(&*vm.assets["dog.svg"].data, b"<svg>dog</svg>");
+
+    // A global of the sprite's old project is found by name
+    let a = vm.find_block(target_id, "a").unwrap();
+    vm.start_block(target_id, a);
+    vm.run_until_idle(std::time::Duration::from_secs(1));
+    let (_, rt) = vm
+        .running_targets
+        .iter()
+        .find(|(_, rt)| rt.target_id == target_id)
+        .unwrap();
+    assert_eq!(rt.variables["l1"], "1".into());
+    assert_eq!(rt.variables["Sprite2: g2"], 3.into());
+    let stage = &vm.running_targets[vm.running_stage_id];
+    assert_eq!(stage.variables["g1"], 0.into());
+    assert_eq!(stage.variables["g2"], "2".into());
+}
+
+#[test]
+fn test_asset_stores() {
+    use crate::*;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    // Two VMs loading the same project share its assets through the cache
+    let cache = Arc::new(AssetCache::default());
+    let vms = (0..2)
+        .map(|_| {
+            let mut vm = VirtualMachine {
+                asset_cache: Some(cache.clone()),
+                ..Default::default()
+            };
+            let file = format!(
+                "{}/test/control-if-true-then-else.sb3",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            sb3_loader::load_sb3(&mut vm, file).unwrap();
+            vm
+        })
+        .collect::<Vec<_>>();
+    // The sb3 files of Scratch have files named by their md5, so they are checked
+    assert_eq!(cache.len(), 5);
+    let md5ext = "83a9787d4cb6f3b7632b4ddfebf74367.wav";
+    assert!(Arc::ptr_eq(
+        &vms[0].assets[md5ext].data,
+        &vms[1].assets[md5ext].data
+    ));
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {},
+            "lists": {},
+            "blocks": {},
+            "costumes": [{"name": "backdrop1", "md5ext": "backdrop.svg"}],
+            "sounds": [{"name": "pop", "md5ext": "pop.wav"}]
+        }],
+        "extensions": []
+    }"#;
+    let mut assets = std::collections::HashMap::new();
+    assets.insert("backdrop.svg".to_owned(), Arc::from(&b"<svg/>"[..]));
+    let mut vm = VirtualMachine::default();
+    let report = sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    assert_eq!(report.missing_assets, ["pop.wav"]);
+
+    // Data that does not have the md5 of its name is left out
+    let good = "677433a0892aaed7b7d2628c313c9775.svg";
+    let bad = "00000000000000000000000000000000.svg";
+    let mut store = std::collections::HashMap::new();
+    store.insert(good.to_owned(), Arc::from(&b"<svg/>"[..]));
+    store.insert(bad.to_owned(), Arc::from(&b"<svg/>"[..]));
+    let cache = AssetCache::default();
+    assert!(cache.load(good, &mut store).is_some());
+    assert!(cache.load(bad, &mut store).is_none());
+    assert_eq!(cache.len(), 1);
+
+    // The least recently used asset is forgotten first
+    let cache = AssetCache::with_limit(8);
+    let mut store = std::collections::HashMap::new();
+    for name in ["a", "b", "c"] {
+        store.insert(name.to_owned(), Arc::from(&b"data"[..]));
+    }
+    cache.load("a", &mut store).unwrap();
+    cache.load("b", &mut store).unwrap();
+    cache.get("a").unwrap();
+    cache.load("c", &mut store).unwrap();
+    assert_eq!(cache.bytes(), 8);
+    assert!(cache.get("a").is_some());
+    assert!(cache.get("b").is_none());
+
+    // A local stand-in for the asset server that only has `pop.wav`
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/asset/{{md5ext}}/get/",
+        listener.local_addr().unwrap()
+    );
+    let server = std::thread::spawn(move || {
+        for stream in listener.incoming().take(2) {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            if request.starts_with("GET /asset/pop.wav/get/ ") {
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nRIFF").unwrap();
+            } else {
+                stream.write_all(b"HTTP/1.0 404 Not Found\r\n\r\n").unwrap();
+            }
+        }
+    });
+    let mut vm = VirtualMachine::default();
+    let report = sb3_loader::load_project_json(&mut vm, project, HttpAssetStore::new(url)).unwrap();
+    server.join().unwrap();
+    assert_eq!(report.missing_assets, ["backdrop.svg"]);
+    assert_eq!(&*vm.assets["pop.wav"].data, b"RIFF");
+}
+
+#[test]
+fn test_live_editing() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["score", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go", "b2": "stop"},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "hat": {"opcode": "event_whenflagclicked", "next": "forever", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "forever": {"opcode": "control_forever", "next": null, "parent": "hat",
+                    "inputs": {"SUBSTACK": [2, "move"]}, "fields": {}, "topLevel": false},
+                "move": {"opcode": "motion_changeyby", "next": null, "parent": "forever",
+                    "inputs": {"DY": [1, [4, "1"]]}, "fields": {}, "topLevel": false},
+                "received": {"opcode": "event_whenbroadcastreceived", "next": "count", "parent": null,
+                    "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]}, "topLevel": true},
+                "count": {"opcode": "data_changevariableby", "next": null, "parent": "received",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["score", "v1"]},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let cat = vm.find_target("Cat").unwrap();
+    let (cat_rt, _) = vm
+        .running_targets
+        .iter()
+        .find(|(_, rt)| rt.target_id == cat)
+        .unwrap();
+    let hat = vm.find_block(cat, "hat").unwrap();
+    let forever = vm.find_block(cat, "forever").unwrap();
+    let move_y = vm.find_block(cat, "move").unwrap();
+    let received = vm.find_block(cat, "received").unwrap();
+    let position = |vm: &VirtualMachine| {
+        let rt = &vm.running_targets[cat_rt];
+        (rt.x, rt.y)
+    };
+
+    // The loop yields at the end of each iteration
+    vm.start_flag();
+    let steps = |vm: &mut VirtualMachine, n| (0..n).for_each(|_| vm.step());
+    steps(&mut vm, 1);
+    assert_eq!(position(&vm), (0., 1.));
+
+    // Edits apply to the running thread
+    vm.set_field(cat, move_y, "DY", 10.into()).unwrap();
+    let set_x = vm
+        .insert_block(
+            cat,
+            "motion_setx",
+            &[5.into()],
+            BlockPosition::After(move_y),
+        )
+        .unwrap();
+    steps(&mut vm, 1);
+    assert_eq!(position(&vm), (5., 11.));
+    vm.delete_block(cat, move_y).unwrap();
+    vm.set_field(cat, set_x, "X", 7.into()).unwrap();
+    steps(&mut vm, 1);
+    assert_eq!(position(&vm), (7., 11.));
+    assert_eq!(vm.threads.len(), 1);
+
+    // A refused edit changes nothing
+    let blocks = vm.targets[cat].blocks.len();
+    assert_eq!(
+        vm.insert_block(
+            cat,
+            "motion_setx",
+            &[1.into()],
+            BlockPosition::Input(received, "BROADCAST_OPTION".into())
+        ),
+        Err(EditError::NotAnInput("BROADCAST_OPTION".into()))
+    );
+    // Values must fit the type of the input
+    assert_eq!(
+        vm.insert_block(
+            cat,
+            "motion_setx",
+            &["left".into()],
+            BlockPosition::TopLevel
+        ),
+        Err(EditError::WrongType("X".into()))
+    );
+    assert_eq!(
+        vm.set_field(cat, set_x, "X", "seven".into()),
+        Err(EditError::WrongType("X".into()))
+    );
+    assert_eq!(vm.targets[cat].blocks[set_x].arguments[0], 7.into());
+    assert_eq!(vm.targets[cat].blocks.len(), blocks);
+    assert_eq!(vm.threads.len(), 1);
+
+    assert_eq!(
+        vm.move_block(cat, hat, BlockPosition::Input(move_y, "DY".into())),
+        Err(EditError::UnknownBlock)
+    );
+    assert_eq!(
+        vm.move_block(cat, forever, BlockPosition::After(set_x)),
+        Err(EditError::MoveIntoItself)
+    );
+    vm.delete_script(cat, hat).unwrap();
+    assert!(vm.is_idle());
+    assert_eq!(
+        vm.set_field(cat, set_x, "X", 1.into()),
+        Err(EditError::UnknownBlock)
+    );
+
+    vm.rename_variable(vm.stage_id, "score", "points").unwrap();
+    assert_eq!(vm.targets[vm.stage_id].variable_id("points"), Some("v1"));
+    assert_eq!(
+        vm.rename_variable(vm.stage_id, "score", "x"),
+        Err(EditError::UnknownVariable("score".into()))
+    );
+    vm.rename_broadcast("go", "start").unwrap();
+    assert_eq!(vm.broadcast("start").len(), 1);
+    // Messages are found ignoring case, so their names must differ in more than case
+    assert_eq!(
+        vm.rename_broadcast("start", "STOP"),
+        Err(EditError::NameTaken("STOP".into()))
+    );
+    vm.rename_broadcast("start", "Start").unwrap();
+    vm.rename_broadcast("Start", "start").unwrap();
+    // The block that changes the variable keeps referring to it
+    steps(&mut vm, 1);
+    assert!(vm.is_idle());
+    assert_eq!(vm.variable(cat_rt, "points"), Some(&1.into()));
+    let count = vm.find_block(cat, "count").unwrap();
+    assert_eq!(vm.targets[cat].blocks[count].arguments[0], "v1".into());
+
+    assert_eq!(vm.delete_target(vm.stage_id), Err(EditError::DeleteStage));
+    vm.delete_target(cat).unwrap();
+    assert!(vm.is_idle());
+    assert!(vm.targets[cat].deleted);
+    assert!(vm.find_target("Cat").is_none());
+    assert_eq!(vm.running_targets.len(), 1);
+    assert_eq!(vm.delete_target(cat), Err(EditError::UnknownTarget));
+    assert_eq!(
+        vm.insert_block(cat, "motion_setx", &[1.into()], BlockPosition::TopLevel),
+        Err(EditError::UnknownTarget)
+    );
+}
+
+#[test]
+fn test_steps_per_iteration() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "hat": {"opcode": "event_whenflagclicked", "next": "start", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "start": {"opcode": "motion_sety", "next": "repeat", "parent": "hat",
+                    "inputs": {"Y": [1, [4, "0"]]}, "fields": {}, "topLevel": false},
+                "repeat": {"opcode": "control_repeat", "next": "end", "parent": "start",
+                    "inputs": {"TIMES": [1, [6, "3"]], "SUBSTACK": [2, "move"]}, "fields": {},
+                    "topLevel": false},
+                "move": {"opcode": "motion_changeyby", "next": null, "parent": "repeat",
+                    "inputs": {"DY": [1, [4, "1"]]}, "fields": {}, "topLevel": false},
+                "end": {"opcode": "motion_setx", "next": null, "parent": "repeat",
+                    "inputs": {"X": [1, [4, "5"]]}, "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let load = || {
+        let mut vm = VirtualMachine::default();
+        let assets = std::collections::HashMap::new();
+        sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+        let cat = vm.find_target("Cat").unwrap();
+        let (cat_rt, _) = vm
+            .running_targets
+            .iter()
+            .find(|(_, rt)| rt.target_id == cat)
+            .unwrap();
+        (vm, cat_rt)
+    };
+    let position = |vm: &VirtualMachine, rt| (vm.running_targets[rt].x, vm.running_targets[rt].y);
+
+    // Blocks run without yielding up to the end of the first iteration, then one iteration a step
+    let (mut vm, cat_rt) = load();
+    vm.start_flag();
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (0., 1.));
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (0., 2.));
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (5., 3.));
+    assert!(vm.is_idle());
+
+    // When the host redraws the stage, a loop that moved a sprite waits for the redraw
+    let (mut vm, cat_rt) = load();
+    vm.mark_stage_refreshed();
+    vm.start_flag();
+    vm.step();
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (0., 1.));
+    vm.mark_stage_refreshed();
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (0., 2.));
+    vm.step();
+    assert_eq!(position(&vm, cat_rt), (0., 2.));
+}
+
+#[test]
+fn test_edge_activated_hats() {
+    use crate::runner::*;
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"t1": ["threshold", 0], "f1": ["fired", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "hat": {"opcode": "event_whengreaterthan", "next": "count", "parent": null,
+                    "inputs": {"VALUE": [3, [12, "threshold", "t1"], [4, "0"]]},
+                    "fields": {"WHENGREATERTHANMENU": ["LOUDNESS", null]}, "topLevel": true},
+                "count": {"opcode": "data_changevariableby", "next": null, "parent": "hat",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["fired", "f1"]},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let stage = vm.running_stage_id;
+    let fired = |vm: &VirtualMachine| vm.variable(stage, "fired").unwrap().to_number();
+
+    // The loudness is always -1
+    vm.step();
+    assert_eq!(fired(&vm), 0.);
+    // The hat fires once when the predicate becomes true, not while it stays true
+    vm.set_variable(stage, "threshold", (-2).into());
+    (0..5).for_each(|_| vm.step());
+    assert_eq!(fired(&vm), 1.);
+    // And again after it was false for a step
+    vm.set_variable(stage, "threshold", 0.into());
+    vm.step();
+    vm.set_variable(stage, "threshold", (-2).into());
+    (0..5).for_each(|_| vm.step());
+    assert_eq!(fired(&vm), 2.);
+
+    // A script that resets the timer sees it go back to zero
+    let file = format!(
+        "{}/test/hat-thread-execution.sb3",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let result = run_file(file.as_ref(), &options);
+    let said = result.speech.iter().map(|s| s.text.as_str()).collect::<Vec<_>>();
+    assert!(said.contains(&"pass Greater than timer is correct"), "{:?}", said);
+    assert!(!said.iter().any(|x| x.starts_with("fail")), "{:?}", said);
+}
+
+#[test]
+fn test_restart_hats() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"t1": ["threshold", -2], "c1": ["count", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "received": {"opcode": "event_whenbroadcastreceived", "next": "count", "parent": null,
+                    "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]}, "topLevel": true},
+                "count": {"opcode": "data_changevariableby", "next": "wait", "parent": "received",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["count", "c1"]},
+                    "topLevel": false},
+                "wait": {"opcode": "control_wait", "next": null, "parent": "count",
+                    "inputs": {"DURATION": [1, [5, "10"]]}, "fields": {}, "topLevel": false},
+                "hat": {"opcode": "event_whengreaterthan", "next": "wait2", "parent": null,
+                    "inputs": {"VALUE": [3, [12, "threshold", "t1"], [4, "0"]]},
+                    "fields": {"WHENGREATERTHANMENU": ["LOUDNESS", null]}, "topLevel": true},
+                "wait2": {"opcode": "control_wait", "next": null, "parent": "hat",
+                    "inputs": {"DURATION": [1, [5, "10"]]}, "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let stage = vm.running_stage_id;
+    let thread_ids = |vm: &VirtualMachine| {
+        let mut ids = vm.threads.iter().map(|t| t.thread_id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    };
+
+    // The edge-activated hat starts a script that keeps waiting
+    vm.step();
+    let edge_thread = thread_ids(&vm);
+    assert_eq!(edge_thread.len(), 1);
+
+    let first = vm.broadcast("go");
+    vm.step();
+    assert_eq!(vm.variable(stage, "count"), Some(&1.into()));
+    assert_eq!(vm.threads.len(), 2);
+
+    // A broadcast restarts its running script from the top, as a new thread in its place
+    let second = vm.broadcast("go");
+    assert_eq!(second.len(), 1);
+    assert_ne!(first, second);
+    assert_eq!(vm.threads.len(), 2);
+    vm.step();
+    assert_eq!(vm.variable(stage, "count"), Some(&2.into()));
+    let mut expected = [edge_thread[0], second[0]];
+    expected.sort_unstable();
+    assert_eq!(thread_ids(&vm), expected);
+
+    // An edge-activated hat does not restart its running script when it fires again
+    vm.set_variable(stage, "threshold", 0.into());
+    vm.step();
+    vm.set_variable(stage, "threshold", (-2).into());
+    vm.step();
+    vm.step();
+    assert_eq!(thread_ids(&vm), expected);
+}
+
+#[test]
+fn test_bytecode_equivalence() {
+    use crate::runner::*;
+    use crate::*;
+
+    // Waits are measured on a clock that advances by a frame each step, so they take the
+    // same steps in both engines
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(10)),
+        step_limit: Some(2000),
+        seed: Some(1),
+        fixed_step: Some(std::time::Duration::from_secs(1) / 30),
+        ..Default::default()
+    };
+    let test_dir = format!("{}/test", env!("CARGO_MANIFEST_DIR"));
+    let mut files = std::fs::read_dir(test_dir)
+        .unwrap()
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.extension().unwrap_or_default() == "sb3")
+        .collect::<Vec<_>>();
+    files.sort();
+    let mut compiled = 0;
+    for file in files {
+        let mut run = |compile: bool| {
+            let mut vm = VirtualMachine::default();
+            sb3_loader::load_path(&mut vm, &file).unwrap();
+            if compile {
+                compiled += vm.compile_bytecode().compiled;
+            }
+            run_vm(&mut vm, &options)
+        };
+        let said = |result: &RunResult| {
+            result
+                .speech
+                .iter()
+                .map(|s| s.text.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let (tree, bytecode) = (run(false), run(true));
+        let name = file.file_name().unwrap().to_string_lossy();
+        assert_eq!(tree.status, bytecode.status, "{}", name);
+        assert_eq!(tree.steps, bytecode.steps, "{}", name);
+        assert_eq!(said(&tree), said(&bytecode), "{}", name);
+        assert_eq!(tree.variables, bytecode.variables, "{}", name);
+        assert_eq!(tree.lists, bytecode.lists, "{}", name);
+    }
+    assert!(compiled > 0);
+}
+
+#[test]
+#[ignore = "timing, run with --release --ignored --nocapture"]
+fn bench_bytecode() {
+    use crate::runner::*;
+    use crate::*;
+    use std::time::Instant;
+
+    // A warp procedure that sums `i mod 7` over 200000 iterations
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"s1": ["sum", 0], "i1": ["i", 0]},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "call", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "call": {"opcode": "procedures_call", "next": null, "parent": "flag",
+                    "inputs": {}, "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "work", "argumentids": "[]"}},
+                "def": {"opcode": "procedures_definition", "next": "repeat", "parent": null,
+                    "inputs": {"custom_block": [1, "proto"]}, "fields": {}, "topLevel": true},
+                "proto": {"opcode": "procedures_prototype", "next": null, "parent": "def",
+                    "inputs": {}, "fields": {}, "shadow": true, "topLevel": false,
+                    "mutation": {"proccode": "work", "argumentids": "[]",
+                        "argumentnames": "[]", "argumentdefaults": "[]", "warp": "true"}},
+                "repeat": {"opcode": "control_repeat", "next": null, "parent": "def",
+                    "inputs": {"TIMES": [1, [6, "200000"]], "SUBSTACK": [2, "add"]},
+                    "fields": {}, "topLevel": false},
+                "add": {"opcode": "data_changevariableby", "next": "inc", "parent": "repeat",
+                    "inputs": {"VALUE": [3, "mod", [4, ""]]},
+                    "fields": {"VARIABLE": ["sum", "s1"]}, "topLevel": false},
+                "mod": {"opcode": "operator_mod", "next": null, "parent": "add",
+                    "inputs": {"NUM1": [3, [12, "i", "i1"], [4, ""]], "NUM2": [1, [4, "7"]]},
+                    "fields": {}, "topLevel": false},
+                "inc": {"opcode": "data_changevariableby", "next": null, "parent": "add",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["i", "i1"]},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let run = |compile: bool| {
+        let mut vm = VirtualMachine::default();
+        let assets = std::collections::HashMap::new();
+        sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+        let options = RunOptions {
+            compile,
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let result = run_vm(&mut vm, &options);
+        let elapsed = started.elapsed();
+        // 200000 / 7 full cycles of 0 + 1 + ... + 6, and 0 + 1 + ... + 4 for the rest
+        assert_eq!(result.variables["Stage"]["sum"], BlockValue::from(599994.));
+        elapsed
+    };
+    // The best of a few runs, the others are slowed down by whatever else runs
+    let best = |compile: bool| (0..3).map(|_| run(compile)).min().unwrap();
+    let (tree, bytecode) = (best(false), best(true));
+    let speedup = tree.as_secs_f64() / bytecode.as_secs_f64();
+    println!("tree {:?}, bytecode {:?}, {:.1}x", tree, bytecode, speedup);
+    // About 2x in a release build
+    assert!(speedup > 1.5, "{:.1}x", speedup);
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn test_jit_equivalence() {
+    use crate::runner::*;
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"s1": ["sum", 0], "i1": ["i", 0], "n1": ["n", 0], "j1": ["j", 0],
+                "x1": ["x", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "repeat", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "repeat": {"opcode": "control_repeat", "next": "until", "parent": "flag",
+                    "inputs": {"TIMES": [1, [6, "300"]], "SUBSTACK": [2, "count"]},
+                    "fields": {}, "topLevel": false},
+                "count": {"opcode": "data_changevariableby", "next": "if", "parent": "repeat",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["i", "i1"]},
+                    "topLevel": false},
+                "if": {"opcode": "control_if_else", "next": null, "parent": "count",
+                    "inputs": {"CONDITION": [2, "eq"], "SUBSTACK": [2, "add_i"],
+                        "SUBSTACK2": [2, "add_1"]},
+                    "fields": {}, "topLevel": false},
+                "eq": {"opcode": "operator_equals", "next": null, "parent": "if",
+                    "inputs": {"OPERAND1": [3, "mod", [10, ""]], "OPERAND2": [1, [10, "0"]]},
+                    "fields": {}, "topLevel": false},
+                "mod": {"opcode": "operator_mod", "next": null, "parent": "eq",
+                    "inputs": {"NUM1": [3, [12, "i", "i1"], [4, ""]], "NUM2": [1, [4, "3"]]},
+                    "fields": {}, "topLevel": false},
+                "add_i": {"opcode": "data_changevariableby", "next": null, "parent": "if",
+                    "inputs": {"VALUE": [3, [12, "i", "i1"], [4, ""]]},
+                    "fields": {"VARIABLE": ["sum", "s1"]}, "topLevel": false},
+                "add_1": {"opcode": "data_changevariableby", "next": null, "parent": "if",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["sum", "s1"]},
+                    "topLevel": false},
+                "until": {"opcode": "control_repeat_until", "next": "call", "parent": "repeat",
+                    "inputs": {"CONDITION": [2, "gt"], "SUBSTACK": [2, "step"]},
+                    "fields": {}, "topLevel": false},
+                "gt": {"opcode": "operator_gt", "next": null, "parent": "until",
+                    "inputs": {"OPERAND1": [3, [12, "n", "n1"], [10, ""]],
+                        "OPERAND2": [1, [10, "300"]]},
+                    "fields": {}, "topLevel": false},
+                "step": {"opcode": "data_changevariableby", "next": null, "parent": "until",
+                    "inputs": {"VALUE": [1, [4, "1.5"]]}, "fields": {"VARIABLE": ["n", "n1"]},
+                    "topLevel": false},
+                "call": {"opcode": "procedures_call", "next": "say", "parent": "until",
+                    "inputs": {}, "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "work", "argumentids": "[]"}},
+                "say": {"opcode": "looks_say", "next": null, "parent": "call",
+                    "inputs": {"MESSAGE": [3, [12, "x", "x1"], [10, ""]]},
+                    "fields": {}, "topLevel": false},
+                "def": {"opcode": "procedures_definition", "next": "while", "parent": null,
+                    "inputs": {"custom_block": [1, "proto"]}, "fields": {}, "topLevel": true},
+                "proto": {"opcode": "procedures_prototype", "next": null, "parent": "def",
+                    "inputs": {}, "fields": {}, "shadow": true, "topLevel": false,
+                    "mutation": {"proccode": "work", "argumentids": "[]",
+                        "argumentnames": "[]", "argumentdefaults": "[]", "warp": "true"}},
+                "while": {"opcode": "control_while", "next": null, "parent": "def",
+                    "inputs": {"CONDITION": [2, "lt"], "SUBSTACK": [2, "inc"]},
+                    "fields": {}, "topLevel": false},
+                "lt": {"opcode": "operator_lt", "next": null, "parent": "while",
+                    "inputs": {"OPERAND1": [3, [12, "j", "j1"], [10, ""]],
+                        "OPERAND2": [1, [10, "1000"]]},
+                    "fields": {}, "topLevel": false},
+                "inc": {"opcode": "data_changevariableby", "next": "set_x", "parent": "while",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["j", "j1"]},
+                    "topLevel": false},
+                "set_x": {"opcode": "data_setvariableto", "next": null, "parent": "inc",
+                    "inputs": {"VALUE": [3, "mod_x", [10, ""]]},
+                    "fields": {"VARIABLE": ["x", "x1"]}, "topLevel": false},
+                "mod_x": {"opcode": "operator_mod", "next": null, "parent": "set_x",
+                    "inputs": {"NUM1": [3, "add_x", [4, ""]], "NUM2": [1, [4, "7"]]},
+                    "fields": {}, "topLevel": false},
+                "add_x": {"opcode": "operator_add", "next": null, "parent": "mod_x",
+                    "inputs": {"NUM1": [3, [12, "x", "x1"], [4, ""]],
+                        "NUM2": [3, "square", [4, ""]]},
+                    "fields": {}, "topLevel": false},
+                "square": {"opcode": "operator_multiply", "next": null, "parent": "add_x",
+                    "inputs": {"NUM1": [3, [12, "j", "j1"], [4, ""]],
+                        "NUM2": [3, [12, "j", "j1"], [4, ""]]},
+                    "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let options = RunOptions {
+        step_limit: Some(2000),
+        ..Default::default()
+    };
+    let run = |compile: bool| {
+        let mut vm = VirtualMachine::default();
+        let assets = std::collections::HashMap::new();
+        sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+        let report = compile.then(|| vm.compile_bytecode());
+        let result = run_vm(&mut vm, &options);
+        let native = vm.bytecode.as_ref().map(|b| b.native_regions());
+        (result, report, native)
+    };
+    let (tree, _, _) = run(false);
+    let (jit, report, native) = run(true);
+    // The three loops, whose bodies have no sites of their own
+    assert_eq!(report.unwrap().native_sites, 3);
+    // All loops ran often enough to be compiled
+    assert_eq!(native, Some(3));
+    assert_eq!(tree.status, RunStatus::Finished);
+    // The loops of the script yield at the end of each iteration, the warp procedure does not
+    assert_eq!(tree.steps, 501);
+    assert_eq!(tree.steps, jit.steps);
+    assert_eq!(tree.variables, jit.variables);
+    let said = |result: &RunResult| {
+        result
+            .speech
+            .iter()
+            .map(|s| s.text.to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(said(&tree), said(&jit));
+}
+
+#[test]
+fn test_optimizer() {
+    use crate::runner::*;
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["v", 0], "r1": ["result", 0], "e1": ["equal", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "set", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "set": {"opcode": "data_setvariableto", "next": "result", "parent": "flag",
+                    "inputs": {"VALUE": [1, [10, "4"]]}, "fields": {"VARIABLE": ["v", "v1"]},
+                    "topLevel": false},
+                "result": {"opcode": "data_setvariableto", "next": "equal", "parent": "set",
+                    "inputs": {"VALUE": [3, "mul", [10, ""]]},
+                    "fields": {"VARIABLE": ["result", "r1"]}, "topLevel": false},
+                "mul": {"opcode": "operator_multiply", "next": null, "parent": "result",
+                    "inputs": {"NUM1": [3, "add", [4, ""]], "NUM2": [3, [12, "v", "v1"], [4, "0"]]},
+                    "fields": {}, "topLevel": false},
+                "add": {"opcode": "operator_add", "next": null, "parent": "mul",
+                    "inputs": {"NUM1": [1, [4, "1"]], "NUM2": [1, [4, "2"]]},
+                    "fields": {}, "topLevel": false},
+                "equal": {"opcode": "data_setvariableto", "next": null, "parent": "result",
+                    "inputs": {"VALUE": [3, "eq", [10, ""]]},
+                    "fields": {"VARIABLE": ["equal", "e1"]}, "topLevel": false},
+                "eq": {"opcode": "operator_equals", "next": null, "parent": "equal",
+                    "inputs": {"OPERAND1": [1, [10, "4"]], "OPERAND2": [3, [12, "v", "v1"], [10, ""]]},
+                    "fields": {}, "topLevel": false},
+                "gt": {"opcode": "operator_gt", "next": null, "parent": null,
+                    "inputs": {"OPERAND1": [1, [10, "5"]], "OPERAND2": [3, "mul2", [10, ""]]},
+                    "fields": {}, "topLevel": true, "x": 0, "y": 0},
+                "mul2": {"opcode": "operator_multiply", "next": null, "parent": "gt",
+                    "inputs": {"NUM1": [3, [12, "v", "v1"], [4, "0"]], "NUM2": [1, [4, "2"]]},
+                    "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let cat = vm.find_target("Cat").unwrap();
+    let block = |vm: &VirtualMachine, id: &str| {
+        vm.targets[cat].blocks[vm.find_block(cat, id).unwrap()].clone()
+    };
+
+    let target = &vm.targets[cat];
+    assert_eq!(infer_type(target, &"12".into()), ValueType::Number);
+    assert_eq!(infer_type(target, &"-12".into()), ValueType::String);
+    assert_eq!(infer_type(target, &true.into()), ValueType::Boolean);
+    let mul = BlockValue::BlockId(vm.find_block(cat, "mul").unwrap());
+    let eq = BlockValue::BlockId(vm.find_block(cat, "eq").unwrap());
+    assert_eq!(infer_type(target, &mul), ValueType::Number);
+    assert_eq!(infer_type(target, &eq), ValueType::Boolean);
+    let variable = block(&vm, "mul").arguments[1].clone();
+    assert_eq!(infer_type(target, &variable), ValueType::Any);
+
+    let report = vm.optimize();
+    // `(1) + (2)` becomes 3, the rest reads a variable
+    assert_eq!(report.folded, 1);
+    assert_eq!(block(&vm, "mul").arguments[0], BlockValue::Number(3.));
+    assert!(matches!(
+        block(&vm, "mul").arguments[0],
+        BlockValue::Number(_)
+    ));
+    // The inputs of `(1) + (2)`, and `"5" > (v * "2")` are always read as numbers,
+    // but `"4" = v` compares strings if `v` is "4.0"
+    assert_eq!(report.numeric_literals, 4);
+    assert!(matches!(
+        block(&vm, "gt").arguments[0],
+        BlockValue::Number(_)
+    ));
+    assert!(matches!(
+        block(&vm, "mul2").arguments[1],
+        BlockValue::Number(_)
+    ));
+    assert!(matches!(
+        block(&vm, "set").arguments[0],
+        BlockValue::String(_)
+    ));
+    assert!(matches!(
+        block(&vm, "eq").arguments[0],
+        BlockValue::String(_)
+    ));
+
+    vm.start_flag();
+    (0..3).for_each(|_| vm.step());
+    let stage = vm.running_stage_id;
+    assert!(matches!(vm.variable(stage, "v"), Some(BlockValue::String(v)) if v == "4"));
+    assert!(matches!(vm.variable(stage, "result"), Some(BlockValue::Number(v)) if *v == 12.));
+    assert!(matches!(
+        vm.variable(stage, "equal"),
+        Some(BlockValue::Boolean(true))
+    ));
+
+    // Optimized projects run like the loaded ones, on a clock that does not depend on timing
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(10)),
+        step_limit: Some(2000),
+        seed: Some(1),
+        fixed_step: Some(std::time::Duration::from_secs(1) / 30),
+        ..Default::default()
+    };
+    let test_dir = format!("{}/test", env!("CARGO_MANIFEST_DIR"));
+    let mut files = std::fs::read_dir(test_dir)
+        .unwrap()
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.extension().unwrap_or_default() == "sb3")
+        .collect::<Vec<_>>();
+    files.sort();
+    for file in files {
+        let run = |optimize: bool| {
+            let mut vm = VirtualMachine::default();
+            sb3_loader::load_path(&mut vm, &file).unwrap();
+            if optimize {
+                vm.optimize();
+            }
+            run_vm(&mut vm, &options)
+        };
+        let said = |result: &RunResult| {
+            result
+                .speech
+                .iter()
+                .map(|s| (s.target.to_owned(), s.text.to_owned()))
+                .collect::<Vec<_>>()
+        };
+        let (loaded, optimized) = (run(false), run(true));
+        let name = file.file_name().unwrap().to_string_lossy();
+        assert_eq!(loaded.status, optimized.status, "{}", name);
+        assert_eq!(loaded.steps, optimized.steps, "{}", name);
+        assert_eq!(said(&loaded), said(&optimized), "{}", name);
+        assert_eq!(loaded.variables, optimized.variables, "{}", name);
+        assert_eq!(loaded.lists, optimized.lists, "{}", name);
+    }
+}
+
+#[test]
+fn test_warp_procedures() {
+    use crate::*;
+
+    // `fast` and `slow` run the same loop, only `fast` runs without screen refresh
+    let procedure = |name: &str, warp: bool, variable: &str| {
+        format!(
+            r#""{name}_def": {{"opcode": "procedures_definition", "next": "{name}_repeat",
+                "parent": null, "inputs": {{"custom_block": [1, "{name}_proto"]}}, "fields": {{}},
+                "topLevel": true}},
+            "{name}_proto": {{"opcode": "procedures_prototype", "next": null,
+                "parent": "{name}_def", "inputs": {{}}, "fields": {{}}, "shadow": true,
+                "topLevel": false, "mutation": {{"proccode": "{name}", "argumentids": "[]",
+                "argumentnames": "[]", "argumentdefaults": "[]", "warp": "{warp}"}}}},
+            "{name}_repeat": {{"opcode": "control_repeat", "next": null, "parent": "{name}_def",
+                "inputs": {{"TIMES": [1, [6, "10"]], "SUBSTACK": [2, "{name}_change"]}},
+                "fields": {{}}, "topLevel": false}},
+            "{name}_change": {{"opcode": "data_changevariableby", "next": "{name}_move",
+                "parent": "{name}_repeat", "inputs": {{"VALUE": [1, [4, "1"]]}},
+                "fields": {{"VARIABLE": ["{variable}", "{variable}"]}}, "topLevel": false}},
+            "{name}_move": {{"opcode": "motion_changexby", "next": null,
+                "parent": "{name}_change", "inputs": {{"DX": [1, [4, "1"]]}}, "fields": {{}},
+                "topLevel": false}},
+            "{name}_flag": {{"opcode": "event_whenflagclicked", "next": "{name}_call",
+                "parent": null, "inputs": {{}}, "fields": {{}}, "topLevel": true}},
+            "{name}_call": {{"opcode": "procedures_call", "next": null, "parent": "{name}_flag",
+                "inputs": {{}}, "fields": {{}}, "topLevel": false,
+                "mutation": {{"proccode": "{name}", "argumentids": "[]"}}}}"#
+        )
+    };
+    let project = format!(
+        r#"{{
+        "targets": [{{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {{"a": ["a", 0], "b": ["b", 0]}},
+            "lists": {{}},
+            "blocks": {{}},
+            "costumes": [],
+            "sounds": []
+        }}, {{
+            "isStage": false,
+            "name": "Cat",
+            "variables": {{}},
+            "lists": {{}},
+            "blocks": {{{}, {}}},
+            "costumes": [],
+            "sounds": []
+        }}],
+        "extensions": []
+    }}"#,
+        procedure("fast", true, "a"),
+        procedure("slow", false, "b")
+    );
+    for compile in [false, true] {
+        let mut vm = VirtualMachine::default();
+        let assets = std::collections::HashMap::new();
+        sb3_loader::load_project_json(&mut vm, &project, assets).unwrap();
+        if compile {
+            assert_eq!(vm.compile_bytecode().compiled, 4);
+        }
+        let stage = vm.running_stage_id;
+        let counts = |vm: &VirtualMachine| {
+            (
+                vm.variable(stage, "a").unwrap().to_number(),
+                vm.variable(stage, "b").unwrap().to_number(),
+            )
+        };
+        vm.start_flag();
+        // The warp procedure finishes in one step, with its motion blocks
+        vm.step();
+        assert_eq!(counts(&vm), (10., 1.), "compile: {}", compile);
+        // The other one yields at the end of each iteration
+        (0..8).for_each(|_| vm.step());
+        assert_eq!(counts(&vm), (10., 9.), "compile: {}", compile);
+        vm.step();
+        assert_eq!(counts(&vm), (10., 10.), "compile: {}", compile);
+        assert!(vm.is_idle());
+    }
+}
+
+#[test]
+fn test_procedure_arguments() {
+    use crate::runner::*;
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"seen": ["seen", 0], "after": ["after", 0], "result": ["result", 0],
+                "missing": ["missing", ""], "missing_bool": ["missing_bool", ""]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "call_outer", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "call_outer": {"opcode": "procedures_call", "next": "set_result",
+                    "parent": "flag", "inputs": {"o1": [1, [10, "5"]]}, "fields": {},
+                    "topLevel": false, "mutation": {"proccode": "outer %s", "argumentids": "[\"o1\"]"}},
+                "set_result": {"opcode": "data_setvariableto", "next": "set_missing",
+                    "parent": "call_outer", "inputs": {"VALUE": [3, "call_quad", [10, ""]]},
+                    "fields": {"VARIABLE": ["result", "result"]}, "topLevel": false},
+                "call_quad": {"opcode": "procedures_call_return", "next": null,
+                    "parent": "set_result", "inputs": {"q1": [1, [10, "3"]]}, "fields": {},
+                    "topLevel": false, "mutation": {"proccode": "quad %s", "argumentids": "[\"q1\"]"}},
+                "set_missing": {"opcode": "data_setvariableto", "next": "set_missing_bool",
+                    "parent": "set_result", "inputs": {"VALUE": [3, "outside", [10, ""]]},
+                    "fields": {"VARIABLE": ["missing", "missing"]}, "topLevel": false},
+                "outside": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "set_missing", "inputs": {}, "fields": {"VALUE": ["n", null]},
+                    "topLevel": false},
+                "set_missing_bool": {"opcode": "data_setvariableto", "next": null,
+                    "parent": "set_missing", "inputs": {"VALUE": [3, "outside_bool", [10, ""]]},
+                    "fields": {"VARIABLE": ["missing_bool", "missing_bool"]}, "topLevel": false},
+                "outside_bool": {"opcode": "argument_reporter_boolean", "next": null,
+                    "parent": "set_missing_bool", "inputs": {}, "fields": {"VALUE": ["b", null]},
+                    "topLevel": false},
+
+                "outer_def": {"opcode": "procedures_definition", "next": "call_inner",
+                    "parent": null, "inputs": {"custom_block": [1, "outer_proto"]}, "fields": {},
+                    "topLevel": true},
+                "outer_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "outer_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "outer %s",
+                    "argumentids": "[\"o1\"]", "argumentnames": "[\"n\"]",
+                    "argumentdefaults": "[\"\"]", "warp": "false"}},
+                "call_inner": {"opcode": "procedures_call", "next": "set_after",
+                    "parent": "outer_def", "inputs": {"i1": [3, "plus", [10, ""]]}, "fields": {},
+                    "topLevel": false, "mutation": {"proccode": "inner %s", "argumentids": "[\"i1\"]"}},
+                "plus": {"opcode": "operator_add", "next": null, "parent": "call_inner",
+                    "inputs": {"NUM1": [3, "outer_n", [4, ""]], "NUM2": [1, [4, "1"]]},
+                    "fields": {}, "topLevel": false},
+                "outer_n": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "plus", "inputs": {}, "fields": {"VALUE": ["n", null]},
+                    "topLevel": false},
+                "set_after": {"opcode": "data_setvariableto", "next": null,
+                    "parent": "call_inner", "inputs": {"VALUE": [3, "after_n", [10, ""]]},
+                    "fields": {"VARIABLE": ["after", "after"]}, "topLevel": false},
+                "after_n": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "set_after", "inputs": {}, "fields": {"VALUE": ["n", null]},
+                    "topLevel": false},
+
+                "inner_def": {"opcode": "procedures_definition", "next": "set_seen",
+                    "parent": null, "inputs": {"custom_block": [1, "inner_proto"]}, "fields": {},
+                    "topLevel": true},
+                "inner_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "inner_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "inner %s",
+                    "argumentids": "[\"i1\"]", "argumentnames": "[\"n\"]",
+                    "argumentdefaults": "[\"\"]", "warp": "false"}},
+                "set_seen": {"opcode": "data_setvariableto", "next": null, "parent": "inner_def",
+                    "inputs": {"VALUE": [3, "inner_n", [10, ""]]},
+                    "fields": {"VARIABLE": ["seen", "seen"]}, "topLevel": false},
+                "inner_n": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "set_seen", "inputs": {}, "fields": {"VALUE": ["n", null]},
+                    "topLevel": false},
+
+                "double_def": {"opcode": "procedures_return_definition", "next": "double_return",
+                    "parent": null, "inputs": {"custom_block": [1, "double_proto"]}, "fields": {},
+                    "topLevel": true},
+                "double_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "double_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "double %s",
+                    "argumentids": "[\"d1\"]", "argumentnames": "[\"x\"]",
+                    "argumentdefaults": "[\"\"]", "warp": "false"}},
+                "double_return": {"opcode": "procedures_return", "next": null,
+                    "parent": "double_def", "inputs": {"VALUE": [3, "times", [10, ""]]},
+                    "fields": {}, "topLevel": false},
+                "times": {"opcode": "operator_multiply", "next": null, "parent": "double_return",
+                    "inputs": {"NUM1": [3, "double_x", [4, ""]], "NUM2": [1, [4, "2"]]},
+                    "fields": {}, "topLevel": false},
+                "double_x": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "times", "inputs": {}, "fields": {"VALUE": ["x", null]},
+                    "topLevel": false},
+
+                "quad_def": {"opcode": "procedures_return_definition", "next": "quad_return",
+                    "parent": null, "inputs": {"custom_block": [1, "quad_proto"]}, "fields": {},
+                    "topLevel": true},
+                "quad_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "quad_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "quad %s",
+                    "argumentids": "[\"q1\"]", "argumentnames": "[\"x\"]",
+                    "argumentdefaults": "[\"\"]", "warp": "false"}},
+                "quad_return": {"opcode": "procedures_return", "next": null,
+                    "parent": "quad_def", "inputs": {"VALUE": [3, "double_outer", [10, ""]]},
+                    "fields": {}, "topLevel": false},
+                "double_outer": {"opcode": "procedures_call_return", "next": null,
+                    "parent": "quad_return", "inputs": {"d1": [3, "double_inner", [10, ""]]},
+                    "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "double %s", "argumentids": "[\"d1\"]"}},
+                "double_inner": {"opcode": "procedures_call_return", "next": null,
+                    "parent": "double_outer", "inputs": {"d1": [3, "quad_x", [10, ""]]},
+                    "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "double %s", "argumentids": "[\"d1\"]"}},
+                "quad_x": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "double_inner", "inputs": {}, "fields": {"VALUE": ["x", null]},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    for compile in [false, true] {
+        let mut vm = VirtualMachine::default();
+        let assets = std::collections::HashMap::new();
+        sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+        if compile {
+            assert_eq!(vm.compile_bytecode().compiled, 5);
+        }
+        vm.start_flag();
+        vm.run_until_idle(std::time::Duration::from_secs(5));
+        let stage = vm.running_stage_id;
+        let variable = |name: &str| vm.variable(stage, name).unwrap().to_string();
+        // `inner` sees the value passed by `outer`, which still sees its own `n` afterwards
+        assert_eq!(variable("seen"), "6", "compile: {}", compile);
+        assert_eq!(variable("after"), "5", "compile: {}", compile);
+        // The returned value of the nested call is the argument of the outer one
+        assert_eq!(variable("result"), "12", "compile: {}", compile);
+        // Arguments that are not bound report their default
+        assert_eq!(variable("missing"), "0", "compile: {}", compile);
+        assert_eq!(variable("missing_bool"), "false", "compile: {}", compile);
+    }
+
+    // Missing arguments of nested calls, arguments outside definitions and recursive defaults
+    let options = RunOptions {
+        time_limit: Some(std::time::Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let test_dir = format!("{}/test", env!("CARGO_MANIFEST_DIR"));
+    let mut files = std::fs::read_dir(test_dir)
+        .unwrap()
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.to_string_lossy().contains("/procedures-"))
+        .collect::<Vec<_>>();
+    files.sort();
+    assert!(files.len() >= 10);
+    for file in files {
+        let result = run_file(&file, &options);
+        let said = result
+            .speech
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<Vec<_>>();
+        let name = file.file_name().unwrap().to_string_lossy();
+        assert!(said.iter().any(|x| x.starts_with("pass")), "{}", name);
+        assert!(
+            !said.iter().any(|x| x.starts_with("fail")),
+            "{}: {:?}",
+            name,
+            said
+        );
+    }
+}
+
+#[test]
+fn test_observer() {
+    use crate::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Events(Arc<Mutex<Vec<String>>>);
+
+    impl Events {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    impl VmObserver for Events {
+        fn thread_started(&mut self, _vm: &VirtualMachine, _thread_id: usize) {
+            self.push("started".into());
+        }
+        fn thread_finished(
+            &mut self,
+            vm: &VirtualMachine,
+            thread_id: usize,
+            _running_target_id: RunningTargetId,
+        ) {
+            assert!(!vm.threads.iter().any(|t| t.thread_id == thread_id));
+            self.push("finished".into());
+        }
+        fn clone_created(&mut self, vm: &VirtualMachine, clone_id: RunningTargetId) {
+            assert!(vm.running_targets[clone_id].is_clone);
+            self.push("clone created".into());
+        }
+        fn clone_deleted(&mut self, vm: &VirtualMachine, clone_id: RunningTargetId) {
+            assert!(vm.running_targets.get(clone_id).is_none());
+            self.push("clone deleted".into());
+        }
+        fn broadcast(&mut self, _vm: &VirtualMachine, name: &str, started_threads: &[usize]) {
+            self.push(format!("broadcast {} {}", name, started_threads.len()));
+        }
+        fn stop_all(&mut self, vm: &VirtualMachine) {
+            assert!(vm.threads.is_empty());
+            self.push("stop all".into());
+        }
+        fn idle(&mut self, _vm: &VirtualMachine) {
+            self.push("idle".into());
+        }
+    }
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["ticks", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "clone", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "clone": {"opcode": "control_create_clone_of", "next": "broadcast",
+                    "parent": "flag", "inputs": {"CLONE_OPTION": [1, "menu"]}, "fields": {},
+                    "topLevel": false},
+                "menu": {"opcode": "control_create_clone_of_menu", "next": null,
+                    "parent": "clone", "inputs": {},
+                    "fields": {"CLONE_OPTION": ["_myself_", null]}, "shadow": true,
+                    "topLevel": false},
+                "broadcast": {"opcode": "event_broadcast", "next": null, "parent": "clone",
+                    "inputs": {"BROADCAST_INPUT": [1, [11, "go", "b1"]]}, "fields": {},
+                    "topLevel": false},
+                "start_clone": {"opcode": "control_start_as_clone", "next": "forever",
+                    "parent": null, "inputs": {}, "fields": {}, "topLevel": true},
+                "forever": {"opcode": "control_forever", "next": null, "parent": "start_clone",
+                    "inputs": {"SUBSTACK": [2, "tick"]}, "fields": {}, "topLevel": false},
+                "tick": {"opcode": "data_changevariableby", "next": null, "parent": "forever",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["ticks", "v1"]},
+                    "topLevel": false},
+                "receive": {"opcode": "event_whenbroadcastreceived", "next": "wait",
+                    "parent": null, "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]},
+                    "topLevel": true},
+                "wait": {"opcode": "control_wait_until", "next": "stop", "parent": "receive",
+                    "inputs": {"CONDITION": [2, "gt"]}, "fields": {}, "topLevel": false},
+                "gt": {"opcode": "operator_gt", "next": null, "parent": "wait",
+                    "inputs": {"OPERAND1": [3, [12, "ticks", "v1"], [10, ""]],
+                        "OPERAND2": [1, [10, "2"]]},
+                    "fields": {}, "topLevel": false},
+                "stop": {"opcode": "control_stop", "next": null, "parent": "wait",
+                    "inputs": {}, "fields": {"STOP_OPTION": ["all", null]}, "topLevel": false,
+                    "mutation": {"tagName": "mutation", "children": [], "hasnext": "false"}}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    vm.add_observer(Events(events.clone()));
+    let take = || std::mem::take(&mut *events.lock().unwrap());
+
+    // The green flag stops everything first
+    vm.start_flag();
+    assert_eq!(take(), ["stop all", "started"]);
+    // The flag script finishes, its message (named by id) starts the receiver and the clone its script
+    vm.step();
+    assert_eq!(
+        take(),
+        [
+            "finished",
+            "started",
+            "broadcast b1 1",
+            "clone created",
+            "started"
+        ]
+    );
+    // The receiver stops all once the clone ticked, which also deletes the clone
+    (0..10).for_each(|_| vm.step());
+    assert_eq!(
+        take(),
+        ["finished", "finished", "clone deleted", "stop all", "idle"]
+    );
+    assert!(vm.is_idle());
+
+    // The host stopping the project notifies the same way
+    vm.set_variable(vm.running_stage_id, "ticks", 0.into());
+    vm.start_flag();
+    (0..2).for_each(|_| vm.step());
+    take();
+    vm.stop_all();
+    assert_eq!(
+        take(),
+        ["finished", "finished", "clone deleted", "stop all"]
+    );
+    assert_eq!(vm.running_targets.len(), 2);
+}
+
+#[test]
+fn test_debugger() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"x": ["x", 0], "y": ["y", 0], "ticks": ["ticks", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "set_x", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "set_x": {"opcode": "data_setvariableto", "next": "call_add", "parent": "flag",
+                    "inputs": {"VALUE": [1, [10, "1"]]}, "fields": {"VARIABLE": ["x", "x"]},
+                    "topLevel": false},
+                "call_add": {"opcode": "procedures_call", "next": "set_y", "parent": "set_x",
+                    "inputs": {"a1": [1, [10, "5"]]}, "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "add %s", "argumentids": "[\"a1\"]"}},
+                "set_y": {"opcode": "data_setvariableto", "next": null, "parent": "call_add",
+                    "inputs": {"VALUE": [3, "read_x", [10, ""]]},
+                    "fields": {"VARIABLE": ["y", "y"]}, "topLevel": false},
+                "read_x": {"opcode": "data_variable", "next": null, "parent": "set_y",
+                    "inputs": {}, "fields": {"VARIABLE": ["x", "x"]}, "topLevel": false},
+
+                "add_def": {"opcode": "procedures_definition", "next": "change_x",
+                    "parent": null, "inputs": {"custom_block": [1, "add_proto"]}, "fields": {},
+                    "topLevel": true},
+                "add_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "add_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "add %s",
+                    "argumentids": "[\"a1\"]", "argumentnames": "[\"n\"]",
+                    "argumentdefaults": "[\"\"]", "warp": "false"}},
+                "change_x": {"opcode": "data_changevariableby", "next": null, "parent": "add_def",
+                    "inputs": {"VALUE": [3, "add_n", [4, ""]]},
+                    "fields": {"VARIABLE": ["x", "x"]}, "topLevel": false},
+                "add_n": {"opcode": "argument_reporter_string_number", "next": null,
+                    "parent": "change_x", "inputs": {}, "fields": {"VALUE": ["n", null]},
+                    "topLevel": false},
+
+                "flag2": {"opcode": "event_whenflagclicked", "next": "forever", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "forever": {"opcode": "control_forever", "next": null, "parent": "flag2",
+                    "inputs": {"SUBSTACK": [2, "tick"]}, "fields": {}, "topLevel": false},
+                "tick": {"opcode": "data_changevariableby", "next": null, "parent": "forever",
+                    "inputs": {"VALUE": [1, [4, "1"]]}, "fields": {"VARIABLE": ["ticks", "ticks"]},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let cat = vm.find_target("Cat").unwrap();
+    let block = |vm: &VirtualMachine, id: &str| vm.find_block(cat, id).unwrap();
+    let change_x = block(&vm, "change_x");
+    let stage = vm.running_stage_id;
+    let ticks = |vm: &VirtualMachine| vm.variable(stage, "ticks").unwrap().to_string();
+
+    assert!(vm.set_breakpoint(change_x));
+    assert!(!vm.set_breakpoint(change_x));
+    vm.start_flag();
+    vm.step();
+    assert!(vm.is_paused());
+    let point = vm.pause_point().unwrap();
+    assert_eq!(point.block_id, change_x);
+    // Paused before the block runs, inside the procedure call
+    assert_eq!(vm.variable(stage, "x").unwrap().to_string(), "1");
+    let arguments = vm.procedure_arguments(point.thread_id).unwrap();
+    assert_eq!(arguments.len(), 1);
+    assert_eq!(arguments[0].0, "n");
+    assert_eq!(arguments[0].1.to_string(), "5");
+
+    // Nothing runs while paused
+    let ticks_at_pause = ticks(&vm);
+    (0..3).for_each(|_| vm.step());
+    assert_eq!(ticks(&vm), ticks_at_pause);
+    assert_eq!(vm.variable(stage, "x").unwrap().to_string(), "1");
+
+    // Changed variables are seen by the paused thread
+    assert!(vm.set_variable(stage, "x", 10.into()));
+    assert!(!vm.set_variable(stage, "missing", 0.into()));
+    // Stepping runs the paused thread alone, one block (or reporter) at a time
+    let trail: Vec<_> = std::iter::from_fn(|| vm.step_thread(point.thread_id)).collect();
+    assert_eq!(trail, ["add_n", "set_y", "read_x"].map(|id| block(&vm, id)));
+    assert!(vm.thread(point.thread_id).is_none());
+    assert_eq!(vm.variable(stage, "y").unwrap().to_string(), "15");
+    assert_eq!(ticks(&vm), ticks_at_pause);
+
+    // The other thread goes on once resumed, and a manual pause holds it again
+    vm.resume();
+    assert!(vm.pause_point().is_none());
+    vm.step();
+    assert_ne!(ticks(&vm), ticks_at_pause);
+    vm.pause();
+    assert!(vm.pause_point().is_none());
+    let ticks_at_pause = ticks(&vm);
+    vm.step();
+    assert_eq!(ticks(&vm), ticks_at_pause);
+    vm.resume();
+
+    // Removed breakpoints no longer pause
+    assert!(vm.remove_breakpoint(change_x));
+    assert_eq!(vm.breakpoints().count(), 0);
+    vm.start_flag();
+    vm.step();
+    assert!(!vm.is_paused());
+    assert_eq!(vm.variable(stage, "x").unwrap().to_string(), "6");
+}
+
+#[test]
+fn test_tracer() {
+    use crate::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"x": ["x", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "set_x", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "set_x": {"opcode": "data_setvariableto", "next": null, "parent": "flag",
+                    "inputs": {"VALUE": [3, "add", [10, ""]]}, "fields": {"VARIABLE": ["x", "x"]},
+                    "topLevel": false},
+                "add": {"opcode": "operator_add", "next": null, "parent": "set_x",
+                    "inputs": {"NUM1": [1, [4, "1"]], "NUM2": [1, [4, "2"]]},
+                    "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let out = Shared::default();
+    vm.tracer = Some(Tracer::new(out.clone()));
+    vm.start_flag();
+    let thread_id = vm.threads[0].thread_id;
+    vm.step();
+    let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let records: Vec<_> = text
+        .lines()
+        .map(|line| json::parse(line).unwrap())
+        .collect();
+    // Reporters are traced before the blocks using their values
+    let opcodes: Vec<_> = records
+        .iter()
+        .map(|r| r["opcode"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        opcodes,
+        [
+            "event_whenflagclicked",
+            "operator_add",
+            "data_setvariableto"
+        ]
+    );
+    let cat = vm.find_target("Cat").unwrap();
+    for (record, sb3_id) in records.iter().zip(["flag", "add", "set_x"]) {
+        assert_eq!(record["thread"].as_usize(), Some(thread_id));
+        assert_eq!(record["target"], "Cat");
+        assert_eq!(record["sb3_id"], sb3_id);
+        let block = vm.find_block(cat, sb3_id).unwrap();
+        assert_eq!(record["block"].as_usize(), Some(block.index()));
+        assert!(record["elapsed_ns"].as_u64().is_some());
+    }
+    assert_eq!(records[1]["arguments"], json::array!["1", "2"]);
+    assert_eq!(records[1]["result"], 3);
+    assert_eq!(records[2]["arguments"], json::array!["x", 3]);
+    assert!(records[2]["result"].is_null());
+
+    // A failing writer keeps the first error and is not written again
+    struct Failing(usize);
+
+    impl Write for Failing {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += 1;
+            assert_eq!(self.0, 1);
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    vm.tracer = Some(Tracer::new(Failing(0)));
+    vm.start_flag();
+    vm.step();
+    let error = vm.tracer.as_ref().unwrap().error().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn test_profiler() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"x": ["x", 0]},
+            "lists": {},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "call_outer", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "call_outer": {"opcode": "procedures_call", "next": null, "parent": "flag",
+                    "inputs": {}, "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "outer", "argumentids": "[]"}},
+
+                "outer_def": {"opcode": "procedures_definition", "next": "set_x",
+                    "parent": null, "inputs": {"custom_block": [1, "outer_proto"]}, "fields": {},
+                    "topLevel": true},
+                "outer_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "outer_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "outer", "argumentids": "[]",
+                    "argumentnames": "[]", "argumentdefaults": "[]", "warp": "false"}},
+                "set_x": {"opcode": "data_setvariableto", "next": "call_inner",
+                    "parent": "outer_def", "inputs": {"VALUE": [3, "add", [10, ""]]},
+                    "fields": {"VARIABLE": ["x", "x"]}, "topLevel": false},
+                "add": {"opcode": "operator_add", "next": null, "parent": "set_x",
+                    "inputs": {"NUM1": [1, [4, "1"]], "NUM2": [1, [4, "2"]]},
+                    "fields": {}, "topLevel": false},
+                "call_inner": {"opcode": "procedures_call", "next": null, "parent": "set_x",
+                    "inputs": {}, "fields": {}, "topLevel": false,
+                    "mutation": {"proccode": "inner", "argumentids": "[]"}},
+
+                "inner_def": {"opcode": "procedures_definition", "next": "change_x",
+                    "parent": null, "inputs": {"custom_block": [1, "inner_proto"]}, "fields": {},
+                    "topLevel": true},
+                "inner_proto": {"opcode": "procedures_prototype", "next": null,
+                    "parent": "inner_def", "inputs": {}, "fields": {}, "shadow": true,
+                    "topLevel": false, "mutation": {"proccode": "inner", "argumentids": "[]",
+                    "argumentnames": "[]", "argumentdefaults": "[]", "warp": "false"}},
+                "change_x": {"opcode": "data_changevariableby", "next": null,
+                    "parent": "inner_def", "inputs": {"VALUE": [1, [4, "1"]]},
+                    "fields": {"VARIABLE": ["x", "x"]}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    vm.profiler = Some(Profiler::default());
+    vm.start_flag();
+    vm.run_until_idle(std::time::Duration::from_secs(10));
+    assert_eq!(
+        vm.variable(vm.running_stage_id, "x").unwrap().to_string(),
+        "4"
+    );
+    let profiler = vm.profiler.take().unwrap();
+    let mut folded = Vec::new();
+    profiler.write_folded(&vm, &mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let lines: Vec<(Vec<&str>, u128)> = folded
+        .lines()
+        .map(|line| {
+            let (stack, time) = line.rsplit_once(' ').unwrap();
+            (stack.split(';').collect(), time.parse().unwrap())
+        })
+        .collect();
+    let stacks: Vec<_> = lines.iter().map(|(stack, _)| stack.join(";")).collect();
+    let script = "Cat;event_whenflagclicked#2";
+    for stack in [
+        "event_whenflagclicked",
+        "procedures_call",
+        "outer;data_setvariableto",
+        "outer;operator_add",
+        "outer;procedures_call",
+        "outer;inner;data_changevariableby",
+    ] {
+        assert!(
+            stacks.contains(&format!("{};{}", script, stack)),
+            "{}",
+            folded
+        );
+    }
+    let time = |f: &dyn Fn(&[&str]) -> bool| -> u128 {
+        lines
+            .iter()
+            .filter(|(stack, _)| f(stack))
+            .map(|(_, time)| time)
+            .sum()
+    };
+
+    // An opcode only has the time of its own blocks, the reporters and procedures they run
+    // are recorded separately
+    let cat = vm.find_target("Cat").unwrap();
+    let opcode = |name: &str| profiler.opcodes[name];
+    assert_eq!(opcode("procedures_call").calls, 2);
+    assert_eq!(opcode("operator_add").calls, 1);
+    assert_eq!(opcode("data_setvariableto").calls, 1);
+    for name in ["procedures_call", "operator_add", "data_setvariableto"] {
+        assert_eq!(
+            opcode(name).time.as_nanos(),
+            time(&|stack| stack.last() == Some(&name))
+        );
+    }
+    // Scripts and procedures include every block they run, nested procedures too
+    let flag = vm.find_block(cat, "flag").unwrap();
+    let script = profiler.scripts[&(cat, flag)];
+    assert_eq!(script.calls, 1);
+    assert_eq!(script.time.as_nanos(), time(&|_| true));
+    for name in ["outer", "inner"] {
+        let definition = vm.find_block(cat, &format!("{}_def", name)).unwrap();
+        let procedure = profiler.procedures[&(cat, definition)];
+        assert_eq!(procedure.calls, 1);
+        assert_eq!(
+            procedure.time.as_nanos(),
+            time(&|stack| stack.contains(&name))
+        );
+    }
+}
+
+#[test]
+fn test_block_registry() {
+    use crate::blocks::*;
+    use crate::*;
+
+    // Core blocks pass the checks extension blocks go through
+    let mut registry = BlockRegistry::default();
+    for (opcode, info) in registry.iter() {
+        assert_eq!(info.validate(), Ok(()), "{}", opcode);
+    }
+    let types = |registry: &BlockRegistry, opcode: &str| -> Vec<ArgType> {
+        let info = registry.get(opcode).unwrap();
+        info.arguments
+            .iter()
+            .map(|(arg_type, _)| *arg_type)
+            .collect()
+    };
+    assert_eq!(
+        types(&registry, "control_if"),
+        [
+            ArgType::Input(InputType::Boolean),
+            ArgType::Input(InputType::Substack)
+        ]
+    );
+    assert_eq!(
+        types(&registry, "motion_movesteps"),
+        [ArgType::Input(InputType::Number)]
+    );
+    assert_eq!(
+        types(&registry, "operator_join"),
+        [ArgType::Input(InputType::String); 2]
+    );
+
+    // Extensions register their own opcodes with typed arguments
+    let block = BlockInfo::new(BlockShape::Reporter, blocks::noop)
+        .input("TEXT", InputType::String)
+        .input("TIMES", InputType::Number)
+        .field("MODE");
+    assert!(!registry.contains("example_repeat"));
+    assert!(registry
+        .register("example_repeat", block.clone())
+        .unwrap()
+        .is_none());
+    assert!(registry
+        .register("example_repeat", block)
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        types(&registry, "example_repeat"),
+        [
+            ArgType::Input(InputType::String),
+            ArgType::Input(InputType::Number),
+            ArgType::Field
+        ]
+    );
+
+    // Blocks with invalid arguments are refused and leave the registry as it was
+    let command = || BlockInfo::new(BlockShape::Command, blocks::noop);
+    assert_eq!(
+        registry.register("", command()).unwrap_err(),
+        RegisterError::EmptyOpcode
+    );
+    assert_eq!(
+        registry
+            .register("example_a", command().input("", InputType::Number))
+            .unwrap_err(),
+        RegisterError::EmptyArgumentName
+    );
+    assert_eq!(
+        registry
+            .register(
+                "example_b",
+                command().input("X", InputType::Number).field("X")
+            )
+            .unwrap_err(),
+        RegisterError::DuplicateArgument("X".into())
+    );
+    assert_eq!(
+        registry
+            .register(
+                "example_c",
+                BlockInfo::new(BlockShape::Reporter, blocks::noop)
+                    .input("SUBSTACK", InputType::Substack)
+            )
+            .unwrap_err(),
+        RegisterError::SubstackNotInCommand("SUBSTACK".into())
+    );
+    assert!(["example_a", "example_b", "example_c"]
+        .iter()
+        .all(|opcode| !registry.contains(opcode)));
+
+    // Literal values each input type takes
+    let accepts = |input_type: InputType, value: BlockValue| input_type.accepts(&value);
+    assert!(accepts(InputType::String, true.into()));
+    assert!(accepts(InputType::Number, " 1.5 ".into()));
+    assert!(accepts(InputType::Number, "".into()));
+    assert!(!accepts(InputType::Number, "ten".into()));
+    assert!(!accepts(InputType::Number, false.into()));
+    assert!(accepts(InputType::Color, "#00ff7F".into()));
+    assert!(accepts(InputType::Color, 65280.into()));
+    assert!(!accepts(InputType::Color, "green".into()));
+    assert!(!accepts(InputType::Boolean, true.into()));
+    assert!(!accepts(InputType::Substack, "1".into()));
+    assert!(accepts(InputType::Boolean, BlockValue::Undefined));
+}
+
+#[test]
+fn test_extensions() {
+    use crate::blocks::*;
+    use crate::*;
+    use std::collections::HashMap;
+
+    struct Example {
+        broken: bool,
+    }
+
+    fn example_double(ctx: &mut BlockContext) -> BlockResult {
+        ctx.acquire_args(1, |ctx| {
+            BlockResult::Resolved(Some((ctx.arg(0).to_number() * 2.).into()))
+        })
+    }
+
+    impl ExtensionProvider for Example {
+        fn info(&self) -> ExtensionInfo {
+            ExtensionInfo {
+                id: "example".into(),
+                version: "1.0.0".into(),
+                category: "Example".into(),
+            }
+        }
+        fn register(&self, registry: &mut BlockRegistry) -> Result<(), RegisterError> {
+            let mut block = BlockInfo::new(BlockShape::Reporter, example_double)
+                .input("NUM", InputType::Number);
+            if self.broken {
+                block = block.field("NUM");
+            }
+            registry.register("example_double", block)?;
+            Ok(())
+        }
+    }
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"x": ["x", 0]},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "set_x", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "set_x": {"opcode": "data_setvariableto", "next": null, "parent": "flag",
+                    "inputs": {"VALUE": [3, "double", [10, ""]]},
+                    "fields": {"VARIABLE": ["x", "x"]}, "topLevel": false},
+                "double": {"opcode": "example_double", "next": null, "parent": "set_x",
+                    "inputs": {"NUM": [1, [4, "21"]]}, "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": ["example"]
+    }"#;
+
+    // Providers register the blocks of the extensions a project lists
+    let mut vm = VirtualMachine::default();
+    vm.add_extension_provider(Example { broken: false });
+    assert!(!vm.registry.contains("example_double"));
+    let report = sb3_loader::load_project_json(&mut vm, project, HashMap::new()).unwrap();
+    assert_eq!(report.loaded, [Example { broken: false }.info()]);
+    assert!(report.missing.is_empty());
+    assert!(vm.registry.contains("example_double"));
+    vm.start_flag();
+    vm.step();
+    assert_eq!(vm.variable(vm.running_stage_id, "x"), Some(&42.into()));
+
+    // Missing extensions are reported, or refused
+    let mut vm = VirtualMachine::default();
+    let report = sb3_loader::load_project_json(&mut vm, project, HashMap::new()).unwrap();
+    assert!(report.loaded.is_empty());
+    assert_eq!(report.missing, ["example"]);
+    assert!(!vm.registry.contains("example_double"));
+    let mut vm = VirtualMachine {
+        missing_extension_policy: MissingExtensionPolicy::Refuse,
+        ..Default::default()
+    };
+    assert!(matches!(
+        sb3_loader::load_project_json(&mut vm, project, HashMap::new()),
+        Err(LoadError::MissingExtensions(ids)) if ids == ["example"]
+    ));
+    // Extensions that are provided are not registered when the project is refused
+    vm.add_extension_provider(Example { broken: false });
+    let other = project.replace(r#"["example"]"#, r#"["example", "other"]"#);
+    assert!(matches!(
+        sb3_loader::load_project_json(&mut vm, &other, HashMap::new()),
+        Err(LoadError::MissingExtensions(ids)) if ids == ["other"]
+    ));
+    assert!(!vm.registry.contains("example_double"));
+
+    // Blocks the registry refuses stop the project from loading
+    let mut vm = VirtualMachine::default();
+    vm.add_extension_provider(Example { broken: true });
+    assert!(matches!(
+        sb3_loader::load_project_json(&mut vm, project, HashMap::new()),
+        Err(LoadError::InvalidExtension(id, RegisterError::DuplicateArgument(name)))
+            if id == "example" && name == "NUM"
+    ));
+}
+
+#[test]
+fn test_runtime_state() {
+    use crate::*;
+    use std::time::Duration;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Score(u32);
+    #[derive(Debug, Default, PartialEq)]
+    struct Lives(u32);
+
+    // One value per type, types with the same layout do not mix
+    let mut extensions = Extensions::default();
+    assert!(extensions.is_empty());
+    assert_eq!(extensions.insert(Score(1)), None);
+    assert_eq!(extensions.insert(Score(2)), Some(Score(1)));
+    assert!(!extensions.contains::<Lives>());
+    assert_eq!(extensions.get::<Lives>(), None);
+    extensions.get_or_default::<Lives>().0 += 3;
+    extensions.get_mut::<Score>().unwrap().0 += 10;
+    assert_eq!(extensions.get::<Score>(), Some(&Score(12)));
+    assert_eq!(extensions.get::<Lives>(), Some(&Lives(3)));
+    assert_eq!(extensions.len(), 2);
+    assert_eq!(extensions.remove::<Score>(), Some(Score(12)));
+    assert_eq!(extensions.remove::<Score>(), None);
+    assert_eq!(extensions.len(), 1);
+
+    // Timer and waits
+    let mut runtime = RuntimeState::default();
+    runtime.step_time += Duration::from_secs(2);
+    assert!(runtime.timer_value() >= 2.);
+    runtime.reset_timer();
+    assert_eq!(runtime.timer_value(), 0.);
+    runtime.report_wait(Duration::from_millis(30));
+    runtime.report_wait(Duration::from_millis(10));
+    runtime.report_wait(Duration::from_millis(20));
+    assert_eq!(runtime.min_wait_time, Some(Duration::from_millis(10)));
+    assert_eq!(runtime.waiting_threads, 3);
+    runtime.clear_wait();
+    assert_eq!((runtime.min_wait_time, runtime.waiting_threads), (None, 0));
+
+    // Blocks keep their state in the runtime, and hosts answer questions through the extensions
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"name": ["name", ""], "count": ["count", 0]},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "incr1", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "incr1": {"opcode": "control_incr_counter", "next": "incr2", "parent": "flag",
+                    "inputs": {}, "fields": {}, "topLevel": false},
+                "incr2": {"opcode": "control_incr_counter", "next": "ask", "parent": "incr1",
+                    "inputs": {}, "fields": {}, "topLevel": false},
+                "ask": {"opcode": "sensing_askandwait", "next": "set_name", "parent": "incr2",
+                    "inputs": {"QUESTION": [1, [10, "name?"]]}, "fields": {}, "topLevel": false},
+                "set_name": {"opcode": "data_setvariableto", "next": "set_count", "parent": "ask",
+                    "inputs": {"VALUE": [3, "answer", [10, ""]]},
+                    "fields": {"VARIABLE": ["name", "name"]}, "topLevel": false},
+                "answer": {"opcode": "sensing_answer", "next": null, "parent": "set_name",
+                    "inputs": {}, "fields": {}, "topLevel": false},
+                "set_count": {"opcode": "data_setvariableto", "next": null, "parent": "set_name",
+                    "inputs": {"VALUE": [3, "counter", [10, ""]]},
+                    "fields": {"VARIABLE": ["count", "count"]}, "topLevel": false},
+                "counter": {"opcode": "control_get_counter", "next": null, "parent": "set_count",
+                    "inputs": {}, "fields": {}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    vm.runtime
+        .extensions
+        .insert(Answers(["Ada".to_owned()].into()));
+    vm.start_flag();
+    vm.step();
+    let stage = vm.running_stage_id;
+    assert_eq!(vm.variable(stage, "name"), Some(&"Ada".into()));
+    assert_eq!(vm.variable(stage, "count"), Some(&2.into()));
+    assert_eq!(vm.runtime.counter, 2);
+    assert_eq!(vm.runtime.answer, "Ada");
+    assert!(vm.runtime.extensions.get::<Answers>().unwrap().0.is_empty());
+}
+
+#[test]
+fn test_stop_all() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["received", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "broadcast", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "broadcast": {"opcode": "event_broadcast", "next": "clone", "parent": "flag",
+                    "inputs": {"BROADCAST_INPUT": [1, [11, "go", "b1"]]}, "fields": {},
+                    "topLevel": false},
+                "clone": {"opcode": "control_create_clone_of", "next": "stop",
+                    "parent": "broadcast", "inputs": {"CLONE_OPTION": [1, "menu"]}, "fields": {},
+                    "topLevel": false},
+                "menu": {"opcode": "control_create_clone_of_menu", "next": null,
+                    "parent": "clone", "inputs": {},
+                    "fields": {"CLONE_OPTION": ["_myself_", null]}, "shadow": true,
+                    "topLevel": false},
+                "stop": {"opcode": "control_stop", "next": null, "parent": "clone",
+                    "inputs": {}, "fields": {"STOP_OPTION": ["all", null]}, "topLevel": false,
+                    "mutation": {"tagName": "mutation", "children": [], "hasnext": "false"}},
+                "receive": {"opcode": "event_whenbroadcastreceived", "next": "set",
+                    "parent": null, "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]},
+                    "topLevel": true},
+                "set": {"opcode": "data_setvariableto", "next": null, "parent": "receive",
+                    "inputs": {"VALUE": [1, [10, "1"]]},
+                    "fields": {"VARIABLE": ["received", "v1"]}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    // Messages and clones of the step that stops all are stopped with it
+    vm.start_flag();
+    vm.step();
+    assert!(vm.threads.is_empty());
+    assert_eq!(vm.running_targets.len(), 2);
+    vm.step();
+    assert_eq!(
+        vm.variable(vm.running_stage_id, "received"),
+        Some(&0.into())
+    );
+}
+
+#[test]
+fn test_broadcast_names() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["message", "GO"], "v2": ["received", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {},
+            "costumes": [],
+            "sounds": []
+        }, {
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "broadcast", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "broadcast": {"opcode": "event_broadcastandwait", "next": null, "parent": "flag",
+                    "inputs": {"BROADCAST_INPUT": [3, [12, "message", "v1"], [11, "go", "b1"]]},
+                    "fields": {}, "topLevel": false},
+                "receive": {"opcode": "event_whenbroadcastreceived", "next": "count",
+                    "parent": null, "inputs": {}, "fields": {"BROADCAST_OPTION": ["go", "b1"]},
+                    "topLevel": true},
+                "count": {"opcode": "data_changevariableby", "next": null, "parent": "receive",
+                    "inputs": {"VALUE": [1, [4, "1"]]},
+                    "fields": {"VARIABLE": ["received", "v2"]}, "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let stage = vm.running_stage_id;
+    let received = |vm: &VirtualMachine| vm.variable(stage, "received").unwrap().to_string();
+
+    // A reporter names the message, which matches its name ignoring case
+    vm.start_flag();
+    vm.run_until_idle(std::time::Duration::from_secs(10));
+    assert_eq!(received(&vm), "1");
+    // Hosts broadcast by id or name, unknown messages start nothing
+    assert_eq!(vm.broadcast("b1").len(), 1);
+    assert_eq!(vm.broadcast("Go").len(), 1);
+    assert!(vm.broadcast("stop").is_empty());
+}
+
+#[test]
+fn test_sprite_broadcasts() {
+    use crate::*;
+
+    let project = r#"{
+        "targets": [{
+            "isStage": true,
+            "name": "Stage",
+            "variables": {"v1": ["received", 0]},
+            "lists": {},
+            "broadcasts": {"b1": "go"},
+            "blocks": {
+                "flag": {"opcode": "event_whenflagclicked", "next": "broadcast", "parent": null,
+                    "inputs": {}, "fields": {}, "topLevel": true},
+                "broadcast": {"opcode": "event_broadcast", "next": null, "parent": "flag",
+                    "inputs": {"BROADCAST_INPUT": [1, [11, "go", "b1"]]}, "fields": {},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }],
+        "extensions": []
+    }"#;
+    let sprite = json::parse(
+        r#"{
+            "isStage": false,
+            "name": "Cat",
+            "variables": {},
+            "lists": {},
+            "broadcasts": {"m1": "Go", "b1": "jump"},
+            "blocks": {
+                "receive": {"opcode": "event_whenbroadcastreceived", "next": "count",
+                    "parent": null, "inputs": {}, "fields": {"BROADCAST_OPTION": ["Go", "m1"]},
+                    "topLevel": true},
+                "count": {"opcode": "data_changevariableby", "next": "jump", "parent": "receive",
+                    "inputs": {"VALUE": [1, [4, "1"]]},
+                    "fields": {"VARIABLE": ["received", "v1"]}, "topLevel": false},
+                "jump": {"opcode": "event_broadcast", "next": null, "parent": "count",
+                    "inputs": {"BROADCAST_INPUT": [1, [11, "jump", "b1"]]}, "fields": {},
+                    "topLevel": false}
+            },
+            "costumes": [],
+            "sounds": []
+        }"#,
+    )
+    .unwrap();
+    let mut vm = VirtualMachine::default();
+    let assets = std::collections::HashMap::new();
+    sb3_loader::load_project_json(&mut vm, project, assets).unwrap();
+    let (target_id, _) =
+        sb3_loader::load_sprite(&mut vm, &sprite, std::collections::HashMap::new()).unwrap();
+
+    // "Go" is the stage's "go", "jump" is new and its id is taken by "go"
+    assert!(vm.targets[target_id].broadcasts.is_empty());
+    let broadcasts = &vm.targets[vm.stage_id].broadcasts;
+    assert_eq!(broadcasts["b1"], "go");
+    assert_eq!(broadcasts["b12"], "jump");
+    vm.start_flag();
+    vm.run_until_idle(std::time::Duration::from_secs(10));
+    let stage = vm.running_stage_id;
+    assert_eq!(vm.variable(stage, "received"), Some(&1.into()));
+    vm.rename_broadcast("jump", "leap").unwrap();
+    assert_eq!(vm.targets[vm.stage_id].broadcasts["b12"], "leap");
+}
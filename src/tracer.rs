@@ -0,0 +1,73 @@
+use crate::*;
+use json::JsonValue;
+use std::io::Write;
+use std::time::Duration;
+
+/// Writes a JSON Lines record for each block that finishes running.
+///
+/// Each record has the fields `thread`, `target`, `opcode`, `block` (the index of the
+/// block in its target), `arguments`, `result` and `elapsed_ns`, the time spent in the
+/// last call of the block function, and `sb3_id`, the id of the block in `project.json`.
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    error: Option<std::io::Error>,
+}
+
+impl Tracer {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            error: None,
+        }
+    }
+
+    /// The first write error. The tracer stops writing after an error.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn trace(
+        &mut self,
+        thread_id: usize,
+        target_name: &str,
+        block_id: BlockId,
+        block: &Block,
+        arguments: &[BlockValue],
+        result: &BlockResult,
+        elapsed: Duration,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = match result {
+            BlockResult::Resolved(Some(v)) => block_value_to_json(v),
+            _ => JsonValue::Null,
+        };
+        let record = json::object! {
+            "thread": thread_id,
+            "target": target_name,
+            "opcode": block.opcode.as_str(),
+            "block": block_id.index(),
+            "arguments": arguments.iter().map(block_value_to_json).collect::<Vec<_>>(),
+            "result": result,
+            "elapsed_ns": elapsed.as_nanos() as u64,
+            "sb3_id": block.block_id.as_str(),
+        };
+        if let Err(e) = writeln!(self.writer, "{}", record.dump()) {
+            self.error = Some(e);
+        }
+    }
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("error", &self.error)
+            .finish()
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::atomic::AtomicUsize;
 
-static mut UID: AtomicUsize = AtomicUsize::new(0);
+static UID: AtomicUsize = AtomicUsize::new(0);
 
 pub fn uid() -> usize {
-    unsafe { UID.fetch_add(1, std::sync::atomic::Ordering::SeqCst) }
+    UID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
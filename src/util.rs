@@ -17,15 +17,15 @@ pub fn ret(v: impl Into<BlockValue>) -> BlockResult {
     BlockResult::Resolved(Some(v.into()))
 }
 
-pub fn get_target_xy(ctx: &BlockContext, target_name: &str) -> Option<(f64, f64)> {
+pub fn get_target_xy(ctx: &mut BlockContext, target_name: &str) -> Option<(f64, f64)> {
     match target_name {
         "_mouse_" => {
             // TODO: get mouse position
             Some((0., 0.))
         }
         "_random_" => {
-            let x = unsafe { THREAD_RND.gen_range(-0.5f64..0.5) };
-            let y = unsafe { THREAD_RND.gen_range(-0.5f64..0.5) };
+            let x = ctx.runtime.rng.gen_range(-0.5f64..0.5);
+            let y = ctx.runtime.rng.gen_range(-0.5f64..0.5);
             let x = (x * 480.).round();
             let y = (y * 360.).round();
             Some((x, y))
@@ -41,6 +41,11 @@ pub fn get_target_xy(ctx: &BlockContext, target_name: &str) -> Option<(f64, f64)
     }
 }
 
-pub(crate) fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
-}
\ No newline at end of file
+pub(crate) fn block_value_to_json(v: &BlockValue) -> json::JsonValue {
+    match v {
+        BlockValue::String(s) => s.as_str().into(),
+        BlockValue::Number(n) => (*n).into(),
+        BlockValue::Boolean(b) => (*b).into(),
+        BlockValue::Undefined | BlockValue::BlockId(_) => json::JsonValue::Null,
+    }
+}
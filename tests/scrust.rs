@@ -0,0 +1,54 @@
+//! Runs the `scrust` binary like a batch grader would.
+
+use std::process::Command;
+
+#[test]
+fn test_warnings_keep_stdout_json() {
+    let dir = std::env::temp_dir().join(format!("scrust-warnings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("project.json"),
+        r#"{
+            "targets": [{
+                "isStage": true,
+                "name": "Stage",
+                "variables": {},
+                "lists": {},
+                "blocks": {
+                    "flag": {"opcode": "event_whenflagclicked", "next": "say", "parent": null,
+                        "inputs": {}, "fields": {}, "topLevel": true},
+                    "say": {"opcode": "looks_say", "next": "unknown", "parent": "flag",
+                        "inputs": {"MESSAGE": [1, [10, "Hello"]]}, "fields": {},
+                        "topLevel": false},
+                    "unknown": {"opcode": "example_unknown", "next": null, "parent": "say",
+                        "inputs": {}, "fields": {}, "topLevel": false}
+                },
+                "costumes": [{"name": "backdrop1", "md5ext": "missing.svg"}],
+                "sounds": []
+            }],
+            "extensions": ["example"]
+        }"#,
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_scrust"))
+        .arg(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let results = json::parse(&stdout).unwrap();
+    let result = &results[0];
+    assert_eq!(result["status"], "finished");
+    assert_eq!(result["say"][0]["text"], "Hello");
+    assert_eq!(
+        result["warnings"],
+        json::array![
+            "missing extension: example",
+            "unknown opcode: example_unknown",
+            "missing asset: missing.svg"
+        ]
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unknown opcode: example_unknown"));
+}